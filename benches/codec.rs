@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+use chrono::DateTime;
+use laji_protocols::{daytime_threads as daytime, rakping, simtcp};
+
+const PONG_NAME: &str = "MCPE;Laji Server;331;1.9.0;0;20;12345678;Laji;Survival";
+
+fn rakping_ping(c: &mut Criterion) {
+    let ping = rakping::Ping::new(0x0011_2233_4455_6677, 0x0123_4567_89ab_cdef);
+    let mut buf = [0u8; 64];
+    let len = ping.encode(&mut buf).unwrap();
+    c.bench_function("rakping ping encode", move |b| {
+        let mut out = [0u8; 64];
+        b.iter(|| black_box(&ping).encode(&mut out).unwrap())
+    });
+    c.bench_function("rakping ping decode", move |b| {
+        b.iter(|| rakping::Ping::decode(black_box(&buf[..len])).unwrap())
+    });
+}
+
+fn rakping_pong(c: &mut Criterion) {
+    let pong = rakping::Pong::new(0x0011_2233_4455_6677, 0x0123_4567_89ab_cdef, PONG_NAME);
+    let mut buf = [0u8; 1024];
+    let len = pong.encode(&mut buf).unwrap();
+    c.bench_function("rakping pong encode", move |b| {
+        let mut out = [0u8; 1024];
+        b.iter(|| black_box(&pong).encode(&mut out).unwrap())
+    });
+    c.bench_function("rakping pong decode", move |b| {
+        b.iter(|| rakping::Packet::decode(black_box(&buf[..len])).unwrap())
+    });
+}
+
+fn daytime_codec(c: &mut Criterion) {
+    let line = "Tue, 1 Jul 2003 10:52:37 +0200";
+    c.bench_function("daytime codec encode", move |b| {
+        let mut codec = daytime::Codec::stream();
+        let mut buf = BytesMut::with_capacity(64);
+        b.iter(|| {
+            buf.clear();
+            codec.encode(line.to_string(), &mut buf).unwrap();
+        })
+    });
+    c.bench_function("daytime codec decode", move |b| {
+        let mut codec = daytime::Codec::stream();
+        b.iter(|| {
+            let mut buf = BytesMut::from(&b"Tue, 1 Jul 2003 10:52:37 +0200\r\n"[..]);
+            codec.decode(&mut buf).unwrap()
+        })
+    });
+}
+
+fn daytime_rfc2822(c: &mut Criterion) {
+    let time = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+    c.bench_function("daytime write_rfc2822", move |b| {
+        let mut out = String::with_capacity(64);
+        b.iter(|| {
+            out.clear();
+            daytime::write_rfc2822(&mut out, black_box(&time)).unwrap();
+        })
+    });
+}
+
+fn simtcp_segment(c: &mut Criterion) {
+    let segment = simtcp::Segment {
+        flags: simtcp::ACK,
+        seq: 0x0123_4567,
+        ack: 0x89ab_cdef,
+        window: 0x4000,
+        payload: vec![0x55; 1024],
+        ..simtcp::Segment::default()
+    };
+    let mut datagram = Vec::new();
+    segment.encode(&mut datagram);
+    c.bench_function("simtcp segment encode", move |b| {
+        let mut out = Vec::with_capacity(2048);
+        b.iter(|| {
+            out.clear();
+            black_box(&segment).encode(&mut out);
+        })
+    });
+    c.bench_function("simtcp segment decode", move |b| {
+        b.iter(|| simtcp::Segment::decode(black_box(&datagram)).unwrap())
+    });
+}
+
+criterion_group!(benches, rakping_ping, rakping_pong, daytime_codec, daytime_rfc2822, simtcp_segment);
+criterion_main!(benches);
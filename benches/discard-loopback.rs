@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, Benchmark, Criterion, Throughput};
+use std::{io::Write, net::{SocketAddr, TcpStream}};
+use laji_protocols::{discard_mio, discard_sync};
+
+static PAYLOAD: [u8; 64 * 1024] = [0x55; 64 * 1024];
+
+fn connect_and_send(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let _ = stream.write_all(&PAYLOAD);
+}
+
+fn discard_sync_loopback(c: &mut Criterion) {
+    let server = discard_sync::listen_spawned("127.0.0.1:0", || |_| {}).unwrap();
+    let addr = server.local_addrs()[0];
+    c.bench("discard loopback", Benchmark::new("sync", move |b| {
+        b.iter(|| connect_and_send(addr))
+    }).throughput(Throughput::Bytes(PAYLOAD.len() as u32)));
+    server.stop().unwrap();
+}
+
+fn discard_mio_loopback(c: &mut Criterion) {
+    let server = discard_mio::listen_spawned("127.0.0.1:0", || |_| {}).unwrap();
+    let addr = server.local_addrs()[0];
+    c.bench("discard loopback", Benchmark::new("mio", move |b| {
+        b.iter(|| connect_and_send(addr))
+    }).throughput(Throughput::Bytes(PAYLOAD.len() as u32)));
+    server.stop().unwrap();
+}
+
+criterion_group!(benches, discard_sync_loopback, discard_mio_loopback);
+criterion_main!(benches);
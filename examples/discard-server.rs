@@ -1,29 +1,31 @@
 use laji_protocols::discard_mio as discard;
 
+#[derive(Clone)]
 struct MyFactory;
 impl discard::Factory for MyFactory {
     type Handler = MyHandler;
-    fn connection_made(&mut self) -> MyHandler {
-        MyHandler(None)
+    fn connection_made(&mut self, info: &discard::ConnectionInfo) -> MyHandler {
+        MyHandler(*info)
     }
 }
-struct MyHandler(Option<discard::Handshake>);
+struct MyHandler(discard::ConnectionInfo);
 impl discard::Handler for MyHandler {
     fn on_open(&mut self, shake: discard::Handshake) {
-        println!("[{} -> {}]: Open!", shake.peer_addr(), shake.local_addr());
-        self.0 = Some(shake);
+        println!("#{} [{} -> {}]: Open!", self.0.id(), shake.peer_addr(), shake.local_addr());
     }
     fn on_close(&mut self) {
-        let shake = self.0.unwrap();
-        println!("[{} -> {}]: Close!", shake.peer_addr(), shake.local_addr());
+        let info = self.0;
+        println!("#{} [{} -> {}]: Close!", info.id(), info.peer_addr(), info.local_addr());
     }
 }
 
 fn main() {
     discard::Builder::new()
-        .bind("0.0.0.0:9").unwrap()
+        .bind_default_ipv4().unwrap()
         .bind("0.0.0.0:999").unwrap()
         .bind("0.0.0.0:9999").unwrap()
+        .bind_udp("0.0.0.0:9").unwrap()
+        .workers(4)
         .build(MyFactory).unwrap()
         .run().unwrap();
 }
@@ -1 +1,14 @@
-fn main () {}
\ No newline at end of file
+use laji_protocols::simtcp;
+use std::io::{Read, Write};
+
+fn main() {
+    let message = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let mut stream = simtcp::connect("127.0.0.1:7070").unwrap();
+    println!("Connected {} -> {}", stream.local_addr(), stream.peer_addr());
+    stream.write_all(message.as_bytes()).unwrap();
+    stream.shutdown().unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    stream.close().unwrap();
+    println!("{}", answer);
+}
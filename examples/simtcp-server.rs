@@ -1 +1,29 @@
-fn main () {}
\ No newline at end of file
+use laji_protocols::simtcp;
+use std::io::{self, Read, Write};
+
+struct Upper;
+impl simtcp::Handler for Upper {
+    fn on_open(&mut self, shake: simtcp::Handshake) {
+        println!("[{} -> {}]: Open!", shake.peer_addr(), shake.local_addr());
+    }
+    fn on_stream(&mut self, stream: &mut simtcp::Stream) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = stream.read(&mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            stream.write_all(&buf[..len].to_ascii_uppercase())?;
+        }
+    }
+    fn on_error(&mut self, err: io::Error) {
+        println!("Error: {}", err);
+    }
+    fn on_close(&mut self) {
+        println!("Close!");
+    }
+}
+
+fn main() {
+    simtcp::listen("0.0.0.0:7070", || Upper).unwrap();
+}
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::dhcp::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode(data) {
+        let mut buf = [0u8; 1500];
+        if let Ok(len) = packet.encode(&mut buf) {
+            assert_eq!(Packet::decode(&buf[..len]).unwrap(), packet);
+        }
+    }
+});
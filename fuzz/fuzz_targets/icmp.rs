@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::icmp::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::decode(data) {
+        let mut buf = vec![0u8; data.len()];
+        let len = message.encode(&mut buf).unwrap();
+        assert_eq!(Message::decode(&buf[..len]).unwrap(), message);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::irc::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let line = match std::str::from_utf8(data) {
+        Ok(line) => line,
+        Err(_) => return,
+    };
+    if let Ok(message) = Message::parse(line) {
+        assert_eq!(Message::parse(&message.to_string()).unwrap(), message);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::modbus_tcp::{self, Memory, Request};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = Request::decode(data) {
+        let mut buf = [0u8; 253];
+        let len = request.encode(&mut buf).unwrap();
+        assert_eq!(Request::decode(&buf[..len]), Ok(request));
+    }
+    // whatever comes in, the server must answer without panicking
+    let mut out = [0u8; 253];
+    modbus_tcp::process(&mut Memory::new(16, 16), data, &mut out);
+});
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::nbns::Response;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Response::decode(data);
+});
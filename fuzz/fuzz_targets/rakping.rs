@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::rakping::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode(data) {
+        let mut buf = vec![0u8; data.len()];
+        let len = packet.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..len]).unwrap(), packet);
+    }
+});
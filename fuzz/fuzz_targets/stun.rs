@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use laji_protocols::stun::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = Message::decode(data) {
+        // padding and oversized attributes may not survive, so only compare what re-encodes
+        let mut buf = vec![0u8; data.len() + 4 * msg.attributes().len()];
+        if let Ok(len) = msg.encode(&mut buf) {
+            assert_eq!(Message::decode(&buf[..len]).unwrap(), msg);
+        }
+    }
+});
@@ -0,0 +1,218 @@
+//! A tiny HTTP status endpoint reporting what a server's `metrics::Recorder`s counted.
+//!
+//! `GET /` (or `/status`) answers with JSON such as
+//!
+//! ```text
+//! {"uptime_secs":42,"listeners":[{"name":"discard","opened":3,"closed":2,"rejected":0,
+//!  "active":1,"bytes_received":4096,"connections":[{"peer":"127.0.0.1:50312","local":"127.0.0.1:9"}]}]}
+//! ```
+//!
+//! and `GET /metrics` with the same numbers in the Prometheus text format.
+//!
+//! There is no HTTP module in this crate yet, so the server only speaks enough HTTP/1.0 for
+//! curl and monitoring probes: one request per connection, each answered on a thread of its
+//! own so a client that sends its request slowly holds up nobody else.
+use std::{
+    fmt::Write as _,
+    io::{self, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use crate::{framing::{LineError, LineReader}, metrics::Recorder, prometheus};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADER_LINES: usize = 64;
+const MAX_LINE_LEN: usize = 8192;
+
+pub fn listen<A>(addr: A, status: Status) -> io::Result<()>
+where
+    A: ToSocketAddrs
+{
+    LajiAdmin::bind(addr, status)?.run()
+}
+
+/// The listeners a status page reports on, with the time it started counting uptime from.
+#[derive(Clone, Debug)]
+pub struct Status {
+    started: Instant,
+    listeners: Arc<Vec<(String, Recorder)>>,
+}
+
+impl Status {
+    #[inline]
+    pub fn new() -> Self {
+        Self { started: Instant::now(), listeners: Arc::new(Vec::new()) }
+    }
+
+    /// Report `recorder` under `name`, in the order listeners were added.
+    #[inline]
+    pub fn listener<S>(mut self, name: S, recorder: Recorder) -> Self
+    where S: Into<String>
+    {
+        Arc::make_mut(&mut self.listeners).push((name.into(), recorder));
+        self
+    }
+
+    /// Every `(name, recorder)`, in the order they were added.
+    #[inline]
+    pub fn listeners(&self) -> impl Iterator<Item = (&str, &Recorder)> {
+        self.listeners.iter().map(|(name, recorder)| (name.as_str(), recorder))
+    }
+
+    #[inline]
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"uptime_secs\":{},\"listeners\":[", self.uptime().as_secs());
+        for (i, (name, recorder)) in self.listeners().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_str(&mut json, name);
+            let _ = write!(json, ",\"opened\":{},\"closed\":{},\"rejected\":{},\"active\":{},\"bytes_received\":{},\"connections\":[",
+                recorder.opened(), recorder.closed(), recorder.rejected(), recorder.active(), recorder.total_bytes_received());
+            for (j, (peer, local)) in recorder.active_connections().iter().enumerate() {
+                if j > 0 {
+                    json.push(',');
+                }
+                let _ = write!(json, "{{\"peer\":\"{}\",\"local\":\"{}\"}}", peer, local);
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl Default for Status {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct LajiAdmin {
+    listener: TcpListener,
+    status: Status,
+}
+
+impl LajiAdmin {
+    #[inline]
+    pub fn bind<A>(addr: A, status: Status) -> io::Result<Self>
+    where A: ToSocketAddrs
+    {
+        Ok(Self { listener: TcpListener::bind(addr)?, status })
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answer requests for as long as the listener lives; failed accepts are skipped.
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let status = self.status.clone();
+            // a client that misbehaves only loses its own answer
+            thread::spawn(move || drop(respond(stream, &status)));
+        }
+        Ok(())
+    }
+}
+
+fn respond(stream: TcpStream, status: &Status) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = LineReader::new(BufReader::new(&stream)).max_len(MAX_LINE_LEN);
+    let request_line = reader.read_line()?.unwrap_or_default();
+    // skip the headers, there is nothing in them we need
+    for _ in 0..MAX_HEADER_LINES {
+        match reader.read_line() {
+            Ok(Some(line)) if !line.is_empty() => {}
+            // a header too long for us is still just a header
+            Err(LineError::TooLong(_)) => {}
+            Ok(_) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let json = "application/json";
+    let (code, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/")) | (Some("GET"), Some("/status")) => ("200 OK", json, status.to_json()),
+        (Some("GET"), Some("/metrics")) => ("200 OK", prometheus::CONTENT_TYPE, prometheus::render(status)),
+        (Some("GET"), Some(_)) => ("404 Not Found", json, "{\"error\":\"not found\"}".to_string()),
+        _ => ("405 Method Not Allowed", json, "{\"error\":\"method not allowed\"}".to_string()),
+    };
+    let mut out = &stream;
+    write!(out, "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code, content_type, body.len(), body)?;
+    out.flush()
+}
+
+pub(crate) fn push_json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(json, "\\u{:04x}", c as u32); }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, thread};
+
+    fn get(addr: SocketAddr, path: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn status_page() -> io::Result<()> {
+        let discard = Recorder::new();
+        let peer: SocketAddr = "127.0.0.1:50312".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        discard.connection_opened(peer, local);
+        discard.connection_opened("127.0.0.1:50313".parse().unwrap(), local);
+        discard.connection_closed("127.0.0.1:50313".parse().unwrap(), local);
+        discard.bytes_received(4096);
+        discard.connection_rejected();
+        let status = Status::new().listener("discard", discard).listener("dis\"card", Recorder::new());
+        let server = LajiAdmin::bind("127.0.0.1:0", status)?;
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run().unwrap());
+        // a client that never sends its request holds up nobody else
+        let _silent = TcpStream::connect(addr)?;
+
+        let response = get(addr, "/status")?;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert_eq!(body, concat!("{\"uptime_secs\":0,\"listeners\":[",
+            "{\"name\":\"discard\",\"opened\":2,\"closed\":1,\"rejected\":1,\"active\":1,\"bytes_received\":4096,",
+            "\"connections\":[{\"peer\":\"127.0.0.1:50312\",\"local\":\"127.0.0.1:9\"}]},",
+            "{\"name\":\"dis\\\"card\",\"opened\":0,\"closed\":0,\"rejected\":0,\"active\":0,\"bytes_received\":0,",
+            "\"connections\":[]}]}"));
+        let metrics = get(addr, "/metrics")?;
+        assert!(metrics.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(metrics.contains("\nlaji_connections_active{listener=\"discard\"} 1\n"));
+        assert!(get(addr, "/nope")?.starts_with("HTTP/1.0 404 Not Found\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::io;
+#[cfg(any(feature = "daytime", feature = "discard"))]
+use crate::config::ConfigError;
+
+/// Pin the calling thread to one CPU core. Cores past `CPU_SETSIZE` are `InvalidInput`.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(core: usize) -> io::Result<()> {
+    use std::mem;
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "cpu core out of range"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Pin the calling thread to one CPU core.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "cpu affinity is not supported on this platform"))
+}
+
+/// Cores handed out round-robin to the threads a backend spawns.
+#[cfg(any(feature = "daytime", feature = "discard", feature = "modbus"))]
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub(crate) struct CoreList {
+    cores: Vec<usize>,
+    next: usize,
+}
+
+#[cfg(any(feature = "daytime", feature = "discard", feature = "modbus"))]
+impl CoreList {
+    #[inline]
+    pub(crate) fn new<I>(cores: I) -> Self 
+    where I: IntoIterator<Item = usize> 
+    {
+        Self { cores: cores.into_iter().collect(), next: 0 }
+    }
+
+    /// Every core can be pinned to, as far as that is known before trying.
+    #[cfg(any(feature = "daytime", feature = "discard"))]
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.cores.iter().any(|&core| core >= libc::CPU_SETSIZE as usize) {
+                return Err(ConfigError::Conflict("cpu core out of range"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The core for the next spawned thread, if any were configured.
+    #[inline]
+    pub(crate) fn next_core(&mut self) -> Option<usize> {
+        if self.cores.is_empty() {
+            return None;
+        }
+        let core = self.cores[self.next % self.cores.len()];
+        self.next += 1;
+        Some(core)
+    }
+}
+
+/// Pin the calling thread if a core was assigned to it.
+#[cfg(any(feature = "daytime", feature = "discard", feature = "modbus"))]
+#[inline]
+pub(crate) fn pin_to(core: Option<usize>) -> io::Result<()> {
+    match core {
+        Some(core) => pin_current_thread(core),
+        None => Ok(()),
+    }
+}
+
+#[cfg(all(test, target_os = "linux", any(feature = "daytime", feature = "discard")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_and_rotate() {
+        let mut cores = CoreList::new(vec![0]);
+        assert_eq!(cores.next_core(), Some(0));
+        assert_eq!(cores.next_core(), Some(0));
+        assert_eq!(CoreList::default().next_core(), None);
+        assert_eq!(cores.validate(), Ok(()));
+        let past = CoreList::new(vec![0, libc::CPU_SETSIZE as usize]);
+        assert_eq!(past.validate(), Err(ConfigError::Conflict("cpu core out of range")));
+        std::thread::spawn(|| pin_current_thread(0).unwrap()).join().unwrap();
+        let err = pin_current_thread(libc::CPU_SETSIZE as usize).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
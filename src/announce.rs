@@ -0,0 +1,284 @@
+//! Multicasting the time to a group, for networks with no time server to ask, and listening
+//! for it.
+//!
+//! An `Announcer` sends the time every interval as a daytime line, an RFC 868 timestamp or
+//! an NTP broadcast-mode packet; a `Listener` joins the group and parses what arrives. The
+//! hop limit stays at the system default of one, so announcements stay on the local network.
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use crate::{
+    clock::{Clock, SystemClock},
+    daytime_threads::{ResponseBuf, Rfc2822, TimeFormat},
+    drift::{self, Protocol},
+    server::ServerHandle,
+    timestamp::{self, NtpTimestamp},
+    wire::{invalid_data, ByteReader, ByteWriter},
+};
+
+/// The group NTP broadcast clients listen on, ntp.mcast.net; announce there on `ports::NTP`.
+pub const NTP_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+
+const NTP_PACKET_LEN: usize = 48;
+// leap indicator 0, version 4, mode 5 (broadcast)
+const NTP_BROADCAST: u8 = 0b00_100_101;
+// an undisciplined local clock, as ntpd's LOCAL driver reports itself
+const NTP_STRATUM: u8 = 10;
+const NTP_REFERENCE_ID: &[u8; 4] = b"LOCL";
+// log2 seconds, about a microsecond
+const NTP_PRECISION: i8 = -20;
+const MAX_ANNOUNCEMENT_LEN: usize = 512;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Format {
+    /// A line of text, the way a daytime server answers (RFC 867).
+    Daytime,
+    /// 32-bit seconds since 1900 (RFC 868).
+    Time,
+    /// An NTP packet in broadcast mode (RFC 5905).
+    Ntp,
+}
+
+/// Sends the time to a multicast group every interval.
+pub struct Announcer {
+    socket: UdpSocket,
+    group: SocketAddr,
+    format: Format,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    time_format: Arc<dyn TimeFormat>,
+}
+
+impl Announcer {
+    /// Announce to `group` from a socket of its own, as daytime lines once a minute.
+    pub fn new<A>(group: A) -> io::Result<Self>
+    where A: Into<SocketAddr>
+    {
+        let group = group.into();
+        if !group.ip().is_multicast() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a multicast group"));
+        }
+        let unspecified = match group {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        Ok(Self {
+            socket: UdpSocket::bind((unspecified, 0))?,
+            group,
+            format: Format::Daytime,
+            interval: Duration::from_secs(60),
+            clock: Arc::new(SystemClock),
+            time_format: Arc::new(Rfc2822),
+        })
+    }
+
+    #[inline]
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    #[inline]
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where C: Clock + 'static
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// How `Format::Daytime` lines render the time; RFC 2822 by default.
+    #[inline]
+    pub fn time_format<T>(mut self, format: T) -> Self
+    where T: TimeFormat + 'static
+    {
+        self.time_format = Arc::new(format);
+        self
+    }
+
+    /// Send one announcement now.
+    pub fn announce(&self) -> io::Result<usize> {
+        let mut buf = [0u8; MAX_ANNOUNCEMENT_LEN];
+        let len = self.encode(&self.clock.now(), &mut buf)?;
+        self.socket.send_to(&buf[..len], self.group)
+    }
+
+    fn encode(&self, now: &DateTime<FixedOffset>, buf: &mut [u8]) -> io::Result<usize> {
+        let mut writer = ByteWriter::new(buf);
+        match self.format {
+            Format::Daytime => {
+                let mut line = ResponseBuf::new();
+                self.time_format.write_time(&mut line, now)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "response too long"))?;
+                writer.write_bytes(line.as_bytes())?;
+                writer.write_bytes(b"\r\n")?;
+            }
+            Format::Time => writer.write_u32_be(timestamp::to_rfc868(SystemTime::from(*now)))?,
+            Format::Ntp => {
+                let sent = NtpTimestamp::from_system_time(SystemTime::from(*now)).to_bits();
+                // one poll interval, in log2 seconds
+                let poll = (64 - self.interval.as_secs().max(1).leading_zeros() - 1) as u8;
+                writer.reserve(NTP_PACKET_LEN)?;
+                writer.write_u8(NTP_BROADCAST)?;
+                writer.write_u8(NTP_STRATUM)?;
+                writer.write_u8(poll)?;
+                writer.write_u8(NTP_PRECISION as u8)?;
+                // root delay and dispersion
+                writer.fill(0, 8)?;
+                writer.write_bytes(NTP_REFERENCE_ID)?;
+                // reference, then origin and receive, which a broadcast has none of
+                writer.write_u64_be(sent)?;
+                writer.fill(0, 16)?;
+                writer.write_u64_be(sent)?;
+            }
+        }
+        Ok(writer.position())
+    }
+
+    /// Announce every `interval` until sending fails.
+    pub fn run(self) -> io::Result<()> {
+        loop {
+            self.announce()?;
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// `run` on a thread of its own.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let local_addrs = vec![self.socket.local_addr()?];
+        ServerHandle::spawn("laji-announcer", local_addrs, move || self.run())
+    }
+}
+
+/// One announcement a `Listener` heard.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Announcement {
+    pub from: SocketAddr,
+    pub time: DateTime<FixedOffset>,
+}
+
+/// A member of a multicast group, reading the announcements sent there.
+#[derive(Debug)]
+pub struct Listener {
+    socket: UdpSocket,
+    format: Format,
+}
+
+impl Listener {
+    /// Join `group` on every interface and wait for announcements in `format` on its port.
+    pub fn join<A>(group: A, format: Format) -> io::Result<Self>
+    where A: Into<SocketAddr>
+    {
+        let socket = match group.into() {
+            SocketAddr::V4(group) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+                socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+                socket
+            }
+            SocketAddr::V6(group) => {
+                let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, group.port()))?;
+                socket.join_multicast_v6(group.ip(), 0)?;
+                socket
+            }
+        };
+        Ok(Self { socket, format })
+    }
+
+    /// Where this listener was bound, with the port the system picked for port 0.
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    #[inline]
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Wait for the next announcement. One that does not parse is `InvalidData`, and the
+    /// listener can go on receiving after it.
+    pub fn recv(&self) -> io::Result<Announcement> {
+        let mut buf = [0u8; MAX_ANNOUNCEMENT_LEN];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        let time = parse(self.format, &buf[..len], SystemTime::now())?;
+        Ok(Announcement { from, time })
+    }
+}
+
+/// The time `datagram` announces; `near`, the local time, picks the era of the 32-bit formats.
+pub fn parse(format: Format, datagram: &[u8], near: SystemTime) -> io::Result<DateTime<FixedOffset>> {
+    let near_utc = DateTime::<Utc>::from(near).with_timezone(&FixedOffset::east(0));
+    let parsed = match format {
+        Format::Daytime => drift::parse(Protocol::Daytime, datagram, near_utc),
+        Format::Time if datagram.len() == 4 => drift::parse(Protocol::Time, datagram, near_utc),
+        Format::Time => return Err(invalid_data("not a time protocol timestamp")),
+        Format::Ntp => {
+            let mut reader = ByteReader::new(datagram);
+            if reader.read_u8()? & 0b111 != NTP_BROADCAST & 0b111 {
+                return Err(invalid_data("not an NTP broadcast"));
+            }
+            reader.skip(39)?;
+            let sent = NtpTimestamp::from_bits(reader.read_u64_be()?).to_system_time(near);
+            Some(DateTime::<Utc>::from(sent).with_timezone(&FixedOffset::east(0)))
+        }
+    };
+    parsed.ok_or_else(|| invalid_data("announcement is not a time"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn formats_round_trip() -> io::Result<()> {
+        let now = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+        let near = SystemTime::from(now);
+        for &format in &[Format::Daytime, Format::Time, Format::Ntp] {
+            let announcer = Announcer::new((Ipv4Addr::new(239, 255, 13, 37), 1337))?
+                .format(format)
+                .interval(Duration::from_secs(64));
+            let mut buf = [0u8; MAX_ANNOUNCEMENT_LEN];
+            let len = announcer.encode(&now, &mut buf)?;
+            assert_eq!(parse(format, &buf[..len], near)?, now, "{:?}", format);
+            if format == Format::Ntp {
+                assert_eq!(len, NTP_PACKET_LEN);
+                assert_eq!(&buf[..4], [0x25, 10, 6, 0xec]);
+                assert_eq!(&buf[12..16], b"LOCL");
+            }
+        }
+        assert_eq!(parse(Format::Daytime, b"Tue, 1 Jul 2003 10:52:37 +0200\r\n", near)?, now);
+        assert_eq!(parse(Format::Time, b"\x00\x00", near).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // a client-mode packet is not an announcement
+        assert!(parse(Format::Ntp, &[0x23; NTP_PACKET_LEN], near).is_err());
+        assert!(Announcer::new(([127, 0, 0, 1], 1337)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn multicast_loopback() -> io::Result<()> {
+        let group = Ipv4Addr::new(239, 255, 13, 37);
+        let listener = Listener::join((group, 0), Format::Ntp)?;
+        listener.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let port = listener.local_addr()?.port();
+        let now = DateTime::parse_from_rfc3339("2036-02-07T06:28:20Z").unwrap();
+        Announcer::new((group, port))?
+            .format(Format::Ntp)
+            .clock(ManualClock::new(now))
+            .announce()?;
+        let heard = listener.recv()?;
+        // past the NTP rollover, seen from a clock still before it
+        assert_eq!(heard.time, now);
+        Ok(())
+    }
+}
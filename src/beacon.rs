@@ -0,0 +1,355 @@
+//! A discovery protocol of this crate's own: servers multicast a beacon listing the services
+//! they run, and `discover` collects the beacons heard for a while, one per server.
+//!
+//! A beacon is one datagram, integers in network order:
+//!
+//! ```text
+//! "LAJI"  version  service count  instance id (8)  host name
+//! then per service:  transport  port (2)  protocol  instance name
+//! then a zero byte if that left the length odd, and an RFC 1071 checksum over it all
+//! ```
+//!
+//! where the names are a length byte and that many bytes of UTF-8. The magic, version and
+//! checksum keep stray datagrams and damaged beacons out; nothing authenticates the sender,
+//! so treat what is discovered as a hint, not as proof of who runs what.
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use crate::{checksum, server::ServerHandle, wire::{invalid_data, ByteReader, ByteWriter}};
+
+pub const MAGIC: [u8; 4] = *b"LAJI";
+pub const VERSION: u8 = 1;
+/// The group beacons go to by default, in the site-local scope.
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 76, 74);
+/// Not assigned by IANA; any port works as long as servers and clients agree.
+pub const PORT: u16 = 45354;
+
+pub const MAX_BEACON_LEN: usize = 1200;
+const MAX_SERVICES: usize = 255;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    #[inline]
+    fn to_u8(self) -> u8 {
+        match self {
+            Transport::Tcp => 6,
+            Transport::Udp => 17,
+        }
+    }
+
+    #[inline]
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            6 => Some(Transport::Tcp),
+            17 => Some(Transport::Udp),
+            _ => None,
+        }
+    }
+}
+
+/// One service a server offers, on the address the beacon came from.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Service {
+    /// Lowercase, as the crate's features are named: `"daytime"`, `"discard"`.
+    pub protocol: String,
+    pub transport: Transport,
+    pub port: u16,
+    /// What the operator calls this instance; may be empty.
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Beacon {
+    /// Picked once per server, so beacons from the same server can be told apart from
+    /// others sent from the same address.
+    pub instance: u64,
+    pub host: String,
+    pub services: Vec<Service>,
+}
+
+impl Beacon {
+    #[inline]
+    pub fn new<S>(instance: u64, host: S) -> Self
+    where S: Into<String>
+    {
+        Self { instance, host: host.into(), services: Vec::new() }
+    }
+
+    #[inline]
+    pub fn service<P, N>(mut self, protocol: P, transport: Transport, port: u16, name: N) -> Self
+    where P: Into<String>, N: Into<String>
+    {
+        self.services.push(Service { protocol: protocol.into(), transport, port, name: name.into() });
+        self
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.services.len() > MAX_SERVICES {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many services for one beacon"));
+        }
+        let mut writer = ByteWriter::new(buf);
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u8(self.services.len() as u8)?;
+        writer.write_u64_be(self.instance)?;
+        write_name(&mut writer, &self.host)?;
+        for service in &self.services {
+            writer.write_u8(service.transport.to_u8())?;
+            writer.write_u16_be(service.port)?;
+            write_name(&mut writer, &service.protocol)?;
+            write_name(&mut writer, &service.name)?;
+        }
+        if !writer.position().is_multiple_of(2) {
+            writer.write_u8(0)?;
+        }
+        let sum = checksum::checksum(writer.written());
+        writer.write_u16_be(sum)?;
+        Ok(writer.position())
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut reader = ByteReader::new(buf);
+        if reader.take(4)? != MAGIC {
+            return Err(invalid_data("not a laji beacon"));
+        }
+        if reader.read_u8()? != VERSION {
+            return Err(invalid_data("unsupported beacon version"));
+        }
+        // with the checksum appended, the whole beacon sums to zero
+        if !buf.len().is_multiple_of(2) || checksum::checksum(buf) != 0 {
+            return Err(invalid_data("beacon checksum mismatch"));
+        }
+        let count = reader.read_u8()?;
+        let instance = reader.read_u64_be()?;
+        let host = read_name(&mut reader)?;
+        let mut services = Vec::with_capacity(count.into());
+        for _ in 0..count {
+            let transport = Transport::from_u8(reader.read_u8()?)
+                .ok_or_else(|| invalid_data("unknown transport"))?;
+            let port = reader.read_u16_be()?;
+            let protocol = read_name(&mut reader)?;
+            let name = read_name(&mut reader)?;
+            services.push(Service { protocol, transport, port, name });
+        }
+        match reader.remaining() {
+            2 => (),
+            3 if reader.read_u8()? == 0 => (),
+            _ => return Err(invalid_data("beacon length mismatch")),
+        }
+        Ok(Self { instance, host, services })
+    }
+}
+
+fn write_name(writer: &mut ByteWriter, name: &str) -> io::Result<()> {
+    if name.len() > usize::from(u8::MAX) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "name longer than 255 bytes"));
+    }
+    writer.write_u8(name.len() as u8)?;
+    writer.write_bytes(name.as_bytes())
+}
+
+fn read_name(reader: &mut ByteReader) -> io::Result<String> {
+    let len = reader.read_u8()?;
+    let bytes = reader.take(len.into())?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("name is not UTF-8"))
+}
+
+/// Sends a beacon every interval; clones of `beacon()` change what it sends.
+#[derive(Debug)]
+pub struct Advertiser {
+    socket: UdpSocket,
+    target: SocketAddr,
+    interval: Duration,
+    beacon: Arc<Mutex<Beacon>>,
+}
+
+impl Advertiser {
+    /// Advertise `beacon` to `GROUP` on `PORT` every ten seconds.
+    pub fn new(beacon: Beacon) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        // so a broadcast `target` works as well as a group
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            target: (GROUP, PORT).into(),
+            interval: Duration::from_secs(10),
+            beacon: Arc::new(Mutex::new(beacon)),
+        })
+    }
+
+    /// Where beacons go: another group, or a broadcast address for networks that drop
+    /// multicast.
+    #[inline]
+    pub fn target<A>(mut self, addr: A) -> Self
+    where A: Into<SocketAddr>
+    {
+        self.target = addr.into();
+        self
+    }
+
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The beacon being sent, to add or remove services while the advertiser runs.
+    #[inline]
+    pub fn beacon(&self) -> Arc<Mutex<Beacon>> {
+        self.beacon.clone()
+    }
+
+    /// Send the beacon once, now.
+    pub fn advertise(&self) -> io::Result<usize> {
+        let mut buf = [0u8; MAX_BEACON_LEN];
+        let len = self.beacon.lock().unwrap().encode(&mut buf)?;
+        self.socket.send_to(&buf[..len], self.target)
+    }
+
+    /// Advertise every `interval` until sending fails.
+    pub fn run(self) -> io::Result<()> {
+        loop {
+            self.advertise()?;
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// `run` on a thread of its own.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let local_addrs = vec![self.socket.local_addr()?];
+        ServerHandle::spawn("laji-beacon", local_addrs, move || self.run())
+    }
+}
+
+/// A server heard from, and the last beacon it sent.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Found {
+    pub from: SocketAddr,
+    pub beacon: Beacon,
+}
+
+/// Listen to `GROUP` on `PORT` for `timeout`, and return every server heard.
+#[inline]
+pub fn discover(timeout: Duration) -> io::Result<Vec<Found>> {
+    Discovery::join((GROUP, PORT))?.collect(timeout)
+}
+
+/// A member of a beacon group, collecting what is advertised there.
+#[derive(Debug)]
+pub struct Discovery {
+    socket: UdpSocket,
+}
+
+impl Discovery {
+    /// Join the IPv4 `group` on every interface, listening on its port; a broadcast or
+    /// unicast address listens without joining anything.
+    pub fn join<A>(group: A) -> io::Result<Self>
+    where A: Into<SocketAddr>
+    {
+        let group = group.into();
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+        if let SocketAddr::V4(group) = group {
+            if group.ip().is_multicast() {
+                socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            }
+        }
+        Ok(Self { socket })
+    }
+
+    /// Where this listener was bound, with the port the system picked for port 0.
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Collect beacons for `timeout`, in the order servers were first heard, one per
+    /// server instance. Datagrams that are not beacons are skipped.
+    pub fn collect(&self, timeout: Duration) -> io::Result<Vec<Found>> {
+        let deadline = Instant::now() + timeout;
+        let mut found: Vec<Found> = Vec::new();
+        let mut buf = [0u8; MAX_BEACON_LEN];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(found);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    return Ok(found),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let beacon = match Beacon::decode(&buf[..len]) {
+                Ok(beacon) => beacon,
+                Err(_) => continue,
+            };
+            match found.iter_mut().find(|seen| seen.beacon.instance == beacon.instance) {
+                Some(seen) => *seen = Found { from, beacon },
+                None => found.push(Found { from, beacon }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lab() -> Beacon {
+        Beacon::new(0x1a71_0000_0000_0001, "lab-7")
+            .service("daytime", Transport::Tcp, 13, "")
+            .service("daytime", Transport::Udp, 13, "")
+            .service("discard", Transport::Tcp, 9999, "load test sink")
+    }
+
+    #[test]
+    fn encode_decode() -> io::Result<()> {
+        let beacon = lab();
+        let mut buf = [0u8; MAX_BEACON_LEN];
+        let len = beacon.encode(&mut buf)?;
+        assert_eq!(&buf[..6], b"LAJI\x01\x03");
+        assert_eq!(Beacon::decode(&buf[..len])?, beacon);
+        // every damaged byte is caught, and so is a short beacon
+        for i in 0..len {
+            let mut damaged = buf;
+            damaged[i] ^= 0x40;
+            assert!(Beacon::decode(&damaged[..len]).is_err(), "byte {}", i);
+        }
+        assert!(Beacon::decode(&buf[..len - 2]).is_err());
+        assert!(Beacon::decode(b"LAJ").is_err());
+        let long = Beacon::new(1, "x".repeat(256));
+        assert_eq!(long.encode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn discover_loopback() -> io::Result<()> {
+        let discovery = Discovery::join((GROUP, 0))?;
+        let target = (GROUP, discovery.local_addr()?.port());
+        let advertiser = Advertiser::new(lab())?.target(target);
+        advertiser.advertise()?;
+        let other = Advertiser::new(Beacon::new(2, "lab-8").service("echo", Transport::Udp, 7, ""))?
+            .target(target);
+        other.advertise()?;
+        // the same server again, now with one service less
+        advertiser.beacon().lock().unwrap().services.pop();
+        advertiser.advertise()?;
+        let found = discovery.collect(Duration::from_millis(300))?;
+        let hosts: Vec<_> = found.iter()
+            .map(|found| (found.beacon.host.as_str(), found.beacon.services.len()))
+            .collect();
+        assert_eq!(hosts, [("lab-7", 2), ("lab-8", 1)]);
+        Ok(())
+    }
+}
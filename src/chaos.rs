@@ -0,0 +1,369 @@
+//! A relay that sits in front of a server and misbehaves on purpose, for testing clients.
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    thread,
+    sync::mpsc,
+    time::Duration,
+};
+use smallvec::SmallVec;
+
+const INLINE_LISTENERS: usize = 4;
+const MAX_DATAGRAM_LEN: usize = 65507;
+// how long a relayed UDP request waits for more replies from upstream
+const UDP_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Relay TCP and UDP on `addr` to `upstream`, faults configured through `LajiChaos`.
+pub fn listen<A, B>(addr: A, upstream: B) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs
+{
+    LajiChaos::new(upstream)?
+        .bind_tcp(&addr)?
+        .bind_udp(&addr)?
+        .run()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Faults {
+    reset: f64,
+    truncate: f64,
+    duplicate: f64,
+    delay: f64,
+    delay_by: Duration,
+}
+
+/// Each fault is given as the chance, from 0 to 1, that it hits one connection or datagram.
+#[derive(Debug)]
+pub struct LajiChaos {
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    udp: SmallVec<[UdpSocket; INLINE_LISTENERS]>,
+    upstream: SocketAddr,
+    faults: Faults,
+    seed: u64,
+}
+
+impl LajiChaos {
+    pub fn new<A>(upstream: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        let upstream = upstream.to_socket_addrs()?.next().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+        Ok(Self {
+            tcp: SmallVec::new(),
+            udp: SmallVec::new(),
+            upstream,
+            faults: Faults::default(),
+            seed: DEFAULT_SEED,
+        })
+    }
+
+    #[inline]
+    pub fn bind_tcp<A>(mut self, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.tcp.push(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn bind_udp<A>(mut self, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Reset TCP connections part way into the response.
+    #[inline]
+    pub fn reset(mut self, chance: f64) -> Self {
+        self.faults.reset = chance;
+        self
+    }
+
+    /// Close TCP connections cleanly part way into the response, so clients see a short reply.
+    #[inline]
+    pub fn truncate(mut self, chance: f64) -> Self {
+        self.faults.truncate = chance;
+        self
+    }
+
+    /// Send UDP replies twice.
+    #[inline]
+    pub fn duplicate(mut self, chance: f64) -> Self {
+        self.faults.duplicate = chance;
+        self
+    }
+
+    /// Hold back a UDP reply, or the start of a TCP response, for `by`.
+    #[inline]
+    pub fn delay(mut self, chance: f64, by: Duration) -> Self {
+        self.faults.delay = chance;
+        self.faults.delay_by = by;
+        self
+    }
+
+    /// Fix which connections and datagrams get hit, for repeatable runs.
+    #[inline]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Relay until a listener fails to accept or a socket to receive. A client whose
+    /// upstream cannot be reached is closed, and the proxy carries on.
+    pub fn run(self) -> io::Result<()> {
+        let (err_tx, err_rx) = mpsc::channel();
+        let mut rng = Rng::new(self.seed);
+        let (upstream, faults) = (self.upstream, self.faults);
+        for listener in self.tcp {
+            let err_tx = err_tx.clone();
+            let mut rng = rng.split();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let rng = rng.split();
+                    let client = match stream {
+                        Ok(client) => client,
+                        Err(e) => { let _ = err_tx.send(e); return }
+                    };
+                    // an upstream that cannot be reached only loses this client
+                    if let Ok(upstream) = TcpStream::connect(upstream) {
+                        thread::spawn(move || relay_tcp(client, upstream, faults, rng));
+                    }
+                }
+            });
+        }
+        for socket in self.udp {
+            let err_tx = err_tx.clone();
+            let mut rng = rng.split();
+            thread::spawn(move || {
+                let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+                loop {
+                    let ans = socket.recv_from(&mut buf).and_then(|(len, origin)| {
+                        let (socket, request, rng) = (socket.try_clone()?, buf[..len].to_vec(), rng.split());
+                        thread::spawn(move || {
+                            // a client that went away is not our error to report
+                            let _ = relay_udp(&socket, origin, upstream, &request, faults, rng);
+                        });
+                        Ok(())
+                    });
+                    if let Err(e) = ans {
+                        let _ = err_tx.send(e);
+                        return;
+                    }
+                }
+            });
+        }
+        drop(err_tx);
+        match err_rx.recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Ending {
+    Complete,
+    Reset,
+    Truncate,
+}
+
+fn relay_tcp(mut client: TcpStream, mut upstream: TcpStream, faults: Faults, mut rng: Rng) -> io::Result<()> {
+    let roll = rng.chance();
+    let ending = if roll < faults.reset {
+        Ending::Reset
+    } else if roll < faults.reset + faults.truncate {
+        Ending::Truncate
+    } else {
+        Ending::Complete
+    };
+    let delay = if rng.chance() < faults.delay { Some(faults.delay_by) } else { None };
+    // requests go through untouched
+    let (mut from_client, mut to_upstream) = (client.try_clone()?, upstream.try_clone()?);
+    thread::spawn(move || {
+        let _ = io::copy(&mut from_client, &mut to_upstream);
+        let _ = to_upstream.shutdown(Shutdown::Write);
+    });
+    let mut buf = [0u8; 4096];
+    let mut first = true;
+    loop {
+        let len = upstream.read(&mut buf)?;
+        if len == 0 {
+            return client.shutdown(Shutdown::Write);
+        }
+        if let (true, Some(delay)) = (first, delay) {
+            thread::sleep(delay);
+        }
+        first = false;
+        if ending == Ending::Complete {
+            client.write_all(&buf[..len])?;
+            continue;
+        }
+        // at least one byte and never the whole chunk, where the chunk allows
+        let cut = if len > 1 { 1 + rng.below(len - 1) } else { 0 };
+        client.write_all(&buf[..cut])?;
+        return match ending {
+            Ending::Reset => abort(&client),
+            _ => client.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+fn relay_udp(socket: &UdpSocket, origin: SocketAddr, upstream: SocketAddr, request: &[u8], faults: Faults, mut rng: Rng) -> io::Result<()> {
+    let local: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let relay = UdpSocket::bind(local)?;
+    relay.connect(upstream)?;
+    relay.set_read_timeout(Some(UDP_REPLY_TIMEOUT))?;
+    relay.send(request)?;
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let len = match relay.recv(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if rng.chance() < faults.delay {
+            thread::sleep(faults.delay_by);
+        }
+        socket.send_to(&buf[..len], origin)?;
+        if rng.chance() < faults.duplicate {
+            socket.send_to(&buf[..len], origin)?;
+        }
+    }
+}
+
+/// Close with a reset instead of a FIN.
+#[cfg(target_os = "linux")]
+fn abort(stream: &TcpStream) -> io::Result<()> {
+    use std::{mem, os::unix::io::AsRawFd};
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // wakes the request copier, whose handle is the last one open; closing it sends the reset
+    stream.shutdown(Shutdown::Read)
+}
+
+/// Close with a reset instead of a FIN.
+#[cfg(not(target_os = "linux"))]
+fn abort(stream: &TcpStream) -> io::Result<()> {
+    // no portable way to ask for a reset, closing early is the closest
+    stream.shutdown(Shutdown::Both)
+}
+
+/// xorshift64*, one per thread so relays never wait on each other.
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    #[inline]
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        Rng(std::cmp::max(seed, 1))
+    }
+
+    #[inline]
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`.
+    #[inline]
+    fn chance(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    #[inline]
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    #[inline]
+    fn split(&mut self) -> Self {
+        Rng::new(self.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const TIME: &[u8] = b"Wed, 14 Oct 2026 10:52:37 +0200\r\n";
+
+    fn daytime_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind(listener.local_addr().unwrap()).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((_, origin)) = socket.recv_from(&mut buf) {
+                socket.send_to(TIME, origin).unwrap();
+            }
+        });
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                stream.unwrap().write_all(TIME).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn relay(chaos: LajiChaos, port: u16) -> SocketAddr {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let chaos = chaos.bind_tcp(addr).unwrap().bind_udp(addr).unwrap();
+        thread::spawn(move || chaos.run().unwrap());
+        addr
+    }
+
+    fn read_tcp(addr: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut reply = Vec::new();
+        TcpStream::connect(addr)?.read_to_end(&mut reply)?;
+        Ok(reply)
+    }
+
+    #[test]
+    fn tcp_faults() {
+        let upstream = daytime_upstream();
+        let clean = relay(LajiChaos::new(upstream).unwrap(), 15424);
+        assert_eq!(read_tcp(clean).unwrap(), TIME);
+        let truncating = relay(LajiChaos::new(upstream).unwrap().truncate(1.0), 15425);
+        for _ in 0..10 {
+            let reply = read_tcp(truncating).unwrap();
+            assert!(!reply.is_empty() && reply.len() < TIME.len() && TIME.starts_with(&reply));
+        }
+        let resetting = relay(LajiChaos::new(upstream).unwrap().reset(1.0), 15426);
+        assert_eq!(read_tcp(resetting).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn udp_faults() {
+        let upstream = daytime_upstream();
+        let addr = relay(LajiChaos::new(upstream).unwrap().duplicate(1.0).delay(1.0, Duration::from_millis(100)), 15427);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let start = Instant::now();
+        socket.send_to(b"\n", addr).unwrap();
+        let mut buf = [0u8; 512];
+        for _ in 0..2 {
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!((&buf[..len], from), (TIME, addr));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,642 @@
+//! UDP character generator (RFC 864), which cannot be turned into a DDoS reflector.
+//!
+//! Every datagram is answered with lines of the usual rotating printable pattern, but never
+//! with more than `amplification` times the bytes it carried, so a spoofed request costs the
+//! spoofer as much as it costs the victim. As with echo, datagrams from
+//! `ports::REFLECTION_PORTS` are dropped.
+//!
+//! With a `rate_limit`, sources over their limit are ignored, or with `Flood::Challenge` sent
+//! `CHALLENGE <cookie>\r\n` instead: a datagram starting with that cookie proves its source
+//! receives what it is sent, and is answered whatever the limit says. Cookies are stateless,
+//! keyed per server, and good for one to two `COOKIE_LIFETIME`s.
+//!
+//! `Client` receives from any chargen server, over TCP or UDP, and checks every byte against
+//! the pattern with a `Verifier`, to tell whether anything on the way altered the stream.
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use smallvec::SmallVec;
+use crate::{config::ConfigError, ports, ratelimit::PerSource, resolve, socks5::{self, Proxy}, virtnet::Datagram};
+
+/// The most RFC 864 sends in one datagram.
+pub const MAX_REPLY_LEN: usize = 512;
+pub const COOKIE_LIFETIME: Duration = Duration::from_secs(30);
+
+const LINE_LEN: usize = 72;
+const PRINTABLE: u8 = 95;
+const COOKIE_LEN: usize = 16;
+const CHALLENGE_PREFIX: &[u8] = b"CHALLENGE ";
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: 'static + Clone + Send,
+    H: Handler
+{
+    LajiChargen::new(factory).bind(addr)?.run()
+}
+
+/// Fill `buf` with the chargen pattern: 72-character lines ending in CRLF, each starting one
+/// printable character further along than the last, the first at line `first_line`.
+pub fn fill(buf: &mut [u8], first_line: usize) {
+    for (i, line) in buf.chunks_mut(LINE_LEN + 2).enumerate() {
+        let start = (first_line + i) % usize::from(PRINTABLE);
+        for (j, b) in line.iter_mut().enumerate() {
+            *b = match j {
+                LINE_LEN => b'\r',
+                j if j > LINE_LEN => b'\n',
+                j => b' ' + ((start + j) % usize::from(PRINTABLE)) as u8,
+            };
+        }
+    }
+}
+
+/// What to do with sources sending more than the rate limit allows.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Flood {
+    Ignore,
+    Challenge,
+}
+
+/// Why a datagram got no characters back.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Dropped {
+    /// It came from one of the refused source ports.
+    ReflectionPort,
+    /// It was empty, so any reply would amplify.
+    Empty,
+    /// Its source IP is over the rate limit.
+    RateLimited,
+    /// Its source IP is over the rate limit and was sent a challenge.
+    Challenged,
+}
+
+#[derive(Clone, Debug)]
+struct Guard {
+    refused_ports: Vec<u16>,
+    amplification: usize,
+    rate_limit: Option<(u32, Duration)>,
+    flood: Flood,
+    key: RandomState,
+}
+
+impl Guard {
+    fn cookie(&self, ip: IpAddr, epoch: u64) -> [u8; COOKIE_LEN] {
+        let hash = self.key.hash_one((ip, epoch));
+        let mut cookie = [0u8; COOKIE_LEN];
+        for (i, b) in cookie.iter_mut().enumerate() {
+            let nibble = (hash >> (4 * i)) & 0xf;
+            *b = b"0123456789abcdef"[nibble as usize];
+        }
+        cookie
+    }
+
+    fn has_cookie(&self, ip: IpAddr, data: &[u8], epoch: u64) -> bool {
+        data.len() >= COOKIE_LEN && (epoch.saturating_sub(1)..=epoch)
+            .any(|epoch| self.cookie(ip, epoch)[..] == data[..COOKIE_LEN])
+    }
+}
+
+fn cookie_epoch() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() / COOKIE_LIFETIME.as_secs()
+}
+
+pub struct LajiChargen<F>
+where
+    F: Factory
+{
+    udp: SmallVec<[UdpSocket; 4]>,
+    factory: F,
+    guard: Guard,
+}
+
+impl<F> LajiChargen<F>
+where
+    F: Factory
+{
+    #[inline]
+    pub fn new(factory: F) -> Self {
+        let guard = Guard {
+            refused_ports: ports::REFLECTION_PORTS.to_vec(),
+            amplification: 1,
+            rate_limit: None,
+            flood: Flood::Ignore,
+            key: RandomState::new(),
+        };
+        Self { udp: SmallVec::new(), factory, guard }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known chargen port, 19, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Self> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::CHARGEN))
+    }
+
+    /// Bind the well-known chargen port, 19, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Self> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::CHARGEN))
+    }
+
+    /// Drop datagrams from these source ports instead of `ports::REFLECTION_PORTS`.
+    #[inline]
+    pub fn refuse_ports<I>(mut self, ports: I) -> Self
+    where I: IntoIterator<Item = u16>
+    {
+        self.guard.refused_ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Answer with up to `factor` times the request's length, and never more than
+    /// `MAX_REPLY_LEN`. The default, 1, never sends more than it received.
+    #[inline]
+    pub fn amplification(mut self, factor: usize) -> Self {
+        self.guard.amplification = factor;
+        self
+    }
+
+    /// Answer each source IP at most `replies` times per `interval`, per bound socket.
+    #[inline]
+    pub fn rate_limit(mut self, replies: u32, interval: Duration) -> Self {
+        self.guard.rate_limit = Some((replies, interval));
+        self
+    }
+
+    /// What sources over the rate limit get; they are ignored unless this says otherwise.
+    #[inline]
+    pub fn on_flood(mut self, flood: Flood) -> Self {
+        self.guard.flood = flood;
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.udp.iter().map(UdpSocket::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.guard.amplification == 0 {
+            return Err(ConfigError::Zero("amplification"));
+        }
+        match self.guard.rate_limit {
+            Some((0, _)) => Err(ConfigError::Zero("rate_limit")),
+            Some((_, interval)) if interval == Duration::from_secs(0) => Err(ConfigError::Zero("rate_limit")),
+            None if self.guard.flood == Flood::Challenge =>
+                Err(ConfigError::Conflict("flood challenges need a rate limit")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<F> LajiChargen<F>
+where
+    F: Factory + Clone + Send + 'static
+{
+    /// Serve every socket on a thread of its own. A datagram that cannot be received or
+    /// answered goes to `on_error`, and the socket goes on serving.
+    pub fn run(self) -> io::Result<()> {
+        self.validate()?;
+        let mut threads = Vec::with_capacity(self.udp.len());
+        for socket in self.udp {
+            let mut factory = self.factory.clone();
+            let guard = self.guard.clone();
+            threads.push(thread::spawn(move || {
+                let limit = guard.rate_limit.map(|(replies, interval)| PerSource::new(replies, interval));
+                let mut state = State { limit, next_line: 0, buf: vec![0u8; MAX_DATAGRAM_LEN] };
+                loop {
+                    serve_one(&mut factory, &socket, &guard, &mut state)
+                        .unwrap_or_else(|e| factory.on_error(e))
+                }
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    limit: Option<PerSource>,
+    next_line: usize,
+    buf: Vec<u8>,
+}
+
+fn serve_one<S, F>(factory: &mut F, socket: &S, guard: &Guard, state: &mut State) -> io::Result<()>
+where
+    S: Datagram,
+    F: Factory
+{
+    let (len, origin) = socket.recv_from(&mut state.buf)?;
+    let mut handler = factory.connection_made();
+    if guard.refused_ports.contains(&origin.port()) {
+        handler.on_dropped(origin, Dropped::ReflectionPort);
+        return Ok(());
+    }
+    let budget = len.saturating_mul(guard.amplification).min(MAX_REPLY_LEN);
+    if budget == 0 {
+        handler.on_dropped(origin, Dropped::Empty);
+        return Ok(());
+    }
+    if let Some(limit) = &mut state.limit {
+        let epoch = cookie_epoch();
+        let proven = guard.flood == Flood::Challenge && guard.has_cookie(origin.ip(), &state.buf[..len], epoch);
+        if !limit.allow(origin.ip()) && !proven {
+            if guard.flood == Flood::Ignore || budget < CHALLENGE_PREFIX.len() + COOKIE_LEN + 2 {
+                handler.on_dropped(origin, Dropped::RateLimited);
+                return Ok(());
+            }
+            let mut challenge = CHALLENGE_PREFIX.to_vec();
+            challenge.extend_from_slice(&guard.cookie(origin.ip(), epoch));
+            challenge.extend_from_slice(b"\r\n");
+            handler.on_dropped(origin, Dropped::Challenged);
+            socket.send_to(&challenge, origin)?;
+            return Ok(());
+        }
+    }
+    let reply = &mut state.buf[..budget];
+    fill(reply, state.next_line);
+    state.next_line = (state.next_line + 1) % usize::from(PRINTABLE);
+    handler.on_reply(origin, reply.len());
+    socket.send_to(reply, origin)?;
+    Ok(())
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// A datagram could not be received or answered, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler,
+    F: FnMut() -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+pub trait Handler {
+    fn on_reply(&mut self, _origin: SocketAddr, _len: usize) {}
+
+    fn on_dropped(&mut self, _origin: SocketAddr, _why: Dropped) {}
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(SocketAddr)
+{
+    #[inline]
+    fn on_reply(&mut self, origin: SocketAddr, _len: usize) {
+        self(origin)
+    }
+}
+
+/// A `Verifier` keeps the offsets of this many corrupt bytes, and only counts the rest.
+pub const MAX_CORRUPTIONS: usize = 64;
+
+/// Checks a stream byte by byte against the chargen pattern. The stream must start at the
+/// start of a line, as servers do; the line it starts at is taken from its first byte.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Verifier {
+    // the printable character the current line starts with, once known, and the column
+    line: Option<usize>,
+    column: usize,
+    offset: u64,
+    corrupt: u64,
+    corruptions: Vec<u64>,
+}
+
+impl Verifier {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            let expected = match self.line {
+                Some(line) => match self.column {
+                    LINE_LEN => b'\r',
+                    column if column > LINE_LEN => b'\n',
+                    column => b' ' + ((line + column) % usize::from(PRINTABLE)) as u8,
+                },
+                None if (b' '..=b'~').contains(&b) => {
+                    self.line = Some(usize::from(b - b' '));
+                    b
+                }
+                // cannot be the start of a line, so it cannot tell where the pattern is either
+                None => !b,
+            };
+            if b != expected {
+                self.corrupt += 1;
+                if self.corruptions.len() < MAX_CORRUPTIONS {
+                    self.corruptions.push(self.offset);
+                }
+            }
+            if self.line.is_some() {
+                self.column += 1;
+                if self.column == LINE_LEN + 2 {
+                    self.column = 0;
+                    self.line = self.line.map(|line| (line + 1) % usize::from(PRINTABLE));
+                }
+            }
+            self.offset += 1;
+        }
+    }
+
+    /// Start over at a new line, such as the next datagram, without forgetting the counts.
+    #[inline]
+    pub fn resync(&mut self) {
+        self.line = None;
+        self.column = 0;
+    }
+
+    /// Bytes fed so far.
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt
+    }
+
+    /// Offsets of the first `MAX_CORRUPTIONS` bytes that were not what the pattern says.
+    #[inline]
+    pub fn corruptions(&self) -> &[u64] {
+        &self.corruptions
+    }
+}
+
+/// Receives for `duration` and verifies all of it.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Client {
+    duration: Duration,
+    timeout: Duration,
+    request_len: usize,
+    proxy: Option<Proxy>,
+}
+
+impl Client {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            duration: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            request_len: MAX_REPLY_LEN,
+            proxy: None,
+        }
+    }
+
+    #[inline]
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// How long connecting, and then each read, may take.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Bytes per UDP request. Servers limiting amplification answer with no more than this.
+    #[inline]
+    pub fn request_len(mut self, len: usize) -> Self {
+        self.request_len = len;
+        self
+    }
+
+    /// Connect to TCP servers through a SOCKS5 proxy.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Read the stream of a TCP chargen server.
+    pub fn receive_tcp<A>(&self, addr: A) -> io::Result<Report>
+    where A: ToSocketAddrs
+    {
+        let mut stream = socks5::connect_to(self.proxy.as_ref(), addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut verifier = Verifier::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let started = Instant::now();
+        while started.elapsed() < self.duration {
+            match stream.read(&mut buf).map_err(resolve::timed_out)? {
+                0 => break,
+                len => verifier.feed(&buf[..len]),
+            }
+        }
+        Ok(Report::new(verifier, 0, started.elapsed()))
+    }
+
+    /// Ask a UDP chargen server for one datagram after another, answering its challenges
+    /// if it sends any. Each datagram is verified on its own.
+    pub fn receive_udp<A>(&self, addr: A) -> io::Result<Report>
+    where A: ToSocketAddrs
+    {
+        if self.request_len == 0 {
+            return Err(ConfigError::Zero("request_len").into());
+        }
+        let socket = resolve::connect_udp(addr)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        let mut request = vec![0u8; self.request_len];
+        let mut verifier = Verifier::new();
+        let mut lost = 0;
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let started = Instant::now();
+        while started.elapsed() < self.duration {
+            socket.send(&request)?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    lost += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let reply = &buf[..len];
+            if reply.starts_with(CHALLENGE_PREFIX) && reply.ends_with(b"\r\n") {
+                let cookie = &reply[CHALLENGE_PREFIX.len()..len - 2];
+                request.clear();
+                request.extend_from_slice(cookie);
+                request.resize(self.request_len.max(cookie.len()), 0);
+                continue;
+            }
+            verifier.resync();
+            verifier.feed(reply);
+        }
+        Ok(Report::new(verifier, lost, started.elapsed()))
+    }
+}
+
+impl Default for Client {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Report {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// How many bytes differed from the pattern.
+    pub corrupt: u64,
+    /// Offsets into everything received of the first `MAX_CORRUPTIONS` corrupt bytes.
+    pub corruptions: Vec<u64>,
+    /// UDP requests that got no answer in time.
+    pub lost: u32,
+}
+
+impl Report {
+    fn new(verifier: Verifier, lost: u32, elapsed: Duration) -> Self {
+        Self {
+            bytes: verifier.offset,
+            elapsed,
+            corrupt: verifier.corrupt,
+            corruptions: verifier.corruptions,
+            lost,
+        }
+    }
+
+    /// Achieved throughput, in megabits per second.
+    #[inline]
+    pub fn mbps(&self) -> f64 {
+        self.bytes as f64 * 8.0 / 1e6 / self.elapsed.as_secs_f64()
+    }
+
+    #[inline]
+    pub fn is_intact(&self) -> bool {
+        self.corrupt == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(client: &UdpSocket, server: SocketAddr, msg: &[u8]) -> Option<Vec<u8>> {
+        client.send_to(msg, server).unwrap();
+        let mut buf = [0u8; MAX_REPLY_LEN];
+        client.recv_from(&mut buf).ok().map(|(len, _)| buf[..len].to_vec())
+    }
+
+    #[test]
+    fn pattern() {
+        let mut buf = [0u8; 2 * (LINE_LEN + 2)];
+        fill(&mut buf, 94);
+        assert_eq!(&buf[..3], b"~ !");
+        assert_eq!(&buf[LINE_LEN..LINE_LEN + 3], b"\r\n ");
+        assert_eq!(&buf[LINE_LEN + 2..LINE_LEN + 4], b" !");
+        assert!(buf.iter().all(|&b| b == b'\r' || b == b'\n' || (b' '..=b'~').contains(&b)));
+    }
+
+    #[test]
+    fn amplification_and_challenges() {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let server = LajiChargen::new(|| |_origin: SocketAddr| {})
+            .bind("127.0.0.1:0").unwrap()
+            .amplification(2)
+            .rate_limit(1, Duration::from_secs(60))
+            .on_flood(Flood::Challenge);
+        let addr = server.local_addrs().unwrap()[0];
+        thread::spawn(move || server.run().unwrap());
+        assert_eq!(request(&client, addr, &[0; 30]).map(|reply| reply.len()), Some(60));
+        // over the limit: a challenge, no larger than the budget, instead of characters
+        let challenge = request(&client, addr, &[0; 30]).unwrap();
+        assert!(challenge.starts_with(CHALLENGE_PREFIX));
+        let cookie = &challenge[CHALLENGE_PREFIX.len()..challenge.len() - 2];
+        assert_eq!(request(&client, addr, cookie).map(|reply| reply.len()), Some(2 * COOKIE_LEN));
+        // a wrong cookie only earns another challenge, a too-small request not even that
+        assert!(request(&client, addr, b"0000000000000000").unwrap().starts_with(CHALLENGE_PREFIX));
+        assert_eq!(request(&client, addr, b"laji"), None);
+        assert_eq!(request(&client, addr, &[]), None);
+    }
+
+    #[test]
+    fn verifier() {
+        let mut stream = vec![0u8; 5 * (LINE_LEN + 2)];
+        fill(&mut stream, 93);
+        let mut verifier = Verifier::new();
+        // split mid-line, to check the state carries over
+        verifier.feed(&stream[..100]);
+        verifier.feed(&stream[100..]);
+        assert_eq!((verifier.offset(), verifier.corrupt()), (stream.len() as u64, 0));
+        stream[3] ^= 1;
+        stream[2 * (LINE_LEN + 2) - 1] = b'x';
+        let mut verifier = Verifier::new();
+        verifier.feed(&stream);
+        assert_eq!(verifier.corruptions(), [3, 2 * (LINE_LEN as u64 + 2) - 1]);
+    }
+
+    #[test]
+    fn client_loopback() -> io::Result<()> {
+        use std::{io::Write, net::TcpListener};
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let tcp = listener.local_addr()?;
+        thread::spawn(move || -> io::Result<()> {
+            let mut stream = vec![0u8; 1000 * (LINE_LEN + 2)];
+            fill(&mut stream, 0);
+            stream[5000] = b'\0';
+            listener.accept()?.0.write_all(&stream)
+        });
+        let client = Client::new().duration(Duration::from_millis(200)).timeout(Duration::from_secs(1));
+        let report = client.receive_tcp(tcp)?;
+        assert_eq!((report.bytes, report.corruptions), (74_000, vec![5000]));
+
+        let server = LajiChargen::new(|| |_origin: SocketAddr| {})
+            .bind("127.0.0.1:0")?
+            .rate_limit(2, Duration::from_secs(60))
+            .on_flood(Flood::Challenge);
+        let udp = server.local_addrs()?[0];
+        thread::spawn(move || server.run().unwrap());
+        let report = client.request_len(100).receive_udp(udp)?;
+        // every request after the second carries the cookie, and is answered in full
+        assert!(report.is_intact() && report.bytes > 10 * 100, "{:?}", report);
+        assert_eq!(report.bytes % 100, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let factory = || |_origin: SocketAddr| {};
+        let server = LajiChargen::new(factory).bind("127.0.0.1:0").unwrap().on_flood(Flood::Challenge);
+        assert_eq!(server.validate(), Err(ConfigError::Conflict("flood challenges need a rate limit")));
+        let server = LajiChargen::new(factory).bind("127.0.0.1:0").unwrap().amplification(0);
+        assert_eq!(server.validate(), Err(ConfigError::Zero("amplification")));
+    }
+}
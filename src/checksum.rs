@@ -0,0 +1,170 @@
+//! The Internet checksum (RFC 1071) carried by IPv4, ICMP, UDP and TCP headers.
+//!
+//! `checksum` does a whole message at once; `Checksum` sums it piece by piece, say a
+//! pseudo-header and then the segment, with pieces of any length. `update` patches a
+//! checksum after one field changed without summing the message again (RFC 1624).
+
+/// The checksum of `buf`, with an odd last byte padded by a zero. Run over a message that
+/// carries a valid checksum, it comes out zero.
+#[inline]
+pub fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add(buf);
+    sum.finish()
+}
+
+/// The checksum after a 16-bit word of the summed data changed from `old` to `new`.
+#[inline]
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    // RFC 1624 eqn. 3: HC' = ~(~HC + ~m + m'), which never gives the -0 that eqn. 2 can
+    !fold(u64::from(!checksum) + u64::from(!old) + u64::from(new))
+}
+
+/// `update` for a 32-bit field, such as an IPv4 address.
+#[inline]
+pub fn update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = update(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    update(checksum, old as u16, new as u16)
+}
+
+/// End-around carry: fold the sum down to 16 bits.
+#[inline]
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// A running one's-complement sum.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Checksum {
+    sum: u64,
+    // the high byte of a word whose low byte is still to come
+    odd: Option<u8>,
+}
+
+impl Checksum {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `buf` as if it directly followed the bytes added so far.
+    pub fn add(&mut self, mut buf: &[u8]) {
+        if let (Some(high), Some((&low, rest))) = (self.odd, buf.split_first()) {
+            self.sum += u64::from(u16::from_be_bytes([high, low]));
+            self.odd = None;
+            buf = rest;
+        }
+        let mut words = buf.chunks_exact(2);
+        for word in &mut words {
+            self.sum += u64::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = *words.remainder() {
+            self.odd = Some(last);
+        }
+    }
+
+    #[inline]
+    pub fn add_u16(&mut self, word: u16) {
+        self.add(&word.to_be_bytes());
+    }
+
+    #[inline]
+    pub fn add_u32(&mut self, word: u32) {
+        self.add(&word.to_be_bytes());
+    }
+
+    /// The checksum of everything added, to put in the header as is.
+    #[inline]
+    pub fn finish(&self) -> u16 {
+        let pad = self.odd.map_or(0, |high| u64::from(u16::from_be_bytes([high, 0])));
+        !fold(self.sum + pad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sum the way RFC 1071 spells it out, padding the copy to even length first.
+    fn reference(buf: &[u8]) -> u16 {
+        let mut padded = buf.to_vec();
+        if padded.len() % 2 == 1 {
+            padded.push(0);
+        }
+        let mut sum = 0u64;
+        for word in padded.chunks(2) {
+            sum += u64::from(word[0]) << 8 | u64::from(word[1]);
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn rfc1071_example() {
+        // section 3's worked example sums to ddf2
+        let buf = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&buf), !0xddf2);
+        let mut with_sum = buf.to_vec();
+        with_sum.extend_from_slice(&checksum(&buf).to_be_bytes());
+        assert_eq!(checksum(&with_sum), 0);
+    }
+
+    #[test]
+    fn odd_lengths_and_pieces() {
+        let data: Vec<u8> = (0..512u32).map(|i| (i * 167 + i / 256) as u8).collect();
+        for len in 0..=67 {
+            let buf = &data[..len];
+            assert_eq!(checksum(buf), reference(buf), "len {}", len);
+            for split in 0..=len {
+                for split2 in split..=len {
+                    let mut sum = Checksum::new();
+                    sum.add(&buf[..split]);
+                    sum.add(&buf[split..split2]);
+                    sum.add(&buf[split2..]);
+                    assert_eq!(sum.finish(), reference(buf), "len {} split {} {}", len, split, split2);
+                }
+            }
+        }
+        assert_eq!(checksum(&data), reference(&data));
+        assert_eq!(checksum(&[0xab]), !0xab00);
+        let mut words = Checksum::new();
+        words.add_u32(0xc0a8_0001);
+        words.add_u16(0x0011);
+        assert_eq!(words.finish(), checksum(&[0xc0, 0xa8, 0x00, 0x01, 0x00, 0x11]));
+    }
+
+    #[test]
+    fn wraparound() {
+        assert_eq!(checksum(&[]), 0xffff);
+        assert_eq!(checksum(&[0, 0, 0]), 0xffff);
+        // data summing to ffff, one's-complement -0, needs a checksum of zero
+        assert_eq!(checksum(&[0xff, 0xff]), 0);
+        assert_eq!(checksum(&[0xff, 0xff, 0x00, 0x01]), !0x0001);
+        assert_eq!(checksum(&[0x80, 0x00, 0x80, 0x00]), !0x0001);
+        // far more words than fit a u32 sum without folding
+        let big = vec![0xffu8; 1 << 20];
+        assert_eq!(checksum(&big), reference(&big));
+        assert_eq!(checksum(&big[1..]), reference(&big[1..]));
+    }
+
+    #[test]
+    fn incremental_update() {
+        let mut buf = [0x45, 0x00, 0x12, 0x34, 0xab, 0xcd, 0xc0, 0xa8, 0x00, 0x01];
+        for old in (0..=0xffffu32).step_by(251) {
+            for new in (0..=0xffffu32).step_by(241).chain(Some(0xffff)) {
+                buf[2..4].copy_from_slice(&(old as u16).to_be_bytes());
+                let before = checksum(&buf);
+                buf[2..4].copy_from_slice(&(new as u16).to_be_bytes());
+                assert_eq!(update(before, old as u16, new as u16), checksum(&buf), "{:04x} -> {:04x}", old, new);
+            }
+        }
+        let before = checksum(&buf);
+        buf[6..10].copy_from_slice(&[10, 0, 0, 7]);
+        assert_eq!(update_u32(before, 0xc0a8_0001, 0x0a00_0007), checksum(&buf));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use chrono::{DateTime, FixedOffset, Local};
+
+/// Where time servers get the time they report.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<FixedOffset>;
+}
+
+/// The system clock, in the local time zone.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> DateTime<FixedOffset> {
+        let now = Local::now();
+        now.with_timezone(now.offset())
+    }
+}
+
+/// A clock that only moves when told to; clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    time: Arc<Mutex<DateTime<FixedOffset>>>,
+}
+
+impl ManualClock {
+    #[inline]
+    pub fn new(time: DateTime<FixedOffset>) -> Self {
+        Self { time: Arc::new(Mutex::new(time)) }
+    }
+
+    #[inline]
+    pub fn set(&self, time: DateTime<FixedOffset>) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    /// Move the time on by `by`, stopping at the latest time chrono can hold.
+    pub fn advance(&self, by: Duration) {
+        let mut time = self.time.lock().unwrap();
+        let ahead = chrono::Duration::from_std(by).ok().and_then(|by| time.checked_add_signed(by));
+        *time = ahead.unwrap_or_else(|| {
+            let latest = chrono::naive::MAX_DATE.and_hms_nano(23, 59, 59, 999_999_999);
+            DateTime::from_utc(latest, *time.offset())
+        });
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> DateTime<FixedOffset> {
+        *self.time.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let start = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+        let clock = ManualClock::new(start);
+        let handle = clock.clone();
+        handle.advance(Duration::from_secs(90));
+        let later = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:54:07 +0200").unwrap();
+        assert_eq!(clock.now(), later);
+        handle.set(start);
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn advance_saturates() {
+        let start = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+        let clock = ManualClock::new(start);
+        clock.advance(Duration::from_secs(u64::MAX));
+        let latest = clock.now();
+        assert!(latest > start);
+        clock.advance(Duration::from_secs(3600 * 24 * 365 * 300_000));
+        assert_eq!(clock.now(), latest);
+    }
+}
@@ -0,0 +1,31 @@
+use std::{error::Error, fmt, io};
+
+/// Why a builder refused its configuration.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum ConfigError {
+    /// Nothing was bound, so the server would never serve anyone.
+    NoListeners,
+    /// The named setting was zero, where it must be at least one.
+    Zero(&'static str),
+    /// Settings that cannot be used together, or one out of its range.
+    Conflict(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NoListeners => f.write_str("no address bound"),
+            ConfigError::Zero(setting) => write!(f, "`{}` must not be zero", setting),
+            ConfigError::Conflict(why) => f.write_str(why),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    #[inline]
+    fn from(err: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
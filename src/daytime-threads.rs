@@ -1,16 +1,33 @@
 use std::{
     borrow::Cow,
-    io::{self, Write},
-    net::{TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs},
+    fmt,
+    io::{self, BufReader, Write},
+    net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs},
     thread,
     sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
+#[cfg(feature = "backend-tokio")]
+use bytes::BytesMut;
+use chrono::{DateTime, Datelike, FixedOffset, Offset, TimeZone, Timelike};
+use smallvec::SmallVec;
+use crate::{affinity::{self, CoreList}, clock::{Clock, SystemClock}, config::ConfigError, ports, resolve, server::{ServerHandle, Stopper}, socks5::{self, Proxy}, udp_batch::{self, Batch}};
+#[cfg(feature = "backend-tokio")]
+use tokio::codec::{Decoder, Encoder};
+use crate::framing;
+#[cfg(feature = "backend-tokio")]
+use crate::framing::LineCodec;
+
+const INLINE_LISTENERS: usize = 4;
+
+/// A listener's own time format, used instead of the factory's when set.
+type FormatOverride = Option<Arc<dyn TimeFormat>>;
 
 pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
 where 
     A: ToSocketAddrs, 
     F: FnMut(Sender) -> H, 
-    F: 'static + Send + Sync,
+    F: 'static + Clone + Send,
     H: Handler 
 {
     LajiDaytime::new(factory)
@@ -19,12 +36,35 @@ where
         .run()
 }
 
+/// `listen` on a thread of its own; binding errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where 
+    A: ToSocketAddrs, 
+    F: FnMut(Sender) -> H, 
+    F: 'static + Clone + Send,
+    H: Handler 
+{
+    LajiDaytime::new(factory)
+        .bind_tcp(&addr)?
+        .bind_udp(&addr)?
+        .run_detached()
+}
+
 pub struct LajiDaytime<F> 
 where 
     F: Factory 
 {
-    tcp: Vec<TcpListener>,
-    udp: Vec<UdpSocket>,
+    tcp: SmallVec<[(TcpListener, FormatOverride); INLINE_LISTENERS]>,
+    udp: SmallVec<[(UdpSocket, FormatOverride); INLINE_LISTENERS]>,
+    udp_workers: usize,
+    udp_queue_len: usize,
+    udp_batch_size: usize,
+    #[cfg(all(windows, feature = "backend-iocp"))]
+    accept_backlog: usize,
+    cores: CoreList,
+    clock: Arc<dyn Clock>,
+    format: Arc<dyn TimeFormat>,
+    banner: Option<Arc<str>>,
     factory: F
 }
 
@@ -35,8 +75,17 @@ where
     #[inline]
     pub fn new(factory: F) -> Self {
         Self {
-            tcp: Vec::new(),
-            udp: Vec::new(),
+            tcp: SmallVec::new(),
+            udp: SmallVec::new(),
+            udp_workers: 0,
+            udp_queue_len: DEFAULT_UDP_QUEUE_LEN,
+            udp_batch_size: 1,
+            #[cfg(all(windows, feature = "backend-iocp"))]
+            accept_backlog: DEFAULT_ACCEPT_BACKLOG,
+            cores: CoreList::default(),
+            clock: Arc::new(SystemClock),
+            format: Arc::new(Rfc2822),
+            banner: None,
             factory
         }
     }
@@ -47,7 +96,7 @@ where
         A: ToSocketAddrs 
     {
         let listener = TcpListener::bind(addr)?;
-        self.tcp.push(listener);
+        self.tcp.push((listener, None));
         Ok(self)
     }
 
@@ -57,70 +106,555 @@ where
         A: ToSocketAddrs 
     {
         let socket = UdpSocket::bind(addr)?;
-        self.udp.push(socket);
+        self.udp.push((socket, None));
+        Ok(self)
+    }
+
+    /// Bind like `bind_tcp`, answering in `format` instead of the server's `time_format`.
+    #[inline]
+    pub fn bind_tcp_formatted<A, T>(mut self, addr: A, format: T) -> io::Result<Self>
+    where 
+        A: ToSocketAddrs,
+        T: TimeFormat + 'static
+    {
+        let listener = TcpListener::bind(addr)?;
+        self.tcp.push((listener, Some(Arc::new(format))));
+        Ok(self)
+    }
+
+    /// Bind like `bind_udp`, answering in `format` instead of the server's `time_format`.
+    #[inline]
+    pub fn bind_udp_formatted<A, T>(mut self, addr: A, format: T) -> io::Result<Self>
+    where 
+        A: ToSocketAddrs,
+        T: TimeFormat + 'static
+    {
+        let socket = UdpSocket::bind(addr)?;
+        self.udp.push((socket, Some(Arc::new(format))));
         Ok(self)
     }
+
+    /// Bind TCP and UDP on the well-known daytime port, 13, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Self> {
+        let addr = (Ipv4Addr::UNSPECIFIED, ports::DAYTIME);
+        self.bind_tcp(addr)?.bind_udp(addr)
+    }
+
+    /// Bind TCP and UDP on the well-known daytime port, 13, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Self> {
+        let addr = (Ipv6Addr::UNSPECIFIED, ports::DAYTIME);
+        self.bind_tcp(addr)?.bind_udp(addr)
+    }
+
+    /// `bind_tcp` every address in turn, stopping at the first that fails.
+    #[inline]
+    pub fn bind_tcp_all<I>(self, addrs: I) -> io::Result<Self>
+    where 
+        I: IntoIterator,
+        I::Item: ToSocketAddrs 
+    {
+        addrs.into_iter().try_fold(self, Self::bind_tcp)
+    }
+
+    /// `bind_udp` every address in turn, stopping at the first that fails.
+    #[inline]
+    pub fn bind_udp_all<I>(self, addrs: I) -> io::Result<Self>
+    where 
+        I: IntoIterator,
+        I::Item: ToSocketAddrs 
+    {
+        addrs.into_iter().try_fold(self, Self::bind_udp)
+    }
+
+    /// `bind_tcp` once per address `addr` resolves to, instead of only the first.
+    #[inline]
+    pub fn bind_tcp_each<A>(self, addr: A) -> io::Result<Self>
+    where A: ToSocketAddrs 
+    {
+        self.bind_tcp_all(resolve::each_addr(addr)?)
+    }
+
+    /// `bind_udp` once per address `addr` resolves to, instead of only the first.
+    #[inline]
+    pub fn bind_udp_each<A>(self, addr: A) -> io::Result<Self>
+    where A: ToSocketAddrs 
+    {
+        self.bind_udp_all(resolve::each_addr(addr)?)
+    }
+
+    /// Serve each UDP socket with `n` worker threads fed by a single receiver 
+    /// thread, instead of handling every datagram on the receiving thread.
+    /// Zero, the default, keeps the inline behavior.
+    #[inline]
+    pub fn udp_workers(mut self, n: usize) -> Self {
+        self.udp_workers = n;
+        self
+    }
+
+    /// Number of pending requests each UDP worker may queue; datagrams 
+    /// arriving while every worker queue is full are dropped.
+    #[inline]
+    pub fn udp_queue_len(mut self, len: usize) -> Self {
+        self.udp_queue_len = len;
+        self
+    }
+
+    /// Answer up to `n` already-queued UDP requests with one batched send
+    /// (`sendmmsg` on Linux). Replies go out when the batch is flushed, after 
+    /// the handlers of that batch have run. Applies to the inline UDP path;
+    /// the default of one sends every reply on its own.
+    #[inline]
+    pub fn udp_batch_size(mut self, n: usize) -> Self {
+        self.udp_batch_size = n;
+        self
+    }
+
+    /// How many accepts each TCP listener keeps posted. On Windows with `backend-iocp` the
+    /// TCP listeners share one thread and completion port, instead of a thread each
+    /// blocking in `accept`.
+    #[cfg(all(windows, feature = "backend-iocp"))]
+    #[inline]
+    pub fn accept_backlog(mut self, backlog: usize) -> Self {
+        self.accept_backlog = backlog;
+        self
+    }
+
+    /// Pin every spawned listener, receiver and worker thread to these cores,
+    /// assigned round-robin in the order the threads are started.
+    #[inline]
+    pub fn cpu_affinity<I>(mut self, cores: I) -> Self 
+    where 
+        I: IntoIterator<Item = usize> 
+    {
+        self.cores = CoreList::new(cores);
+        self
+    }
+
+    /// Report the time from `clock` instead of the system clock, e.g. a `ManualClock` in tests.
+    #[inline]
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Render the time with `format` on every listener not bound with one of its own;
+    /// RFC 2822 by default.
+    #[inline]
+    pub fn time_format<T>(mut self, format: T) -> Self
+    where
+        T: TimeFormat + 'static
+    {
+        self.format = Arc::new(format);
+        self
+    }
+
+    /// Greet every TCP stream with `line` and a CRLF as soon as it is accepted, before the
+    /// handler hears of it and before the time, for clients that wait for a greeting.
+    #[inline]
+    pub fn banner<S>(mut self, line: S) -> Self
+    where
+        S: Into<String>
+    {
+        self.banner = Some(Arc::from(line.into()));
+        self
+    }
+
+    /// TCP addresses first, then UDP, each in bind order.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(|(listener, _)| listener.local_addr())
+            .chain(self.udp.iter().map(|(socket, _)| socket.local_addr()))
+            .collect()
+    }
+
+    /// What `run` checks before starting any thread.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.udp_batch_size == 0 {
+            return Err(ConfigError::Zero("udp_batch_size"));
+        }
+        self.cores.validate()?;
+        if self.udp_workers > 0 && self.udp_queue_len == 0 {
+            return Err(ConfigError::Zero("udp_queue_len"));
+        }
+        if self.udp_workers > 0 && self.udp_batch_size > 1 {
+            return Err(ConfigError::Conflict("udp_batch_size only applies without udp_workers"));
+        }
+        if let Some(banner) = &self.banner {
+            if banner.contains(&['\r', '\n'][..]) || banner.len() + 2 > MAX_BANNER_LEN {
+                return Err(ConfigError::Conflict("banner must be a single line of at most 510 bytes"));
+            }
+        }
+        #[cfg(all(windows, feature = "backend-iocp"))]
+        {
+            if self.accept_backlog == 0 {
+                return Err(ConfigError::Zero("accept_backlog"));
+            }
+        }
+        Ok(())
+    }
 }
 
+const DEFAULT_UDP_QUEUE_LEN: usize = 64;
+const MAX_BANNER_LEN: usize = 512;
+// how long a TCP listener waits after a failed accept, doubling while accepts keep failing
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(all(windows, feature = "backend-iocp"))]
+const DEFAULT_ACCEPT_BACKLOG: usize = 64;
+
 impl<F> LajiDaytime<F> 
 where 
-    F: Factory + Send + Sync + 'static 
+    F: Factory + Clone + Send + 'static 
 {
+    /// Serve until a thread fails to start; see `run_until`.
+    #[inline]
     pub fn run(self) -> io::Result<()> {
+        self.run_until(&Stopper::new())
+    }
+
+    /// Serve until `stopper` is stopped, then close every socket and wait for the listener,
+    /// receiver and worker threads. A failed connection or datagram goes to `on_error` and
+    /// the server carries on; a thread that cannot start stops `stopper` with its error.
+    pub fn run_until(self, stopper: &Stopper) -> io::Result<()> {
+        self.validate()?;
         let (err_tx, err_rx) = mpsc::channel();
-        let factory = Arc::new(Mutex::new(self.factory));
-        for listener in self.tcp { 
+        let mut threads = Vec::new();
+        let mut cores = self.cores;
+        let default_format = self.format;
+        let tcp = self.tcp;
+        for (listener, _) in &tcp {
+            stopper.wake_tcp(listener.local_addr()?);
+        }
+        for (socket, _) in &self.udp {
+            stopper.wake_udp(socket.local_addr()?);
+        }
+        #[cfg(all(windows, feature = "backend-iocp"))]
+        let tcp = if tcp.is_empty() {
+            tcp
+        } else {
+            let err_tx = err_tx.clone();
+            let stopper = stopper.clone();
+            let factory = self.factory.clone();
+            let clock = self.clock.clone();
+            let banner = self.banner.clone();
+            let backlog = self.accept_backlog;
+            let listeners: Vec<_> = tcp.into_iter()
+                .map(|(listener, format)| (listener, format.unwrap_or_else(|| default_format.clone())))
+                .collect();
+            let core = cores.next_core();
+            threads.push(thread::spawn(move || {
+                let served = affinity::pin_to(core)
+                    .and_then(|()| serve_tcp_iocp(listeners, backlog, factory, &clock, banner.as_deref(), &stopper));
+                if let Err(e) = served {
+                    err_tx.send(e).unwrap();
+                }
+            }));
+            SmallVec::new()
+        };
+        for (listener, format) in tcp { 
             let err_tx = err_tx.clone();
-            let factory = Arc::clone(&factory);
-            thread::spawn(move || {
+            let stopper = stopper.clone();
+            let mut factory = self.factory.clone();
+            let clock = self.clock.clone();
+            let format = format.unwrap_or_else(|| default_format.clone());
+            let banner = self.banner.clone();
+            let core = cores.next_core();
+            threads.push(thread::spawn(move || {
+                if let Err(e) = affinity::pin_to(core) {
+                    err_tx.send(e).unwrap();
+                    return;
+                }
+                let mut backoff = MIN_ACCEPT_BACKOFF;
                 for stream in listener.incoming() {
-                    let ans = || {
-                        let stream = stream?;
-                        let hs = Handshake::read_tcp_stream(&stream)?;
-                        let mut sender = Sender::new_tcp(stream);
-                        let mut handler = factory.lock().unwrap().connection_made(sender.try_clone()?);
-                        handler.on_open(hs);
-                        sender.send_time()?;
-                        handler.on_request();
-                        handler.on_close();
-                        Ok(())
-                    };
-                    ans().unwrap_or_else(|e: io::Error| err_tx.send(e).unwrap())
+                    if stopper.is_stopped() {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            backoff = MIN_ACCEPT_BACKOFF;
+                            serve_tcp(&mut factory, &clock, &*format, banner.as_deref(), stream)
+                                .unwrap_or_else(|e| factory.on_error(e))
+                        }
+                        // accepting again at once would likely fail the same way, e.g. on EMFILE
+                        Err(e) => {
+                            factory.on_error(e);
+                            thread::sleep(backoff);
+                            backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        }
+                    }
                 }
-            });   
+            }));
         }
-        for socket in self.udp {
+        for (socket, format) in self.udp {
+            let socket = Arc::new(socket);
+            let format = format.unwrap_or_else(|| default_format.clone());
+            if self.udp_workers == 0 && self.udp_batch_size > 1 {
+                let err_tx = err_tx.clone();
+                let stopper = stopper.clone();
+                let mut factory = self.factory.clone();
+                let mut batch = UdpBatch::new(self.udp_batch_size);
+                let clock = self.clock.clone();
+                let core = cores.next_core();
+                threads.push(thread::spawn(move || {
+                    if let Err(e) = affinity::pin_to(core) {
+                        err_tx.send(e).unwrap();
+                        return;
+                    }
+                    while !stopper.is_stopped() {
+                        batch.serve(&mut factory, &clock, &*format, &socket, &stopper)
+                            .unwrap_or_else(|e| factory.on_error(e))
+                    }
+                }));
+                continue;
+            }
+            if self.udp_workers == 0 {
+                let err_tx = err_tx.clone();
+                let stopper = stopper.clone();
+                let mut factory = self.factory.clone();
+                let clock = self.clock.clone();
+                let core = cores.next_core();
+                threads.push(thread::spawn(move || {
+                    if let Err(e) = affinity::pin_to(core) {
+                        err_tx.send(e).unwrap();
+                        return;
+                    }
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        let received = socket.recv_from(&mut buf);
+                        if stopper.is_stopped() {
+                            break;
+                        }
+                        received.and_then(|(_size, addr)| serve_udp(&mut factory, &clock, &*format, &socket, addr))
+                            .unwrap_or_else(|e| factory.on_error(e))
+                    }
+                }));
+                continue;
+            }
+            let mut queues = Vec::with_capacity(self.udp_workers);
+            for _ in 0..self.udp_workers {
+                let (job_tx, job_rx) = mpsc::sync_channel::<SocketAddr>(self.udp_queue_len);
+                let err_tx = err_tx.clone();
+                let mut factory = self.factory.clone();
+                let socket = socket.clone();
+                let clock = self.clock.clone();
+                let format = format.clone();
+                let core = cores.next_core();
+                threads.push(thread::spawn(move || {
+                    if let Err(e) = affinity::pin_to(core) {
+                        err_tx.send(e).unwrap();
+                        return;
+                    }
+                    for addr in job_rx {
+                        serve_udp(&mut factory, &clock, &*format, &socket, addr)
+                            .unwrap_or_else(|e| factory.on_error(e))
+                    }
+                }));
+                queues.push(job_tx);
+            }
             let err_tx = err_tx.clone();
-            let factory = Arc::clone(&factory);
-            thread::spawn(move || {
+            let stopper = stopper.clone();
+            let mut factory = self.factory.clone();
+            let core = cores.next_core();
+            threads.push(thread::spawn(move || {
+                if let Err(e) = affinity::pin_to(core) {
+                    err_tx.send(e).unwrap();
+                    return;
+                }
                 let mut buf = [0u8; 1024];
-                let mut ans = || {
-                    let (_size, addr) = socket.recv_from(&mut buf)?;
-                    let hs = Handshake::from_udp_addr(addr);
-                    let mut sender = Sender::new_udp(socket.try_clone()?, addr);
-                    let mut handler = factory.lock().unwrap().connection_made(sender.try_clone()?);
-                    handler.on_open(hs);
-                    sender.send_time()?;
-                    handler.on_request();
-                    handler.on_close();
-                    Ok(())
-                };
+                let mut next = 0;
+                // the workers finish their queues and stop once `queues` is dropped
                 loop {
-                    ans().unwrap_or_else(|e: io::Error| err_tx.send(e).unwrap())
+                    // daytime ignores the datagram payload, only the origin is forwarded
+                    let received = socket.recv_from(&mut buf);
+                    if stopper.is_stopped() {
+                        break;
+                    }
+                    let addr = match received {
+                        Ok((_size, addr)) => addr,
+                        Err(e) => { factory.on_error(e); continue }
+                    };
+                    for i in 0..queues.len() {
+                        let queue = &queues[(next + i) % queues.len()];
+                        if queue.try_send(addr).is_ok() {
+                            break;
+                        }
+                    }
+                    next = (next + 1) % queues.len();
                 }
-            });
+            }));
         }
-        while let Ok(err) = err_rx.recv() {
-            return Err(err);
+        drop(err_tx);
+        let ans = match err_rx.recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        };
+        stopper.stop();
+        for thread in threads {
+            let _ = thread.join();
         }
-        Ok(())
+        ans
     } 
+
+    /// `run_until` on a thread of its own, stopped through the returned handle.
+    pub fn run_detached(self) -> io::Result<ServerHandle> {
+        let local_addrs = self.local_addrs()?;
+        let stopper = Stopper::new();
+        let serving = stopper.clone();
+        ServerHandle::spawn_stoppable("laji-daytime", local_addrs, stopper, move || self.run_until(&serving))
+    }
+}
+
+fn serve_tcp<F>(factory: &mut F, clock: &Arc<dyn Clock>, format: &dyn TimeFormat, banner: Option<&str>, mut stream: TcpStream) -> io::Result<()>
+where 
+    F: Factory 
+{
+    let hs = Handshake::read_tcp_stream(&stream)?;
+    if let Some(banner) = banner {
+        framing::write_line(&mut stream, banner, MAX_BANNER_LEN)?;
+    }
+    let mut sender = Sender::new_tcp(stream, clock.clone());
+    let mut handler = factory.connection_made(sender.try_clone()?);
+    handler.on_open(hs);
+    answer(&mut handler, &mut sender, format, clock);
+    Ok(())
+}
+
+/// Send the time for a handler that was opened, telling it how that went.
+fn answer<H>(handler: &mut H, sender: &mut Sender, format: &dyn TimeFormat, clock: &Arc<dyn Clock>)
+where 
+    H: Handler 
+{
+    match sender.send_time_formatted(format, &clock.now()) {
+        Ok(_) => handler.on_request(),
+        Err(e) => handler.on_error(e),
+    }
+    handler.on_close();
+}
+
+/// Serve every TCP listener from one completion port, `backlog` accepts posted on each.
+#[cfg(all(windows, feature = "backend-iocp"))]
+fn serve_tcp_iocp<F>(
+    listeners: Vec<(TcpListener, Arc<dyn TimeFormat>)>,
+    backlog: usize,
+    mut factory: F,
+    clock: &Arc<dyn Clock>,
+    banner: Option<&str>,
+    stopper: &Stopper,
+) -> io::Result<()>
+where 
+    F: Factory 
+{
+    use miow::iocp::{CompletionPort, CompletionStatus};
+    use crate::iocp::{self, Acceptor};
+    let port = CompletionPort::new(1)?;
+    let mut acceptors = Vec::with_capacity(listeners.len());
+    let mut formats = Vec::with_capacity(listeners.len());
+    let ans = (|| -> io::Result<()> {
+        for (token, (listener, format)) in listeners.into_iter().enumerate() {
+            acceptors.push(Acceptor::new(listener, &port, token, backlog)?);
+            formats.push(format);
+        }
+        let mut statuses = vec![CompletionStatus::zero(); iocp::STATUS_CAPACITY];
+        while !stopper.is_stopped() {
+            for status in iocp::wait(&port, &mut statuses, None)?.iter() {
+                let token = status.token();
+                if let Some((stream, _, _)) = acceptors[token].complete(status)? {
+                    if stopper.is_stopped() {
+                        break;
+                    }
+                    serve_tcp(&mut factory, clock, &*formats[token], banner, stream)
+                        .unwrap_or_else(|e| factory.on_error(e));
+                }
+            }
+        }
+        Ok(())
+    })();
+    iocp::shut_down(&port, acceptors, 0, ());
+    ans
+}
+
+fn serve_udp<F>(factory: &mut F, clock: &Arc<dyn Clock>, format: &dyn TimeFormat, socket: &Arc<UdpSocket>, addr: SocketAddr) -> io::Result<()>
+where 
+    F: Factory 
+{
+    let hs = Handshake::from_udp_addr(addr);
+    let mut sender = Sender::new_udp(socket.clone(), addr, clock.clone());
+    let mut handler = factory.connection_made(sender.try_clone()?);
+    handler.on_open(hs);
+    answer(&mut handler, &mut sender, format, clock);
+    Ok(())
+}
+
+/// Answers whatever datagrams are waiting with one time and one `Batch` flush.
+struct UdpBatch {
+    buf: [u8; 1024],
+    origins: Vec<SocketAddr>,
+    batch: Batch,
+}
+
+impl UdpBatch {
+    fn new(size: usize) -> Self {
+        Self { buf: [0u8; 1024], origins: Vec::with_capacity(size), batch: Batch::with_capacity(size) }
+    }
+
+    /// Wait for a datagram, take every other one already waiting up to the batch size, and
+    /// answer them all. Handlers hear how the answers went once they were sent.
+    fn serve<F>(&mut self, factory: &mut F, clock: &Arc<dyn Clock>, format: &dyn TimeFormat, socket: &Arc<UdpSocket>, stopper: &Stopper) -> io::Result<()>
+    where
+        F: Factory
+    {
+        let received = socket.recv_from(&mut self.buf);
+        if stopper.is_stopped() {
+            return Ok(());
+        }
+        let (_size, addr) = received?;
+        self.origins.clear();
+        self.origins.push(addr);
+        while self.origins.len() < self.batch.capacity() {
+            match udp_batch::recv_ready(socket, &mut self.buf)? {
+                Some((_size, addr)) => self.origins.push(addr),
+                None => break,
+            }
+        }
+        let now = clock.now();
+        let mut time = ResponseBuf::new();
+        format.write_time(&mut time, &now)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "response too long"))?;
+        let mut handlers = Vec::with_capacity(self.origins.len());
+        for &addr in &self.origins {
+            let mut handler = factory.connection_made(Sender::new_udp(socket.clone(), addr, clock.clone()));
+            handler.on_open(Handshake::from_udp_addr(addr));
+            self.batch.push(addr, time.as_bytes());
+            handlers.push(handler);
+        }
+        let sent = self.batch.flush(socket);
+        for mut handler in handlers {
+            match &sent {
+                Ok(_) => handler.on_request(),
+                Err(e) => handler.on_error(io::Error::new(e.kind(), e.to_string())),
+            }
+            handler.on_close();
+        }
+        sent.map(drop)
+    }
 }
 
 pub trait Factory {
     type Handler: Handler; 
 
     fn connection_made(&mut self, _sender: Sender) -> Self::Handler;
+
+    /// Accepting, receiving or answering failed before a handler was made, or a batch of
+    /// answers could not be sent. The server keeps serving.
+    #[inline]
+    fn on_error(&mut self, _err: io::Error) {}
 }
 
 impl<F, H> Factory for F 
@@ -136,52 +670,416 @@ where
     }
 }
 
+/// Sends to the one peer a handler was made for, the time it sends coming from the server's
+/// `Clock`.
+pub struct Sender {
+    link: Link,
+    clock: Arc<dyn Clock>,
+}
+
 #[derive(Debug)]
-pub enum Sender {
+enum Link {
     Tcp {
         stream: TcpStream,
     },
     Udp {
-        socket: UdpSocket,
+        socket: Arc<UdpSocket>,
         target: SocketAddr,
     }
 }
 
+impl fmt::Debug for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender").field("link", &self.link).finish()
+    }
+}
+
 impl Sender {
     #[inline]
-    fn new_tcp(stream: TcpStream) -> Self {
-        Sender::Tcp { stream }
+    fn new_tcp(stream: TcpStream, clock: Arc<dyn Clock>) -> Self {
+        Sender { link: Link::Tcp { stream }, clock }
     }
 
     #[inline]
-    fn new_udp(socket: UdpSocket, target: SocketAddr) -> Self {
-        Sender::Udp { socket, target }
+    fn new_udp(socket: Arc<UdpSocket>, target: SocketAddr, clock: Arc<dyn Clock>) -> Self {
+        Sender { link: Link::Udp { socket, target }, clock }
     }
 
     #[inline]
     pub fn send<'m, M>(&mut self, msg: M) -> io::Result<usize>
     where M: Into<Cow<'m, str>> {
-        let buf = msg.into().to_string().into_bytes();
-        match self {
-            Sender::Tcp { stream } => stream.write(&buf),
-            Sender::Udp { socket, target } => socket.send_to(&buf, *target)
+        let msg = msg.into();
+        match &mut self.link {
+            Link::Tcp { stream } => stream.write(msg.as_bytes()),
+            Link::Udp { socket, target } => socket.send_to(msg.as_bytes(), *target)
         }
     }
 
+    /// Send the whole buffer, as one write sequence on TCP or one datagram on UDP.
+    #[inline]
+    pub fn send_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &mut self.link {
+            Link::Tcp { stream } => stream.write_all(buf),
+            Link::Udp { socket, target } => {
+                let len = socket.send_to(buf, *target)?;
+                if len != buf.len() {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "datagram truncated"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    pub fn send_fmt(&mut self, args: fmt::Arguments) -> io::Result<usize> {
+        let mut buf = ResponseBuf::new();
+        fmt::write(&mut buf, args)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "response too long"))?;
+        self.send_all(buf.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    /// Send the time from the server's `Clock` as a daytime response.
     #[inline]
     pub fn send_time(&mut self) -> io::Result<usize> {
-        let time_string = chrono::offset::Local::now().to_rfc2822();
-        self.send(time_string)
+        let now = self.clock.now();
+        self.send_time_at(&now)
+    }
+
+    /// Send `time` as a daytime response, instead of the current time.
+    #[inline]
+    pub fn send_time_at<Tz>(&mut self, time: &DateTime<Tz>) -> io::Result<usize>
+    where
+        Tz: TimeZone
+    {
+        let mut buf = ResponseBuf::new();
+        write_rfc2822(&mut buf, time)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "response too long"))?;
+        self.send_all(buf.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    /// Send `time` rendered by `format`.
+    #[inline]
+    pub fn send_time_formatted(&mut self, format: &dyn TimeFormat, time: &DateTime<FixedOffset>) -> io::Result<usize> {
+        let mut buf = ResponseBuf::new();
+        format.write_time(&mut buf, time)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "response too long"))?;
+        self.send_all(buf.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    /// Enable or disable Nagle's algorithm on a TCP sender; UDP senders ignore it.
+    #[inline]
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match &self.link {
+            Link::Tcp { stream } => stream.set_nodelay(nodelay),
+            Link::Udp { .. } => Ok(()),
+        }
+    }
+
+    /// Start a corked write: everything written to the returned guard is held
+    /// back and sent in one piece when it is finished or dropped.
+    #[inline]
+    pub fn corked(&mut self) -> Corked<'_> {
+        Corked { sender: self, buf: Vec::new() }
     }
 
     #[inline]
     pub fn try_clone(&self) -> io::Result<Self> {
-        Ok(match self {
-            Sender::Tcp { stream } => 
-                Sender::Tcp { stream: stream.try_clone()? },
-            Sender::Udp { socket, target } => 
-                Sender::Udp { socket: socket.try_clone()?, target: target.clone() }
-        })
+        let link = match &self.link {
+            Link::Tcp { stream } => 
+                Link::Tcp { stream: stream.try_clone()? },
+            Link::Udp { socket, target } => 
+                Link::Udp { socket: socket.clone(), target: *target }
+        };
+        Ok(Sender { link, clock: self.clock.clone() })
+    }
+
+    /// Turn this sender into one that can be cloned and moved to other threads, for replies
+    /// produced after the callback returned.
+    #[inline]
+    pub fn into_shared(self) -> SharedSender {
+        SharedSender { inner: Arc::new(Mutex::new(self)) }
+    }
+}
+
+/// A `Sender` shared between threads; clones write to the same peer, one send at a time.
+///
+/// A TCP connection stays open until the server and every clone are done with it.
+#[derive(Clone, Debug)]
+pub struct SharedSender {
+    inner: Arc<Mutex<Sender>>,
+}
+
+impl SharedSender {
+    #[inline]
+    pub fn send<'m, M>(&self, msg: M) -> io::Result<usize>
+    where M: Into<Cow<'m, str>> {
+        self.inner.lock().unwrap().send(msg)
+    }
+
+    #[inline]
+    pub fn send_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.lock().unwrap().send_all(buf)
+    }
+
+    #[inline]
+    pub fn send_fmt(&self, args: fmt::Arguments) -> io::Result<usize> {
+        self.inner.lock().unwrap().send_fmt(args)
+    }
+
+    #[inline]
+    pub fn send_time(&self) -> io::Result<usize> {
+        self.inner.lock().unwrap().send_time()
+    }
+
+    #[inline]
+    pub fn send_time_at<Tz>(&self, time: &DateTime<Tz>) -> io::Result<usize>
+    where
+        Tz: TimeZone
+    {
+        self.inner.lock().unwrap().send_time_at(time)
+    }
+}
+
+impl From<Sender> for SharedSender {
+    #[inline]
+    fn from(sender: Sender) -> Self {
+        sender.into_shared()
+    }
+}
+
+#[derive(Debug)]
+pub struct Corked<'a> {
+    sender: &'a mut Sender,
+    buf: Vec<u8>,
+}
+
+impl Corked<'_> {
+    /// Send everything written so far and end the corked write.
+    #[inline]
+    pub fn finish(mut self) -> io::Result<()> {
+        self.uncork()
+    }
+
+    #[inline]
+    fn uncork(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let ans = self.sender.send_all(&self.buf);
+        self.buf.clear();
+        ans
+    }
+}
+
+impl Write for Corked<'_> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Corked<'_> {
+    fn drop(&mut self) {
+        let _ = self.uncork();
+    }
+}
+
+const RESPONSE_BUF_LEN: usize = 256;
+
+/// Fixed-size stack buffer a daytime response is formatted into before sending.
+pub struct ResponseBuf {
+    buf: [u8; RESPONSE_BUF_LEN],
+    len: usize,
+}
+
+impl Default for ResponseBuf {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseBuf {
+    #[inline]
+    pub fn new() -> Self {
+        Self { buf: [0u8; RESPONSE_BUF_LEN], len: 0 }
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // only ever filled through fmt::Write, so always valid utf-8
+        std::str::from_utf8(self.as_bytes()).unwrap()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for ResponseBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > RESPONSE_BUF_LEN {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ResponseBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ResponseBuf").field(&self.as_str()).finish()
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", 
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Write `time` in RFC 2822 form without allocating.
+///
+/// The day of month is written without padding (`Tue, 1 Jul 2003 ..`), unlike
+/// `DateTime::to_rfc2822`, which pads it with a space on some chrono versions.
+pub fn write_rfc2822<W, Tz>(w: &mut W, time: &DateTime<Tz>) -> fmt::Result
+where 
+    W: fmt::Write,
+    Tz: TimeZone,
+{
+    let offset = time.offset().fix().local_minus_utc();
+    let (sign, offset) = if offset < 0 { ('-', -offset) } else { ('+', offset) };
+    write!(w, "{}, {} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+        WEEKDAYS[time.weekday().num_days_from_monday() as usize],
+        time.day(), MONTHS[time.month0() as usize], time.year(),
+        time.hour(), time.minute(), time.second(),
+        sign, offset / 3600, offset / 60 % 60)
+}
+
+/// How a daytime server renders the time it reports.
+///
+/// Closures taking the writer and the time implement it too.
+pub trait TimeFormat: Send + Sync {
+    fn write_time(&self, w: &mut dyn fmt::Write, time: &DateTime<FixedOffset>) -> fmt::Result;
+}
+
+impl<F> TimeFormat for F
+where
+    F: Fn(&mut dyn fmt::Write, &DateTime<FixedOffset>) -> fmt::Result + Send + Sync
+{
+    #[inline]
+    fn write_time(&self, w: &mut dyn fmt::Write, time: &DateTime<FixedOffset>) -> fmt::Result {
+        self(w, time)
+    }
+}
+
+/// The default format, as written by `write_rfc2822`.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Rfc2822;
+
+impl TimeFormat for Rfc2822 {
+    #[inline]
+    fn write_time(&self, mut w: &mut dyn fmt::Write, time: &DateTime<FixedOffset>) -> fmt::Result {
+        write_rfc2822(&mut w, time)
+    }
+}
+
+/// Day and month names of a language, and the pattern they are put together with.
+///
+/// The pattern understands `%A` (weekday name), `%B` (month name), `%e` (day of month),
+/// `%d` and `%m` (day and month, two digits), `%Y`, `%H`, `%M`, `%S`, `%z` (as `+0200`) and
+/// `%%`; anything else is copied as it is.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Locale {
+    pub weekdays: [&'static str; 7],
+    pub months: [&'static str; 12],
+    pub pattern: &'static str,
+}
+
+impl Locale {
+    pub const ENGLISH: Locale = Locale {
+        weekdays: ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"],
+        months: ["January", "February", "March", "April", "May", "June",
+            "July", "August", "September", "October", "November", "December"],
+        pattern: "%A, %e %B %Y %H:%M:%S %z",
+    };
+
+    pub const GERMAN: Locale = Locale {
+        weekdays: ["Montag", "Dienstag", "Mittwoch", "Donnerstag", "Freitag", "Samstag", "Sonntag"],
+        months: ["Januar", "Februar", "März", "April", "Mai", "Juni",
+            "Juli", "August", "September", "Oktober", "November", "Dezember"],
+        pattern: "%A, %e. %B %Y %H:%M:%S %z",
+    };
+
+    pub const FRENCH: Locale = Locale {
+        weekdays: ["lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche"],
+        months: ["janvier", "février", "mars", "avril", "mai", "juin",
+            "juillet", "août", "septembre", "octobre", "novembre", "décembre"],
+        pattern: "%A %e %B %Y %H:%M:%S %z",
+    };
+
+    pub const CHINESE: Locale = Locale {
+        weekdays: ["星期一", "星期二", "星期三", "星期四", "星期五", "星期六", "星期日"],
+        months: ["1月", "2月", "3月", "4月", "5月", "6月",
+            "7月", "8月", "9月", "10月", "11月", "12月"],
+        pattern: "%Y年%B%e日 %A %H:%M:%S %z",
+    };
+}
+
+impl TimeFormat for Locale {
+    fn write_time(&self, w: &mut dyn fmt::Write, time: &DateTime<FixedOffset>) -> fmt::Result {
+        let mut chars = self.pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                w.write_char(c)?;
+                continue;
+            }
+            match chars.next() {
+                Some('A') => w.write_str(self.weekdays[time.weekday().num_days_from_monday() as usize])?,
+                Some('B') => w.write_str(self.months[time.month0() as usize])?,
+                Some('e') => write!(w, "{}", time.day())?,
+                Some('d') => write!(w, "{:02}", time.day())?,
+                Some('m') => write!(w, "{:02}", time.month())?,
+                Some('Y') => write!(w, "{:04}", time.year())?,
+                Some('H') => write!(w, "{:02}", time.hour())?,
+                Some('M') => write!(w, "{:02}", time.minute())?,
+                Some('S') => write!(w, "{:02}", time.second())?,
+                Some('z') => {
+                    let offset = time.offset().local_minus_utc();
+                    let (sign, offset) = if offset < 0 { ('-', -offset) } else { ('+', offset) };
+                    write!(w, "{}{:02}{:02}", sign, offset / 3600, offset / 60 % 60)?
+                }
+                Some('%') => w.write_char('%')?,
+                Some(other) => { w.write_char('%')?; w.write_char(other)? }
+                None => w.write_char('%')?,
+            }
+        }
+        Ok(())
     }
 }
 
@@ -190,6 +1088,9 @@ pub trait Handler {
 
     fn on_request(&mut self) {}
 
+    /// Sending the time failed, in place of `on_request`; `on_close` follows.
+    fn on_error(&mut self, _err: io::Error) {}
+
     fn on_close(&mut self) {}
 }
 
@@ -245,6 +1146,194 @@ impl Handshake {
     }
 }
 
+/// Ask the daytime server at `addr` for the time over TCP, with `Client`'s defaults.
+pub fn connect<A, H>(addr: A, handler: H) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    H: ClientHandler
+{
+    Client::new().query_tcp(addr, handler)
+}
+
+/// Ask the daytime server at `addr` for the time over UDP, with `Client`'s defaults.
+pub fn connect_udp<A, H>(addr: A, handler: H) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    H: ClientHandler
+{
+    Client::new().query_udp(addr, handler)
+}
+
+const MAX_CLIENT_LINES: usize = 8;
+
+/// The querying side of the protocol. Where the server's handler hears of requests, this
+/// one hears of the answers.
+pub trait ClientHandler {
+    /// The connection, or for UDP the server a reply came from.
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// A line sent ahead of the time, such as a server's greeting banner.
+    fn on_banner(&mut self, _line: &str) {}
+
+    /// The time string, without its line terminator.
+    fn on_time(&mut self, time: &str);
+
+    fn on_close(&mut self) {}
+}
+
+impl<F> ClientHandler for F
+where
+    F: FnMut(&str)
+{
+    #[inline]
+    fn on_time(&mut self, time: &str) {
+        self(time)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Client {
+    timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Client {
+    #[inline]
+    pub fn new() -> Self {
+        Self { timeout: Duration::from_secs(5), proxy: None }
+    }
+
+    /// How long connecting, and then each read, may take.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connect to TCP servers through a SOCKS5 proxy.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Connect and read until the server closes. The last line is the time; any before it
+    /// go to `on_banner`.
+    pub fn query_tcp<A, H>(&self, addr: A, mut handler: H) -> io::Result<()>
+    where
+        A: ToSocketAddrs,
+        H: ClientHandler
+    {
+        let stream = socks5::connect_to(self.proxy.as_ref(), addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        handler.on_open(Handshake::read_tcp_stream(&stream)?);
+        let mut reader = BufReader::new(stream);
+        let mut previous: Option<String> = None;
+        for _ in 0..=MAX_CLIENT_LINES {
+            let mut line = String::new();
+            if framing::read_line(&mut reader, &mut line, MAX_BANNER_LEN).map_err(resolve::timed_out)? == 0 {
+                let time = previous
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "daytime server sent nothing"))?;
+                handler.on_time(&time);
+                handler.on_close();
+                return Ok(());
+            }
+            let line = framing::trim_line(&line).to_string();
+            if let Some(banner) = previous.replace(line) {
+                handler.on_banner(&banner);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "daytime server sent too many lines"))
+    }
+
+    /// Send an empty datagram and wait for the answer. Datagrams from anywhere but the
+    /// server are ignored. Each of the server's addresses is asked in turn until one
+    /// answers within the timeout.
+    pub fn query_udp<A, H>(&self, addr: A, mut handler: H) -> io::Result<()>
+    where
+        A: ToSocketAddrs,
+        H: ClientHandler
+    {
+        let mut buf = [0u8; MAX_BANNER_LEN];
+        let (server, len) = resolve::try_each(addr, |server| {
+            self.ask_udp(server, &mut buf).map(|len| (server, len))
+        })?;
+        let time = std::str::from_utf8(&buf[..len])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "daytime reply is not utf-8"))?;
+        handler.on_open(Handshake::from_udp_addr(server));
+        handler.on_time(framing::trim_line(time));
+        handler.on_close();
+        Ok(())
+    }
+    fn ask_udp(&self, server: SocketAddr, buf: &mut [u8]) -> io::Result<usize> {
+        let socket = resolve::bind_ephemeral_for(server)?;
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.send(&[])?;
+        socket.recv(buf).map_err(resolve::timed_out)
+    }
+}
+
+impl Default for Client {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Codec {
+    datagram: bool,
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Codec {
+    /// Codec for TCP streams, yielding one time string per line or at EOF.
+    #[inline]
+    pub fn stream() -> Self {
+        Self { datagram: false }
+    }
+
+    /// Codec for UDP sockets, yielding one time string per datagram.
+    #[inline]
+    pub fn datagram() -> Self {
+        Self { datagram: true }
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for Codec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        if self.datagram {
+            let len = buf.len();
+            let line = buf.split_to(len);
+            let line = std::str::from_utf8(&line)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "daytime reply is not utf-8"))?;
+            return Ok(Some(framing::trim_line(line).to_string()));
+        }
+        LineCodec::new().decode(buf)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        LineCodec::new().decode_eof(buf)
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for Codec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(line.as_bytes());
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     mod laji_daytime {
@@ -263,6 +1352,7 @@ mod tests {
     #[test]
     fn listen_batch() -> io::Result<()> {
         use super::*;
+        #[derive(Clone)]
         struct MyFactory;
         impl Factory for MyFactory {
             type Handler = MyHandler;
@@ -270,7 +1360,8 @@ mod tests {
                 MyHandler(sender)
             }
         }
-        struct MyHandler(Sender);
+        // held only to keep the connection alive for the handler's lifetime
+        struct MyHandler(#[allow(dead_code)] Sender);
         impl Handler for MyHandler {
             fn on_open(&mut self, shake: Handshake) {
                 println!("Open! Shake: [{:?}]", shake);
@@ -292,4 +1383,359 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn udp_worker_pool() -> io::Result<()> {
+        use super::*;
+        let server = LajiDaytime::new(|_sender| || {})
+            .bind_udp("127.0.0.1:0")?
+            .udp_workers(2);
+        let addr = server.local_addrs()?[0];
+        thread::spawn(move || server.run().unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        let mut buf = [0u8; 128];
+        for _ in 0..4 {
+            client.send_to(b"", addr)?;
+            let (len, _) = client.recv_from(&mut buf)?;
+            assert!(chrono::DateTime::parse_from_rfc2822(std::str::from_utf8(&buf[..len]).unwrap()).is_ok());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corked_tcp() -> io::Result<()> {
+        use super::*;
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let mut sender = Sender::new_tcp(listener.accept()?.0, Arc::new(SystemClock));
+        sender.set_nodelay(true)?;
+        {
+            let mut corked = sender.corked();
+            corked.write_all(b"Tue, 1 Jul 2003 ")?;
+            corked.write_all(b"10:52:37 +0200")?;
+            corked.finish()?;
+        }
+        drop(sender);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply)?;
+        assert_eq!(reply, "Tue, 1 Jul 2003 10:52:37 +0200");
+        Ok(())
+    }
+
+    #[test]
+    fn send_time_reads_the_clock() -> io::Result<()> {
+        use super::*;
+        use std::io::Read;
+        use crate::clock::ManualClock;
+        let clock = ManualClock::new(DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let sender = Sender::new_tcp(listener.accept()?.0, Arc::new(clock)).into_shared();
+        sender.send_time()?;
+        drop(sender);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply)?;
+        assert_eq!(reply, "Tue, 1 Jul 2003 10:52:37 +0200");
+        Ok(())
+    }
+
+    #[test]
+    fn udp_batched() -> io::Result<()> {
+        use super::*;
+        let server = LajiDaytime::new(|_sender| || {})
+            .bind_udp("127.0.0.1:0")?
+            .udp_batch_size(8);
+        let addr = server.local_addrs()?[0];
+        thread::spawn(move || server.run().unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        for _ in 0..4 {
+            client.send_to(b"", addr)?;
+        }
+        let mut buf = [0u8; 128];
+        for _ in 0..4 {
+            let (len, _) = client.recv_from(&mut buf)?;
+            assert!(chrono::DateTime::parse_from_rfc2822(std::str::from_utf8(&buf[..len]).unwrap()).is_ok());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn manual_clock() -> io::Result<()> {
+        use super::*;
+        use std::io::Read;
+        use crate::clock::ManualClock;
+        let clock = ManualClock::new(DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap());
+        let server = LajiDaytime::new(|_sender| || {})
+            .bind_tcp("127.0.0.1:0")?
+            .bind_udp("127.0.0.1:0")?
+            .clock(clock.clone());
+        let addrs = server.local_addrs()?;
+        thread::spawn(move || server.run().unwrap());
+        let mut reply = String::new();
+        TcpStream::connect(addrs[0])?.read_to_string(&mut reply)?;
+        assert_eq!(reply, "Tue, 1 Jul 2003 10:52:37 +0200");
+        clock.advance(std::time::Duration::from_secs(24 * 3600));
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        client.send_to(b"", addrs[1])?;
+        let mut buf = [0u8; 128];
+        let (len, _) = client.recv_from(&mut buf)?;
+        assert_eq!(&buf[..len], b"Wed, 2 Jul 2003 10:52:37 +0200");
+        Ok(())
+    }
+
+    #[test]
+    fn rfc2822_literals() {
+        use super::*;
+        let times = [
+            (FixedOffset::east(2 * 3600).ymd(2003, 7, 1).and_hms(10, 52, 37),
+                "Tue, 1 Jul 2003 10:52:37 +0200"),
+            (FixedOffset::west(9 * 3600 + 30 * 60).ymd(1999, 12, 31).and_hms(23, 59, 59),
+                "Fri, 31 Dec 1999 23:59:59 -0930"),
+            (FixedOffset::east(0).ymd(2019, 2, 10).and_hms(0, 0, 0),
+                "Sun, 10 Feb 2019 00:00:00 +0000"),
+        ];
+        for (time, expected) in &times {
+            let mut buf = ResponseBuf::new();
+            write_rfc2822(&mut buf, time).unwrap();
+            assert_eq!(buf.as_str(), *expected);
+            assert_eq!(DateTime::parse_from_rfc2822(buf.as_str()).unwrap(), *time);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "backend-tokio")]
+    fn codec_lines() -> io::Result<()> {
+        use super::*;
+        let mut codec = Codec::stream();
+        let mut buf = BytesMut::from(&b"Tue, 1 Jul 2003 10:52:37 +0200\r\nWed, 2"[..]);
+        assert_eq!(codec.decode(&mut buf)?, Some("Tue, 1 Jul 2003 10:52:37 +0200".to_string()));
+        assert_eq!(codec.decode(&mut buf)?, None);
+        assert_eq!(codec.decode_eof(&mut buf)?, Some("Wed, 2".to_string()));
+        let mut buf = BytesMut::from(&b"Tue, 1 Jul 2003 10:52:37 +0200"[..]);
+        assert_eq!(Codec::datagram().decode(&mut buf)?, Some("Tue, 1 Jul 2003 10:52:37 +0200".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn validate() -> io::Result<()> {
+        use super::*;
+        let factory = |_sender: Sender| || {};
+        assert_eq!(LajiDaytime::new(factory).validate(), Err(ConfigError::NoListeners));
+        let daytime = LajiDaytime::new(factory).bind_udp("127.0.0.1:0")?;
+        assert_eq!(daytime.udp_batch_size(0).validate(), Err(ConfigError::Zero("udp_batch_size")));
+        let daytime = LajiDaytime::new(factory).bind_udp("127.0.0.1:0")?.udp_workers(2).udp_batch_size(8);
+        assert_eq!(daytime.run().unwrap_err().to_string(), "udp_batch_size only applies without udp_workers");
+        Ok(())
+    }
+
+    #[test]
+    fn bind_all() -> io::Result<()> {
+        use super::*;
+        let daytime = LajiDaytime::new(|_sender: Sender| || {})
+            .bind_tcp_all(&["127.0.0.1:0", "127.0.0.1:0"])?
+            .bind_udp_all(vec![("127.0.0.1", 0)])?;
+        assert_eq!((daytime.tcp.len(), daytime.udp.len()), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn listen_spawned() -> io::Result<()> {
+        use std::io::Read;
+        let server = laji_daytime::listen_spawned("127.0.0.1:0", |_sender| || {})?;
+        let (tcp, udp) = (server.local_addrs()[0], server.local_addrs()[1]);
+        let mut reply = String::new();
+        std::net::TcpStream::connect(tcp)?.read_to_string(&mut reply)?;
+        assert!(chrono::DateTime::parse_from_rfc2822(reply.trim_end()).is_ok(), "{}", reply);
+        assert_eq!(udp.ip(), tcp.ip());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_sender() -> io::Result<()> {
+        use std::io::Read;
+        let server = laji_daytime::listen_spawned("127.0.0.1:0", |sender: laji_daytime::Sender| {
+            let worker = sender.into_shared();
+            std::thread::spawn(move || worker.send("done later\r\n").unwrap());
+            || {}
+        })?;
+        let mut reply = String::new();
+        std::net::TcpStream::connect(server.local_addrs()[0])?.read_to_string(&mut reply)?;
+        assert!(reply.contains("done later\r\n"), "{}", reply);
+        Ok(())
+    }
+
+    #[test]
+    fn locales() {
+        use super::*;
+        let time = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+        let formats: [(&dyn TimeFormat, &str); 4] = [
+            (&Rfc2822, "Tue, 1 Jul 2003 10:52:37 +0200"),
+            (&Locale::GERMAN, "Dienstag, 1. Juli 2003 10:52:37 +0200"),
+            (&Locale::CHINESE, "2003年7月1日 星期二 10:52:37 +0200"),
+            (&|w: &mut dyn fmt::Write, t: &DateTime<FixedOffset>| write!(w, "{}", t.timestamp()), "1057049557"),
+        ];
+        for (format, expected) in &formats {
+            let mut buf = ResponseBuf::new();
+            format.write_time(&mut buf, &time).unwrap();
+            assert_eq!(buf.as_str(), *expected);
+        }
+    }
+
+    #[test]
+    fn formatted_listeners() -> io::Result<()> {
+        use super::*;
+        use std::io::Read;
+        use crate::clock::ManualClock;
+        let clock = ManualClock::new(DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap());
+        let server = LajiDaytime::new(|_sender| || {})
+            .time_format(Locale::FRENCH)
+            .bind_tcp("127.0.0.1:0")?
+            .bind_tcp_formatted("127.0.0.1:0", Locale::ENGLISH)?
+            .clock(clock);
+        let addrs = server.local_addrs()?;
+        thread::spawn(move || server.run().unwrap());
+        let mut replies = Vec::new();
+        for addr in addrs {
+            let mut reply = String::new();
+            TcpStream::connect(addr)?.read_to_string(&mut reply)?;
+            replies.push(reply);
+        }
+        assert_eq!(replies, ["mardi 1 juillet 2003 10:52:37 +0200", "Tuesday, 1 July 2003 10:52:37 +0200"]);
+        Ok(())
+    }
+
+    #[test]
+    fn banner() -> io::Result<()> {
+        use super::*;
+        use std::io::Read;
+        use crate::clock::ManualClock;
+        let clock = ManualClock::new(DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap());
+        let (tx, rx) = mpsc::channel();
+        let server = LajiDaytime::new(move |mut sender: Sender| {
+            // the greeting is out before the handler is made
+            sender.send("!").unwrap();
+            let tx = tx.clone();
+            move || tx.send(()).unwrap()
+        })
+            .bind_tcp("127.0.0.1:0")?
+            .bind_udp("127.0.0.1:0")?
+            .banner("200 laji daytime ready")
+            .clock(clock);
+        let addrs = server.local_addrs()?;
+        thread::spawn(move || server.run().unwrap());
+        let mut reply = String::new();
+        TcpStream::connect(addrs[0])?.read_to_string(&mut reply)?;
+        assert_eq!(reply, "200 laji daytime ready\r\n!Tue, 1 Jul 2003 10:52:37 +0200");
+        rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        // datagrams are answered with the time alone
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        socket.send_to(b"", addrs[1])?;
+        let mut buf = [0u8; 128];
+        let (len, _) = socket.recv_from(&mut buf)?;
+        assert_eq!(&buf[..len], b"!");
+
+        let multiline = LajiDaytime::new(|_sender| || {}).bind_tcp("127.0.0.1:0")?.banner("one\r\ntwo");
+        assert!(matches!(multiline.validate(), Err(ConfigError::Conflict(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn errors_and_stop() -> io::Result<()> {
+        use super::*;
+        struct Outcomes(mpsc::Sender<Result<(), io::ErrorKind>>);
+        impl Handler for Outcomes {
+            fn on_request(&mut self) {
+                self.0.send(Ok(())).unwrap();
+            }
+            fn on_error(&mut self, err: io::Error) {
+                self.0.send(Err(err.kind())).unwrap();
+            }
+        }
+        let (tx, outcomes) = mpsc::channel();
+        let failing = |_: &mut dyn fmt::Write, _: &DateTime<FixedOffset>| Err(fmt::Error);
+        let server = LajiDaytime::new(move |_sender| Outcomes(tx.clone()))
+            .bind_tcp_formatted("127.0.0.1:0", failing)?
+            .bind_tcp("127.0.0.1:0")?
+            .bind_udp("127.0.0.1:0")?
+            .udp_workers(2)
+            .run_detached()?;
+        let addrs = server.local_addrs().to_vec();
+        let next = || outcomes.recv_timeout(Duration::from_secs(2)).unwrap();
+        // a request that failed is reported, and the listener goes on serving
+        for _ in 0..2 {
+            TcpStream::connect(addrs[0])?;
+            assert_eq!(next(), Err(io::ErrorKind::InvalidInput));
+        }
+        TcpStream::connect(addrs[1])?;
+        assert_eq!(next(), Ok(()));
+        connect_udp(addrs[2], |_: &str| {})?;
+        assert_eq!(next(), Ok(()));
+        server.stop()?;
+        assert!(TcpStream::connect(addrs[1]).is_err());
+        assert!(outcomes.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn client_round_trip() -> io::Result<()> {
+        use super::*;
+        use crate::clock::ManualClock;
+        let clock = ManualClock::new(DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap());
+        let server = LajiDaytime::new(|_sender| || {})
+            .bind_tcp("127.0.0.1:0")?
+            .bind_udp("127.0.0.1:0")?
+            .banner("200 laji daytime ready")
+            .clock(clock);
+        let addrs = server.local_addrs()?;
+        thread::spawn(move || server.run().unwrap());
+
+        struct Recording(mpsc::Sender<String>);
+        impl ClientHandler for Recording {
+            fn on_open(&mut self, shake: Handshake) {
+                self.0.send(match shake {
+                    Handshake::Tcp { peer_addr, .. } => format!("tcp {}", peer_addr),
+                    Handshake::Udp { origin_addr } => format!("udp {}", origin_addr),
+                }).unwrap();
+            }
+            fn on_banner(&mut self, line: &str) {
+                self.0.send(format!("banner {}", line)).unwrap();
+            }
+            fn on_time(&mut self, time: &str) {
+                self.0.send(format!("time {}", time)).unwrap();
+            }
+            fn on_close(&mut self) {
+                self.0.send("close".to_string()).unwrap();
+            }
+        }
+        let client = Client::new().timeout(Duration::from_secs(2));
+        let (tx, events) = mpsc::channel();
+        client.query_tcp(addrs[0], Recording(tx.clone()))?;
+        client.query_udp(addrs[1], Recording(tx))?;
+        assert_eq!(events.iter().collect::<Vec<_>>(), [
+            format!("tcp {}", addrs[0]),
+            "banner 200 laji daytime ready".to_string(),
+            "time Tue, 1 Jul 2003 10:52:37 +0200".to_string(),
+            "close".to_string(),
+            format!("udp {}", addrs[1]),
+            "time Tue, 1 Jul 2003 10:52:37 +0200".to_string(),
+            "close".to_string(),
+        ]);
+
+        let mut times = Vec::new();
+        connect(addrs[0], |time: &str| times.push(time.to_string()))?;
+        connect_udp(addrs[1], |time: &str| times.push(time.to_string()))?;
+        assert_eq!(times, ["Tue, 1 Jul 2003 10:52:37 +0200"; 2]);
+
+        // a server that closes without a word has not told the time
+        let silent = TcpListener::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?;
+        thread::spawn(move || silent.accept().map(drop));
+        let err = client.query_tcp(silent_addr, |_: &str| panic!("no time was sent")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
 }
@@ -0,0 +1,553 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+use crate::{random::random, wire::invalid_data};
+
+pub const SERVER_PORT: u16 = crate::ports::DHCP_SERVER;
+pub const CLIENT_PORT: u16 = crate::ports::DHCP_CLIENT;
+
+/// Broadcast a DISCOVER for `mac` and report every OFFER that comes back within `wait`.
+///
+/// Nothing is requested, so no lease is ever committed. Binding the client port
+/// needs root, and nothing else may be holding it (a running DHCP client will).
+pub fn discover<H>(mac: [u8; 6], wait: Duration, handler: &mut H) -> io::Result<usize>
+where
+    H: Handler
+{
+    let socket = UdpSocket::bind(("0.0.0.0", CLIENT_PORT))?;
+    socket.set_broadcast(true)?;
+    discover_on(&socket, SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT), mac, wait, handler)
+}
+
+/// Like `discover`, but over an existing socket and to any target, e.g. a relay or a test server.
+pub fn discover_on<A, H>(socket: &UdpSocket, target: A, mac: [u8; 6], wait: Duration, handler: &mut H) -> io::Result<usize>
+where
+    A: ToSocketAddrs,
+    H: Handler
+{
+    let target = target.to_socket_addrs()?.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+    let request = Packet::discover(random(), mac);
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    let len = request.encode(&mut buf)?;
+    let old_timeout = socket.read_timeout()?;
+    socket.send_to(&buf[..len], target)?;
+    let ans = collect(socket, request.xid(), Instant::now() + wait, handler);
+    socket.set_read_timeout(old_timeout)?;
+    ans
+}
+
+fn collect<H>(socket: &UdpSocket, xid: u32, deadline: Instant, handler: &mut H) -> io::Result<usize>
+where
+    H: Handler
+{
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    let mut count = 0;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(count);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(ans) => ans,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => return Ok(count),
+            Err(e) => return Err(e),
+        };
+        match Packet::decode(&buf[..len]) {
+            Ok(ref offer) if offer.op() == OP_REPLY && offer.xid() == xid
+                && offer.message_type() == Some(MessageType::Offer) => {
+                count += 1;
+                handler.on_offer(from, offer);
+            }
+            _ => continue,
+        }
+    }
+}
+
+pub trait Handler {
+    fn on_offer(&mut self, from: SocketAddr, offer: &Packet);
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(SocketAddr, &Packet)
+{
+    #[inline]
+    fn on_offer(&mut self, from: SocketAddr, offer: &Packet) {
+        self(from, offer)
+    }
+}
+
+pub const OP_REQUEST: u8 = 1;
+pub const OP_REPLY: u8 = 2;
+
+pub const MAGIC_COOKIE: u32 = 0x6382_5363;
+
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+// op through file, then the magic cookie
+const FIXED_LEN: usize = 236;
+const HEADER_LEN: usize = FIXED_LEN + 4;
+// some relays drop anything shorter than a BOOTP packet
+const MIN_PACKET_LEN: usize = 300;
+const MAX_PACKET_LEN: usize = 1500;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    #[inline]
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+
+    #[inline]
+    fn to_u8(self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum DhcpOption {
+    SubnetMask(Ipv4Addr),
+    Router(Vec<Ipv4Addr>),
+    DnsServers(Vec<Ipv4Addr>),
+    RequestedAddress(Ipv4Addr),
+    LeaseTime(u32),
+    MessageType(MessageType),
+    ServerIdentifier(Ipv4Addr),
+    ParameterRequestList(Vec<u8>),
+    Unknown { code: u8, value: Vec<u8> },
+}
+
+impl DhcpOption {
+    fn decode(code: u8, value: &[u8]) -> io::Result<Self> {
+        let addr = |value: &[u8]| match value {
+            [a, b, c, d] => Ok(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => Err(invalid_data("dhcp address option must be 4 bytes")),
+        };
+        let addrs = |value: &[u8]| {
+            if value.is_empty() || !value.len().is_multiple_of(4) {
+                return Err(invalid_data("dhcp address list option must be a multiple of 4 bytes"));
+            }
+            value.chunks(4).map(addr).collect()
+        };
+        Ok(match code {
+            OPTION_SUBNET_MASK => DhcpOption::SubnetMask(addr(value)?),
+            OPTION_ROUTER => DhcpOption::Router(addrs(value)?),
+            OPTION_DNS_SERVERS => DhcpOption::DnsServers(addrs(value)?),
+            OPTION_REQUESTED_ADDRESS => DhcpOption::RequestedAddress(addr(value)?),
+            OPTION_LEASE_TIME => match value {
+                [a, b, c, d] => DhcpOption::LeaseTime(u32::from_be_bytes([*a, *b, *c, *d])),
+                _ => return Err(invalid_data("dhcp lease time must be 4 bytes")),
+            },
+            OPTION_MESSAGE_TYPE => match value {
+                [value] => DhcpOption::MessageType(MessageType::from_u8(*value)
+                    .ok_or_else(|| invalid_data("unknown dhcp message type"))?),
+                _ => return Err(invalid_data("dhcp message type must be 1 byte")),
+            },
+            OPTION_SERVER_IDENTIFIER => DhcpOption::ServerIdentifier(addr(value)?),
+            OPTION_PARAMETER_REQUEST_LIST => DhcpOption::ParameterRequestList(value.to_vec()),
+            code => DhcpOption::Unknown { code, value: value.to_vec() },
+        })
+    }
+
+    /// Append code, length and value to `out`.
+    fn encode(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut push = |code: u8, value: &[u8]| {
+            if value.len() > 255 {
+                return Err(invalid_data("dhcp option longer than 255 bytes"));
+            }
+            out.push(code);
+            out.push(value.len() as u8);
+            out.extend_from_slice(value);
+            Ok(())
+        };
+        let flatten = |addrs: &[Ipv4Addr]| addrs.iter().flat_map(|addr| addr.octets().to_vec()).collect::<Vec<_>>();
+        match self {
+            DhcpOption::SubnetMask(addr) => push(OPTION_SUBNET_MASK, &addr.octets()),
+            DhcpOption::Router(addrs) => push(OPTION_ROUTER, &flatten(addrs)),
+            DhcpOption::DnsServers(addrs) => push(OPTION_DNS_SERVERS, &flatten(addrs)),
+            DhcpOption::RequestedAddress(addr) => push(OPTION_REQUESTED_ADDRESS, &addr.octets()),
+            DhcpOption::LeaseTime(secs) => push(OPTION_LEASE_TIME, &secs.to_be_bytes()),
+            DhcpOption::MessageType(kind) => push(OPTION_MESSAGE_TYPE, &[kind.to_u8()]),
+            DhcpOption::ServerIdentifier(addr) => push(OPTION_SERVER_IDENTIFIER, &addr.octets()),
+            DhcpOption::ParameterRequestList(codes) => push(OPTION_PARAMETER_REQUEST_LIST, codes),
+            DhcpOption::Unknown { code, value } => push(*code, value),
+        }
+    }
+}
+
+/// A BOOTP/DHCP packet. The `sname` and `file` fields are carried as zeros and
+/// option overloading into them is not supported.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Packet {
+    op: u8,
+    xid: u32,
+    secs: u16,
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    yiaddr: Ipv4Addr,
+    siaddr: Ipv4Addr,
+    giaddr: Ipv4Addr,
+    chaddr: [u8; 16],
+    options: Vec<DhcpOption>,
+}
+
+impl Packet {
+    /// A DISCOVER asking for a broadcast reply, with the usual parameter request list.
+    pub fn discover(xid: u32, mac: [u8; 6]) -> Self {
+        let mut chaddr = [0u8; 16];
+        chaddr[..6].copy_from_slice(&mac);
+        Self {
+            op: OP_REQUEST,
+            xid,
+            secs: 0,
+            flags: FLAG_BROADCAST,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            options: vec![
+                DhcpOption::MessageType(MessageType::Discover),
+                DhcpOption::ParameterRequestList(vec![
+                    OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVERS, OPTION_LEASE_TIME,
+                ]),
+            ],
+        }
+    }
+
+    /// A reply to `request` offering `addr`, as a server would send it.
+    pub fn offer(request: &Packet, addr: Ipv4Addr, server: Ipv4Addr) -> Self {
+        Self {
+            op: OP_REPLY,
+            xid: request.xid,
+            secs: 0,
+            flags: request.flags,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: addr,
+            siaddr: server,
+            giaddr: request.giaddr,
+            chaddr: request.chaddr,
+            options: vec![
+                DhcpOption::MessageType(MessageType::Offer),
+                DhcpOption::ServerIdentifier(server),
+            ],
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, option: DhcpOption) {
+        self.options.push(option);
+    }
+
+    #[inline]
+    pub fn op(&self) -> u8 {
+        self.op
+    }
+
+    #[inline]
+    pub fn xid(&self) -> u32 {
+        self.xid
+    }
+
+    /// The address being offered or assigned.
+    #[inline]
+    pub fn your_addr(&self) -> Ipv4Addr {
+        self.yiaddr
+    }
+
+    #[inline]
+    pub fn server_addr(&self) -> Ipv4Addr {
+        self.siaddr
+    }
+
+    #[inline]
+    pub fn relay_addr(&self) -> Ipv4Addr {
+        self.giaddr
+    }
+
+    #[inline]
+    pub fn mac(&self) -> [u8; 6] {
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&self.chaddr[..6]);
+        mac
+    }
+
+    #[inline]
+    pub fn options(&self) -> &[DhcpOption] {
+        &self.options
+    }
+
+    pub fn message_type(&self) -> Option<MessageType> {
+        self.options.iter().find_map(|option| match option {
+            DhcpOption::MessageType(kind) => Some(*kind),
+            _ => None,
+        })
+    }
+
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        self.options.iter().find_map(|option| match option {
+            DhcpOption::ServerIdentifier(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+
+    pub fn lease_time(&self) -> Option<Duration> {
+        self.options.iter().find_map(|option| match option {
+            DhcpOption::LeaseTime(secs) => Some(Duration::from_secs(*secs as u64)),
+            _ => None,
+        })
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            return Err(invalid_data("dhcp packet too short"));
+        }
+        let addr = |at: usize| Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3]);
+        if u32::from_be_bytes([buf[236], buf[237], buf[238], buf[239]]) != MAGIC_COOKIE {
+            return Err(invalid_data("dhcp magic cookie mismatch"));
+        }
+        let mut chaddr = [0u8; 16];
+        chaddr.copy_from_slice(&buf[28..44]);
+        let mut options = Vec::new();
+        let mut at = HEADER_LEN;
+        while at < buf.len() && buf[at] != OPTION_END {
+            if buf[at] == OPTION_PAD {
+                at += 1;
+                continue;
+            }
+            let len = *buf.get(at + 1).ok_or_else(|| invalid_data("dhcp option truncated"))? as usize;
+            let value = buf.get(at + 2..at + 2 + len).ok_or_else(|| invalid_data("dhcp option truncated"))?;
+            options.push(DhcpOption::decode(buf[at], value)?);
+            at += 2 + len;
+        }
+        Ok(Self {
+            op: buf[0],
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            secs: u16::from_be_bytes([buf[8], buf[9]]),
+            flags: u16::from_be_bytes([buf[10], buf[11]]),
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            giaddr: addr(24),
+            chaddr,
+            options,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut options = Vec::new();
+        for option in &self.options {
+            option.encode(&mut options)?;
+        }
+        options.push(OPTION_END);
+        let len = (HEADER_LEN + options.len()).max(MIN_PACKET_LEN);
+        if buf.len() < len {
+            return Err(invalid_data("buffer too small for dhcp packet"));
+        }
+        let buf = &mut buf[..len];
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+        buf[0] = self.op;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6;
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buf[8..10].copy_from_slice(&self.secs.to_be_bytes());
+        buf[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.ciaddr.octets());
+        buf[16..20].copy_from_slice(&self.yiaddr.octets());
+        buf[20..24].copy_from_slice(&self.siaddr.octets());
+        buf[24..28].copy_from_slice(&self.giaddr.octets());
+        buf[28..44].copy_from_slice(&self.chaddr);
+        buf[FIXED_LEN..HEADER_LEN].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf[HEADER_LEN..HEADER_LEN + options.len()].copy_from_slice(&options);
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for MessageType {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(MessageType::from_u8(u.int_in_range(1..=8)?).unwrap())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for DhcpOption {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let addr = |u: &mut arbitrary::Unstructured| u.arbitrary::<u32>().map(Ipv4Addr::from);
+        let addrs = |u: &mut arbitrary::Unstructured| -> arbitrary::Result<Vec<Ipv4Addr>> {
+            (0..u.int_in_range(1..=8)?).map(|_| addr(u)).collect()
+        };
+        Ok(match u.int_in_range(0..=8)? {
+            0 => DhcpOption::SubnetMask(addr(u)?),
+            1 => DhcpOption::Router(addrs(u)?),
+            2 => DhcpOption::DnsServers(addrs(u)?),
+            3 => DhcpOption::RequestedAddress(addr(u)?),
+            4 => DhcpOption::LeaseTime(u.arbitrary()?),
+            5 => DhcpOption::MessageType(u.arbitrary()?),
+            6 => DhcpOption::ServerIdentifier(addr(u)?),
+            7 => {
+                let mut codes: Vec<u8> = u.arbitrary()?;
+                codes.truncate(255);
+                DhcpOption::ParameterRequestList(codes)
+            }
+            _ => {
+                let code = u.arbitrary()?;
+                match code {
+                    OPTION_PAD | OPTION_SUBNET_MASK | OPTION_ROUTER | OPTION_DNS_SERVERS
+                    | OPTION_REQUESTED_ADDRESS | OPTION_LEASE_TIME | OPTION_MESSAGE_TYPE
+                    | OPTION_SERVER_IDENTIFIER | OPTION_PARAMETER_REQUEST_LIST | OPTION_END =>
+                        return Err(arbitrary::Error::IncorrectFormat),
+                    _ => {}
+                }
+                let mut value: Vec<u8> = u.arbitrary()?;
+                value.truncate(255);
+                DhcpOption::Unknown { code, value }
+            }
+        })
+    }
+}
+
+/// Only packets that encode: at most 4 options of at most 255 bytes each fit in
+/// `MAX_PACKET_LEN` with the header.
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Packet {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let addr = |u: &mut arbitrary::Unstructured| u.arbitrary::<u32>().map(Ipv4Addr::from);
+        let mut chaddr = [0u8; 16];
+        u.fill_buffer(&mut chaddr)?;
+        Ok(Self {
+            op: *u.choose(&[OP_REQUEST, OP_REPLY])?,
+            xid: u.arbitrary()?,
+            secs: u.arbitrary()?,
+            flags: u.arbitrary()?,
+            ciaddr: addr(u)?,
+            yiaddr: addr(u)?,
+            siaddr: addr(u)?,
+            giaddr: addr(u)?,
+            chaddr,
+            options: (0..u.int_in_range(0..=4)?).map(|_| u.arbitrary()).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const MAC: [u8; 6] = [0x02, 0x00, 0x5e, 0x10, 0x00, 0x01];
+
+    #[test]
+    fn discover_round_trip() {
+        let discover = Packet::discover(0xdead_beef, MAC);
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = discover.encode(&mut buf).unwrap();
+        assert_eq!(len, MIN_PACKET_LEN);
+        assert_eq!(&buf[236..243], &[0x63, 0x82, 0x53, 0x63, 53, 1, 1]);
+        let decoded = Packet::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded, discover);
+        assert_eq!(decoded.mac(), MAC);
+        assert_eq!(decoded.message_type(), Some(MessageType::Discover));
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = Packet::discover(1, MAC).encode(&mut buf).unwrap();
+        buf[HEADER_LEN + 1] = 200;
+        assert!(Packet::decode(&buf[..len]).is_err());
+        buf[236] = 0;
+        assert!(Packet::decode(&buf[..len]).is_err());
+        assert!(Packet::decode(&buf[..100]).is_err());
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_round_trip() {
+        crate::prop::for_all(|packet: Packet| {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            let len = packet.encode(&mut buf).unwrap();
+            assert_eq!(Packet::decode(&buf[..len]).unwrap(), packet);
+        });
+    }
+
+    #[test]
+    fn offer_loopback() -> io::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let target = server.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::decode(&buf[..len]).unwrap();
+            assert_eq!(request.message_type(), Some(MessageType::Discover));
+            let mut offer = Packet::offer(&request, Ipv4Addr::new(10, 0, 0, 23), Ipv4Addr::new(10, 0, 0, 1));
+            offer.push(DhcpOption::LeaseTime(3600));
+            offer.push(DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]));
+            // a stray ack for the same client must not be reported as an offer
+            let mut ack = offer.clone();
+            ack.options[0] = DhcpOption::MessageType(MessageType::Ack);
+            for packet in &[ack, offer] {
+                let len = packet.encode(&mut buf).unwrap();
+                server.send_to(&buf[..len], from).unwrap();
+            }
+        });
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        let mut offers = Vec::new();
+        let count = discover_on(&client, target, MAC, Duration::from_millis(300), &mut |_, offer: &Packet| {
+            offers.push((offer.your_addr(), offer.server_identifier(), offer.lease_time()));
+        })?;
+        assert_eq!(count, 1);
+        assert_eq!(offers, [(Ipv4Addr::new(10, 0, 0, 23), Some(Ipv4Addr::new(10, 0, 0, 1)),
+            Some(Duration::from_secs(3600)))]);
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+//! A discard server for Windows, on an I/O completion port.
+//!
+//! Where `discard_sync` spends a thread on every stream, this serves them all from the thread
+//! calling `run`: each listener keeps `accept_backlog` accepts posted, and each stream one
+//! overlapped read, so ten thousand idle streams cost ten thousand read buffers and nothing
+//! more.
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use miow::{Overlapped, iocp::{CompletionPort, CompletionStatus}, net::TcpStreamExt};
+use slab::Slab;
+use smallvec::SmallVec;
+use crate::{config::ConfigError, iocp::{self, Acceptor}, ports, resolve, server::ServerHandle};
+
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    H: Handler
+{
+    Builder::new().bind(addr)?.build(factory)?.run()
+}
+
+/// `listen` on a thread of its own; binding and configuration errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Send + 'static,
+    H: Handler
+{
+    let builder = Builder::new().bind(addr)?;
+    builder.validate()?;
+    let local_addrs = builder.local_addrs()?;
+    ServerHandle::spawn("laji-discard", local_addrs, move || builder.build(factory)?.run())
+}
+
+pub struct LajiDiscard<F>
+where F: Factory
+{
+    port: CompletionPort,
+    listeners: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    accept_backlog: usize,
+    conns: Slab<Connection<F::Handler>>,
+    factory: F,
+    read_buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    spare_bufs: Vec<Vec<u8>>,
+}
+
+// boxed, so the kernel's pointers into it stay put while the slab moves connections
+struct Read {
+    overlapped: Overlapped,
+    buf: Vec<u8>,
+}
+
+struct Connection<H> {
+    // `None` once closed, while the read it had posted is still on its way back
+    stream: Option<TcpStream>,
+    handler: H,
+    read: Box<Read>,
+    deadline: Option<Instant>,
+}
+
+/// Post a read into `read.buf`; it completes through the port even when data is there now.
+fn post_read(stream: &TcpStream, read: &mut Read) -> io::Result<()> {
+    read.overlapped = Overlapped::zero();
+    unsafe { stream.read_overlapped(&mut read.buf, read.overlapped.raw())? };
+    Ok(())
+}
+
+impl<F> LajiDiscard<F>
+where F: Factory
+{
+    /// Serve until the port or posting an accept fails. Streams still open then are closed,
+    /// and `run` returns once the kernel has given back their buffers.
+    pub fn run(mut self) -> io::Result<()> {
+        let listeners = std::mem::replace(&mut self.listeners, SmallVec::new());
+        let mut acceptors = Vec::with_capacity(listeners.len());
+        let ans = self.serve(listeners, &mut acceptors);
+        for (_, conn) in self.conns.iter_mut() {
+            if conn.stream.take().is_some() {
+                conn.handler.on_close();
+            }
+        }
+        // every connection left has a read posted, cancelled by closing the stream
+        let pending = self.conns.len();
+        iocp::shut_down(&self.port, acceptors, pending, std::mem::replace(&mut self.conns, Slab::new()));
+        ans
+    }
+
+    fn serve(&mut self, listeners: SmallVec<[TcpListener; INLINE_LISTENERS]>, acceptors: &mut Vec<Acceptor>) -> io::Result<()> {
+        // streams take the completion keys after the listeners'
+        let base = listeners.len();
+        for (token, listener) in listeners.into_iter().enumerate() {
+            acceptors.push(Acceptor::new(listener, &self.port, token, self.accept_backlog)?);
+        }
+        let mut statuses = vec![CompletionStatus::zero(); iocp::STATUS_CAPACITY];
+        loop {
+            let timeout = self.next_timeout();
+            for status in iocp::wait(&self.port, &mut statuses, timeout)?.iter() {
+                let token = status.token();
+                if token < base {
+                    if let Some((stream, peer_addr, local_addr)) = acceptors[token].complete(status)? {
+                        self.open_stream(stream, Handshake { peer_addr, local_addr }, base);
+                    }
+                } else {
+                    self.read_done(token - base);
+                }
+            }
+            self.reap_idle();
+        }
+    }
+
+    fn open_stream(&mut self, stream: TcpStream, shake: Handshake, base: usize) {
+        if !self.factory.accept(&shake) {
+            return;
+        }
+        let entry = self.conns.vacant_entry();
+        if self.port.add_socket(base + entry.key(), &stream).is_err() {
+            return;
+        }
+        let mut handler = self.factory.connection_made();
+        handler.on_open(shake);
+        let read_buffer_size = self.read_buffer_size;
+        let mut buf = self.spare_bufs.pop()
+            .unwrap_or_else(|| vec![0u8; read_buffer_size]);
+        buf.resize(read_buffer_size, 0);
+        let mut read = Box::new(Read { overlapped: Overlapped::zero(), buf });
+        if post_read(&stream, &mut read).is_err() {
+            handler.on_close();
+            self.spare_bufs.push(read.buf);
+            return;
+        }
+        let deadline = self.idle_timeout.map(|timeout| Instant::now() + timeout);
+        entry.insert(Connection { stream: Some(stream), handler, read, deadline });
+    }
+
+    fn read_done(&mut self, key: usize) {
+        let idle_timeout = self.idle_timeout;
+        let finished = match self.conns.get_mut(key) {
+            Some(conn) => match &conn.stream {
+                // the cancelled read of a stream closed already
+                None => true,
+                Some(stream) => {
+                    let read = &mut *conn.read;
+                    let more = match unsafe { stream.result(read.overlapped.raw()) } {
+                        Ok((0, _)) => false,
+                        Ok((len, _)) => {
+                            conn.handler.on_data(&read.buf[..len]);
+                            conn.deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+                            post_read(stream, read).is_ok()
+                        }
+                        Err(_) => false,
+                    };
+                    if !more {
+                        conn.stream = None;
+                        conn.handler.on_close();
+                    }
+                    !more
+                }
+            },
+            None => false,
+        };
+        if finished {
+            let conn = self.conns.remove(key);
+            // keep the buffer for the next accepted stream
+            self.spare_bufs.push(conn.read.buf);
+        }
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.conns.iter()
+            .filter(|(_, conn)| conn.stream.is_some())
+            .filter_map(|(_, conn)| conn.deadline)
+            .min()
+            .map(|deadline| if deadline > now { deadline - now } else { Duration::from_secs(0) })
+    }
+
+    /// Close streams past their deadline; they are freed when their reads come back.
+    fn reap_idle(&mut self) {
+        let now = Instant::now();
+        for (_, conn) in self.conns.iter_mut() {
+            if conn.stream.is_some() && conn.deadline.map_or(false, |d| d <= now) {
+                conn.stream = None;
+                conn.handler.on_close();
+            }
+        }
+    }
+}
+
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+const DEFAULT_ACCEPT_BACKLOG: usize = 64;
+
+#[derive(Debug)]
+pub struct Builder {
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    accept_backlog: usize,
+}
+
+impl Builder {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            tcp: SmallVec::new(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            idle_timeout: None,
+            accept_backlog: DEFAULT_ACCEPT_BACKLOG,
+        }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.tcp.push(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// `bind` every address in turn, stopping at the first that fails.
+    #[inline]
+    pub fn bind_all<I>(self, addrs: I) -> io::Result<Builder>
+    where
+        I: IntoIterator,
+        I::Item: ToSocketAddrs
+    {
+        addrs.into_iter().try_fold(self, Builder::bind)
+    }
+
+    /// Bind one listener per address `addr` resolves to, where `bind` takes only the first.
+    #[inline]
+    pub fn bind_each<A>(self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.bind_all(resolve::each_addr(addr)?)
+    }
+
+    /// Size of the buffer each accepted stream reads into before dropping the bytes.
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Builder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Close streams that have not sent anything for `timeout`.
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How many accepts each listener keeps posted, which is how many connections can
+    /// arrive at once before any of them waits on the listen queue.
+    #[inline]
+    pub fn accept_backlog(mut self, backlog: usize) -> Builder {
+        self.accept_backlog = backlog;
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        if self.idle_timeout == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::Zero("idle_timeout"));
+        }
+        if self.accept_backlog == 0 {
+            return Err(ConfigError::Zero("accept_backlog"));
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration first; its errors are `InvalidInput` wrapping a
+    /// `ConfigError`.
+    pub fn build<F>(self, factory: F) -> io::Result<LajiDiscard<F>>
+    where F: Factory
+    {
+        self.validate()?;
+        Ok(LajiDiscard {
+            port: CompletionPort::new(1)?,
+            listeners: self.tcp,
+            accept_backlog: self.accept_backlog,
+            conns: Slab::new(),
+            factory,
+            read_buffer_size: self.read_buffer_size,
+            idle_timeout: self.idle_timeout,
+            spare_bufs: Vec::new(),
+        })
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
+
+pub trait Handler {
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// Bytes just read from the stream, before they are discarded.
+    fn on_data(&mut self, _data: &[u8]) {}
+
+    /// The stream was closed, by the peer, an error, the idle timeout or the server stopping.
+    fn on_close(&mut self) {}
+}
+
+impl<F> Handler for F
+where F: FnMut(Handshake) {
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self(shake)
+    }
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    /// Whether to serve this stream at all. Refused streams are closed before
+    /// `connection_made` is asked for a handler.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self) -> Self::Handler;
+}
+
+impl<F, H> Factory for F
+where H: Handler, F: FnMut() -> H {
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, sync::mpsc, thread};
+
+    #[test]
+    fn serve_and_idle() {
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0").unwrap()
+            .idle_timeout(Duration::from_millis(200))
+            .accept_backlog(2);
+        let addr = builder.local_addrs().unwrap()[0];
+        thread::spawn(move || builder.build(move || {
+            struct Counting(mpsc::Sender<&'static str>);
+            impl Handler for Counting {
+                fn on_data(&mut self, data: &[u8]) {
+                    assert_eq!(data, b"laji");
+                    self.0.send("data").unwrap();
+                }
+                fn on_close(&mut self) {
+                    self.0.send("close").unwrap();
+                }
+            }
+            Counting(tx.clone())
+        }).unwrap().run().unwrap());
+        // more streams at once than accepts posted
+        let mut clients: Vec<_> = (0..5).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for client in &mut clients {
+            client.write_all(b"laji").unwrap();
+            assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), "data");
+        }
+        drop(clients.pop());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), "close");
+        // the rest go idle
+        for _ in 0..4 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), "close");
+        }
+    }
+}
@@ -1,106 +1,837 @@
-use mio::{Poll, PollOpt, Ready, Token, Events, net::{TcpListener, TcpStream}};
-use std::{io, net::{ToSocketAddrs, SocketAddr}};
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness, Token, Events, net::{TcpListener, TcpStream, UdpSocket}};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs, SocketAddr},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 use slab::Slab;
+use smallvec::SmallVec;
+use crate::{affinity, config::ConfigError, ports, resolve, server::{ServerHandle, Stopper}};
 
 pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
 where 
     A: ToSocketAddrs, 
     F: FnMut() -> H,
-    F: Send + Sync + 'static,
+    F: Clone + Send + 'static,
     H: Handler 
 {
     Builder::new().bind(addr)?.build(factory)?.run()
 }
 
+/// `listen` on a thread of its own; binding and configuration errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where 
+    A: ToSocketAddrs, 
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler 
+{
+    let builder = Builder::new().bind(addr)?;
+    builder.validate()?;
+    let local_addrs = builder.local_addrs()?;
+    let stopper = Stopper::new();
+    let serving = stopper.clone();
+    // built on the serving thread, so handlers need not be `Send`
+    ServerHandle::spawn_stoppable("laji-discard", local_addrs, stopper, move || builder.build(factory)?.run_until(&serving))
+}
+
 pub struct LajiDiscard<F> 
 where F: Factory 
 {
     poll: Poll,
-    listeners: Slab<TcpListener>,
+    entries: Slab<Entry<F::Handler>>,
+    // each worker loop has a clone of its own, and makes the handlers of its streams itself
     factory: F,
+    read_buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    trigger: Trigger,
+    core: Option<usize>,
+    workers: usize,
+    spare_bufs: Vec<Vec<u8>>,
+    // one `(deadline, token, id)` per stream with an idle timeout, by earliest deadline;
+    // entries whose stream was touched or closed since are fixed up once they come due
+    timers: BinaryHeap<Reverse<(Instant, usize, u64)>>,
+    expired: Vec<usize>,
+    datagram_buf: Vec<u8>,
+    next_id: u64,
+    outboxes: Vec<Outbox>,
+    next_outbox: usize,
+    inbox: Option<Inbox>,
+}
+
+/// How sockets are registered with the poll.
+///
+/// Edge-triggered loops accept or read until the socket would block on every
+/// wakeup; level-triggered loops do one accept or read per event and rely on
+/// the poll to report the socket again while it stays ready.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+impl Trigger {
+    #[inline]
+    fn poll_opt(self) -> PollOpt {
+        match self {
+            Trigger::Edge => PollOpt::edge(),
+            Trigger::Level => PollOpt::level(),
+        }
+    }
+}
+
+enum Entry<H> {
+    Listener(Listener),
+    Udp(UdpSocket),
+    Stream(Connection<H>),
+}
+
+struct Listener {
+    listener: TcpListener,
+    config: ListenerConfig,
+    // streams from this listener still open, on whichever loop serves them
+    open: Arc<AtomicUsize>,
+}
+
+struct Connection<H> {
+    stream: TcpStream,
+    handler: H,
+    buf: Vec<u8>,
+    open: Arc<AtomicUsize>,
+    id: u64,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // whether `timers` holds an entry for this stream
+    scheduled: bool,
+}
+
+impl<H> Connection<H>
+where H: Handler
+{
+    #[inline]
+    fn touch(&mut self) {
+        let idle_timeout = self.handler.idle_timeout().or(self.idle_timeout);
+        self.deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Give the stream its entry in `timers`, if it has a deadline and no entry yet.
+    #[inline]
+    fn schedule(&mut self, token_index: usize, timers: &mut BinaryHeap<Reverse<(Instant, usize, u64)>>) {
+        if let (false, Some(deadline)) = (self.scheduled, self.deadline) {
+            timers.push(Reverse((deadline, token_index, self.id)));
+            self.scheduled = true;
+        }
+    }
+}
+
+/// A stream the accepting loop admitted, on its way to the loop that will serve it.
+struct Handoff {
+    stream: TcpStream,
+    info: ConnectionInfo,
+    open: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    read_buffer_size: usize,
+}
+
+/// The accepting loop's end of a worker's queue.
+struct Outbox {
+    tx: mpsc::Sender<Handoff>,
+    readiness: SetReadiness,
+}
+
+/// A worker's end of its queue, polled under `HANDOFF_TOKEN`.
+struct Inbox {
+    rx: mpsc::Receiver<Handoff>,
+    readiness: SetReadiness,
+    _registration: Registration,
 }
 
 impl<F> LajiDiscard<F>
 where F: Factory
 {
-    fn from_tcp(tcp: Vec<TcpListener>, factory: F) -> io::Result<Self> {
-        let poll = Poll::new()?;
-        let mut listeners = Slab::new();
-        for listener in tcp {
-            let entry = listeners.vacant_entry();
-            let token = Token(entry.key().into());
-            poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
-            entry.insert(listener);
-        }
-        let ans = Self {
-            poll,
-            listeners,
+    fn new_loop(factory: F, read_buffer_size: usize, idle_timeout: Option<Duration>, trigger: Trigger) -> io::Result<Self> {
+        Ok(Self {
+            poll: Poll::new()?,
+            entries: Slab::new(),
             factory,
-        };
+            read_buffer_size,
+            idle_timeout,
+            trigger,
+            core: None,
+            workers: 1,
+            spare_bufs: Vec::new(),
+            timers: BinaryHeap::new(),
+            expired: Vec::new(),
+            datagram_buf: Vec::new(),
+            next_id: 0,
+            outboxes: Vec::new(),
+            next_outbox: 0,
+            inbox: None,
+        })
+    }
+
+    fn from_builder(builder: Builder, factory: F) -> io::Result<Self> {
+        let mut ans = Self::new_loop(factory, builder.read_buffer_size, builder.idle_timeout, builder.trigger)?;
+        ans.core = builder.core;
+        ans.workers = builder.workers;
+        for (listener, config) in builder.tcp {
+            let entry = ans.entries.vacant_entry();
+            let token = Token(entry.key());
+            ans.poll.register(&listener, token, Ready::readable(), ans.trigger.poll_opt())?;
+            entry.insert(Entry::Listener(Listener { listener, config, open: Arc::new(AtomicUsize::new(0)) }));
+        }
+        if !builder.udp.is_empty() {
+            ans.datagram_buf = vec![0u8; MAX_DATAGRAM_LEN];
+        }
+        for socket in builder.udp {
+            let entry = ans.entries.vacant_entry();
+            let token = Token(entry.key());
+            ans.poll.register(&socket, token, Ready::readable(), ans.trigger.poll_opt())?;
+            entry.insert(Entry::Udp(socket));
+        }
         Ok(ans)
     }
 }
 
+impl<F> LajiDiscard<F> 
+where F: Factory + Clone + Send + 'static
+{
+    /// Serve until polling fails; see `run_until`.
+    #[inline]
+    pub fn run(self) -> io::Result<()> {
+        self.run_until(&Stopper::new())
+    }
+
+    /// Serve until `stopper` is stopped or polling fails; streams still open then are closed
+    /// with `CloseReason::ServerShutdown`. Failed accepts go to `Factory::on_error`, failed
+    /// reads close only their own stream.
+    ///
+    /// With more than one of `workers`, this thread accepts streams and reads datagrams, and
+    /// deals the streams round-robin between itself and the workers. A loop that fails stops
+    /// the others.
+    pub fn run_until(mut self, stopper: &Stopper) -> io::Result<()> {
+        affinity::pin_to(self.core)?;
+        let mut workers = Vec::with_capacity(self.workers - 1);
+        for _ in 1..self.workers {
+            let (tx, rx) = mpsc::channel();
+            let (registration, readiness) = Registration::new2();
+            self.outboxes.push(Outbox { tx, readiness: readiness.clone() });
+            let factory = self.factory.clone();
+            let (read_buffer_size, idle_timeout, trigger) = (self.read_buffer_size, self.idle_timeout, self.trigger);
+            let stopper = stopper.clone();
+            workers.push(thread::spawn(move || {
+                let ans = LajiDiscard::new_loop(factory, read_buffer_size, idle_timeout, trigger)
+                    .and_then(|mut worker| {
+                        worker.poll.register(&registration, HANDOFF_TOKEN, Ready::readable(), PollOpt::edge())?;
+                        worker.inbox = Some(Inbox { rx, readiness, _registration: registration });
+                        worker.serve_until(&stopper)
+                    });
+                stopper.stop();
+                ans
+            }));
+        }
+        let ans = self.serve_until(stopper);
+        stopper.stop();
+        workers.into_iter().fold(ans, |ans, worker| {
+            let worker = worker.join()
+                .unwrap_or_else(|_| Err(io::Error::other("worker thread panicked")));
+            ans.and(worker)
+        })
+    }
+
+    /// `run_until` on a thread of its own, stopped through the returned handle. Handlers that
+    /// are not `Send` can still be served detached with `listen_spawned`.
+    pub fn run_detached(self) -> io::Result<ServerHandle>
+    where F::Handler: Send
+    {
+        let local_addrs = self.local_addrs()?;
+        let stopper = Stopper::new();
+        let serving = stopper.clone();
+        ServerHandle::spawn_stoppable("laji-discard", local_addrs, stopper, move || self.run_until(&serving))
+    }
+}
+
 impl<F> LajiDiscard<F> 
 where F: Factory 
 {
-    pub fn run(mut self) -> io::Result<()> {
-        let mut events = Events::with_capacity(self.listeners.len());
+    fn serve_until(&mut self, stopper: &Stopper) -> io::Result<()> {
+        let (registration, readiness) = Registration::new2();
+        self.poll.register(&registration, STOP_TOKEN, Ready::readable(), PollOpt::edge())?;
+        stopper.on_stop(move || {
+            let _ = readiness.set_readiness(Ready::readable());
+        });
+        let ans = self.serve();
+        let open: Vec<usize> = self.entries.iter()
+            .filter_map(|(index, entry)| match entry {
+                Entry::Stream(_) => Some(index),
+                _ => None,
+            })
+            .collect();
+        for token_index in open {
+            self.close_stream(token_index, CloseReason::ServerShutdown);
+        }
+        ans
+    }
+
+    fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.entries.iter()
+            .filter_map(|(_, entry)| match entry {
+                Entry::Listener(l) => Some(l.listener.local_addr()),
+                Entry::Udp(socket) => Some(socket.local_addr()),
+                Entry::Stream(_) => None,
+            })
+            .collect()
+    }
+
+    fn serve(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut ready = Vec::with_capacity(EVENTS_CAPACITY);
         loop {
-            self.poll.poll(&mut events, None)?;
+            let timeout = self.next_timeout();
+            self.poll.poll(&mut events, timeout)?;
             for event in &events {
+                if event.token() == STOP_TOKEN {
+                    return Ok(());
+                }
+                if event.token() == HANDOFF_TOKEN {
+                    self.take_handoffs();
+                    continue;
+                }
                 let token_index = event.token().into();
-                if let Some(listener) = self.listeners.get(token_index) {
-                    loop {
-                        match listener.accept() {
-                            Ok((stream, _addr)) => {
-                                process_one_stream(&mut self.factory, stream)?;
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                            Err(e) => return Err(e),
+                match self.entries.get(token_index) {
+                    Some(Entry::Listener(..)) => self.accept_all(token_index),
+                    Some(Entry::Udp(_)) => self.read_datagrams(token_index),
+                    Some(Entry::Stream(_)) => ready.push(token_index),
+                    None => {}
+                }
+            }
+            for token_index in ready.drain(..) {
+                self.read_all(token_index);
+            }
+            self.reap_idle();
+        }
+    }
+
+    fn accept_all(&mut self, token_index: usize) {
+        loop {
+            let (accepted, refusal) = match &self.entries[token_index] {
+                Entry::Listener(listener) => {
+                    let accepted = listener.listener.accept();
+                    let refusal = match &accepted {
+                        Ok((_, addr)) => listener.refusal(addr.ip()),
+                        Err(_) => None,
+                    };
+                    (accepted, refusal)
+                }
+                _ => unreachable!(),
+            };
+            match accepted {
+                Ok((stream, _addr)) => {
+                    match refusal {
+                        None => if let Err(e) = self.admit_stream(stream, token_index) {
+                            self.factory.on_error(e);
+                        },
+                        Some(reason) => self.reject_stream(stream, token_index, reason),
+                    }
+                    if self.trigger == Trigger::Level {
+                        return;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // try again on the listener's next event, rather than spin on e.g. EMFILE
+                Err(e) => return self.factory.on_error(e),
+            }
+        }
+    }
+
+    /// Ask the factory about a stream, and if it is wanted, serve it here or hand it to the
+    /// worker whose turn it is.
+    fn admit_stream(&mut self, stream: TcpStream, listener: usize) -> io::Result<()> {
+        let shake = Handshake::read_stream(&stream)?;
+        let (listener_tag, idle_timeout, read_buffer_size, open) = match &self.entries[listener] {
+            Entry::Listener(l) => {
+                let config = &l.config;
+                (config.tag, config.idle_timeout.or(self.idle_timeout),
+                    config.read_buffer_size.unwrap_or(self.read_buffer_size), l.open.clone())
+            }
+            _ => unreachable!(),
+        };
+        let info = ConnectionInfo { shake, listener_tag, id: self.next_id };
+        self.next_id += 1;
+        if !self.factory.accept(&info) {
+            self.factory.on_reject(&info, RejectReason::Refused);
+            return Ok(());
+        }
+        open.fetch_add(1, Ordering::SeqCst);
+        let handoff = Handoff { stream, info, open, idle_timeout, read_buffer_size };
+        // the turns go to each worker and then to this loop
+        let turn = self.next_outbox;
+        self.next_outbox = (turn + 1) % (self.outboxes.len() + 1);
+        let handoff = match self.outboxes.get(turn) {
+            Some(outbox) => match outbox.tx.send(handoff) {
+                Ok(()) => {
+                    let _ = outbox.readiness.set_readiness(Ready::readable());
+                    return Ok(());
+                }
+                // the worker is gone, so the stream is served here
+                Err(mpsc::SendError(handoff)) => handoff,
+            },
+            None => handoff,
+        };
+        self.open_stream(handoff)
+    }
+
+    fn take_handoffs(&mut self) {
+        let handoffs: Vec<Handoff> = match &self.inbox {
+            Some(inbox) => {
+                // cleared before draining, so a stream sent meanwhile wakes the poll again
+                let _ = inbox.readiness.set_readiness(Ready::empty());
+                inbox.rx.try_iter().collect()
+            }
+            None => return,
+        };
+        for handoff in handoffs {
+            if let Err(e) = self.open_stream(handoff) {
+                self.factory.on_error(e);
+            }
+        }
+    }
+
+    fn open_stream(&mut self, handoff: Handoff) -> io::Result<()> {
+        let Handoff { stream, info, open, idle_timeout, read_buffer_size } = handoff;
+        let entry = self.entries.vacant_entry();
+        let token = Token(entry.key());
+        if let Err(e) = self.poll.register(&stream, token, Ready::readable(), self.trigger.poll_opt()) {
+            open.fetch_sub(1, Ordering::SeqCst);
+            return Err(e);
+        }
+        let mut handler = self.factory.connection_made(&info);
+        handler.on_open(*info.handshake());
+        let mut buf = self.spare_bufs.pop()
+            .unwrap_or_else(|| vec![0u8; read_buffer_size]);
+        // listeners may read into smaller or bigger buffers than the one recycled
+        buf.resize(read_buffer_size, 0);
+        let mut conn = Connection {
+            stream,
+            handler,
+            buf,
+            open,
+            id: info.id,
+            idle_timeout,
+            deadline: None,
+            scheduled: false,
+        };
+        conn.touch();
+        conn.schedule(entry.key(), &mut self.timers);
+        entry.insert(Entry::Stream(conn));
+        Ok(())
+    }
+
+    /// Streams the listener refused are closed and reported to `Factory::on_reject`, without
+    /// a handler made for them.
+    fn reject_stream(&mut self, stream: TcpStream, listener: usize, reason: RejectReason) {
+        // a peer gone before its addresses were read is not worth reporting
+        let shake = match Handshake::read_stream(&stream) {
+            Ok(shake) => shake,
+            Err(_) => return,
+        };
+        drop(stream);
+        let listener_tag = match &self.entries[listener] {
+            Entry::Listener(l) => l.config.tag,
+            _ => unreachable!(),
+        };
+        let info = ConnectionInfo { shake, listener_tag, id: self.next_id };
+        self.next_id += 1;
+        self.factory.on_reject(&info, reason);
+    }
+
+    /// Every datagram is a connection of its own: opened, given its bytes and closed.
+    fn read_datagrams(&mut self, token_index: usize) {
+        let socket = match &self.entries[token_index] {
+            Entry::Udp(socket) => socket,
+            _ => unreachable!(),
+        };
+        let local_addr = match socket.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => return self.factory.on_error(e),
+        };
+        loop {
+            let (len, peer_addr) = match socket.recv_from(&mut self.datagram_buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return self.factory.on_error(e),
+            };
+            let shake = Handshake { peer_addr, local_addr };
+            let info = ConnectionInfo { shake, listener_tag: None, id: self.next_id };
+            self.next_id += 1;
+            if self.factory.accept(&info) {
+                let mut handler = self.factory.connection_made(&info);
+                handler.on_open(shake);
+                handler.on_data(&self.datagram_buf[..len]);
+                handler.on_close_with(CloseReason::PeerClosed);
+            } else {
+                self.factory.on_reject(&info, RejectReason::Refused);
+            }
+            if self.trigger == Trigger::Level {
+                return;
+            }
+        }
+    }
+
+    fn read_all(&mut self, token_index: usize) {
+        let level = self.trigger == Trigger::Level;
+        let closed = match self.entries.get_mut(token_index) {
+            Some(Entry::Stream(conn)) => loop {
+                match conn.stream.read(&mut conn.buf) {
+                    Ok(0) => break Some(CloseReason::PeerClosed),
+                    Ok(len) => {
+                        conn.handler.on_data(&conn.buf[..len]);
+                        conn.touch();
+                        conn.schedule(token_index, &mut self.timers);
+                        if level {
+                            break None;
                         }
                     }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break None,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => break Some(CloseReason::Error(e.kind())),
                 }
+            },
+            _ => None,
+        };
+        if let Some(reason) = closed {
+            self.close_stream(token_index, reason);
+        }
+    }
+
+    fn close_stream(&mut self, token_index: usize, reason: CloseReason) {
+        if let Entry::Stream(mut conn) = self.entries.remove(token_index) {
+            let _ = self.poll.deregister(&conn.stream);
+            drop(conn.stream);
+            conn.handler.on_close_with(reason);
+            conn.open.fetch_sub(1, Ordering::SeqCst);
+            // keep the buffer for the next accepted stream, unless enough are kept already
+            if self.spare_bufs.len() < MAX_SPARE_BUFS {
+                self.spare_bufs.push(conn.buf);
+            }
+        }
+    }
+
+    /// Until the earliest timer; it may be stale, which only wakes the loop early.
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.timers.peek()
+            .map(|&Reverse((deadline, _, _))| if deadline > now { deadline - now } else { Duration::from_secs(0) })
+    }
+
+    fn reap_idle(&mut self) {
+        let now = Instant::now();
+        let mut expired = std::mem::take(&mut self.expired);
+        while let Some(&Reverse((due, token_index, id))) = self.timers.peek() {
+            if due > now {
+                break;
+            }
+            self.timers.pop();
+            match self.entries.get_mut(token_index) {
+                // a closed stream's token may have gone to another since
+                Some(Entry::Stream(conn)) if conn.id == id => match conn.deadline {
+                    Some(deadline) if deadline <= now => expired.push(token_index),
+                    // touched since the timer was set
+                    Some(deadline) => self.timers.push(Reverse((deadline, token_index, id))),
+                    None => conn.scheduled = false,
+                },
+                _ => {}
             }
         }
+        for token_index in expired.drain(..) {
+            self.close_stream(token_index, CloseReason::Idle);
+        }
+        self.expired = expired;
     }
 }
 
-fn process_one_stream<F>(factory: &mut F, stream: TcpStream) -> io::Result<()> 
-where F: Factory
-{
-    let mut handler = factory.connection_made();
-    handler.on_open(Handshake::read_stream(&stream)?);
-    drop(stream);
-    handler.on_close();
-    Ok(())
+const EVENTS_CAPACITY: usize = 1024;
+// slab keys never get this high, and mio keeps `usize::MAX` for itself
+const STOP_TOKEN: Token = Token(usize::MAX - 1);
+const HANDOFF_TOKEN: Token = Token(usize::MAX - 2);
+const MAX_DATAGRAM_LEN: usize = 64 * 1024;
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+// read buffers kept from closed streams, so a burst of closes does not pin their memory
+const MAX_SPARE_BUFS: usize = 64;
+
+/// Settings for one bound address, overriding the builder's for streams accepted there.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct ListenerConfig {
+    tag: Option<&'static str>,
+    read_buffer_size: Option<usize>,
+    idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    allow: Vec<(IpAddr, u8)>,
+}
+
+impl ListenerConfig {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reported in the `ConnectionInfo` of every stream accepted on this listener.
+    #[inline]
+    pub fn tag(mut self, tag: &'static str) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = Some(size);
+        self
+    }
+
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Close streams accepted while this many from the listener are already open.
+    #[inline]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Only accept peers in `ip/prefix_len`; once any network is allowed, peers outside
+    /// all of them are closed right away.
+    #[inline]
+    pub fn allow(mut self, ip: IpAddr, prefix_len: u8) -> Self {
+        self.allow.push((ip, prefix_len));
+        self
+    }
+}
+
+impl ListenerConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.read_buffer_size == Some(0) {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        if self.idle_timeout == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::Zero("idle_timeout"));
+        }
+        if self.max_connections == Some(0) {
+            return Err(ConfigError::Zero("max_connections"));
+        }
+        let too_long = |&(net, len): &(IpAddr, u8)| len > if net.is_ipv4() { 32 } else { 128 };
+        if self.allow.iter().any(too_long) {
+            return Err(ConfigError::Conflict("allowed network prefix longer than its address"));
+        }
+        Ok(())
+    }
+}
+
+impl Listener {
+    /// Why a stream from `peer` is refused, if it is.
+    fn refusal(&self, peer: IpAddr) -> Option<RejectReason> {
+        let config = &self.config;
+        if !config.allow.is_empty() && !config.allow.iter().any(|&(net, len)| in_network(peer, net, len)) {
+            return Some(RejectReason::NotAllowed);
+        }
+        if config.max_connections.is_some_and(|max| self.open.load(Ordering::SeqCst) >= max) {
+            return Some(RejectReason::ConnectionLimit);
+        }
+        None
+    }
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, prefix_len: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len.min(32))).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len.min(128))).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
 }
 
 #[derive(Debug)]
 pub struct Builder {
-    tcp: Vec<TcpListener>,
+    tcp: SmallVec<[(TcpListener, ListenerConfig); INLINE_LISTENERS]>,
+    udp: SmallVec<[UdpSocket; INLINE_LISTENERS]>,
+    workers: usize,
+    read_buffer_size: usize,
+    idle_timeout: Option<Duration>,
+    trigger: Trigger,
+    core: Option<usize>,
 }
 
 impl Builder {
     #[inline]
     pub fn new() -> Self {
-        Self { tcp: Vec::new() }
+        Self { 
+            tcp: SmallVec::new(),
+            udp: SmallVec::new(),
+            workers: 1,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            idle_timeout: None,
+            trigger: Trigger::Edge,
+            core: None,
+        }
+    }
+
+    #[inline]
+    pub fn bind<A>(self, addr: A) -> io::Result<Builder> 
+    where A: ToSocketAddrs 
+    {
+        self.bind_with(addr, ListenerConfig::new())
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// `bind` every address in turn, stopping at the first that fails.
+    #[inline]
+    pub fn bind_all<I>(self, addrs: I) -> io::Result<Builder> 
+    where 
+        I: IntoIterator,
+        I::Item: ToSocketAddrs 
+    {
+        addrs.into_iter().try_fold(self, Builder::bind)
     }
 
+    /// Bind one listener per address `addr` resolves to, where `bind` takes only the first,
+    /// so `"localhost:9"` listens on both 127.0.0.1 and ::1. `local_addrs` tells what was bound.
     #[inline]
-    pub fn bind<A>(mut self, addr: A) -> io::Result<Builder> 
+    pub fn bind_each<A>(self, addr: A) -> io::Result<Builder> 
+    where A: ToSocketAddrs 
+    {
+        self.bind_all(resolve::each_addr(addr)?)
+    }
+
+    /// Bind like `bind`, and report `tag` in the `ConnectionInfo` of every stream accepted here.
+    #[inline]
+    pub fn bind_tagged<A>(self, addr: A, tag: &'static str) -> io::Result<Builder> 
+    where A: ToSocketAddrs 
+    {
+        self.bind_with(addr, ListenerConfig::new().tag(tag))
+    }
+
+    /// Bind with settings of its own, falling back to the builder's for those `config`
+    /// leaves unset.
+    #[inline]
+    pub fn bind_with<A>(mut self, addr: A, config: ListenerConfig) -> io::Result<Builder> 
     where A: ToSocketAddrs 
     {
         let new_listener = TcpListener::from_std(std::net::TcpListener::bind(addr)?)?;
-        self.tcp.push(new_listener);
+        self.tcp.push((new_listener, config));
         Ok(self)
     }
 
+    /// Discard datagrams arriving at `addr` as well, as RFC 863 has it. Each datagram gets a
+    /// handler of its own, which sees `on_open`, one `on_data` and then `CloseReason::PeerClosed`.
+    #[inline]
+    pub fn bind_udp<A>(mut self, addr: A) -> io::Result<Builder> 
+    where A: ToSocketAddrs 
+    {
+        self.udp.push(UdpSocket::from_socket(std::net::UdpSocket::bind(addr)?)?);
+        Ok(self)
+    }
+
+    /// Serve streams from `n` event loops: the one `run` is called on, which also accepts
+    /// every stream and reads every datagram, and `n - 1` threads of their own. The default
+    /// is one. `cpu_affinity` pins only the first.
+    #[inline]
+    pub fn workers(mut self, n: usize) -> Builder {
+        self.workers = n;
+        self
+    }
+
+    /// Size of the buffer each accepted stream reads into before dropping the bytes.
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Builder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Close streams that have not sent anything for `timeout`.
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Register sockets edge-triggered (the default) or level-triggered.
+    #[inline]
+    pub fn trigger(mut self, trigger: Trigger) -> Builder {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Pin the thread that calls `run` to this core before entering the event loop.
+    #[inline]
+    pub fn cpu_affinity(mut self, core: usize) -> Builder {
+        self.core = Some(core);
+        self
+    }
+
+    /// The TCP addresses, then the UDP ones.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(|(listener, _)| listener.local_addr())
+            .chain(self.udp.iter().map(UdpSocket::local_addr))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.workers == 0 {
+            return Err(ConfigError::Zero("workers"));
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        if self.idle_timeout == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::Zero("idle_timeout"));
+        }
+        self.tcp.iter().try_for_each(|(_, config)| config.validate())
+    }
+
+    /// Validates the configuration first; its errors are `InvalidInput` wrapping a
+    /// `ConfigError`.
     #[inline]
     pub fn build<F>(self, factory: F) -> io::Result<LajiDiscard<F>> 
     where F: Factory
     {
-        LajiDiscard::from_tcp(self.tcp, factory)
+        self.validate()?;
+        LajiDiscard::from_builder(self, factory)
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -130,10 +861,109 @@ impl Handshake {
     }
 }
 
+/// What a factory knows about the connection it is making a handler for.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    shake: Handshake,
+    listener_tag: Option<&'static str>,
+    id: u64,
+}
+
+impl ConnectionInfo {
+    #[inline]
+    pub fn handshake(&self) -> &Handshake {
+        &self.shake
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        self.shake.peer_addr()
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        self.shake.local_addr()
+    }
+
+    /// The tag of the listener that accepted the stream, if it was bound with `bind_tagged`.
+    #[inline]
+    pub fn listener_tag(&self) -> Option<&'static str> {
+        self.listener_tag
+    }
+
+    /// Counts up from 0 per server, whatever loop serves the stream; unlike poll tokens, ids
+    /// are never reused.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Why the event loop closed a stream.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum CloseReason {
+    /// The peer shut down its sending side.
+    PeerClosed,
+    /// Nothing arrived within the idle timeout.
+    Idle,
+    /// Reading failed.
+    Error(io::ErrorKind),
+    /// The event loop stopped with the stream still open.
+    ServerShutdown,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::PeerClosed => f.write_str("peer closed"),
+            CloseReason::Idle => f.write_str("idle timeout"),
+            CloseReason::Error(kind) => write!(f, "error: {:?}", kind),
+            CloseReason::ServerShutdown => f.write_str("server shutdown"),
+        }
+    }
+}
+
+/// Why a stream was refused before any handler was made for it.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum RejectReason {
+    /// `Factory::accept` said no.
+    Refused,
+    /// The peer is outside the listener's allow list.
+    NotAllowed,
+    /// The listener already serves its `max_connections`.
+    ConnectionLimit,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::Refused => f.write_str("refused"),
+            RejectReason::NotAllowed => f.write_str("not allowed"),
+            RejectReason::ConnectionLimit => f.write_str("connection limit"),
+        }
+    }
+}
+
 pub trait Handler {
     fn on_open(&mut self, _shake: Handshake) {}
 
+    /// Bytes just read from the stream, before they are discarded.
+    fn on_data(&mut self, _data: &[u8]) {}
+
     fn on_close(&mut self) {}
+
+    /// What the event loop calls on close; handlers that do not care why can keep to
+    /// `on_close`.
+    #[inline]
+    fn on_close_with(&mut self, _reason: CloseReason) {
+        self.on_close()
+    }
+
+    /// Close this stream after it sent nothing for so long, instead of after the builder's
+    /// `idle_timeout`.
+    fn idle_timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<F> Handler for F 
@@ -147,10 +977,57 @@ where F: FnMut(Handshake) {
     fn on_close(&mut self) {}
 }
 
+impl Handler for Box<dyn Handler> {
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        (**self).on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        (**self).on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        (**self).on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        (**self).on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        (**self).idle_timeout()
+    }
+}
+
+/// Makes one handler per accepted stream.
+///
+/// The handler lives as long as its connection and gets every later callback, so whatever
+/// it needs from `info`, or any other per-connection context, can be moved into it here.
 pub trait Factory {
     type Handler: Handler; 
 
-    fn connection_made(&mut self) -> Self::Handler; 
+    /// Whether to serve this stream at all. Refused streams are closed before
+    /// `connection_made` is asked for a handler.
+    #[inline]
+    fn accept(&mut self, _info: &ConnectionInfo) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self, info: &ConnectionInfo) -> Self::Handler; 
+
+    /// A stream or datagram was refused, by `accept` or by the listener, and closed without
+    /// a handler.
+    #[inline]
+    fn on_reject(&mut self, _info: &ConnectionInfo, _reason: RejectReason) {}
+
+    /// Accepting or registering a stream failed. The event loop keeps serving.
+    #[inline]
+    fn on_error(&mut self, _err: io::Error) {}
 }
 
 impl<F, H> Factory for F 
@@ -161,11 +1038,146 @@ where
     type Handler = H;
 
     #[inline]
-    fn connection_made(&mut self) -> H {
+    fn connection_made(&mut self, _info: &ConnectionInfo) -> H {
         self()
     }
 }
 
+type BoxedFactory = Box<dyn RouteFactory>;
+
+/// A route's factory, cloned along with `Routes` for every worker loop.
+trait RouteFactory: Factory<Handler = Box<dyn Handler>> + Send {
+    fn clone_boxed(&self) -> BoxedFactory;
+}
+
+#[derive(Clone)]
+struct Boxing<F>(F);
+
+impl<F> RouteFactory for Boxing<F>
+where
+    F: Factory + Clone + Send + 'static,
+    F::Handler: 'static
+{
+    #[inline]
+    fn clone_boxed(&self) -> BoxedFactory {
+        Box::new(self.clone())
+    }
+}
+
+impl<F> Factory for Boxing<F>
+where
+    F: Factory,
+    F::Handler: 'static
+{
+    type Handler = Box<dyn Handler>;
+
+    #[inline]
+    fn accept(&mut self, info: &ConnectionInfo) -> bool {
+        self.0.accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &ConnectionInfo) -> Box<dyn Handler> {
+        Box::new(self.0.connection_made(info))
+    }
+
+    #[inline]
+    fn on_reject(&mut self, info: &ConnectionInfo, reason: RejectReason) {
+        self.0.on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.0.on_error(err)
+    }
+}
+
+/// A factory per local address, so one event loop can serve several services.
+///
+/// Connections on an address without a route get a handler that does nothing.
+pub struct Routes {
+    routes: Vec<(SocketAddr, BoxedFactory)>,
+}
+
+impl Routes {
+    #[inline]
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Connections accepted on `addr` get their handlers from `factory`. An unspecified IP,
+    /// as in `0.0.0.0:9`, matches that port on any address unless a more exact route does.
+    pub fn route<A, F>(mut self, addr: A, factory: F) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+        F: Factory + Clone + Send + 'static,
+        F::Handler: 'static
+    {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+        self.routes.retain(|(route, _)| *route != addr);
+        self.routes.push((addr, Box::new(Boxing(factory))));
+        Ok(self)
+    }
+
+    fn factory_for(&mut self, local_addr: SocketAddr) -> Option<&mut BoxedFactory> {
+        let exact = self.routes.iter().position(|(route, _)| *route == local_addr);
+        let any_ip = || self.routes.iter().position(|(route, _)|
+            route.ip().is_unspecified() && route.port() == local_addr.port());
+        match exact.or_else(any_ip) {
+            Some(index) => Some(&mut self.routes[index].1),
+            None => None,
+        }
+    }
+}
+
+impl Clone for Routes {
+    fn clone(&self) -> Self {
+        Self { routes: self.routes.iter().map(|(addr, factory)| (*addr, factory.clone_boxed())).collect() }
+    }
+}
+
+impl Default for Routes {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Routes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.routes.iter().map(|(addr, _)| addr)).finish()
+    }
+}
+
+impl Factory for Routes {
+    type Handler = Box<dyn Handler>;
+
+    fn accept(&mut self, info: &ConnectionInfo) -> bool {
+        self.factory_for(*info.local_addr()).is_none_or(|factory| factory.accept(info))
+    }
+
+    fn connection_made(&mut self, info: &ConnectionInfo) -> Box<dyn Handler> {
+        match self.factory_for(*info.local_addr()) {
+            Some(factory) => factory.connection_made(info),
+            None => Box::new(|_shake: Handshake| {}),
+        }
+    }
+
+    fn on_reject(&mut self, info: &ConnectionInfo, reason: RejectReason) {
+        if let Some(factory) = self.factory_for(*info.local_addr()) {
+            factory.on_reject(info, reason);
+        }
+    }
+
+    /// Failures don't say which listener they came from, so every route hears of them.
+    fn on_error(&mut self, err: io::Error) {
+        for (_, factory) in &mut self.routes {
+            factory.on_error(io::Error::new(err.kind(), err.to_string()));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     mod laji_discard {
@@ -198,27 +1210,27 @@ mod tests {
     #[test]
     fn test_batch() -> std::io::Result<()> {
         use super::*;
+        #[derive(Clone)]
         struct MyFactory;
         impl Factory for MyFactory {
             type Handler = MyHandler;
-            fn connection_made(&mut self) -> MyHandler {
-                MyHandler(None)
+            fn connection_made(&mut self, info: &ConnectionInfo) -> MyHandler {
+                MyHandler(*info)
             }
         }
-        struct MyHandler(Option<Handshake>);
+        struct MyHandler(ConnectionInfo);
         impl Handler for MyHandler {
             fn on_open(&mut self, shake: Handshake) {                
                 println!("Remote {} connected to {}", shake.peer_addr(), shake.local_addr());
-                self.0 = Some(shake);
             }
             fn on_close(&mut self) {
-                let shake = self.0.unwrap();
-                println!("Closed remote {} at {}!", shake.peer_addr(), shake.local_addr());
+                let info = self.0;
+                println!("Closed connection {} from {} on {:?}!", info.id(), info.peer_addr(), info.listener_tag());
             }
         }
         thread::spawn(move || {
             Builder::new()
-                .bind("0.0.0.0:9").unwrap()
+                .bind_tagged("0.0.0.0:9", "discard").unwrap()
                 .bind("0.0.0.0:9999").unwrap()
                 .build(MyFactory).unwrap()
                 .run().unwrap();
@@ -228,4 +1240,341 @@ mod tests {
         std::net::TcpStream::connect("127.0.0.1:9999").unwrap();
         Ok(())
     }
+
+    #[test]
+    fn test_idle_timeout() {
+        use super::*;
+        use std::{io::Write, sync::mpsc, time::Duration};
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new()
+            .bind("127.0.0.1:0").unwrap()
+            .idle_timeout(Duration::from_millis(100));
+        let addr = builder.local_addrs().unwrap()[0];
+        thread::spawn(move || {
+            builder.build(move || {
+                let tx = tx.clone();
+                struct Closing(mpsc::Sender<()>);
+                impl Handler for Closing {
+                    fn on_close(&mut self) {
+                        self.0.send(()).unwrap();
+                    }
+                }
+                Closing(tx)
+            }).unwrap().run().unwrap();
+        });
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"discard me").unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    }
+
+    #[test]
+    fn test_level_triggered() {
+        use super::*;
+        use std::{io::Write, sync::mpsc, time::Duration};
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new()
+            .bind("127.0.0.1:0").unwrap()
+            .trigger(Trigger::Level)
+            .read_buffer_size(4);
+        let addr = builder.local_addrs().unwrap()[0];
+        thread::spawn(move || {
+            builder.build(move || {
+                let tx = tx.clone();
+                move |_shake: Handshake| tx.send(()).unwrap()
+            }).unwrap().run().unwrap();
+        });
+        for _ in 0..3 {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.write_all(b"longer than one read buffer").unwrap();
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        }
+    }
+    #[test]
+    fn test_routes() {
+        use super::*;
+        use std::{sync::mpsc, time::Duration};
+        let (tx, rx) = mpsc::channel();
+        let tagging = |tag: &'static str| {
+            let tx = tx.clone();
+            move || {
+                let tx = tx.clone();
+                move |_shake: Handshake| tx.send(tag).unwrap()
+            }
+        };
+        let builder = Builder::new()
+            .bind("127.0.0.1:0").unwrap()
+            .bind("127.0.0.1:0").unwrap()
+            .bind("127.0.0.1:0").unwrap();
+        let addrs = builder.local_addrs().unwrap();
+        let routes = Routes::new()
+            .route(addrs[0], tagging("daytime")).unwrap()
+            .route(("0.0.0.0", addrs[1].port()), tagging("discard")).unwrap();
+        thread::spawn(move || builder.build(routes).unwrap().run().unwrap());
+        for &(addr, tag) in &[(addrs[0], "daytime"), (addrs[1], "discard"), (addrs[0], "daytime")] {
+            std::net::TcpStream::connect(addr).unwrap();
+            assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(tag));
+        }
+        std::net::TcpStream::connect(addrs[2]).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_connection_info() {
+        use super::*;
+        use std::{sync::mpsc, time::Duration};
+        #[derive(Clone)]
+        struct Reporting(mpsc::Sender<ConnectionInfo>);
+        impl Factory for Reporting {
+            type Handler = fn(Handshake);
+            fn connection_made(&mut self, info: &ConnectionInfo) -> fn(Handshake) {
+                self.0.send(*info).unwrap();
+                |_shake| {}
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new()
+            .bind_tagged("127.0.0.1:0", "admin").unwrap()
+            .bind("127.0.0.1:0").unwrap();
+        let addrs = builder.local_addrs().unwrap();
+        thread::spawn(move || builder.build(Reporting(tx)).unwrap().run().unwrap());
+        let first = std::net::TcpStream::connect(addrs[0]).unwrap();
+        let info = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!((info.id(), info.listener_tag()), (0, Some("admin")));
+        assert_eq!(*info.peer_addr(), first.local_addr().unwrap());
+        drop(first);
+        std::net::TcpStream::connect(addrs[1]).unwrap();
+        let info = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!((info.id(), info.listener_tag()), (1, None));
+        assert_eq!(*info.local_addr(), addrs[1]);
+    }
+
+    #[test]
+    fn test_listener_config() {
+        use super::*;
+        use std::{io::Read, sync::mpsc, time::Duration};
+        let (tx, rx) = mpsc::channel();
+        let public = ListenerConfig::new()
+            .allow("10.0.0.0".parse().unwrap(), 8);
+        let local = ListenerConfig::new()
+            .allow("127.0.0.0".parse().unwrap(), 8)
+            .max_connections(1)
+            .idle_timeout(Duration::from_millis(100));
+        let builder = Builder::new()
+            .bind_with("127.0.0.1:0", public).unwrap()
+            .bind_with("127.0.0.1:0", local).unwrap();
+        let addrs = builder.local_addrs().unwrap();
+        thread::spawn(move || {
+            builder.build(move || {
+                let tx = tx.clone();
+                move |shake: Handshake| tx.send(shake.local_addr().port()).unwrap()
+            }).unwrap().run().unwrap();
+        });
+        let mut refused = std::net::TcpStream::connect(addrs[0]).unwrap();
+        assert_eq!(refused.read(&mut [0u8; 1]).unwrap_or(0), 0);
+        let mut first = std::net::TcpStream::connect(addrs[1]).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(addrs[1].port()));
+        let mut over_limit = std::net::TcpStream::connect(addrs[1]).unwrap();
+        assert_eq!(over_limit.read(&mut [0u8; 1]).unwrap_or(0), 0);
+        // the listener's idle timeout applies although the builder has none
+        first.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(first.read(&mut [0u8; 1]).unwrap(), 0);
+        std::net::TcpStream::connect(addrs[1]).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(addrs[1].port()));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_in_network() {
+        use super::*;
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(in_network(ip("192.168.1.7"), ip("192.168.0.0"), 16));
+        assert!(!in_network(ip("192.169.1.7"), ip("192.168.0.0"), 16));
+        assert!(in_network(ip("8.8.8.8"), ip("0.0.0.0"), 0));
+        assert!(in_network(ip("fe80::1"), ip("fe80::"), 10));
+        assert!(!in_network(ip("::ffff:127.0.0.1"), ip("127.0.0.0"), 8));
+    }
+
+    #[test]
+    fn test_validate() {
+        use super::*;
+        let factory = || |_shake: Handshake| {};
+        let err = Builder::new().build(factory).err().unwrap();
+        assert_eq!(err.to_string(), "no address bound");
+        let config = ListenerConfig::new().max_connections(0);
+        let err = Builder::new().bind_with("127.0.0.1:0", config).unwrap().build(factory).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "`max_connections` must not be zero");
+        let config = ListenerConfig::new().allow("10.0.0.0".parse().unwrap(), 33);
+        assert!(Builder::new().bind_with("127.0.0.1:0", config).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_bind_all() {
+        use super::*;
+        let builder = Builder::new().bind_all(vec!["127.0.0.1:0", "127.0.0.1:0"]).unwrap();
+        assert_eq!(builder.tcp.len(), 2);
+        assert!(Builder::new().bind_all(&["127.0.0.1:0", "256.0.0.1:9"]).is_err());
+    }
+
+    #[test]
+    fn test_bind_each() {
+        use super::*;
+        let addrs: [SocketAddr; 3] = ["127.0.0.1:0".parse().unwrap(), "127.0.0.2:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap()];
+        let builder = Builder::new().bind_each(&addrs[..]).unwrap();
+        let bound = builder.local_addrs().unwrap();
+        let ips: Vec<_> = bound.iter().map(SocketAddr::ip).collect();
+        assert_eq!(ips, [addrs[0].ip(), addrs[1].ip()]);
+        assert!(bound.iter().all(|addr| addr.port() != 0));
+        assert!(Builder::new().bind_each(&[][..] as &[SocketAddr]).is_err());
+    }
+
+    #[test]
+    fn test_listen_spawned() {
+        use super::*;
+        use std::{sync::mpsc, time::Duration};
+        let (tx, rx) = mpsc::channel();
+        let server = listen_spawned("127.0.0.1:0", move || {
+            let tx = tx.clone();
+            move |_shake: Handshake| tx.send(()).unwrap()
+        }).unwrap();
+        std::net::TcpStream::connect(server.local_addrs()[0]).unwrap();
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    }
+
+    #[test]
+    fn test_close_reasons() {
+        use super::*;
+        use std::{sync::mpsc, time::Duration};
+        #[derive(Clone)]
+        struct Reasons(mpsc::Sender<Result<CloseReason, RejectReason>>);
+        impl Handler for Reasons {
+            fn on_close_with(&mut self, reason: CloseReason) {
+                self.0.send(Ok(reason)).unwrap();
+            }
+        }
+        impl Factory for Reasons {
+            type Handler = Reasons;
+            fn connection_made(&mut self, _info: &ConnectionInfo) -> Reasons {
+                Reasons(self.0.clone())
+            }
+            fn on_reject(&mut self, _info: &ConnectionInfo, reason: RejectReason) {
+                self.0.send(Err(reason)).unwrap();
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let public = ListenerConfig::new().allow("10.0.0.0".parse().unwrap(), 8);
+        let builder = Builder::new()
+            .bind("127.0.0.1:0").unwrap()
+            .bind_with("127.0.0.1:0", public).unwrap()
+            .idle_timeout(Duration::from_millis(100));
+        let addrs = builder.local_addrs().unwrap();
+        thread::spawn(move || builder.build(Reasons(tx)).unwrap().run().unwrap());
+        let next = || rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        drop(std::net::TcpStream::connect(addrs[0]).unwrap());
+        assert_eq!(next(), Ok(CloseReason::PeerClosed));
+        let _idle = std::net::TcpStream::connect(addrs[0]).unwrap();
+        assert_eq!(next(), Ok(CloseReason::Idle));
+        let _refused = std::net::TcpStream::connect(addrs[1]).unwrap();
+        assert_eq!(next(), Err(RejectReason::NotAllowed));
+    }
+
+    #[test]
+    fn stop_closes_open_streams() -> std::io::Result<()> {
+        use super::*;
+        use std::{io::Write, sync::mpsc, time::Duration};
+        struct Reasons(mpsc::Sender<CloseReason>);
+        impl Handler for Reasons {
+            fn on_close_with(&mut self, reason: CloseReason) {
+                self.0.send(reason).unwrap();
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let server = Builder::new()
+            .bind("127.0.0.1:0")?
+            .build(move || Reasons(tx.clone()))?
+            .run_detached()?;
+        let addr = server.local_addrs()[0];
+        let mut open = std::net::TcpStream::connect(addr)?;
+        open.write_all(b"x")?;
+        thread::sleep(Duration::from_millis(50));
+        server.stop()?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(CloseReason::ServerShutdown));
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn workers_share_streams() -> std::io::Result<()> {
+        use super::*;
+        use std::{collections::HashSet, io::Write, sync::mpsc, time::Duration};
+        struct Served(mpsc::Sender<(thread::ThreadId, usize)>, usize);
+        impl Handler for Served {
+            fn on_data(&mut self, data: &[u8]) {
+                self.1 += data.len();
+            }
+            fn on_close(&mut self) {
+                self.0.send((thread::current().id(), self.1)).unwrap();
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let server = Builder::new()
+            .bind("127.0.0.1:0")?
+            .workers(3)
+            .build(move || Served(tx.clone(), 0))?
+            .run_detached()?;
+        let addr = server.local_addrs()[0];
+        let mut loops = HashSet::new();
+        for len in 1..=6 {
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            stream.write_all(&vec![b'x'; len])?;
+            drop(stream);
+            let (loop_id, received) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert_eq!(received, len);
+            loops.insert(loop_id);
+        }
+        // dealt round-robin, so every loop served two
+        assert_eq!(loops.len(), 3);
+        server.stop()?;
+        assert_eq!(Builder::new().bind("127.0.0.1:0")?.workers(0).validate(), Err(ConfigError::Zero("workers")));
+        Ok(())
+    }
+
+    #[test]
+    fn udp_datagrams() -> std::io::Result<()> {
+        use super::*;
+        use std::{sync::mpsc, time::Duration};
+        #[derive(Debug, PartialEq)]
+        enum Event { Open(SocketAddr), Data(Vec<u8>), Close(CloseReason) }
+        struct Events(mpsc::Sender<Event>);
+        impl Handler for Events {
+            fn on_open(&mut self, shake: Handshake) {
+                self.0.send(Event::Open(*shake.peer_addr())).unwrap();
+            }
+            fn on_data(&mut self, data: &[u8]) {
+                self.0.send(Event::Data(data.to_vec())).unwrap();
+            }
+            fn on_close_with(&mut self, reason: CloseReason) {
+                self.0.send(Event::Close(reason)).unwrap();
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0")?.bind_udp("127.0.0.1:0")?;
+        let udp = builder.local_addrs()?[1];
+        let server = builder.build(move || Events(tx.clone()))?.run_detached()?;
+        let client = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let next = || rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        for payload in &[&b"laji"[..], b"discard"] {
+            client.send_to(payload, udp)?;
+            assert_eq!(next(), Event::Open(client.local_addr()?));
+            assert_eq!(next(), Event::Data(payload.to_vec()));
+            assert_eq!(next(), Event::Close(CloseReason::PeerClosed));
+        }
+        server.stop()?;
+        assert!(Builder::new().bind_udp("127.0.0.1:0")?.validate().is_ok());
+        Ok(())
+    }
 }
@@ -1,6 +1,57 @@
 #![feature(async_await, await_macro, futures_api)]
 use romio::tcp::{TcpListener, TcpStream};
 use futures::prelude::*;
+use std::{io, net::{ToSocketAddrs, SocketAddr}};
+
+pub type Connection = TcpStream;
+
+pub fn incoming<A>(addr: A) -> io::Result<impl Stream<Item = io::Result<(Handshake, Connection)>>>
+where
+    A: ToSocketAddrs
+{
+    let mut last_err = None;
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpListener::bind(&socket_addr) {
+            Ok(listener) => {
+                let ans = listener.incoming().map(|stream| {
+                    let stream = stream?;
+                    let shake = Handshake::read_stream(&stream)?;
+                    Ok((shake, stream))
+                });
+                return Ok(ans);
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    fn read_stream(ts: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            peer_addr: ts.peer_addr()?,
+            local_addr: ts.local_addr()?,
+        })
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
 
 async fn say_hello(mut stream: TcpStream) {
     await!(stream.write_all(b"Shall I hear more, or shall I speak at this?"));
@@ -1,59 +1,116 @@
 use std::{
-    io,
-    net::{ToSocketAddrs, TcpListener, TcpStream, SocketAddr},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, ToSocketAddrs, TcpListener, TcpStream, SocketAddr},
     thread,
-    sync::{mpsc, Arc, Mutex},
+    sync::mpsc,
+    time::{Duration, Instant},
 };
+use smallvec::SmallVec;
+use crate::{affinity::{self, CoreList}, config::ConfigError, ports, ratelimit::TokenBucket, resolve, server::{ServerHandle, Stopper}, socks5::{self, Proxy}};
+
+const INLINE_LISTENERS: usize = 4;
 
 pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
 where 
     A: ToSocketAddrs, 
     F: FnMut() -> H,
-    F: Send + Sync + 'static,
+    F: Clone + Send + 'static,
     H: Handler 
 {
     Builder::new().bind(addr)?.build(factory).run()
 }
 
+/// `listen` on a thread of its own; binding errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where 
+    A: ToSocketAddrs, 
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler 
+{
+    Builder::new().bind(addr)?.build(factory).run_detached()
+}
+
 #[derive(Debug)]
 pub struct LajiDiscard<F>
 where F: Factory
 {
-    tcp: Vec<TcpListener>,
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    cores: CoreList,
     factory: F
 }
 
 impl<F> LajiDiscard<F>
 where   
-    F: 'static + Factory + Send + Sync 
+    F: 'static + Factory + Clone + Send 
 {
+    /// Serve until a listener thread fails to start; see `run_until`.
+    #[inline]
     pub fn run(self) -> io::Result<()> {
+        self.run_until(&Stopper::new())
+    }
+
+    /// Serve until `stopper` is stopped, then close the listeners and wait for their threads.
+    /// A stream that fails goes to `Factory::on_error` and the server carries on; a thread
+    /// that cannot start stops `stopper`, and so the whole server, with its error.
+    pub fn run_until(self, stopper: &Stopper) -> io::Result<()> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners.into());
+        }
         let (err_tx, err_rx) = mpsc::channel();
-        let factory = Arc::new(Mutex::new(self.factory));
+        let mut cores = self.cores;
+        let mut threads = Vec::with_capacity(self.tcp.len());
         for listener in self.tcp {
+            stopper.wake_tcp(listener.local_addr()?);
+            let stopper = stopper.clone();
             let err_tx = err_tx.clone();
-            let listener = listener.try_clone()?;
-            let factory = Arc::clone(&factory);
-            thread::spawn(move || {
+            let mut factory = self.factory.clone();
+            let core = cores.next_core();
+            threads.push(thread::spawn(move || {
+                if let Err(e) = affinity::pin_to(core) {
+                    err_tx.send(e).unwrap();
+                    return;
+                }
                 for stream in listener.incoming() {
-                    process_one_stream(factory.clone(), stream)
-                        .unwrap_or_else(|e| err_tx.send(e).unwrap())
+                    if stopper.is_stopped() {
+                        break;
+                    }
+                    process_one_stream(&mut factory, stream)
+                        .unwrap_or_else(|e| factory.on_error(e))
                 }
-            });
+            }));
         }
-        while let Ok(err) = err_rx.recv() {
-            return Err(err);
+        drop(err_tx);
+        let ans = match err_rx.recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        };
+        stopper.stop();
+        for thread in threads {
+            let _ = thread.join();
         }
-        Ok(())
+        ans
+    }
+
+    /// `run_until` on a thread of its own, stopped through the returned handle.
+    pub fn run_detached(self) -> io::Result<ServerHandle> {
+        let local_addrs = self.tcp.iter().map(TcpListener::local_addr).collect::<io::Result<_>>()?;
+        let stopper = Stopper::new();
+        let serving = stopper.clone();
+        ServerHandle::spawn_stoppable("laji-discard", local_addrs, stopper, move || self.run_until(&serving))
     }
 }
 
-fn process_one_stream<F>(factory: Arc<Mutex<F>>, stream: io::Result<TcpStream>) -> io::Result<()> 
+fn process_one_stream<F>(factory: &mut F, stream: io::Result<TcpStream>) -> io::Result<()> 
 where F: Factory
 {
-    let mut handler = factory.lock().unwrap().connection_made();
     let stream = stream?;
-    handler.on_open(Handshake::read_stream(&stream)?);
+    let shake = Handshake::read_stream(&stream)?;
+    if !factory.accept(&shake) {
+        return Ok(());
+    }
+    let mut handler = factory.connection_made();
+    handler.on_open(shake);
     drop(stream);
     handler.on_close();
     Ok(())
@@ -61,12 +118,13 @@ where F: Factory
 
 #[derive(Debug)]
 pub struct Builder {
-    tcp: Vec<TcpListener>,
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    cores: CoreList,
 }
 
 impl Builder {
     pub fn new() -> Self {
-        Self { tcp: Vec::new() }
+        Self { tcp: SmallVec::new(), cores: CoreList::default() }
     }
 
     pub fn bind<A>(mut self, addr: A) -> io::Result<Builder> 
@@ -77,14 +135,77 @@ impl Builder {
         Ok(self)
     }
 
+    /// Bind the well-known discard port, 9, on every IPv4 address.
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv6 address.
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// `bind` every address in turn, stopping at the first that fails.
+    pub fn bind_all<I>(self, addrs: I) -> io::Result<Builder> 
+    where 
+        I: IntoIterator,
+        I::Item: ToSocketAddrs 
+    {
+        addrs.into_iter().try_fold(self, Builder::bind)
+    }
+
+    /// Bind one listener per address `addr` resolves to, where `bind` takes only the first,
+    /// so `"localhost:9"` listens on both 127.0.0.1 and ::1. `local_addrs` tells what was bound.
+    #[inline]
+    pub fn bind_each<A>(self, addr: A) -> io::Result<Builder> 
+    where A: ToSocketAddrs 
+    {
+        self.bind_all(resolve::each_addr(addr)?)
+    }
+
+    /// Pin listener threads to these cores, assigned round-robin in bind order.
+    pub fn cpu_affinity<I>(mut self, cores: I) -> Builder 
+    where I: IntoIterator<Item = usize>
+    {
+        self.cores = CoreList::new(cores);
+        self
+    }
+
     pub fn build<F>(self, factory: F) -> LajiDiscard<F> 
     where F: Factory
     {
         LajiDiscard {
             tcp: self.tcp,
+            cores: self.cores,
             factory,
         }
     }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        self.cores.validate()
+    }
+
+    /// `build`, after checking the configuration.
+    pub fn try_build<F>(self, factory: F) -> Result<LajiDiscard<F>, ConfigError> 
+    where F: Factory
+    {
+        self.validate()?;
+        Ok(self.build(factory))
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
@@ -131,7 +252,18 @@ where F: FnMut(Handshake) {
 pub trait Factory {
     type Handler: Handler; 
 
+    /// Whether to serve this stream at all. Refused streams are closed before
+    /// `connection_made` is asked for a handler.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
     fn connection_made(&mut self) -> Self::Handler; 
+
+    /// Accepting or setting up a stream failed. The server keeps serving the others.
+    #[inline]
+    fn on_error(&mut self, _err: io::Error) {}
 }
 
 impl<F, H> Factory for F 
@@ -143,6 +275,217 @@ where H: Handler, F: FnMut() -> H {
     }
 }
 
+/// A write taking longer than this counts as a stall in a `BlastReport`.
+pub const STALL: Duration = Duration::from_millis(50);
+
+/// An upload tester: streams filler bytes to a discard server for a while, as fast as
+/// possible or paced to a target rate, then says how it went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Blast {
+    mbps: Option<f64>,
+    chunk_size: usize,
+    duration: Duration,
+    connect_timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Blast {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            mbps: None,
+            chunk_size: 16 * 1024,
+            duration: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(5),
+            proxy: None,
+        }
+    }
+
+    /// Send at `mbps` megabits per second rather than as fast as the connection takes.
+    #[inline]
+    pub fn target_mbps(mut self, mbps: f64) -> Self {
+        self.mbps = Some(mbps);
+        self
+    }
+
+    /// Bytes per write; paced sends also burst at most this much.
+    #[inline]
+    pub fn chunk_size(mut self, len: usize) -> Self {
+        self.chunk_size = len;
+        self
+    }
+
+    #[inline]
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    #[inline]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Blast through a SOCKS5 proxy, which then takes part in what is measured.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn run<A>(&self, addr: A) -> io::Result<BlastReport>
+    where A: ToSocketAddrs
+    {
+        if self.chunk_size == 0 {
+            return Err(ConfigError::Zero("chunk_size").into());
+        }
+        if self.mbps.is_some_and(|mbps| mbps.is_nan() || mbps <= 0.0) {
+            return Err(ConfigError::Zero("target_mbps").into());
+        }
+        let mut stream = socks5::connect_to(self.proxy.as_ref(), addr, self.connect_timeout)?;
+        let chunk = vec![0u8; self.chunk_size];
+        let mut bucket = self.mbps.map(|mbps| TokenBucket::new(mbps * 1e6 / 8.0, self.chunk_size as f64));
+        let mut report = BlastReport::default();
+        let started = Instant::now();
+        while started.elapsed() < self.duration {
+            if let Some(bucket) = &mut bucket {
+                thread::sleep(bucket.take(chunk.len() as f64));
+            }
+            let write_started = Instant::now();
+            stream.write_all(&chunk)?;
+            let took = write_started.elapsed();
+            if took > STALL {
+                report.stalls += 1;
+                report.longest_stall = report.longest_stall.max(took);
+            }
+            report.bytes_sent += chunk.len() as u64;
+        }
+        report.elapsed = started.elapsed();
+        Ok(report)
+    }
+}
+
+impl Default for Blast {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct BlastReport {
+    pub bytes_sent: u64,
+    pub elapsed: Duration,
+    /// Writes that took longer than `STALL`, because the peer or the path fell behind.
+    pub stalls: u32,
+    pub longest_stall: Duration,
+}
+
+impl BlastReport {
+    /// Achieved throughput, in megabits per second.
+    #[inline]
+    pub fn mbps(&self) -> f64 {
+        self.bytes_sent as f64 * 8.0 / 1e6 / self.elapsed.as_secs_f64()
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open a stream to the discard server at `addr`, for sending whatever the caller likes.
+pub fn connect<A>(addr: A) -> io::Result<Sender>
+where A: ToSocketAddrs
+{
+    Sender::connect(None, addr, CONNECT_TIMEOUT)
+}
+
+/// The client end of a discard stream. Sends block while the server is not keeping up,
+/// so a slow server slows the sender down instead of piling data up in memory.
+#[derive(Debug)]
+pub struct Sender {
+    stream: TcpStream,
+    shake: Handshake,
+    sent: u64,
+}
+
+impl Sender {
+    /// `connect`, optionally through a SOCKS5 proxy, giving up after `timeout`.
+    pub fn connect<A>(proxy: Option<&Proxy>, addr: A, timeout: Duration) -> io::Result<Sender>
+    where A: ToSocketAddrs
+    {
+        let stream = socks5::connect_to(proxy, addr, timeout)?;
+        let shake = Handshake::read_stream(&stream)?;
+        Ok(Sender { stream, shake, sent: 0 })
+    }
+
+    /// The stream's addresses; `peer_addr` is the server.
+    #[inline]
+    pub fn handshake(&self) -> &Handshake {
+        &self.shake
+    }
+
+    /// Fail a send with `TimedOut` once the server has taken nothing for this long,
+    /// instead of waiting on it forever. `None`, the default, waits.
+    #[inline]
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Send all of `buf`, blocking until the server has made room for it. What went out
+    /// before a timeout still counts toward `sent`.
+    #[inline]
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    /// Send everything `reader` has, returning how many bytes that was.
+    pub fn send_from<R>(&mut self, reader: &mut R) -> io::Result<u64>
+    where R: Read
+    {
+        let mut buf = [0u8; 16 * 1024];
+        let mut total = 0;
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.send(&buf[..len])?;
+            total += len as u64;
+        }
+    }
+
+    /// Bytes sent so far.
+    #[inline]
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Close our side of the stream, so the server sees the end of the data, and return
+    /// the bytes sent in all.
+    pub fn finish(self) -> io::Result<u64> {
+        self.stream.shutdown(Shutdown::Write)?;
+        Ok(self.sent)
+    }
+}
+
+impl Write for Sender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "write timed out"),
+            _ => e,
+        })?;
+        self.sent += len as u64;
+        Ok(len)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     mod laji_discard {
@@ -175,6 +518,7 @@ mod tests {
     #[test]
     fn test_batch() -> std::io::Result<()> {
         use super::*;
+        #[derive(Clone)]
         struct MyFactory;
         impl Factory for MyFactory {
             type Handler = MyHandler;
@@ -205,4 +549,90 @@ mod tests {
         TcpStream::connect("127.0.0.1:9999").unwrap();
         Ok(())
     }
+
+    #[test]
+    fn test_validate() {
+        use super::*;
+        use crate::config::ConfigError;
+        let factory = || |_shake: Handshake| {};
+        assert_eq!(Builder::new().try_build(factory).err(), Some(ConfigError::NoListeners));
+        let err = Builder::default().build(factory).run().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "no address bound");
+    }
+
+    #[test]
+    fn run_detached_stops() -> std::io::Result<()> {
+        use super::*;
+        let (tx, rx) = mpsc::channel();
+        let server = Builder::new()
+            .bind("127.0.0.1:0")?
+            .bind("127.0.0.1:0")?
+            .build(move || {
+                let tx = tx.clone();
+                move |shake: Handshake| tx.send(*shake.peer_addr()).unwrap()
+            })
+            .run_detached()?;
+        let addrs = server.local_addrs().to_vec();
+        for addr in &addrs {
+            let stream = TcpStream::connect(addr)?;
+            assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(stream.local_addr()?));
+        }
+        let stopper = server.stopper().unwrap();
+        thread::spawn(move || stopper.stop());
+        server.join()?;
+        // the wake-up connections were not handed to the factory
+        assert!(rx.try_recv().is_err());
+        for addr in &addrs {
+            assert!(TcpStream::connect(addr).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn paced_blast() -> std::io::Result<()> {
+        use super::*;
+        let sink = TcpListener::bind("127.0.0.1:0")?;
+        let addr = sink.local_addr()?;
+        thread::spawn(move || io::copy(&mut sink.accept()?.0, &mut io::sink()));
+        let report = Blast::new()
+            .target_mbps(8.0)
+            .chunk_size(10_000)
+            .duration(Duration::from_millis(300))
+            .run(addr)?;
+        // one megabyte a second for 0.3 seconds, give or take a chunk
+        assert!(report.bytes_sent >= 250_000 && report.bytes_sent <= 350_000, "{:?}", report);
+        assert!(report.mbps() > 6.0 && report.mbps() < 10.0, "{:?}", report);
+        assert_eq!(Blast::new().chunk_size(0).run(addr).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn sender_round_trip() -> std::io::Result<()> {
+        use super::*;
+        let sink = TcpListener::bind("127.0.0.1:0")?;
+        let addr = sink.local_addr()?;
+        let received = thread::spawn(move || io::copy(&mut sink.accept()?.0, &mut io::sink()));
+        let mut sender = connect(addr)?;
+        assert_eq!(*sender.handshake().peer_addr(), addr);
+        sender.send(b"hello, discard")?;
+        assert_eq!(sender.send_from(&mut io::repeat(b'x').take(100_000))?, 100_000);
+        assert_eq!(sender.finish()?, 100_014);
+        assert_eq!(received.join().unwrap()?, 100_014);
+        Ok(())
+    }
+
+    #[test]
+    fn sender_backpressure() -> std::io::Result<()> {
+        use super::*;
+        // accepts, then never reads
+        let stuck = TcpListener::bind("127.0.0.1:0")?;
+        let mut sender = connect(stuck.local_addr()?)?;
+        let _held = stuck.accept()?;
+        sender.set_write_timeout(Some(Duration::from_millis(200)))?;
+        let err = sender.send(&vec![0u8; 64 * 1024 * 1024]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(sender.sent() > 0 && sender.sent() < 64 * 1024 * 1024, "{}", sender.sent());
+        Ok(())
+    }
 }
@@ -1,23 +1,532 @@
-// todo: encapsule
-
-use tokio::prelude::*;
-use tokio::net::TcpListener;
-
-#[allow(unused)]
-fn main() {
-    // Bind the server's socket.
-    let addr = "127.0.0.1:12345".parse().unwrap();
-    let listener = TcpListener::bind(&addr)
-        .expect("unable to bind TCP listener");
-
-    // Pull out a stream of sockets for incoming connections
-    let server = listener.incoming()
-        .map_err(|e| eprintln!("accept failed = {:?}", e))
-        .for_each(|sock| {
-            drop(sock);
-            tokio::spawn(future::ok(()))
-        });
-
-    // Start the Tokio runtime
-    tokio::run(server);
-}
\ No newline at end of file
+use tokio::prelude::{future::FutureResult, *};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::runtime::Runtime;
+use std::{io, mem, net::{Ipv4Addr, Ipv6Addr, ToSocketAddrs, SocketAddr}};
+use smallvec::SmallVec;
+use crate::{config::ConfigError, ports, resolve, server::ServerHandle};
+
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+
+pub type Connection = TcpStream;
+
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler + Send + 'static
+{
+    Builder::new().bind(addr)?.build(factory).run()
+}
+
+/// `listen` on a thread of its own; binding errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler + Send + 'static
+{
+    let builder = Builder::new().bind(addr)?;
+    let local_addrs = builder.local_addrs()?;
+    let server = builder.build(factory);
+    ServerHandle::spawn("laji-discard", local_addrs, move || server.run())
+}
+
+/// Accepted streams with their handshakes, for callers that drive tokio themselves.
+pub fn incoming<A>(addr: A) -> io::Result<impl Stream<Item = (Handshake, Connection), Error = io::Error>>
+where
+    A: ToSocketAddrs
+{
+    incoming_on(std::net::TcpListener::bind(addr)?)
+}
+
+fn incoming_on(listener: std::net::TcpListener) -> io::Result<impl Stream<Item = (Handshake, Connection), Error = io::Error>> {
+    let listener = TcpListener::from_std(listener, &Handle::default())?;
+    let ans = listener.incoming().and_then(|stream| {
+        let shake = Handshake::read_stream(&stream)?;
+        Ok((shake, stream))
+    });
+    Ok(ans)
+}
+
+#[derive(Debug)]
+pub struct LajiDiscard<F>
+where F: AsyncFactory
+{
+    tcp: SmallVec<[std::net::TcpListener; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+    factory: F
+}
+
+impl<F> LajiDiscard<F>
+where F: AsyncFactory + Clone + Send + 'static
+{
+    /// Serve on a runtime of its own until accepting fails.
+    pub fn run(self) -> io::Result<()> {
+        let mut runtime = Runtime::new()?;
+        runtime.block_on(self.serve()?)
+    }
+
+    /// The server as a future, for a runtime that is already running; listeners are
+    /// registered with the default reactor. Handlers are called on the runtime's threads,
+    /// so they may `tokio::spawn` work of their own, and must not block.
+    pub fn serve(self) -> io::Result<impl Future<Item = (), Error = io::Error> + Send> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners.into());
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size").into());
+        }
+        let read_buffer_size = self.read_buffer_size;
+        let mut accepting = Vec::with_capacity(self.tcp.len());
+        for listener in self.tcp {
+            let listener = TcpListener::from_std(listener, &Handle::default())?;
+            let mut factory = self.factory.clone();
+            accepting.push(listener.incoming().for_each(move |stream| {
+                // a peer gone before its addresses were read is not worth serving
+                let shake = match Handshake::read_stream(&stream) {
+                    Ok(shake) => shake,
+                    Err(_) => return Ok(()),
+                };
+                if factory.accept(&shake) {
+                    let step = Step::<F::Handler>::Opening(factory.connection_made().on_open(shake));
+                    tokio::spawn(Draining { stream, step, buf: vec![0u8; read_buffer_size] });
+                }
+                Ok(())
+            }));
+        }
+        // the first listener to fail ends the server
+        Ok(future::join_all(accepting).map(|_| ()))
+    }
+}
+
+/// Reads a stream to its end, handing every read to the handler and reading no more until
+/// the handler's future for it is done.
+struct Draining<H>
+where H: AsyncHandler
+{
+    stream: TcpStream,
+    step: Step<H>,
+    buf: Vec<u8>,
+}
+
+enum Step<H>
+where H: AsyncHandler
+{
+    Opening(H::Opened),
+    Reading(H),
+    Handling(H::Handled),
+    Closing(H::Closed),
+    Done,
+}
+
+impl<H> Future for Draining<H>
+where H: AsyncHandler
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            // a handler that failed is dropped with its stream, without `on_close`
+            self.step = match mem::replace(&mut self.step, Step::Done) {
+                Step::Opening(mut opening) => match opening.poll() {
+                    Ok(Async::Ready(handler)) => Step::Reading(handler),
+                    Ok(Async::NotReady) => {
+                        self.step = Step::Opening(opening);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Ok(Async::Ready(())),
+                },
+                Step::Reading(handler) => match self.stream.poll_read(&mut self.buf) {
+                    Ok(Async::Ready(0)) | Err(_) => Step::Closing(handler.on_close()),
+                    Ok(Async::Ready(len)) => Step::Handling(handler.on_data(&self.buf[..len])),
+                    Ok(Async::NotReady) => {
+                        self.step = Step::Reading(handler);
+                        return Ok(Async::NotReady);
+                    }
+                },
+                Step::Handling(mut handling) => match handling.poll() {
+                    Ok(Async::Ready(handler)) => Step::Reading(handler),
+                    Ok(Async::NotReady) => {
+                        self.step = Step::Handling(handling);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Ok(Async::Ready(())),
+                },
+                Step::Closing(mut closing) => match closing.poll() {
+                    Ok(Async::NotReady) => {
+                        self.step = Step::Closing(closing);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(())) | Err(_) => return Ok(Async::Ready(())),
+                },
+                Step::Done => return Ok(Async::Ready(())),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Builder {
+    tcp: SmallVec<[std::net::TcpListener; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+}
+
+impl Builder {
+    #[inline]
+    pub fn new() -> Self {
+        Self { tcp: SmallVec::new(), read_buffer_size: DEFAULT_READ_BUFFER_SIZE }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.tcp.push(std::net::TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// Bind the well-known discard port, 9, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::DISCARD))
+    }
+
+    /// `bind` every address in turn, stopping at the first that fails.
+    #[inline]
+    pub fn bind_all<I>(self, addrs: I) -> io::Result<Builder>
+    where
+        I: IntoIterator,
+        I::Item: ToSocketAddrs
+    {
+        addrs.into_iter().try_fold(self, Builder::bind)
+    }
+
+    /// Bind one listener per address `addr` resolves to, where `bind` takes only the first,
+    /// so `"localhost:9"` listens on both 127.0.0.1 and ::1. `local_addrs` tells what was bound.
+    #[inline]
+    pub fn bind_each<A>(self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.bind_all(resolve::each_addr(addr)?)
+    }
+
+    /// Size of the buffer each accepted stream reads into before dropping the bytes.
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Builder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(std::net::TcpListener::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        Ok(())
+    }
+
+    pub fn build<F>(self, factory: F) -> LajiDiscard<Immediate<F>>
+    where
+        F: Factory,
+        F::Handler: Send + 'static
+    {
+        self.build_async(Immediate(factory))
+    }
+
+    /// `build` for a factory whose handlers answer with futures of their own.
+    pub fn build_async<F>(self, factory: F) -> LajiDiscard<F>
+    where F: AsyncFactory
+    {
+        LajiDiscard {
+            tcp: self.tcp,
+            read_buffer_size: self.read_buffer_size,
+            factory,
+        }
+    }
+
+    /// `build`, after checking the configuration.
+    pub fn try_build<F>(self, factory: F) -> Result<LajiDiscard<Immediate<F>>, ConfigError>
+    where
+        F: Factory,
+        F::Handler: Send + 'static
+    {
+        self.validate()?;
+        Ok(self.build(factory))
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    fn read_stream(ts: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            peer_addr: ts.peer_addr()?,
+            local_addr: ts.local_addr()?,
+        })
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
+
+pub trait Handler {
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// Bytes just read from the stream, before they are discarded.
+    fn on_data(&mut self, _data: &[u8]) {}
+
+    fn on_close(&mut self) {}
+}
+
+impl<F> Handler for F
+where F: FnMut(Handshake) {
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self(shake)
+    }
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    /// Whether to serve this stream at all. Refused streams are closed before
+    /// `connection_made` is asked for a handler.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self) -> Self::Handler;
+}
+
+impl<F, H> Factory for F
+where H: Handler, F: FnMut() -> H {
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+/// A `Handler` for work that cannot be done before the callback returns. Each callback takes
+/// the handler and gives it back when its future resolves, the way `tokio::io::write_all`
+/// gives back its writer, and each future is a type of the handler's own, so serving one
+/// allocates nothing per read. Nothing more is read from a stream until the future for the
+/// last read resolves; one that fails closes the stream.
+pub trait AsyncHandler: Sized + Send + 'static {
+    type Opened: Future<Item = Self, Error = io::Error> + Send + 'static;
+    type Handled: Future<Item = Self, Error = io::Error> + Send + 'static;
+    type Closed: Future<Item = (), Error = io::Error> + Send + 'static;
+
+    fn on_open(self, shake: Handshake) -> Self::Opened;
+
+    /// Bytes just read from the stream, before they are discarded; copy what the future
+    /// needs, as `data` is read over once it resolves.
+    fn on_data(self, data: &[u8]) -> Self::Handled;
+
+    fn on_close(self) -> Self::Closed;
+}
+
+pub trait AsyncFactory {
+    type Handler: AsyncHandler;
+
+    /// Whether to serve this stream at all, as in `Factory::accept`.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self) -> Self::Handler;
+}
+
+impl<F, H> AsyncFactory for F
+where H: AsyncHandler, F: FnMut() -> H {
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+/// A `Factory` or `Handler` served as an `AsyncFactory` or `AsyncHandler`, each callback done
+/// by the time it returns; what `Builder::build` serves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Immediate<T>(pub T);
+
+impl<H> AsyncHandler for Immediate<H>
+where H: Handler + Send + 'static
+{
+    type Opened = FutureResult<Self, io::Error>;
+    type Handled = FutureResult<Self, io::Error>;
+    type Closed = FutureResult<(), io::Error>;
+
+    #[inline]
+    fn on_open(mut self, shake: Handshake) -> Self::Opened {
+        self.0.on_open(shake);
+        future::ok(self)
+    }
+
+    #[inline]
+    fn on_data(mut self, data: &[u8]) -> Self::Handled {
+        self.0.on_data(data);
+        future::ok(self)
+    }
+
+    #[inline]
+    fn on_close(mut self) -> Self::Closed {
+        self.0.on_close();
+        future::ok(())
+    }
+}
+
+impl<F> AsyncFactory for Immediate<F>
+where
+    F: Factory,
+    F::Handler: Send + 'static
+{
+    type Handler = Immediate<F::Handler>;
+
+    #[inline]
+    fn accept(&mut self, shake: &Handshake) -> bool {
+        self.0.accept(shake)
+    }
+
+    #[inline]
+    fn connection_made(&mut self) -> Self::Handler {
+        Immediate(self.0.connection_made())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, sync::mpsc, time::Duration};
+
+    #[test]
+    fn incoming_pull() -> io::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let client = std::thread::spawn(move || std::net::TcpStream::connect(addr));
+        let accepted = incoming_on(listener)?.take(1).collect();
+        let accepted = tokio::runtime::current_thread::Runtime::new()?.block_on(accepted)?;
+        let client = client.join().unwrap()?;
+        assert_eq!(accepted.len(), 1);
+        let (shake, _conn) = &accepted[0];
+        assert_eq!((*shake.peer_addr(), *shake.local_addr()), (client.local_addr()?, addr));
+        Ok(())
+    }
+
+    struct Counting(mpsc::Sender<usize>, usize);
+
+    impl Handler for Counting {
+        fn on_data(&mut self, data: &[u8]) {
+            self.1 += data.len();
+        }
+
+        fn on_close(&mut self) {
+            self.0.send(self.1).unwrap();
+        }
+    }
+
+    #[test]
+    fn builder_serves_each_bind() -> io::Result<()> {
+        let (closed_tx, closed) = mpsc::channel();
+        let builder = Builder::new()
+            .bind("127.0.0.1:0")?
+            .bind("127.0.0.1:0")?
+            .read_buffer_size(16);
+        let addrs = builder.local_addrs()?;
+        let server = builder.build(move || Counting(closed_tx.clone(), 0));
+        std::thread::spawn(move || server.run());
+        for (addr, len) in addrs.iter().zip(&[100usize, 3]) {
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            stream.write_all(&vec![b'x'; *len])?;
+            drop(stream);
+            assert_eq!(closed.recv_timeout(Duration::from_secs(2)), Ok(*len));
+        }
+        assert_eq!(Builder::new().try_build(|| |_shake: Handshake| {}).err(), Some(ConfigError::NoListeners));
+        Ok(())
+    }
+
+    struct Summing(mpsc::Sender<usize>, usize);
+
+    impl AsyncHandler for Summing {
+        type Opened = FutureResult<Self, io::Error>;
+        type Handled = FutureResult<Self, io::Error>;
+        type Closed = FutureResult<(), io::Error>;
+
+        fn on_open(self, _shake: Handshake) -> Self::Opened {
+            future::ok(self)
+        }
+
+        fn on_data(mut self, data: &[u8]) -> Self::Handled {
+            if data.contains(&b'!') {
+                return future::err(io::Error::new(io::ErrorKind::InvalidData, "refused"));
+            }
+            self.1 += data.len();
+            future::ok(self)
+        }
+
+        fn on_close(self) -> Self::Closed {
+            future::result(self.0.send(self.1).map_err(io::Error::other))
+        }
+    }
+
+    #[test]
+    fn async_handlers_close_on_error() -> io::Result<()> {
+        let (closed_tx, closed) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0")?;
+        let addr = builder.local_addrs()?[0];
+        let server = builder.build_async(move || Summing(closed_tx.clone(), 0));
+        std::thread::spawn(move || server.run());
+        for request in &[&b"discarded"[..], b"!", b"tail"] {
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            stream.write_all(request)?;
+        }
+        let timeout = Duration::from_secs(2);
+        let mut sums = vec![closed.recv_timeout(timeout).unwrap(), closed.recv_timeout(timeout).unwrap()];
+        sums.sort_unstable();
+        assert_eq!(sums, vec![4, 9]);
+        // the refused stream is dropped without `on_close`
+        assert!(closed.recv_timeout(Duration::from_millis(100)).is_err());
+        Ok(())
+    }
+}
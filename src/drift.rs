@@ -0,0 +1,290 @@
+//! Watching how far daytime and time servers are from the local clock.
+//!
+//! A `Poller` queries every server it was given once per interval and reports each answer
+//! as the offset between the server's time and the local clock at the middle of the round
+//! trip. Both protocols only count whole seconds, so offsets below a second are noise.
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use crate::{clock::{Clock, SystemClock}, ports, resolve, socks5::{self, Proxy}, timestamp};
+
+const MAX_DAYTIME_LEN: u64 = 256;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Protocol {
+    /// RFC 867, a line of text, read as RFC 2822 or RFC 3339.
+    Daytime,
+    /// RFC 868, 32-bit seconds since 1900.
+    Time,
+}
+
+impl Protocol {
+    #[inline]
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Daytime => ports::DAYTIME,
+            Protocol::Time => ports::TIME,
+        }
+    }
+}
+
+/// What one query of one server came to.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Outcome {
+    /// The server's time minus the local one, and how long the query took.
+    Drift { offset: chrono::Duration, rtt: Duration },
+    /// Connecting or reading failed.
+    Unavailable(io::ErrorKind),
+    /// The server answered with something that is not a time.
+    Unparsable(String),
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Report {
+    /// The address as given to the `Poller`.
+    pub server: String,
+    pub protocol: Protocol,
+    pub outcome: Outcome,
+    /// Queries of this server so far, this one included.
+    pub polled: u64,
+    /// How many of them were answered with a time.
+    pub answered: u64,
+}
+
+impl Report {
+    /// `answered / polled`.
+    #[inline]
+    pub fn availability(&self) -> f64 {
+        self.answered as f64 / self.polled as f64
+    }
+}
+
+struct Server {
+    addr: String,
+    protocol: Protocol,
+    polled: u64,
+    answered: u64,
+}
+
+pub struct Poller {
+    servers: Vec<Server>,
+    interval: Duration,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    proxy: Option<Proxy>,
+}
+
+impl Poller {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            servers: Vec::new(),
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+            clock: Arc::new(SystemClock),
+            proxy: None,
+        }
+    }
+
+    /// Poll a daytime server at `addr`, anything `ToSocketAddrs` takes as a string, such as
+    /// `"time.example.org:13"`.
+    #[inline]
+    pub fn daytime<S>(self, addr: S) -> Self
+    where S: Into<String>
+    {
+        self.server(addr, Protocol::Daytime)
+    }
+
+    /// Poll a time protocol server at `addr`.
+    #[inline]
+    pub fn time<S>(self, addr: S) -> Self
+    where S: Into<String>
+    {
+        self.server(addr, Protocol::Time)
+    }
+
+    #[inline]
+    pub fn server<S>(mut self, addr: S, protocol: Protocol) -> Self
+    where S: Into<String>
+    {
+        self.servers.push(Server { addr: addr.into(), protocol, polled: 0, answered: 0 });
+        self
+    }
+
+    /// Time between the starts of two rounds of queries.
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long connecting to a server, and then reading its answer, may take.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Compare against `clock` instead of the system clock.
+    #[inline]
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Query the servers through a SOCKS5 proxy. The proxy's own delay counts into the
+    /// round trip, so offsets get less precise.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Query every server once, one after the other, reporting each.
+    pub fn poll_once<F>(&mut self, mut on_report: F)
+    where F: FnMut(&Report)
+    {
+        for server in &mut self.servers {
+            let outcome = query(&server.addr, server.protocol, self.timeout, &*self.clock, self.proxy.as_ref());
+            server.polled += 1;
+            if let Outcome::Drift { .. } = outcome {
+                server.answered += 1;
+            }
+            on_report(&Report {
+                server: server.addr.clone(),
+                protocol: server.protocol,
+                outcome,
+                polled: server.polled,
+                answered: server.answered,
+            });
+        }
+    }
+
+    /// `poll_once` every `interval`, forever.
+    pub fn run<F>(mut self, mut on_report: F) -> !
+    where F: FnMut(&Report)
+    {
+        loop {
+            let started = Instant::now();
+            self.poll_once(&mut on_report);
+            thread::sleep(self.interval.checked_sub(started.elapsed()).unwrap_or_default());
+        }
+    }
+}
+
+impl Default for Poller {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn query(addr: &str, protocol: Protocol, timeout: Duration, clock: &dyn Clock, proxy: Option<&Proxy>) -> Outcome {
+    let sent = clock.now();
+    let started = Instant::now();
+    let response = match fetch(addr, protocol, timeout, proxy) {
+        Ok(response) => response,
+        Err(e) => return Outcome::Unavailable(e.kind()),
+    };
+    let rtt = started.elapsed();
+    let remote = match parse(protocol, &response, sent) {
+        Some(remote) => remote,
+        None => return Outcome::Unparsable(String::from_utf8_lossy(&response).into_owned()),
+    };
+    let local = sent + chrono::Duration::from_std(rtt / 2).unwrap_or_else(|_| chrono::Duration::zero());
+    Outcome::Drift { offset: remote.signed_duration_since(local), rtt }
+}
+
+fn fetch(addr: &str, protocol: Protocol, timeout: Duration, proxy: Option<&Proxy>) -> io::Result<Vec<u8>> {
+    let stream = socks5::connect_to(proxy, addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut response = Vec::new();
+    match protocol {
+        Protocol::Daytime => { stream.take(MAX_DAYTIME_LEN).read_to_end(&mut response).map_err(resolve::timed_out)?; }
+        Protocol::Time => {
+            response.resize(4, 0);
+            (&stream).read_exact(&mut response).map_err(resolve::timed_out)?;
+        }
+    }
+    Ok(response)
+}
+
+/// `near`, the local time, picks the time protocol's era, so answers past 2036 still parse.
+pub(crate) fn parse(protocol: Protocol, response: &[u8], near: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+    match protocol {
+        Protocol::Daytime => {
+            let line = std::str::from_utf8(response).ok()?.trim();
+            DateTime::parse_from_rfc2822(line).or_else(|_| DateTime::parse_from_rfc3339(line)).ok()
+        }
+        Protocol::Time => {
+            let secs = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+            let utc = DateTime::<Utc>::from(timestamp::from_rfc868(secs, SystemTime::from(near)));
+            Some(utc.with_timezone(&FixedOffset::east(0)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener};
+    use crate::{clock::ManualClock, daytime_threads::LajiDaytime};
+
+    #[test]
+    fn drift_reports() -> io::Result<()> {
+        let now = DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap();
+        let daytime = LajiDaytime::new(|_sender| || {})
+            .bind_tcp("127.0.0.1:0")?
+            .clock(ManualClock::new(now + chrono::Duration::seconds(90)));
+        let daytime_addr = daytime.local_addrs()?[0];
+        thread::spawn(move || daytime.run().unwrap());
+        let time = TcpListener::bind("127.0.0.1:0")?;
+        let time_addr = time.local_addr()?;
+        thread::spawn(move || -> io::Result<()> {
+            let secs = timestamp::to_rfc868(SystemTime::from(now) - Duration::from_secs(30));
+            time.accept()?.0.write_all(&secs.to_be_bytes())
+        });
+        let gone = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+        let mut poller = Poller::new()
+            .daytime(daytime_addr.to_string())
+            .time(time_addr.to_string())
+            .daytime(gone.to_string())
+            .timeout(Duration::from_secs(2))
+            .clock(ManualClock::new(now));
+        let mut reports = Vec::new();
+        poller.poll_once(|report| reports.push(report.clone()));
+        let offsets: Vec<_> = reports.iter().map(|report| match report.outcome {
+            // the half round trip makes the offsets a little short of whole seconds
+            Outcome::Drift { offset, .. } => Some((offset.num_milliseconds() as f64 / 1000.0).round() as i64),
+            _ => None,
+        }).collect();
+        assert_eq!(offsets, [Some(90), Some(-30), None]);
+        assert_eq!(reports[2].outcome, Outcome::Unavailable(io::ErrorKind::ConnectionRefused));
+        assert_eq!((reports[2].polled, reports[2].availability()), (1, 0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_time_across_rollover() {
+        let near = DateTime::parse_from_rfc3339("2036-02-07T06:00:00+00:00").unwrap();
+        let after = DateTime::parse_from_rfc3339("2036-02-07T07:00:00+00:00").unwrap();
+        let secs = timestamp::to_rfc868(SystemTime::from(after));
+        assert!(secs < 3600);
+        assert_eq!(parse(Protocol::Time, &secs.to_be_bytes(), near), Some(after));
+    }
+
+    #[test]
+    fn parse_daytime() {
+        let near = DateTime::parse_from_rfc3339("2003-07-01T10:52:37+02:00").unwrap();
+        assert!(parse(Protocol::Daytime, b"2003-07-01T10:52:37+02:00\r\n", near).is_some());
+        assert!(parse(Protocol::Daytime, b"Tuesday, July 1, 2003 10:52:37-PDT", near).is_none());
+    }
+}
@@ -0,0 +1,611 @@
+//! Echo (RFC 862) over TCP and UDP on one mio event loop.
+//!
+//! What a handler sends on a stream goes into that stream's output buffer, which the loop
+//! writes out as the socket takes it. Once a stream has `max_pending` bytes waiting, the
+//! loop stops reading from it until the peer has read enough of its replies, so a client
+//! that sends without ever reading cannot make the server buffer without end.
+//!
+//! Streams are registered for both readability and writability, edge-triggered, once:
+//! reads go on until the socket would block or the buffer is full, writes until it would
+//! block or the buffer is empty, and each event picks up where the last left off.
+//!
+//! Datagrams go through the same safeguards as `echo::LajiEcho`, with a rate limiter per
+//! bound socket.
+use mio::{Poll, PollOpt, Ready, Token, Events, net::{TcpListener, TcpStream, UdpSocket}};
+use std::{io::{self, Read, Write}, net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs}, time::Duration};
+use slab::Slab;
+use smallvec::SmallVec;
+use crate::{config::ConfigError, echo::{Dropped, Guard}, ports, ratelimit::PerSource, server::ServerHandle};
+
+const EVENTS_CAPACITY: usize = 1024;
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+const DEFAULT_MAX_PENDING: usize = 64 * 1024;
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Echo on `addr` over both TCP and UDP.
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Send + 'static,
+    H: Handler
+{
+    Builder::new().bind(addr)?.build(factory)?.run()
+}
+
+/// `listen` on a thread of its own; binding and configuration errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Send + 'static,
+    H: Handler
+{
+    let builder = Builder::new().bind(addr)?;
+    builder.validate()?;
+    let local_addrs = builder.local_addrs()?;
+    ServerHandle::spawn("laji-echo", local_addrs, move || builder.build(factory)?.run())
+}
+
+pub struct LajiEcho<F>
+where F: Factory
+{
+    poll: Poll,
+    entries: Slab<Entry<F::Handler>>,
+    factory: F,
+    // big enough for any datagram; streams read at most `read_buffer_size` of it
+    read_buf: Vec<u8>,
+    read_buffer_size: usize,
+    max_pending: usize,
+    guard: Guard,
+}
+
+enum Entry<H> {
+    Listener(TcpListener),
+    Udp(UdpSocket, Option<PerSource>),
+    Stream(Connection<H>),
+}
+
+struct Connection<H> {
+    stream: TcpStream,
+    handler: H,
+    // replies not yet taken by the socket
+    out: Vec<u8>,
+    // the last read would have blocked, so wait for the next readable event
+    read_blocked: bool,
+    // the peer shut down its side; close once `out` is written
+    read_closed: bool,
+}
+
+impl<F> LajiEcho<F>
+where F: Factory
+{
+    fn from_builder(builder: Builder, factory: F) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut entries = Slab::new();
+        for listener in builder.tcp {
+            let entry = entries.vacant_entry();
+            poll.register(&listener, Token(entry.key()), Ready::readable(), PollOpt::edge())?;
+            entry.insert(Entry::Listener(listener));
+        }
+        for socket in builder.udp {
+            let entry = entries.vacant_entry();
+            poll.register(&socket, Token(entry.key()), Ready::readable(), PollOpt::edge())?;
+            entry.insert(Entry::Udp(socket, builder.guard.limiter()));
+        }
+        Ok(Self {
+            poll,
+            entries,
+            factory,
+            read_buf: vec![0u8; builder.read_buffer_size.max(MAX_DATAGRAM_LEN)],
+            read_buffer_size: builder.read_buffer_size,
+            max_pending: builder.max_pending,
+            guard: builder.guard,
+        })
+    }
+
+    /// Serve until polling fails; streams still open then are closed. A failed accept or
+    /// receive goes to `on_error`, and the loop carries on.
+    pub fn run(mut self) -> io::Result<()> {
+        let ans = self.serve();
+        let open: Vec<usize> = self.entries.iter()
+            .filter_map(|(index, entry)| match entry {
+                Entry::Stream(_) => Some(index),
+                _ => None,
+            })
+            .collect();
+        for token_index in open {
+            self.close_stream(token_index);
+        }
+        ans
+    }
+
+    fn serve(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut ready = Vec::with_capacity(EVENTS_CAPACITY);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in &events {
+                let token_index = event.token().0;
+                match self.entries.get_mut(token_index) {
+                    Some(Entry::Listener(_)) => self.accept_all(token_index),
+                    Some(Entry::Udp(..)) => self.echo_datagrams(token_index),
+                    Some(Entry::Stream(conn)) => {
+                        if event.readiness().is_readable() {
+                            conn.read_blocked = false;
+                        }
+                        ready.push(token_index);
+                    }
+                    None => {}
+                }
+            }
+            for token_index in ready.drain(..) {
+                self.service(token_index);
+            }
+        }
+    }
+
+    fn accept_all(&mut self, token_index: usize) {
+        loop {
+            let accepted = match &self.entries[token_index] {
+                Entry::Listener(listener) => listener.accept(),
+                _ => unreachable!(),
+            };
+            match accepted {
+                Ok((stream, _addr)) => self.open_stream(stream).unwrap_or_else(|e| self.factory.on_error(e)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => return self.factory.on_error(e),
+            }
+        }
+    }
+
+    fn open_stream(&mut self, stream: TcpStream) -> io::Result<()> {
+        let shake = match Handshake::read_stream(&stream) {
+            Ok(shake) => shake,
+            // gone before it could be served
+            Err(_) => return Ok(()),
+        };
+        if !self.factory.accept(&shake) {
+            return Ok(());
+        }
+        let entry = self.entries.vacant_entry();
+        let token = Token(entry.key());
+        self.poll.register(&stream, token, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+        let mut handler = self.factory.connection_made();
+        handler.on_open(shake);
+        entry.insert(Entry::Stream(Connection {
+            stream,
+            handler,
+            out: Vec::new(),
+            read_blocked: false,
+            read_closed: false,
+        }));
+        Ok(())
+    }
+
+    /// Read and write as far as the socket and the output buffer allow, and close the
+    /// stream if that finished it.
+    fn service(&mut self, token_index: usize) {
+        let (max_pending, read_buffer_size) = (self.max_pending, self.read_buffer_size);
+        let buf = &mut self.read_buf;
+        let conn = match self.entries.get_mut(token_index) {
+            Some(Entry::Stream(conn)) => conn,
+            _ => return,
+        };
+        let done = loop {
+            while !conn.read_blocked && !conn.read_closed && conn.out.len() < max_pending {
+                // don't let one stream's replies go past the cap by more than a read
+                let room = (max_pending - conn.out.len()).min(read_buffer_size);
+                match conn.stream.read(&mut buf[..room]) {
+                    Ok(0) => conn.read_closed = true,
+                    Ok(len) => {
+                        let mut sender = Sender { out: &mut conn.out };
+                        if conn.handler.on_message(&buf[..len], &mut sender).is_err() {
+                            return self.close_stream(token_index);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => conn.read_blocked = true,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => return self.close_stream(token_index),
+                }
+            }
+            let was_full = conn.out.len() >= max_pending;
+            match flush(&mut conn.stream, &mut conn.out) {
+                Ok(()) => {}
+                Err(_) => break true,
+            }
+            if conn.out.is_empty() && conn.read_closed {
+                break true;
+            }
+            // writing made room, and the socket may still have more to read
+            if !(was_full && conn.out.len() < max_pending && !conn.read_blocked && !conn.read_closed) {
+                break false;
+            }
+        };
+        if done {
+            self.close_stream(token_index);
+        }
+    }
+
+    fn echo_datagrams(&mut self, token_index: usize) {
+        let (socket, limit) = match &mut self.entries[token_index] {
+            Entry::Udp(socket, limit) => (socket, limit),
+            _ => unreachable!(),
+        };
+        loop {
+            let (len, origin) = match socket.recv_from(&mut self.read_buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                // Windows reports an earlier reply's ICMP unreachable here
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return self.factory.on_error(e),
+            };
+            if let Err(why) = self.guard.check(origin, limit) {
+                self.factory.on_dropped(origin, why);
+                continue;
+            }
+            let local_addr = match socket.local_addr() {
+                Ok(local_addr) => local_addr,
+                Err(e) => return self.factory.on_error(e),
+            };
+            let shake = Handshake { transport: Transport::Udp, peer_addr: origin, local_addr };
+            if !self.factory.accept(&shake) {
+                continue;
+            }
+            let mut handler = self.factory.connection_made();
+            handler.on_open(shake);
+            let mut reply = Vec::new();
+            if handler.on_message(&self.read_buf[..len], &mut Sender { out: &mut reply }).is_ok() && !reply.is_empty() {
+                reply.truncate(self.guard.reply_len(reply.len()));
+                // a reply the socket has no room for is lost like any other datagram
+                let _ = socket.send_to(&reply, &origin);
+            }
+            handler.on_close();
+        }
+    }
+
+    fn close_stream(&mut self, token_index: usize) {
+        if let Entry::Stream(mut conn) = self.entries.remove(token_index) {
+            let _ = self.poll.deregister(&conn.stream);
+            drop(conn.stream);
+            conn.handler.on_close();
+        }
+    }
+}
+
+/// Write `out` until it is empty or the socket would block, dropping what was written.
+fn flush(stream: &mut TcpStream, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut written = 0;
+    let ans = loop {
+        if written == out.len() {
+            break Ok(());
+        }
+        match stream.write(&out[written..]) {
+            Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => written += len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    out.drain(..written);
+    ans
+}
+
+#[derive(Debug)]
+pub struct Builder {
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    udp: SmallVec<[UdpSocket; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+    max_pending: usize,
+    guard: Guard,
+}
+
+impl Builder {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            tcp: SmallVec::new(),
+            udp: SmallVec::new(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            max_pending: DEFAULT_MAX_PENDING,
+            guard: Guard::new(),
+        }
+    }
+
+    /// Bind `addr` for both TCP and UDP.
+    #[inline]
+    pub fn bind<A>(self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.bind_tcp(&addr)?.bind_udp(&addr)
+    }
+
+    #[inline]
+    pub fn bind_tcp<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.tcp.push(TcpListener::from_std(std::net::TcpListener::bind(addr)?)?);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn bind_udp<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::from_socket(std::net::UdpSocket::bind(addr)?)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// The most a stream reads at once, and so the most one `on_message` gets.
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Builder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Stop reading from a stream while this many bytes of its replies wait to be sent;
+    /// 64 KiB by default.
+    #[inline]
+    pub fn max_pending(mut self, len: usize) -> Builder {
+        self.max_pending = len;
+        self
+    }
+
+    /// Drop datagrams from these source ports instead of `ports::REFLECTION_PORTS`.
+    #[inline]
+    pub fn refuse_ports<I>(mut self, ports: I) -> Builder
+    where I: IntoIterator<Item = u16>
+    {
+        self.guard.refused_ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Send at most `len` bytes back for each datagram; streams are held back by
+    /// `max_pending` instead.
+    #[inline]
+    pub fn max_reply_len(mut self, len: usize) -> Builder {
+        self.guard.max_reply_len = Some(len);
+        self
+    }
+
+    /// Answer each source IP at most `replies` datagrams per `interval`, per bound socket.
+    #[inline]
+    pub fn rate_limit(mut self, replies: u32, interval: Duration) -> Builder {
+        self.guard.rate_limit = Some((replies, interval));
+        self
+    }
+
+    /// TCP listeners first, then UDP sockets, each in bind order.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(TcpListener::local_addr)
+            .chain(self.udp.iter().map(UdpSocket::local_addr))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        if self.max_pending == 0 {
+            return Err(ConfigError::Zero("max_pending"));
+        }
+        self.guard.validate()
+    }
+
+    /// Validates the configuration first; its errors are `InvalidInput` wrapping a
+    /// `ConfigError`.
+    #[inline]
+    pub fn build<F>(self, factory: F) -> io::Result<LajiEcho<F>>
+    where F: Factory
+    {
+        self.validate()?;
+        LajiEcho::from_builder(self, factory)
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Who a handler talks to: a stream's peer, or the source of one datagram.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    transport: Transport,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    fn read_stream(ts: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            transport: Transport::Tcp,
+            peer_addr: ts.peer_addr()?,
+            local_addr: ts.local_addr()?,
+        })
+    }
+
+    #[inline]
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
+
+/// Queues replies to the peer a message came from. On a stream the loop writes them out
+/// in order after the callback; for a datagram everything sent goes back as one datagram.
+#[derive(Debug)]
+pub struct Sender<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Sender<'_> {
+    #[inline]
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.extend_from_slice(buf);
+        Ok(())
+    }
+
+    /// Bytes queued to this peer and not yet written.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.out.len()
+    }
+}
+
+pub trait Handler {
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// What one read from a stream returned, or one whole datagram. Echoes it unless
+    /// overridden; an error closes the stream, dropping replies not yet written.
+    #[inline]
+    fn on_message(&mut self, data: &[u8], sender: &mut Sender) -> io::Result<()> {
+        sender.send(data)
+    }
+
+    /// A stream closed, or a datagram was answered.
+    fn on_close(&mut self) {}
+}
+
+impl<F> Handler for F
+where F: FnMut(Handshake) {
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self(shake)
+    }
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    /// Whether to serve this stream or datagram at all; refused ones get no handler.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// A datagram from `origin` was refused by the safeguards, before `accept` was asked.
+    fn on_dropped(&mut self, _origin: SocketAddr, _why: Dropped) {}
+
+    /// Accepting a stream or receiving a datagram failed, and the loop carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler,
+    F: FnMut() -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net, thread, time::Duration};
+
+    #[test]
+    fn backpressure() -> io::Result<()> {
+        let builder = Builder::new().bind_tcp("127.0.0.1:0")?.max_pending(1024);
+        let addr = builder.local_addrs()?[0];
+        thread::spawn(move || builder.build(|| |_shake: Handshake| {})?.run());
+        let mut stream = net::TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        // far more than the socket buffers and the cap hold, so the server has to wait
+        // for replies to be read before it reads more
+        let sent: Vec<u8> = (0..4 << 20).map(|i| (i % 251) as u8).collect();
+        let mut writer = stream.try_clone()?;
+        let data = sent.clone();
+        let writing = thread::spawn(move || -> io::Result<()> {
+            writer.write_all(&data)?;
+            writer.shutdown(net::Shutdown::Write)
+        });
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed)?;
+        writing.join().unwrap()?;
+        assert_eq!(echoed.len(), sent.len());
+        assert!(echoed == sent);
+        Ok(())
+    }
+
+    struct Counted(std::sync::mpsc::Sender<usize>, usize);
+
+    impl Handler for Counted {
+        fn on_message(&mut self, data: &[u8], sender: &mut Sender) -> io::Result<()> {
+            self.1 += data.len();
+            sender.send(data)?;
+            sender.send(b"!")
+        }
+
+        fn on_close(&mut self) {
+            self.0.send(self.1).unwrap();
+        }
+    }
+
+    #[test]
+    fn udp_and_handlers() -> io::Result<()> {
+        let (closed_tx, closed) = std::sync::mpsc::channel();
+        let handle = listen_spawned("127.0.0.1:0", move || Counted(closed_tx.clone(), 0))?;
+        let (tcp, udp) = (handle.local_addrs()[0], handle.local_addrs()[1]);
+        let client = net::UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        client.send_to(b"laji", udp)?;
+        let mut buf = [0u8; 64];
+        let (len, from) = client.recv_from(&mut buf)?;
+        assert_eq!((&buf[..len], from), (&b"laji!"[..], udp));
+        assert_eq!(closed.recv_timeout(Duration::from_secs(2)), Ok(4));
+
+        let mut stream = net::TcpStream::connect(tcp)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.write_all(b"abc")?;
+        stream.read_exact(&mut buf[..4])?;
+        assert_eq!(&buf[..4], b"abc!");
+        drop(stream);
+        assert_eq!(closed.recv_timeout(Duration::from_secs(2)), Ok(3));
+        assert_eq!(Builder::new().bind_udp("127.0.0.1:0")?.max_pending(0).validate(),
+            Err(ConfigError::Zero("max_pending")));
+        Ok(())
+    }
+}
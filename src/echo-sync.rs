@@ -0,0 +1,511 @@
+//! Echo (RFC 862) over TCP and UDP on blocking sockets, a thread per connection.
+//!
+//! Unlike `echo::LajiEcho`, which only answers datagrams, this keeps TCP streams open and
+//! hands every read to the handler with a `Sender` back to the peer; a handler that does
+//! not override `on_message` echoes. Datagrams go through the same safeguards as
+//! `echo::LajiEcho`: refused source ports, an optional per-source rate limit and an optional
+//! reply size cap.
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    thread,
+    time::Duration,
+};
+use smallvec::SmallVec;
+use crate::{config::ConfigError, echo::{Dropped, Guard}, ports, ratelimit::PerSource, server::ServerHandle};
+
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Echo on `addr` over both TCP and UDP.
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler + Send + 'static
+{
+    Builder::new().bind(addr)?.build(factory).run()
+}
+
+/// `listen` on a thread of its own; binding errors are returned here.
+pub fn listen_spawned<A, F, H>(addr: A, factory: F) -> io::Result<ServerHandle>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: Clone + Send + 'static,
+    H: Handler + Send + 'static
+{
+    let builder = Builder::new().bind(addr)?;
+    let local_addrs = builder.local_addrs()?;
+    let server = builder.build(factory);
+    ServerHandle::spawn("laji-echo", local_addrs, move || server.run())
+}
+
+#[derive(Debug)]
+pub struct LajiEcho<F>
+where F: Factory
+{
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    udp: SmallVec<[UdpSocket; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+    guard: Guard,
+    factory: F
+}
+
+impl<F> LajiEcho<F>
+where
+    F: Factory + Clone + Send + 'static
+{
+    /// Serve every listener and socket on a thread of its own. A failed accept or receive
+    /// goes to `on_error` and the listener carries on; a stream whose reads or writes fail
+    /// is closed.
+    pub fn run(self) -> io::Result<()> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            return Err(ConfigError::NoListeners.into());
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size").into());
+        }
+        self.guard.validate()?;
+        let read_buffer_size = self.read_buffer_size;
+        let mut threads = Vec::with_capacity(self.tcp.len() + self.udp.len());
+        for listener in self.tcp {
+            let mut factory = self.factory.clone();
+            threads.push(thread::spawn(move || {
+                for stream in listener.incoming() {
+                    accept_one_stream(&mut factory, stream, read_buffer_size)
+                        .unwrap_or_else(|e| factory.on_error(e))
+                }
+            }));
+        }
+        for socket in self.udp {
+            let mut factory = self.factory.clone();
+            let guard = self.guard.clone();
+            threads.push(thread::spawn(move || {
+                let mut limit = guard.limiter();
+                let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+                loop {
+                    serve_one_datagram(&mut factory, &socket, &guard, &mut limit, &mut buf)
+                        .unwrap_or_else(|e| factory.on_error(e))
+                }
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+fn accept_one_stream<F>(factory: &mut F, stream: io::Result<TcpStream>, read_buffer_size: usize) -> io::Result<()>
+where F: Factory + Send + 'static
+{
+    let stream = stream?;
+    let shake = match Handshake::read_stream(&stream) {
+        Ok(shake) => shake,
+        // gone before it could be served
+        Err(_) => return Ok(()),
+    };
+    if !factory.accept(&shake) {
+        return Ok(());
+    }
+    let handler = factory.connection_made();
+    thread::spawn(move || serve_stream(handler, stream, shake, read_buffer_size));
+    Ok(())
+}
+
+fn serve_stream<H>(mut handler: H, mut stream: TcpStream, shake: Handshake, read_buffer_size: usize)
+where H: Handler
+{
+    handler.on_open(shake);
+    let mut buf = vec![0u8; read_buffer_size];
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let mut sender = Sender::Tcp { stream: &mut stream };
+        if handler.on_message(&buf[..len], &mut sender).is_err() {
+            break;
+        }
+    }
+    drop(stream);
+    handler.on_close();
+}
+
+fn serve_one_datagram<F>(factory: &mut F, socket: &UdpSocket, guard: &Guard, limit: &mut Option<PerSource>, buf: &mut [u8]) -> io::Result<()>
+where F: Factory
+{
+    let (len, origin) = match socket.recv_from(buf) {
+        Ok(received) => received,
+        // Windows reports an earlier reply's ICMP unreachable here
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if let Err(why) = guard.check(origin, limit) {
+        factory.on_dropped(origin, why);
+        return Ok(());
+    }
+    let shake = Handshake { transport: Transport::Udp, peer_addr: origin, local_addr: socket.local_addr()? };
+    if !factory.accept(&shake) {
+        return Ok(());
+    }
+    let mut handler = factory.connection_made();
+    handler.on_open(shake);
+    let mut sender = Sender::Udp { socket, target: origin, max_len: guard.max_reply_len };
+    // a reply that cannot be sent is lost like any other datagram
+    let _ = handler.on_message(&buf[..len], &mut sender);
+    handler.on_close();
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Builder {
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    udp: SmallVec<[UdpSocket; INLINE_LISTENERS]>,
+    read_buffer_size: usize,
+    guard: Guard,
+}
+
+impl Builder {
+    #[inline]
+    pub fn new() -> Self {
+        Self { tcp: SmallVec::new(), udp: SmallVec::new(), read_buffer_size: DEFAULT_READ_BUFFER_SIZE, guard: Guard::new() }
+    }
+
+    /// Bind `addr` for both TCP and UDP.
+    #[inline]
+    pub fn bind<A>(self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.bind_tcp(&addr)?.bind_udp(&addr)
+    }
+
+    #[inline]
+    pub fn bind_tcp<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.tcp.push(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn bind_udp<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// The most a stream reads at once, and so the most one `on_message` gets.
+    #[inline]
+    pub fn read_buffer_size(mut self, size: usize) -> Builder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Drop datagrams from these source ports instead of `ports::REFLECTION_PORTS`.
+    #[inline]
+    pub fn refuse_ports<I>(mut self, ports: I) -> Builder
+    where I: IntoIterator<Item = u16>
+    {
+        self.guard.refused_ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Send at most `len` bytes back for each datagram; streams are not capped.
+    #[inline]
+    pub fn max_reply_len(mut self, len: usize) -> Builder {
+        self.guard.max_reply_len = Some(len);
+        self
+    }
+
+    /// Answer each source IP at most `replies` datagrams per `interval`, per bound socket.
+    #[inline]
+    pub fn rate_limit(mut self, replies: u32, interval: Duration) -> Builder {
+        self.guard.rate_limit = Some((replies, interval));
+        self
+    }
+
+    /// TCP listeners first, then UDP sockets, each in bind order.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(TcpListener::local_addr)
+            .chain(self.udp.iter().map(UdpSocket::local_addr))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.read_buffer_size == 0 {
+            return Err(ConfigError::Zero("read_buffer_size"));
+        }
+        self.guard.validate()
+    }
+
+    pub fn build<F>(self, factory: F) -> LajiEcho<F>
+    where F: Factory
+    {
+        LajiEcho {
+            tcp: self.tcp,
+            udp: self.udp,
+            read_buffer_size: self.read_buffer_size,
+            guard: self.guard,
+            factory,
+        }
+    }
+
+    /// `build`, after checking the configuration.
+    pub fn try_build<F>(self, factory: F) -> Result<LajiEcho<F>, ConfigError>
+    where F: Factory
+    {
+        self.validate()?;
+        Ok(self.build(factory))
+    }
+}
+
+impl Default for Builder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Who a handler talks to: a stream's peer, or the source of one datagram.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    transport: Transport,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    fn read_stream(ts: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            transport: Transport::Tcp,
+            peer_addr: ts.peer_addr()?,
+            local_addr: ts.local_addr()?,
+        })
+    }
+
+    #[inline]
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
+
+/// Writes back to the peer a message came from.
+#[derive(Debug)]
+pub enum Sender<'a> {
+    Tcp {
+        stream: &'a mut TcpStream,
+    },
+    Udp {
+        socket: &'a UdpSocket,
+        target: SocketAddr,
+        /// The builder's `max_reply_len`.
+        max_len: Option<usize>,
+    }
+}
+
+impl Sender<'_> {
+    /// Send all of `buf`, as one write sequence on TCP or one datagram on UDP, cut to
+    /// `max_len` there.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Sender::Tcp { stream } => stream.write_all(buf),
+            Sender::Udp { socket, target, max_len } => {
+                let buf = &buf[..max_len.map_or(buf.len(), |max| buf.len().min(max))];
+                let len = socket.send_to(buf, *target)?;
+                if len != buf.len() {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "datagram truncated"));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub trait Handler {
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// What one read from a stream returned, or one whole datagram. Echoes it unless
+    /// overridden; an error closes the stream.
+    #[inline]
+    fn on_message(&mut self, data: &[u8], sender: &mut Sender) -> io::Result<()> {
+        sender.send(data)
+    }
+
+    /// A stream closed, or a datagram was answered.
+    fn on_close(&mut self) {}
+}
+
+impl<F> Handler for F
+where F: FnMut(Handshake) {
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self(shake)
+    }
+}
+
+pub trait Factory {
+    type Handler: Handler + Send + 'static;
+
+    /// Whether to serve this stream or datagram at all; refused ones get no handler.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// A datagram from `origin` was refused by the safeguards, before `accept` was asked.
+    fn on_dropped(&mut self, _origin: SocketAddr, _why: Dropped) {}
+
+    /// Accepting a stream or receiving a datagram failed, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler + Send + 'static,
+    F: FnMut() -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    struct Shout;
+
+    impl Handler for Shout {
+        fn on_message(&mut self, data: &[u8], sender: &mut Sender) -> io::Result<()> {
+            if data.starts_with(b"bye") {
+                return Err(io::Error::other("done"));
+            }
+            sender.send(&data.to_ascii_uppercase())
+        }
+    }
+
+    #[test]
+    fn tcp_and_udp() -> io::Result<()> {
+        let (opened_tx, opened) = mpsc::channel();
+        let handle = listen_spawned("127.0.0.1:0", move || {
+            let opened_tx = opened_tx.clone();
+            move |shake: Handshake| opened_tx.send(shake.transport()).unwrap()
+        })?;
+        let (tcp, udp) = (handle.local_addrs()[0], handle.local_addrs()[1]);
+        let mut stream = TcpStream::connect(tcp)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut buf = [0u8; 64];
+        // the stream stays open across messages
+        for msg in &[&b"laji"[..], b"protocols"] {
+            stream.write_all(msg)?;
+            stream.read_exact(&mut buf[..msg.len()])?;
+            assert_eq!(&buf[..msg.len()], *msg);
+        }
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        client.send_to(b"datagram", udp)?;
+        let (len, from) = client.recv_from(&mut buf)?;
+        assert_eq!((&buf[..len], from), (&b"datagram"[..], udp));
+        assert_eq!(opened.try_iter().collect::<Vec<_>>(), [Transport::Tcp, Transport::Udp]);
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct Guarded(mpsc::Sender<Dropped>);
+
+    impl Factory for Guarded {
+        type Handler = Shout;
+
+        fn connection_made(&mut self) -> Shout {
+            Shout
+        }
+
+        fn on_dropped(&mut self, _origin: SocketAddr, why: Dropped) {
+            self.0.send(why).unwrap();
+        }
+    }
+
+    #[test]
+    fn udp_safeguards() -> io::Result<()> {
+        let (dropped_tx, dropped) = mpsc::channel();
+        let server = Builder::new().bind_udp("127.0.0.1:0")?
+            .max_reply_len(4)
+            .rate_limit(1, Duration::from_secs(60));
+        let addr = server.local_addrs()?[0];
+        thread::spawn(move || server.build(Guarded(dropped_tx)).run());
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut buf = [0u8; 64];
+        client.send_to(b"laji-protocols", addr)?;
+        let (len, _) = client.recv_from(&mut buf)?;
+        assert_eq!(&buf[..len], b"LAJI");
+        client.send_to(b"again", addr)?;
+        assert_eq!(dropped.recv_timeout(Duration::from_secs(2)), Ok(Dropped::RateLimited));
+        assert_eq!(Builder::new().bind_udp("127.0.0.1:0")?.max_reply_len(0).validate(),
+            Err(ConfigError::Zero("max_reply_len")));
+        Ok(())
+    }
+
+    #[test]
+    fn custom_handler() -> io::Result<()> {
+        let server = Builder::new().bind_tcp("127.0.0.1:0")?.read_buffer_size(16);
+        let addr = server.local_addrs()?[0];
+        thread::spawn(move || server.build(|| Shout).run());
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.write_all(b"quiet")?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"QUIET");
+        // the handler's error closes the stream
+        stream.write_all(b"bye")?;
+        assert_eq!(stream.read(&mut buf)?, 0);
+        assert_eq!(Builder::new().try_build(|| Shout).err(), Some(ConfigError::NoListeners));
+        Ok(())
+    }
+}
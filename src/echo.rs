@@ -0,0 +1,467 @@
+//! UDP echo (RFC 862), with the safeguards a server answering spoofable datagrams needs.
+//!
+//! Datagrams from the ports in `ports::REFLECTION_PORTS` are never answered, so two simple
+//! services cannot be set to echo at each other forever. Replies can be capped in size, and
+//! each source IP limited to so many replies per interval; both are off unless configured.
+//!
+//! `Prober` is the other end: it sends numbered probes to an echo server and sums up the
+//! round trips and losses, for a quick look at a path.
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+use smallvec::SmallVec;
+use crate::{config::ConfigError, ports, ratelimit::PerSource, virtnet::Datagram};
+
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: 'static + Clone + Send,
+    H: Handler
+{
+    LajiEcho::new(factory).bind(addr)?.run()
+}
+
+/// Why a datagram got no echo.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Dropped {
+    /// It came from one of the refused source ports.
+    ReflectionPort,
+    /// Its source IP used up its replies for now.
+    RateLimited,
+}
+
+/// The safeguards every echo server puts in front of its UDP sockets, `echo_sync` and
+/// `echo_mio` included.
+#[derive(Clone, Debug)]
+pub(crate) struct Guard {
+    pub(crate) refused_ports: Vec<u16>,
+    pub(crate) max_reply_len: Option<usize>,
+    pub(crate) rate_limit: Option<(u32, Duration)>,
+}
+
+impl Guard {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self { refused_ports: ports::REFLECTION_PORTS.to_vec(), max_reply_len: None, rate_limit: None }
+    }
+
+    /// A fresh limiter for one socket, if replies are limited.
+    #[inline]
+    pub(crate) fn limiter(&self) -> Option<PerSource> {
+        self.rate_limit.map(|(replies, interval)| PerSource::new(replies, interval))
+    }
+
+    /// Why a datagram from `origin` must go unanswered, if it must; a datagram let through
+    /// counts against its source's limit.
+    pub(crate) fn check(&self, origin: SocketAddr, limit: &mut Option<PerSource>) -> Result<(), Dropped> {
+        if self.refused_ports.contains(&origin.port()) {
+            return Err(Dropped::ReflectionPort);
+        }
+        match limit.as_mut().map(|limit| limit.allow(origin.ip())) {
+            Some(false) => Err(Dropped::RateLimited),
+            _ => Ok(()),
+        }
+    }
+
+    /// How much of a `len` byte reply may be sent.
+    #[inline]
+    pub(crate) fn reply_len(&self, len: usize) -> usize {
+        self.max_reply_len.map_or(len, |max| len.min(max))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_reply_len == Some(0) {
+            return Err(ConfigError::Zero("max_reply_len"));
+        }
+        match self.rate_limit {
+            Some((0, _)) => Err(ConfigError::Zero("rate_limit")),
+            Some((_, interval)) if interval == Duration::from_secs(0) => Err(ConfigError::Zero("rate_limit")),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct LajiEcho<F>
+where
+    F: Factory
+{
+    udp: SmallVec<[UdpSocket; 4]>,
+    factory: F,
+    guard: Guard,
+}
+
+impl<F> LajiEcho<F>
+where
+    F: Factory
+{
+    #[inline]
+    pub fn new(factory: F) -> Self {
+        Self { udp: SmallVec::new(), factory, guard: Guard::new() }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Self> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// Bind the well-known echo port, 7, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Self> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::ECHO))
+    }
+
+    /// Drop datagrams from these source ports instead of `ports::REFLECTION_PORTS`.
+    #[inline]
+    pub fn refuse_ports<I>(mut self, ports: I) -> Self
+    where I: IntoIterator<Item = u16>
+    {
+        self.guard.refused_ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Echo at most the first `len` bytes of every datagram.
+    #[inline]
+    pub fn max_reply_len(mut self, len: usize) -> Self {
+        self.guard.max_reply_len = Some(len);
+        self
+    }
+
+    /// Answer each source IP at most `replies` times per `interval`, per bound socket.
+    #[inline]
+    pub fn rate_limit(mut self, replies: u32, interval: Duration) -> Self {
+        self.guard.rate_limit = Some((replies, interval));
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.udp.iter().map(UdpSocket::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        self.guard.validate()
+    }
+}
+
+impl<F> LajiEcho<F>
+where
+    F: Factory + Clone + Send + 'static
+{
+    /// Serve every socket on a thread of its own. A datagram that cannot be received or
+    /// echoed goes to `on_error`, and the socket goes on serving.
+    pub fn run(self) -> io::Result<()> {
+        self.validate()?;
+        let mut threads = Vec::with_capacity(self.udp.len());
+        for socket in self.udp {
+            let mut factory = self.factory.clone();
+            let guard = self.guard.clone();
+            threads.push(thread::spawn(move || {
+                let mut limit = guard.limiter();
+                let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+                loop {
+                    serve_one(&mut factory, &socket, &guard, &mut limit, &mut buf)
+                        .unwrap_or_else(|e| factory.on_error(e))
+                }
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+fn serve_one<S, F>(factory: &mut F, socket: &S, guard: &Guard, limit: &mut Option<PerSource>, buf: &mut [u8]) -> io::Result<()>
+where
+    S: Datagram,
+    F: Factory
+{
+    let (len, origin) = socket.recv_from(buf)?;
+    let mut handler = factory.connection_made();
+    if let Err(why) = guard.check(origin, limit) {
+        handler.on_dropped(origin, why);
+        return Ok(());
+    }
+    let len = guard.reply_len(len);
+    handler.on_echo(origin, &buf[..len]);
+    socket.send_to(&buf[..len], origin)?;
+    Ok(())
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// A datagram could not be received or echoed, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler,
+    F: FnMut() -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+pub trait Handler {
+    /// `data` is about to be sent back to `origin`, already cut to the reply size cap.
+    fn on_echo(&mut self, _origin: SocketAddr, _data: &[u8]) {}
+
+    fn on_dropped(&mut self, _origin: SocketAddr, _why: Dropped) {}
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(SocketAddr)
+{
+    #[inline]
+    fn on_echo(&mut self, origin: SocketAddr, _data: &[u8]) {
+        self(origin)
+    }
+}
+
+// sequence number and send time
+const PROBE_HEADER_LEN: usize = 16;
+
+/// Sends `count` probes, one at a time, each waiting up to `timeout` for its echo.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Prober {
+    count: u32,
+    interval: Duration,
+    timeout: Duration,
+    payload_len: usize,
+}
+
+impl Prober {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            count: 10,
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(1),
+            payload_len: 64,
+        }
+    }
+
+    #[inline]
+    pub fn count(mut self, count: u32) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Time between the starts of two probes.
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long to wait for each echo before counting the probe lost.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Bytes per probe, at least 16 for the sequence number and send time.
+    #[inline]
+    pub fn payload_len(mut self, len: usize) -> Self {
+        self.payload_len = len;
+        self
+    }
+
+    pub fn probe<A>(&self, addr: A) -> io::Result<ProbeStats>
+    where A: ToSocketAddrs
+    {
+        if self.count == 0 {
+            return Err(ConfigError::Zero("count").into());
+        }
+        if self.payload_len < PROBE_HEADER_LEN || self.payload_len > MAX_DATAGRAM_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "probe payload must be 16 to 65507 bytes"));
+        }
+        let socket = crate::resolve::connect_udp(addr)?;
+        let epoch = Instant::now();
+        let mut stats = ProbeStats::default();
+        let mut rtts = Vec::new();
+        let mut probe = vec![0u8; self.payload_len];
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        for seq in 0..u64::from(self.count) {
+            let started = Instant::now();
+            fill_probe(&mut probe, seq, started.duration_since(epoch));
+            socket.send(&probe)?;
+            stats.sent += 1;
+            let deadline = started + self.timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    break;
+                }
+                socket.set_read_timeout(Some(left))?;
+                let len = match socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e),
+                };
+                let echo = &buf[..len];
+                if len < PROBE_HEADER_LEN || echo[..8] != probe[..8] {
+                    // an earlier probe's echo arriving after we gave up on it, or noise
+                    stats.late += 1;
+                    continue;
+                }
+                if echo == &probe[..] {
+                    stats.received += 1;
+                    rtts.push(started.elapsed());
+                } else {
+                    stats.corrupted += 1;
+                }
+                break;
+            }
+            if seq + 1 < u64::from(self.count) {
+                thread::sleep(self.interval.checked_sub(started.elapsed()).unwrap_or_default());
+            }
+        }
+        rtts.sort();
+        if !rtts.is_empty() {
+            stats.min = rtts[0];
+            stats.avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+            stats.p99 = rtts[(rtts.len() * 99).div_ceil(100) - 1];
+            stats.max = rtts[rtts.len() - 1];
+        }
+        Ok(stats)
+    }
+}
+
+impl Default for Prober {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn fill_probe(probe: &mut [u8], seq: u64, sent: Duration) {
+    probe[..8].copy_from_slice(&seq.to_be_bytes());
+    probe[8..16].copy_from_slice(&(sent.as_nanos() as u64).to_be_bytes());
+    for (i, byte) in probe[16..].iter_mut().enumerate() {
+        *byte = (seq as usize + i) as u8;
+    }
+}
+
+/// Round trips are of the probes that came back intact; all zero if none did.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct ProbeStats {
+    pub sent: u32,
+    pub received: u32,
+    /// Echoes of the right probe with different content, truncated by a reply cap for one.
+    pub corrupted: u32,
+    /// Echoes arriving after their probe was given up on.
+    pub late: u32,
+    pub min: Duration,
+    pub avg: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl ProbeStats {
+    /// The share of probes that got no intact echo in time.
+    #[inline]
+    pub fn loss(&self) -> f64 {
+        1.0 - f64::from(self.received) / f64::from(self.sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    struct Drops(mpsc::Sender<Dropped>);
+
+    impl Handler for Drops {
+        fn on_dropped(&mut self, _origin: SocketAddr, why: Dropped) {
+            self.0.send(why).unwrap();
+        }
+    }
+
+    fn echo(client: &UdpSocket, server: SocketAddr, msg: &[u8]) -> Option<Vec<u8>> {
+        client.send_to(msg, server).unwrap();
+        let mut buf = [0u8; 64];
+        client.recv_from(&mut buf).ok().map(|(len, _)| buf[..len].to_vec())
+    }
+
+    #[test]
+    fn safeguards() {
+        let (tx, rx) = mpsc::channel();
+        let refused = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for socket in &[&refused, &client] {
+            socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        }
+        let server = LajiEcho::new(move || Drops(tx.clone()))
+            .bind("127.0.0.1:0").unwrap()
+            .refuse_ports(vec![refused.local_addr().unwrap().port()])
+            .max_reply_len(4)
+            .rate_limit(2, Duration::from_secs(60));
+        let addr = server.local_addrs().unwrap()[0];
+        thread::spawn(move || server.run().unwrap());
+        assert_eq!(echo(&refused, addr, b"loop"), None);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(Dropped::ReflectionPort));
+        assert_eq!(echo(&client, addr, b"laji").as_ref().map(|v| &v[..]), Some(&b"laji"[..]));
+        assert_eq!(echo(&client, addr, b"lajilaji").as_ref().map(|v| &v[..]), Some(&b"laji"[..]));
+        assert_eq!(echo(&client, addr, b"laji"), None);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(Dropped::RateLimited));
+    }
+
+    #[test]
+    fn probe_stats() -> io::Result<()> {
+        let server = LajiEcho::new(|| |_origin: SocketAddr| {})
+            .bind("127.0.0.1:0")?
+            .max_reply_len(40)
+            .rate_limit(5, Duration::from_secs(60));
+        let addr = server.local_addrs()?[0];
+        thread::spawn(move || server.run().unwrap());
+        let prober = Prober::new().count(4).interval(Duration::from_millis(10)).timeout(Duration::from_millis(300));
+        let stats = prober.payload_len(32).probe(addr)?;
+        assert_eq!((stats.sent, stats.received, stats.corrupted), (4, 4, 0));
+        assert!(stats.min <= stats.avg && stats.avg <= stats.p99 && stats.p99 <= stats.max);
+        assert_eq!(stats.loss(), 0.0);
+        // one more fits the rate limit, the cap cuts it short, and the rest go unanswered
+        let stats = prober.payload_len(48).probe(addr)?;
+        assert_eq!((stats.received, stats.corrupted), (0, 1));
+        assert_eq!((stats.loss(), stats.p99), (1.0, Duration::from_secs(0)));
+        assert_eq!(prober.payload_len(8).probe(addr).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let factory = || |_origin: SocketAddr| {};
+        assert_eq!(LajiEcho::new(factory).validate(), Err(ConfigError::NoListeners));
+        let server = LajiEcho::new(factory).bind("127.0.0.1:0").unwrap();
+        assert_eq!(server.max_reply_len(0).validate(), Err(ConfigError::Zero("max_reply_len")));
+    }
+}
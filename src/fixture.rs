@@ -0,0 +1,58 @@
+//! Golden wire-format fixtures: packets captured from real implementations, kept under `fixtures/`.
+//! Files named `synthesized-*` were built by hand from the specification instead.
+//!
+//! `.hex` files hold whitespace-separated hex with `#` comments, anything else is read as raw bytes.
+// which helpers get used depends on the enabled features
+#![allow(dead_code)]
+use std::{fs, io, path::PathBuf};
+
+/// Read `fixtures/<name>`, panicking with the path if it is missing or malformed.
+pub(crate) fn load(name: &str) -> Vec<u8> {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "fixtures", name].iter().collect();
+    let data = fs::read(&path).unwrap_or_else(|e| panic!("reading {}: {}", path.display(), e));
+    if path.extension().is_none_or(|ext| ext != "hex") {
+        return data;
+    }
+    let text = String::from_utf8(data).unwrap_or_else(|_| panic!("{} is not utf-8", path.display()));
+    parse_hex(&text).unwrap_or_else(|msg| panic!("{}: {}", path.display(), msg))
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.bytes())
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits.chunks(2).map(|pair| {
+        let pair = std::str::from_utf8(pair).map_err(|_| "non-ascii hex digit".to_string())?;
+        u8::from_str_radix(pair, 16).map_err(|_| format!("bad hex byte {:?}", pair))
+    }).collect()
+}
+
+/// Check that `encode` writes exactly `expected`, reporting the first differing offset if not.
+pub(crate) fn assert_encodes<F>(expected: &[u8], encode: F)
+where
+    F: FnOnce(&mut [u8]) -> io::Result<usize>
+{
+    let mut buf = vec![0u8; expected.len() + 64];
+    let len = encode(&mut buf).expect("encoding fixture");
+    let actual = &buf[..len];
+    if let Some(offset) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+        panic!("encoding differs at byte {}: got {:02x}, expected {:02x}", offset, actual[offset], expected[offset]);
+    }
+    assert_eq!(len, expected.len(), "encoded length differs from fixture");
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_format() {
+        assert_eq!(parse_hex("# header\n01 ff\n\tA0# trailing\n"), Ok(vec![0x01, 0xff, 0xa0]));
+        assert!(parse_hex("012").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+}
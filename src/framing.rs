@@ -0,0 +1,673 @@
+//! Splitting byte streams into frames: length-prefixed, CRLF lines, and fixed-size records.
+//!
+//! The functions read from `Read`/`BufRead` and write to `Write` or a `Vec`, for blocking
+//! sockets. With `backend-tokio` the same framings come as codecs, `LengthCodec`,
+//! `LineCodec` and `RecordCodec`, for `Decoder::framed` on a tokio stream.
+//!
+//! Servers reading requests from clients they do not trust want `LineReader`, which bounds
+//! each line and can carry on after one that is too long.
+use std::{error::Error, fmt, io::{self, BufRead, Read, Write}};
+#[cfg(feature = "backend-tokio")]
+use bytes::BytesMut;
+#[cfg(feature = "backend-tokio")]
+use tokio::codec::{Decoder, Encoder};
+use crate::wire::invalid_data;
+
+/// How a frame's length is written before it.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Prefix {
+    /// Two bytes, big endian.
+    U16,
+    /// Four bytes, big endian.
+    U32,
+    /// A Minecraft-style VarInt, as `write_varint` writes it.
+    VarInt,
+}
+
+impl Prefix {
+    /// The longest body the prefix can describe.
+    #[inline]
+    pub fn max_len(self) -> usize {
+        match self {
+            Prefix::U16 => usize::from(u16::MAX),
+            Prefix::U32 => u32::MAX as usize,
+            Prefix::VarInt => i32::MAX as usize,
+        }
+    }
+
+    /// Append `body` with its length in front.
+    pub fn write_frame(self, out: &mut Vec<u8>, body: &[u8]) -> io::Result<()> {
+        if body.len() > self.max_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too long for its length prefix"));
+        }
+        match self {
+            Prefix::U16 => out.extend_from_slice(&(body.len() as u16).to_be_bytes()),
+            Prefix::U32 => out.extend_from_slice(&(body.len() as u32).to_be_bytes()),
+            Prefix::VarInt => write_varint(out, body.len() as i32),
+        }
+        out.extend_from_slice(body);
+        Ok(())
+    }
+
+    /// Read one frame's body, refusing frames longer than `max_len` before reading them.
+    pub fn read_frame<R: Read>(self, reader: &mut R, max_len: usize) -> io::Result<Vec<u8>> {
+        let len = match self {
+            Prefix::U16 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                usize::from(u16::from_be_bytes(len))
+            }
+            Prefix::U32 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_be_bytes(len) as usize
+            }
+            Prefix::VarInt => match read_varint(reader)? {
+                len if len < 0 => return Err(invalid_data("negative frame length")),
+                len => len as usize,
+            },
+        };
+        if len > max_len {
+            return Err(invalid_data("frame longer than allowed"));
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    /// The prefix's length and the body's, once `buf` holds the whole prefix.
+    pub fn parse(self, buf: &[u8], max_len: usize) -> io::Result<Option<(usize, usize)>> {
+        let (header_len, len) = match self {
+            Prefix::U16 if buf.len() >= 2 => (2, usize::from(u16::from_be_bytes([buf[0], buf[1]]))),
+            Prefix::U32 if buf.len() >= 4 => (4, u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize),
+            Prefix::VarInt => match parse_varint(buf)? {
+                Some((len, _)) if len < 0 => return Err(invalid_data("negative frame length")),
+                Some((len, header_len)) => (header_len, len as usize),
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        if len > max_len {
+            return Err(invalid_data("frame longer than allowed"));
+        }
+        Ok(Some((header_len, len)))
+    }
+}
+
+/// Protocol VarInt: 7 bits per byte, low bits first, negative numbers take all five bytes.
+pub fn write_varint(out: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut value = 0u32;
+    for shift in (0..5).map(|i| i * 7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u32) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(invalid_data("varint longer than 5 bytes"))
+}
+
+/// A VarInt at the start of `buf` and how many bytes it took, or `None` if it goes on past
+/// the end of `buf`.
+fn parse_varint(buf: &[u8]) -> io::Result<Option<(i32, usize)>> {
+    let mut value = 0u32;
+    for (i, &byte) in buf.iter().take(5).enumerate() {
+        value |= u32::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value as i32, i + 1)));
+        }
+    }
+    match buf.len() {
+        len if len >= 5 => Err(invalid_data("varint longer than 5 bytes")),
+        _ => Ok(None),
+    }
+}
+
+/// Where the first line in `buf` ends, just past its `\n`. Lines may run to `max_len` bytes
+/// with the terminator; a longer one is an error as soon as it cannot fit.
+pub fn find_line(buf: &[u8], max_len: usize) -> io::Result<Option<usize>> {
+    match buf.iter().take(max_len).position(|&b| b == b'\n') {
+        Some(pos) => Ok(Some(pos + 1)),
+        None if buf.len() >= max_len => Err(invalid_data("line longer than allowed")),
+        None => Ok(None),
+    }
+}
+
+/// `line` without its CRLF or bare LF.
+#[inline]
+pub fn trim_line(line: &str) -> &str {
+    line.trim_end_matches(&['\r', '\n'][..])
+}
+
+/// `BufRead::read_line`, except that a line over `max_len` bytes is an error instead of
+/// however much memory the peer cares to fill. The terminator is kept; 0 means EOF.
+pub fn read_line<R: BufRead>(reader: &mut R, line: &mut String, max_len: usize) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    loop {
+        let (done, used) = {
+            let buf = match reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if buf.is_empty() {
+                (true, 0)
+            } else {
+                match buf.iter().position(|&b| b == b'\n') {
+                    Some(pos) => { bytes.extend_from_slice(&buf[..=pos]); (true, pos + 1) }
+                    None => { bytes.extend_from_slice(buf); (false, buf.len()) }
+                }
+            }
+        };
+        reader.consume(used);
+        if bytes.len() > max_len {
+            return Err(invalid_data("line longer than allowed"));
+        }
+        if done {
+            break;
+        }
+    }
+    let text = std::str::from_utf8(&bytes).map_err(|_| invalid_data("line is not utf-8"))?;
+    line.push_str(text);
+    Ok(bytes.len())
+}
+
+/// Why `LineReader` gave up on a line.
+#[derive(Debug)]
+pub enum LineError {
+    /// The line ran past the reader's limit, given here; the next read skips the rest of it.
+    TooLong(usize),
+    NotUtf8,
+    Io(io::Error),
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LineError::TooLong(max_len) => write!(f, "line longer than {} bytes", max_len),
+            LineError::NotUtf8 => f.write_str("line is not utf-8"),
+            LineError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for LineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LineError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LineError {
+    #[inline]
+    fn from(err: io::Error) -> LineError {
+        LineError::Io(err)
+    }
+}
+
+impl From<LineError> for io::Error {
+    #[inline]
+    fn from(err: LineError) -> io::Error {
+        match err {
+            LineError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Reads request lines ending in CRLF or a bare LF, holding at most `max_len` bytes of a
+/// line (plus what the inner reader buffers) however long the client keeps it going.
+#[derive(Debug)]
+pub struct LineReader<R> {
+    inner: R,
+    max_len: usize,
+    skipping: bool,
+}
+
+impl<R> LineReader<R>
+where
+    R: BufRead
+{
+    /// Lines of up to 1024 bytes.
+    #[inline]
+    pub fn new(inner: R) -> Self {
+        Self { inner, max_len: 1024, skipping: false }
+    }
+
+    /// The longest line to accept, not counting its terminator.
+    #[inline]
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The next line without its terminator, or `None` at EOF. A last line the client did
+    /// not terminate is still returned.
+    pub fn read_line(&mut self) -> Result<Option<String>, LineError> {
+        if self.skipping && !self.skip_line()? {
+            return Ok(None);
+        }
+        let mut bytes = Vec::new();
+        loop {
+            let (ended, used) = {
+                let buf = match self.inner.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                match buf.iter().position(|&b| b == b'\n') {
+                    Some(pos) => { bytes.extend_from_slice(&buf[..pos]); (true, pos + 1) }
+                    None => { bytes.extend_from_slice(buf); (buf.is_empty(), buf.len()) }
+                }
+            };
+            self.inner.consume(used);
+            // a line ends at its LF, or at EOF
+            if bytes.ends_with(b"\r") && ended {
+                bytes.pop();
+            }
+            // one byte over may yet be the CR of a CRLF
+            if bytes.len() > self.max_len + usize::from(!ended) {
+                self.skipping = !ended;
+                return Err(LineError::TooLong(self.max_len));
+            }
+            if ended {
+                if bytes.is_empty() && used == 0 {
+                    return Ok(None);
+                }
+                return String::from_utf8(bytes).map(Some).map_err(|_| LineError::NotUtf8);
+            }
+        }
+    }
+
+    /// Throw away the rest of a line that was too long; false if the stream ended first.
+    fn skip_line(&mut self) -> io::Result<bool> {
+        loop {
+            let (found, used) = {
+                let buf = match self.inner.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                if buf.is_empty() {
+                    return Ok(false);
+                }
+                match buf.iter().position(|&b| b == b'\n') {
+                    Some(pos) => (true, pos + 1),
+                    None => (false, buf.len()),
+                }
+            };
+            self.inner.consume(used);
+            if found {
+                self.skipping = false;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Write `line` and a CRLF in one go. `max_len` counts the CRLF, and `line` must not hold
+/// line breaks of its own.
+pub fn write_line<W: Write>(writer: &mut W, line: &str, max_len: usize) -> io::Result<()> {
+    if line.contains(&['\r', '\n'][..]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "line contains a line break"));
+    }
+    if line.len() + 2 > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "line longer than allowed"));
+    }
+    let mut buf = Vec::with_capacity(line.len() + 2);
+    buf.extend_from_slice(line.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+    writer.write_all(&buf)
+}
+
+/// Fill `buf` with one record, or return false if the stream ended cleanly before it.
+/// Ending partway through a record is `UnexpectedEof`.
+pub fn read_record<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Length-prefixed frames over a tokio stream, yielding and taking the bodies.
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct LengthCodec {
+    prefix: Prefix,
+    max_len: usize,
+}
+
+#[cfg(feature = "backend-tokio")]
+impl LengthCodec {
+    /// Frames up to what `prefix` can describe, or 8 MiB if that is less.
+    #[inline]
+    pub fn new(prefix: Prefix) -> Self {
+        Self { prefix, max_len: prefix.max_len().min(8 << 20) }
+    }
+
+    /// Refuse incoming frames longer than this.
+    #[inline]
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for LengthCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        let (header_len, len) = match self.prefix.parse(&buf[..], self.max_len)? {
+            Some(lens) => lens,
+            None => return Ok(None),
+        };
+        if buf.len() < header_len + len {
+            buf.reserve(header_len + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(header_len);
+        Ok(Some(buf.split_to(len).to_vec()))
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for LengthCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, body: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(body.len() + 5);
+        self.prefix.write_frame(&mut frame, &body)?;
+        buf.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+/// CRLF lines over a tokio stream. Decoded lines come without their terminator, and a last
+/// line without one is still yielded at EOF; encoded lines get a CRLF added.
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct LineCodec {
+    max_len: usize,
+}
+
+#[cfg(feature = "backend-tokio")]
+impl LineCodec {
+    #[inline]
+    pub fn new() -> Self {
+        Self { max_len: usize::MAX }
+    }
+
+    /// Lines, terminator included, may be at most this long either way.
+    #[inline]
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    fn take_line(buf: &mut BytesMut, len: usize) -> io::Result<String> {
+        let line = buf.split_to(len);
+        let line = std::str::from_utf8(&line).map_err(|_| invalid_data("line is not utf-8"))?;
+        Ok(trim_line(line).to_string())
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Default for LineCodec {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        match find_line(&buf[..], self.max_len)? {
+            Some(len) => Self::take_line(buf, len).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                let len = buf.len();
+                Self::take_line(buf, len).map(Some)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for LineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> io::Result<()> {
+        let mut out = Vec::with_capacity(line.len() + 2);
+        write_line(&mut out, &line, self.max_len)?;
+        buf.extend_from_slice(&out);
+        Ok(())
+    }
+}
+
+/// Records of one fixed size over a tokio stream.
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct RecordCodec {
+    len: usize,
+}
+
+#[cfg(feature = "backend-tokio")]
+impl RecordCodec {
+    #[inline]
+    pub fn new(len: usize) -> Self {
+        Self { len }
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for RecordCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        if buf.len() < self.len {
+            return Ok(None);
+        }
+        Ok(Some(buf.split_to(self.len).to_vec()))
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for RecordCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, record: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        if record.len() != self.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record is not the codec's size"));
+        }
+        buf.extend_from_slice(&record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefixes() -> io::Result<()> {
+        for &(prefix, header_len) in &[(Prefix::U16, 2), (Prefix::U32, 4), (Prefix::VarInt, 1)] {
+            let mut out = Vec::new();
+            prefix.write_frame(&mut out, b"laji")?;
+            prefix.write_frame(&mut out, &[7u8; 300])?;
+            assert_eq!(prefix.parse(&out, 16)?, Some((header_len, 4)));
+            assert_eq!(prefix.parse(&out[..header_len - 1], 16)?, None);
+            let mut reader = &out[..];
+            assert_eq!(prefix.read_frame(&mut reader, 512)?, b"laji");
+            assert_eq!(prefix.read_frame(&mut reader, 512)?, vec![7u8; 300]);
+            assert!(prefix.read_frame(&mut &out[..], 3).is_err());
+        }
+        assert_eq!(Prefix::U16.max_len(), 65535);
+        assert!(Prefix::U16.write_frame(&mut Vec::new(), &vec![0u8; 1 << 16]).is_err());
+        assert!(Prefix::VarInt.parse(&[0xff; 5], usize::MAX).is_err());
+        assert!(Prefix::VarInt.parse(&[0xff, 0xff, 0xff, 0xff, 0x0f], usize::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn varints() -> io::Result<()> {
+        let cases: &[(i32, &[u8])] = &[
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (25565, &[0xdd, 0xc7, 0x01]),
+            (i32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x07]),
+            (-1, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+        for &(value, bytes) in cases {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(out, bytes);
+            assert_eq!(read_varint(&mut &bytes[..])?, value);
+            assert_eq!(parse_varint(bytes)?, Some((value, bytes.len())));
+        }
+        assert!(read_varint(&mut &[0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..]).is_err());
+        assert_eq!(parse_varint(&[0x80])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn lines() -> io::Result<()> {
+        let mut reader = io::BufReader::with_capacity(4, &b"PING :one\r\nlast"[..]);
+        let mut line = String::new();
+        assert_eq!(read_line(&mut reader, &mut line, 16)?, 11);
+        assert_eq!(trim_line(&line), "PING :one");
+        line.clear();
+        assert_eq!(read_line(&mut reader, &mut line, 16)?, 4);
+        assert_eq!(line, "last");
+        assert_eq!(read_line(&mut reader, &mut line, 16)?, 0);
+        assert!(read_line(&mut &b"far too long a line\n"[..], &mut String::new(), 8).is_err());
+
+        assert_eq!(find_line(b"one\r\ntwo", 8)?, Some(5));
+        assert_eq!(find_line(b"one", 8)?, None);
+        assert!(find_line(b"no newline here", 8).is_err());
+
+        let mut out = Vec::new();
+        write_line(&mut out, "JOIN #laji", 12)?;
+        assert_eq!(out, b"JOIN #laji\r\n");
+        assert!(write_line(&mut out, "JOIN #laji!", 12).is_err());
+        assert_eq!(write_line(&mut out, "a\nb", 12).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn line_reader() -> io::Result<()> {
+        let input = &b"GET / HTTP/1.0\r\nHost: x\n\r\n0123456789abcdef\r\n0123456789\r\nlast\r"[..];
+        let mut reader = LineReader::new(io::BufReader::with_capacity(4, input)).max_len(10);
+        assert!(matches!(reader.read_line(), Err(LineError::TooLong(10))));
+        assert_eq!(reader.read_line()?, Some("Host: x".to_string()));
+        assert_eq!(reader.read_line()?, Some(String::new()));
+        let err = reader.read_line().unwrap_err();
+        assert_eq!(err.to_string(), "line longer than 10 bytes");
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(reader.read_line()?, Some("0123456789".to_string()));
+        assert_eq!(reader.read_line()?, Some("last".to_string()));
+        assert_eq!(reader.read_line()?, None);
+
+        let mut reader = LineReader::new(&[0xff, b'\n', b'a'][..]);
+        assert!(matches!(reader.read_line(), Err(LineError::NotUtf8)));
+        assert_eq!(reader.read_line()?, Some("a".to_string()));
+        let mut reader = LineReader::new(&b"endless"[..]).max_len(3);
+        assert!(reader.read_line().is_err());
+        assert_eq!(reader.read_line()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn records() -> io::Result<()> {
+        let mut reader = &[1u8, 2, 3, 4, 5][..];
+        let mut record = [0u8; 2];
+        assert!(read_record(&mut reader, &mut record)?);
+        assert_eq!(record, [1, 2]);
+        assert!(read_record(&mut reader, &mut record)?);
+        assert_eq!(read_record(&mut reader, &mut record).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!read_record(&mut reader, &mut record)?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "backend-tokio")]
+    fn codecs() -> io::Result<()> {
+        let mut codec = LengthCodec::new(Prefix::VarInt);
+        let mut buf = BytesMut::new();
+        codec.encode(b"laji".to_vec(), &mut buf)?;
+        let frame = buf.clone();
+        buf.truncate(3);
+        assert_eq!(codec.decode(&mut buf)?, None);
+        buf.extend_from_slice(&frame[3..]);
+        assert_eq!(codec.decode(&mut buf)?, Some(b"laji".to_vec()));
+        assert!(buf.is_empty());
+        assert!(codec.max_len(2).decode(&mut frame.clone()).is_err());
+
+        let mut lines = LineCodec::new().max_len(16);
+        let mut buf = BytesMut::from(&b"one\r\ntwo"[..]);
+        assert_eq!(lines.decode(&mut buf)?, Some("one".to_string()));
+        assert_eq!(lines.decode(&mut buf)?, None);
+        assert_eq!(lines.decode_eof(&mut buf)?, Some("two".to_string()));
+        lines.encode("three".to_string(), &mut buf)?;
+        assert_eq!(&buf[..], b"three\r\n");
+        assert!(lines.decode(&mut BytesMut::from(&[b'x'; 20][..])).is_err());
+
+        let mut records = RecordCodec::new(3);
+        let mut buf = BytesMut::from(&[1u8, 2, 3, 4][..]);
+        assert_eq!(records.decode(&mut buf)?, Some(vec![1, 2, 3]));
+        assert_eq!(records.decode(&mut buf)?, None);
+        assert!(records.encode(vec![1], &mut buf).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! Gopher client (RFC 1436).
+//!
+//! `Client::menu` fetches a directory and parses it one line at a time as the `Menu` is
+//! iterated. Each `Item` remembers where it points, so following a link is `item.menu()` or
+//! `item.fetch()`; nothing is fetched until asked for.
+use std::{
+    io::{self, Read},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+    vec,
+};
+use crate::{framing, resolve, socks5::{self, Proxy}, wire::invalid_data};
+
+pub const DEFAULT_PORT: u16 = crate::ports::GOPHER;
+
+// far beyond any menu, but stops a server streaming forever
+const MAX_RESPONSE_LEN: u64 = 16 << 20;
+// a selector, and a search query after it, with the CRLF
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Fetch `selector` with the default `Client` settings.
+pub fn fetch(host: &str, port: u16, selector: &str) -> io::Result<Vec<u8>> {
+    Client::new().fetch(host, port, selector)
+}
+
+/// Fetch `selector` as a menu with the default `Client` settings.
+pub fn menu(host: &str, port: u16, selector: &str) -> io::Result<Menu> {
+    Client::new().menu(host, port, selector)
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Client {
+    timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Client {
+    #[inline]
+    pub fn new() -> Self {
+        Self { timeout: Duration::from_secs(10), proxy: None }
+    }
+
+    /// How long connecting, and then each read, may take.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connect through a SOCKS5 proxy, which also resolves the host names.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Everything the server sends for `selector`, as it was sent.
+    pub fn fetch(&self, host: &str, port: u16, selector: &str) -> io::Result<Vec<u8>> {
+        self.request(socks5::connect(self.proxy.as_ref(), host, port, self.timeout)?, selector)
+    }
+
+    pub fn fetch_addr(&self, addr: SocketAddr, selector: &str) -> io::Result<Vec<u8>> {
+        self.request(socks5::connect_to(self.proxy.as_ref(), addr, self.timeout)?, selector)
+    }
+
+    pub fn menu(&self, host: &str, port: u16, selector: &str) -> io::Result<Menu> {
+        Ok(Menu::parse(&self.fetch(host, port, selector)?, self.clone()))
+    }
+
+    pub fn menu_addr(&self, addr: SocketAddr, selector: &str) -> io::Result<Menu> {
+        Ok(Menu::parse(&self.fetch_addr(addr, selector)?, self.clone()))
+    }
+
+    /// Run a full-text search: the server answers with a menu of what matched `query`.
+    pub fn search(&self, host: &str, port: u16, selector: &str, query: &str) -> io::Result<Menu> {
+        self.menu(host, port, &format!("{}\t{}", selector, query))
+    }
+
+    fn request(&self, mut stream: TcpStream, selector: &str) -> io::Result<Vec<u8>> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        framing::write_line(&mut stream, selector, MAX_REQUEST_LEN)?;
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE_LEN).read_to_end(&mut response).map_err(resolve::timed_out)?;
+        Ok(response)
+    }
+}
+
+impl Default for Client {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The type character that starts every menu line.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum ItemType {
+    Text,
+    Menu,
+    CsoPhoneBook,
+    Error,
+    BinHex,
+    Dos,
+    Uuencoded,
+    Search,
+    Telnet,
+    Binary,
+    Mirror,
+    Gif,
+    Image,
+    Tn3270,
+    /// `i`, text shown in the menu that links nowhere.
+    Info,
+    /// `h`, usually a `URL:` selector.
+    Html,
+    Other(char),
+}
+
+impl ItemType {
+    pub fn from_char(c: char) -> Self {
+        match c {
+            '0' => ItemType::Text,
+            '1' => ItemType::Menu,
+            '2' => ItemType::CsoPhoneBook,
+            '3' => ItemType::Error,
+            '4' => ItemType::BinHex,
+            '5' => ItemType::Dos,
+            '6' => ItemType::Uuencoded,
+            '7' => ItemType::Search,
+            '8' => ItemType::Telnet,
+            '9' => ItemType::Binary,
+            '+' => ItemType::Mirror,
+            'g' => ItemType::Gif,
+            'I' => ItemType::Image,
+            'T' => ItemType::Tn3270,
+            'i' => ItemType::Info,
+            'h' => ItemType::Html,
+            other => ItemType::Other(other),
+        }
+    }
+}
+
+/// One line of a menu.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Item {
+    kind: ItemType,
+    display: String,
+    selector: String,
+    host: String,
+    port: u16,
+    client: Client,
+}
+
+impl Item {
+    #[inline]
+    pub fn kind(&self) -> ItemType {
+        self.kind
+    }
+
+    /// What the menu shows for this item.
+    #[inline]
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    #[inline]
+    pub fn selector(&self) -> &str {
+        &self.selector
+    }
+
+    #[inline]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    #[inline]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Fetch what this item points to, with the client that fetched its menu.
+    pub fn fetch(&self) -> io::Result<Vec<u8>> {
+        self.client.fetch(&self.host, self.port, &self.selector)
+    }
+
+    /// Fetch what this item points to as a menu; meant for `ItemType::Menu` items.
+    pub fn menu(&self) -> io::Result<Menu> {
+        self.client.menu(&self.host, self.port, &self.selector)
+    }
+
+    /// Search with this `ItemType::Search` item.
+    pub fn search(&self, query: &str) -> io::Result<Menu> {
+        self.client.search(&self.host, self.port, &self.selector, query)
+    }
+
+    fn parse(line: &str, client: Client) -> io::Result<Self> {
+        let mut chars = line.chars();
+        let kind = chars.next().map(ItemType::from_char).ok_or_else(|| invalid_data("empty menu line"))?;
+        let mut fields = chars.as_str().split('\t');
+        let (display, selector, host, port) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(display), Some(selector), Some(host), Some(port)) => (display, selector, host, port),
+            _ => return Err(invalid_data("menu line has fewer than four fields")),
+        };
+        let port = port.trim().parse().map_err(|_| invalid_data("bad port in menu line"))?;
+        Ok(Item {
+            kind,
+            display: display.to_string(),
+            selector: selector.to_string(),
+            host: host.to_string(),
+            port,
+            client,
+        })
+    }
+}
+
+/// The items of a fetched menu, parsed as they are iterated; a malformed line is an error
+/// in its place, and iteration can go on past it.
+#[derive(Debug)]
+pub struct Menu {
+    lines: vec::IntoIter<String>,
+    client: Client,
+}
+
+impl Menu {
+    /// Menus are not always ASCII, so bytes that are not UTF-8 are replaced rather than
+    /// failing the whole menu.
+    fn parse(body: &[u8], client: Client) -> Self {
+        let body = String::from_utf8_lossy(body);
+        let lines: Vec<String> = body.lines()
+            .map(framing::trim_line)
+            .take_while(|&line| line != ".")
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self { lines: lines.into_iter(), client }
+    }
+}
+
+impl Iterator for Menu {
+    type Item = io::Result<Item>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let client = &self.client;
+        self.lines.next().map(|line| Item::parse(&line, client.clone()))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.lines.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, Write}, net::TcpListener, thread};
+
+    #[test]
+    fn item_lines() {
+        let item = Item::parse("1Phlog\t/phlog\tgopher.example.org\t70", Client::new()).unwrap();
+        assert_eq!((item.kind(), item.display(), item.selector()), (ItemType::Menu, "Phlog", "/phlog"));
+        assert_eq!((item.host(), item.port()), ("gopher.example.org", 70));
+        assert_eq!(ItemType::from_char('Z'), ItemType::Other('Z'));
+        assert!(Item::parse("0no tabs", Client::new()).is_err());
+        assert!(Item::parse("0a\tb\tc\tseventy", Client::new()).is_err());
+    }
+
+    #[test]
+    fn menu_loopback() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> io::Result<Vec<String>> {
+            let mut selectors = Vec::new();
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream?;
+                let mut selector = String::new();
+                io::BufReader::new(&stream).read_line(&mut selector)?;
+                let body = match selector.trim_end() {
+                    "" => format!("iWelcome\t\terror.host\t1\r\n\
+                        0About\t/about.txt\t127.0.0.1\t{}\r\nbroken line\r\n.\r\n", addr.port()),
+                    _ => "laji\r\n".to_string(),
+                };
+                selectors.push(selector.trim_end().to_string());
+                stream.write_all(body.as_bytes())?;
+            }
+            Ok(selectors)
+        });
+        let client = Client::new().timeout(Duration::from_secs(2));
+        let items: Vec<_> = client.menu_addr(addr, "")?.collect();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap().kind(), ItemType::Info);
+        assert!(items[2].is_err());
+        let about = items[1].as_ref().unwrap();
+        assert_eq!(about.fetch()?, b"laji\r\n");
+        assert_eq!(server.join().unwrap()?, ["", "/about.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn through_socks5() -> io::Result<()> {
+        let (proxy, handle) = crate::socks5::tests::fake_proxy(None, |stream| {
+            let mut selector = String::new();
+            io::BufReader::new(&*stream).read_line(&mut selector)?;
+            stream.write_all(format!("0{}\t/x\tfar.example\t70\r\n", selector.trim_end()).as_bytes())
+        })?;
+        let items: Vec<_> = Client::new().proxy(proxy).menu("far.example", 70, "/hidden")?.collect();
+        assert_eq!(items[0].as_ref().unwrap().display(), "/hidden");
+        assert_eq!(handle.join().unwrap()?, b"\x03\x0bfar.example\x00\x46");
+        Ok(())
+    }
+
+    #[test]
+    fn silent_server_times_out() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || -> io::Result<()> {
+            let (_held, _) = listener.accept()?;
+            thread::sleep(Duration::from_secs(2));
+            Ok(())
+        });
+        let err = Client::new().timeout(Duration::from_millis(100)).fetch_addr(addr, "").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+use crate::{server::ServerHandle, stun, virtnet::Datagram};
+
+// Every datagram is one line of text: "LAJI <verb> <argument>".
+const REGISTER: &str = "REGISTER";
+const PEER: &str = "PEER";
+const PUNCH: &str = "PUNCH";
+const PUNCHED: &str = "PUNCHED";
+
+const MAX_DATAGRAM_LEN: usize = 512;
+const SESSION_TTL: Duration = Duration::from_secs(30);
+/// Sessions tracked at most; new ones are refused while this many are still current.
+const MAX_SESSIONS: usize = 4096;
+/// How often expired sessions are swept out.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+const PUNCHED_REPEAT: usize = 3;
+
+pub fn listen<A>(addr: A) -> io::Result<()>
+where
+    A: ToSocketAddrs
+{
+    Rendezvous::bind(addr)?.run()
+}
+
+#[inline]
+fn encode<'b>(buf: &'b mut [u8], verb: &str, argument: &str) -> io::Result<&'b [u8]> {
+    use std::io::Write;
+    let mut cursor = io::Cursor::new(&mut buf[..]);
+    write!(cursor, "LAJI {} {}", verb, argument)?;
+    let len = cursor.position() as usize;
+    Ok(&buf[..len])
+}
+
+#[inline]
+fn decode(buf: &[u8]) -> Option<(&str, &str)> {
+    let text = std::str::from_utf8(buf).ok()?;
+    let mut words = text.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("LAJI"), Some(verb), Some(argument), None) => Some((verb, argument)),
+        _ => None,
+    }
+}
+
+/// Pairs up two clients registering the same session name and tells each
+/// one the address the other was seen from. A paired session is kept for `SESSION_TTL`,
+/// answering either client again if it registers again because its answer was lost.
+#[derive(Debug)]
+pub struct Rendezvous<S = UdpSocket> {
+    socket: S,
+    sessions: HashMap<String, Session>,
+    next_sweep: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Session {
+    first: SocketAddr,
+    second: Option<SocketAddr>,
+    // when the first client registered, or when the second one paired the session up
+    since: Instant,
+}
+
+impl Session {
+    #[inline]
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.since) >= SESSION_TTL
+    }
+}
+
+impl Rendezvous {
+    #[inline]
+    pub fn bind<A>(addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        Ok(Self::with_socket(UdpSocket::bind(addr)?))
+    }
+
+    /// Run the server on its own thread, for demos that punch from the same process.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let local_addrs = vec![self.socket.local_addr()?];
+        ServerHandle::spawn("laji-rendezvous", local_addrs, move || self.run())
+    }
+}
+
+impl<S> Rendezvous<S>
+where
+    S: Datagram
+{
+    #[inline]
+    pub fn with_socket(socket: S) -> Self {
+        Self { socket, sessions: HashMap::new(), next_sweep: Instant::now() + SWEEP_INTERVAL }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Serve until receiving fails for good. Errors one datagram or one peer caused, such as
+    /// the ICMP unreachable some platforms report for an earlier send, are skipped.
+    pub fn run(mut self) -> io::Result<()> {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        loop {
+            let (len, origin) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if is_transient(e) => continue,
+                Err(e) => return Err(e),
+            };
+            let session = match decode(&buf[..len]) {
+                Some((REGISTER, session)) => session.to_string(),
+                _ => continue,
+            };
+            self.register(session, origin);
+        }
+    }
+
+    /// Pair `origin` up, telling both sides about each other, or tell it again about the
+    /// peer it was paired with; a side that cannot be sent to only misses its own answer.
+    fn register(&mut self, session: String, origin: SocketAddr) {
+        let now = Instant::now();
+        if now >= self.next_sweep {
+            self.sessions.retain(|_, session| !session.is_expired(now));
+            self.next_sweep = now + SWEEP_INTERVAL;
+        }
+        let current = self.sessions.get_mut(&session).filter(|session| !session.is_expired(now));
+        let answers = match current {
+            Some(Session { first, second: None, .. }) if *first == origin => return,
+            Some(current @ Session { second: None, .. }) => {
+                current.second = Some(origin);
+                current.since = now;
+                vec![(origin, current.first), (current.first, origin)]
+            }
+            Some(&mut Session { first, second: Some(second), .. }) => {
+                if origin == first {
+                    vec![(origin, second)]
+                } else if origin == second {
+                    vec![(origin, first)]
+                } else {
+                    // taken until it expires
+                    return;
+                }
+            }
+            None => {
+                if self.sessions.len() >= MAX_SESSIONS && !self.sessions.contains_key(&session) {
+                    return;
+                }
+                self.sessions.insert(session, Session { first: origin, second: None, since: now });
+                return;
+            }
+        };
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        for (to, about) in answers {
+            if let Ok(answer) = encode(&mut buf, PEER, &about.to_string()) {
+                let _ = self.socket.send_to(answer, to);
+            }
+        }
+    }
+}
+
+#[inline]
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+pub trait Handler {
+    fn on_reflexive(&mut self, _addr: SocketAddr) {}
+
+    fn on_peer(&mut self, _peer: SocketAddr) {}
+
+    fn on_probe(&mut self, _peer: SocketAddr, _attempt: usize) {}
+
+    fn on_verified(&mut self, _peer: SocketAddr) {}
+}
+
+impl Handler for () {}
+
+/// Client side: learns the peer's address from a rendezvous server, then sends
+/// probes to it until a probe or an answer comes back over the same path.
+#[derive(Debug)]
+pub struct Puncher<'a, S = UdpSocket> {
+    socket: &'a S,
+    stun_server: Option<SocketAddr>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl<'a, S> Puncher<'a, S>
+where
+    S: Datagram
+{
+    #[inline]
+    pub fn new(socket: &'a S) -> Self {
+        Self {
+            socket,
+            stun_server: None,
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Query this STUN server for the socket's reflexive address before registering.
+    #[inline]
+    pub fn stun<A>(mut self, server: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.stun_server = server.to_socket_addrs()?.next();
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn punch<A, H>(&self, rendezvous: A, session: &str, handler: &mut H) -> io::Result<SocketAddr>
+    where
+        A: ToSocketAddrs,
+        H: Handler
+    {
+        let rendezvous = rendezvous.to_socket_addrs()?.next().ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+        if let Some(stun_server) = self.stun_server {
+            handler.on_reflexive(stun::query(self.socket, stun_server)?);
+        }
+        let old_timeout = self.socket.read_timeout()?;
+        let deadline = Instant::now() + self.timeout;
+        let ans = self.find_peer(rendezvous, session, deadline)
+            .and_then(|peer| {
+                handler.on_peer(peer);
+                self.probe(peer, session, deadline, handler)
+            });
+        self.socket.set_read_timeout(old_timeout)?;
+        ans
+    }
+
+    fn find_peer(&self, rendezvous: SocketAddr, session: &str, deadline: Instant) -> io::Result<SocketAddr> {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        loop {
+            self.socket.send_to(encode(&mut buf, REGISTER, session)?, rendezvous)?;
+            let (len, from) = match self.recv_until(&mut buf, deadline)? {
+                Some(ans) => ans,
+                None => continue,
+            };
+            if from != rendezvous {
+                continue;
+            }
+            if let Some((PEER, peer)) = decode(&buf[..len]) {
+                return peer.parse().map_err(|_|
+                    io::Error::new(io::ErrorKind::InvalidData, "rendezvous sent a bad peer address"));
+            }
+        }
+    }
+
+    fn probe<H>(&self, peer: SocketAddr, session: &str, deadline: Instant, handler: &mut H) -> io::Result<SocketAddr>
+    where
+        H: Handler
+    {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.socket.send_to(encode(&mut buf, PUNCH, session)?, peer)?;
+            handler.on_probe(peer, attempt);
+            let (len, from) = match self.recv_until(&mut buf, deadline)? {
+                Some(ans) => ans,
+                None => continue,
+            };
+            if from != peer {
+                continue;
+            }
+            match decode(&buf[..len]) {
+                Some((PUNCH, s)) if s == session => {
+                    // the peer may stop listening once it hears from us, so say it a few times
+                    for _ in 0..PUNCHED_REPEAT {
+                        self.socket.send_to(encode(&mut buf, PUNCHED, session)?, peer)?;
+                    }
+                }
+                Some((PUNCHED, s)) if s == session => {}
+                _ => continue,
+            }
+            handler.on_verified(peer);
+            return Ok(peer);
+        }
+    }
+
+    /// Wait for one datagram for at most one interval; `None` when the interval passed.
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<(usize, SocketAddr)>> {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "hole punching timed out"));
+        }
+        let wait = std::cmp::min(self.interval, deadline - now);
+        self.socket.set_read_timeout(Some(wait))?;
+        match self.socket.recv_from(buf) {
+            Ok(ans) => Ok(Some(ans)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn punch_loopback() {
+        let server = Rendezvous::bind("127.0.0.1:0").unwrap().spawn().unwrap().local_addrs()[0];
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let other = thread::spawn(move || {
+            Puncher::new(&b).punch(server, "laji", &mut ()).unwrap()
+        });
+        struct Progress(Vec<&'static str>);
+        impl Handler for Progress {
+            fn on_peer(&mut self, _peer: SocketAddr) { self.0.push("peer") }
+            fn on_verified(&mut self, _peer: SocketAddr) { self.0.push("verified") }
+        }
+        let mut progress = Progress(Vec::new());
+        assert_eq!(Puncher::new(&a).punch(server, "laji", &mut progress).unwrap(), b_addr);
+        assert_eq!(other.join().unwrap(), a_addr);
+        assert_eq!(progress.0, ["peer", "verified"]);
+    }
+
+    #[test]
+    fn rendezvous_survives_failures() -> io::Result<()> {
+        // fails its first receive, and every send to `dead`
+        struct Flaky { socket: UdpSocket, dead: SocketAddr, failed: std::cell::Cell<bool> }
+        impl Datagram for Flaky {
+            fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+                if target == self.dead {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unreachable"));
+                }
+                self.socket.send_to(buf, target)
+            }
+            fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+                if !self.failed.replace(true) {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset, "icmp unreachable"));
+                }
+                self.socket.recv_from(buf)
+            }
+            fn local_addr(&self) -> io::Result<SocketAddr> { self.socket.local_addr() }
+            fn read_timeout(&self) -> io::Result<Option<Duration>> { self.socket.read_timeout() }
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> { self.socket.set_read_timeout(timeout) }
+        }
+        let dead = UdpSocket::bind("127.0.0.1:0")?;
+        let flaky = Flaky { socket: UdpSocket::bind("127.0.0.1:0")?, dead: dead.local_addr()?, failed: Default::default() };
+        let server = flaky.local_addr()?;
+        thread::spawn(move || Rendezvous::with_socket(flaky).run().unwrap());
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        dead.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        let a = UdpSocket::bind("127.0.0.1:0")?;
+        let b = UdpSocket::bind("127.0.0.1:0")?;
+        a.set_read_timeout(Some(Duration::from_secs(2)))?;
+        thread::sleep(Duration::from_millis(50));
+        a.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        let (len, _) = a.recv_from(&mut buf)?;
+        assert_eq!(decode(&buf[..len]), Some((PEER, &*dead.local_addr()?.to_string())));
+        a.send_to(encode(&mut buf, REGISTER, "again")?, server)?;
+        thread::sleep(Duration::from_millis(50));
+        b.send_to(encode(&mut buf, REGISTER, "again")?, server)?;
+        let (len, _) = a.recv_from(&mut buf)?;
+        assert_eq!(decode(&buf[..len]), Some((PEER, &*b.local_addr()?.to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn paired_sessions_answer_again() -> io::Result<()> {
+        let server = Rendezvous::bind("127.0.0.1:0")?.spawn()?.local_addrs()[0];
+        let a = UdpSocket::bind("127.0.0.1:0")?;
+        let b = UdpSocket::bind("127.0.0.1:0")?;
+        let c = UdpSocket::bind("127.0.0.1:0")?;
+        for socket in &[&a, &b, &c] {
+            socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        }
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        a.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        thread::sleep(Duration::from_millis(50));
+        b.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        let b_addr = b.local_addr()?.to_string();
+        let (len, _) = a.recv_from(&mut buf)?;
+        assert_eq!(decode(&buf[..len]), Some((PEER, &*b_addr)));
+        // a's answer got lost, as far as a knows, so it registers again
+        a.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        let (len, _) = a.recv_from(&mut buf)?;
+        assert_eq!(decode(&buf[..len]), Some((PEER, &*b_addr)));
+        // a third client is not paired into the session
+        c.send_to(encode(&mut buf, REGISTER, "laji")?, server)?;
+        assert!(c.recv_from(&mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sessions_capped() -> io::Result<()> {
+        let mut rendezvous = Rendezvous::bind("127.0.0.1:0")?;
+        let origin: SocketAddr = "192.0.2.1:9".parse().unwrap();
+        for i in 0..=MAX_SESSIONS {
+            rendezvous.register(i.to_string(), origin);
+        }
+        assert_eq!(rendezvous.sessions.len(), MAX_SESSIONS);
+        assert!(!rendezvous.sessions.contains_key(&MAX_SESSIONS.to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_lines() {
+        assert_eq!(decode(b"LAJI PEER 127.0.0.1:9"), Some((PEER, "127.0.0.1:9")));
+        assert_eq!(decode(b"LAJI PEER"), None);
+        assert_eq!(decode(b"HELLO PEER x"), None);
+        assert_eq!(decode(&[0xff, 0xfe]), None);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, ToSocketAddrs},
+    process,
+    thread,
+    time::{Duration, Instant},
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use crate::{checksum::checksum, wire::invalid_data};
+
+/// Send `count` echo requests to `host`, one per `interval`, and report what came back.
+///
+/// Needs a raw socket, so usually root or CAP_NET_RAW. IPv4 only.
+pub fn ping<A, H>(host: A, count: u16, interval: Duration, handler: &mut H) -> io::Result<Report>
+where
+    A: ToSocketAddrs,
+    H: Handler
+{
+    let target = host.to_socket_addrs()?
+        .find_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no IPv4 address to ping"))?;
+    let mut pinger = Pinger::new()?;
+    let mut report = Report::default();
+    for sequence in 0..count {
+        if sequence != 0 {
+            thread::sleep(interval);
+        }
+        report.sent += 1;
+        match pinger.ping(target, sequence, DEFAULT_PAYLOAD) {
+            Ok(rtt) => {
+                report.rtts.push(rtt);
+                handler.on_reply(target, sequence, rtt);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => handler.on_timeout(target, sequence),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+const DEFAULT_PAYLOAD: &[u8] = b"laji-protocols ping laji-protocols ping ";
+const MAX_PACKET_LEN: usize = 1500;
+
+pub trait Handler {
+    fn on_reply(&mut self, _from: Ipv4Addr, _sequence: u16, _rtt: Duration) {}
+
+    fn on_timeout(&mut self, _target: Ipv4Addr, _sequence: u16) {}
+}
+
+impl Handler for () {}
+
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Report {
+    sent: usize,
+    rtts: Vec<Duration>,
+}
+
+impl Report {
+    #[inline]
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    #[inline]
+    pub fn received(&self) -> usize {
+        self.rtts.len()
+    }
+
+    #[inline]
+    pub fn rtts(&self) -> &[Duration] {
+        &self.rtts
+    }
+
+    /// Fraction of requests that got no reply, from 0.0 to 1.0.
+    #[inline]
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.received() as f64 / self.sent as f64
+    }
+
+    #[inline]
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().cloned()
+    }
+
+    #[inline]
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().cloned()
+    }
+
+    #[inline]
+    pub fn avg(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+}
+
+/// A raw ICMPv4 socket that sends echo requests with its own identifier.
+#[derive(Debug)]
+pub struct Pinger {
+    socket: Socket,
+    identifier: u16,
+    timeout: Duration,
+}
+
+impl Pinger {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            socket: raw_socket()?,
+            identifier: process::id() as u16,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[inline]
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Send one echo request and wait for its reply, returning the round-trip time.
+    pub fn ping(&mut self, target: Ipv4Addr, sequence: u16, payload: &[u8]) -> io::Result<Duration> {
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let request = Message::EchoRequest { identifier: self.identifier, sequence, payload };
+        let len = request.encode(&mut buf)?;
+        let start = Instant::now();
+        self.socket.send_to(&buf[..len], &SockAddr::from(SocketAddrV4::new(target, 0)))?;
+        let deadline = start + self.timeout;
+        let own = self.identifier;
+        let reply = recv_until(&self.socket, &mut buf, deadline, |from, message| match *message {
+            Message::EchoReply { identifier, sequence: s, .. }
+                if from == target && identifier == own && s == sequence => Some(start.elapsed()),
+            _ => None,
+        })?;
+        reply.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no echo reply"))
+    }
+}
+
+#[inline]
+pub(crate) fn raw_socket() -> io::Result<Socket> {
+    Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4()))
+}
+
+/// Receive ICMP messages on a raw socket until `answer` picks one, by its source and what
+/// it parsed as, or `deadline` passes.
+pub(crate) fn recv_until<T, F>(socket: &Socket, buf: &mut [u8], deadline: Instant, mut answer: F)
+    -> io::Result<Option<T>>
+where F: FnMut(Ipv4Addr, &Message) -> Option<T>
+{
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(buf) {
+            Ok(ans) => ans,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let from = match from.as_inet() {
+            Some(addr) => *addr.ip(),
+            None => continue,
+        };
+        if let Some(Ok(message)) = strip_ipv4_header(&buf[..len]).map(Message::decode) {
+            if let Some(ans) = answer(from, &message) {
+                return Ok(Some(ans));
+            }
+        }
+    }
+}
+
+/// Raw IPv4 sockets hand back the IP header in front of the ICMP message.
+#[inline]
+pub(crate) fn strip_ipv4_header(packet: &[u8]) -> Option<&[u8]> {
+    let header_len = (*packet.first()? & 0x0f) as usize * 4;
+    if header_len < 20 {
+        return None;
+    }
+    packet.get(header_len..)
+}
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+
+/// The destination unreachable code a host sends for a UDP port nothing listens on.
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Message<'a> {
+    EchoReply { identifier: u16, sequence: u16, payload: &'a [u8] },
+    EchoRequest { identifier: u16, sequence: u16, payload: &'a [u8] },
+    /// `original` is the IP header and leading bytes of the datagram that triggered it.
+    DestinationUnreachable { code: u8, original: &'a [u8] },
+    TimeExceeded { code: u8, original: &'a [u8] },
+    Other { icmp_type: u8, code: u8 },
+}
+
+impl<'a> Message<'a> {
+    pub fn decode(buf: &'a [u8]) -> io::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            return Err(invalid_data("icmp message too short"));
+        }
+        if checksum(buf) != 0 {
+            return Err(invalid_data("icmp checksum mismatch"));
+        }
+        let (icmp_type, code) = (buf[0], buf[1]);
+        let identifier = u16::from_be_bytes([buf[4], buf[5]]);
+        let sequence = u16::from_be_bytes([buf[6], buf[7]]);
+        let rest = &buf[HEADER_LEN..];
+        Ok(match icmp_type {
+            TYPE_ECHO_REPLY => Message::EchoReply { identifier, sequence, payload: rest },
+            TYPE_ECHO_REQUEST => Message::EchoRequest { identifier, sequence, payload: rest },
+            TYPE_DESTINATION_UNREACHABLE => Message::DestinationUnreachable { code, original: rest },
+            TYPE_TIME_EXCEEDED => Message::TimeExceeded { code, original: rest },
+            icmp_type => Message::Other { icmp_type, code },
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (icmp_type, code, rest_of_header, body) = match *self {
+            Message::EchoReply { identifier, sequence, payload } =>
+                (TYPE_ECHO_REPLY, 0, echo_header(identifier, sequence), payload),
+            Message::EchoRequest { identifier, sequence, payload } =>
+                (TYPE_ECHO_REQUEST, 0, echo_header(identifier, sequence), payload),
+            Message::DestinationUnreachable { code, original } =>
+                (TYPE_DESTINATION_UNREACHABLE, code, [0; 4], original),
+            Message::TimeExceeded { code, original } =>
+                (TYPE_TIME_EXCEEDED, code, [0; 4], original),
+            Message::Other { icmp_type, code } => (icmp_type, code, [0; 4], &[][..]),
+        };
+        let len = HEADER_LEN + body.len();
+        if buf.len() < len {
+            return Err(invalid_data("buffer too small for icmp message"));
+        }
+        buf[0] = icmp_type;
+        buf[1] = code;
+        buf[2..4].copy_from_slice(&[0, 0]);
+        buf[4..8].copy_from_slice(&rest_of_header);
+        buf[8..len].copy_from_slice(body);
+        let sum = checksum(&buf[..len]);
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+        Ok(len)
+    }
+}
+
+#[inline]
+fn echo_header(identifier: u16, sequence: u16) -> [u8; 4] {
+    let (id, seq) = (identifier.to_be_bytes(), sequence.to_be_bytes());
+    [id[0], id[1], seq[0], seq[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_round_trip() {
+        let request = Message::EchoRequest { identifier: 0x1234, sequence: 7, payload: b"laji" };
+        let mut buf = [0u8; 64];
+        let len = request.encode(&mut buf).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(Message::decode(&buf[..len]).unwrap(), request);
+        buf[9] ^= 0xff;
+        assert!(Message::decode(&buf[..len]).is_err());
+    }
+
+    #[test]
+    fn decode_behind_ip_header() {
+        // what a raw socket hands back: IPv4 header, then an echo reply for id 1, seq 0
+        let packet = [
+            0x45, 0x00, 0x00, 0x1c, 0x2a, 0x4f, 0x00, 0x00, 0x40, 0x01, 0x52, 0x90,
+            0x7f, 0x00, 0x00, 0x01, 0x7f, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0xff, 0xfe, 0x00, 0x01, 0x00, 0x00,
+        ];
+        let icmp = strip_ipv4_header(&packet).unwrap();
+        assert_eq!(Message::decode(icmp).unwrap(),
+            Message::EchoReply { identifier: 1, sequence: 0, payload: &[] });
+    }
+
+    #[test]
+    fn loopback_echo_reply_fixture() {
+        let packet = crate::fixture::load("icmp/echo-reply-loopback.hex");
+        let icmp = strip_ipv4_header(&packet).unwrap();
+        let reply = Message::decode(icmp).unwrap();
+        assert_eq!(reply, Message::EchoReply { identifier: 0x4c41, sequence: 1, payload: b"laji-protocols" });
+        crate::fixture::assert_encodes(icmp, |buf| reply.encode(buf));
+    }
+
+    #[test]
+    fn report_stats() {
+        let report = Report {
+            sent: 4,
+            rtts: vec![Duration::from_millis(10), Duration::from_millis(30)],
+        };
+        assert_eq!(report.loss(), 0.5);
+        assert_eq!(report.min(), Some(Duration::from_millis(10)));
+        assert_eq!(report.avg(), Some(Duration::from_millis(20)));
+        assert_eq!(report.max(), Some(Duration::from_millis(30)));
+    }
+}
@@ -0,0 +1,140 @@
+//! Accepting on a Windows I/O completion port with AcceptEx, the way IIS and friends do.
+//!
+//! Each listener keeps a number of accepts posted ahead of time, each with a fresh socket
+//! for the kernel to hand the next connection to, so a burst of connections is taken in
+//! without waiting on a thread or a readiness round trip per stream. The servers built on
+//! this share the port for their streams' overlapped I/O, telling listeners from streams by
+//! completion key.
+use std::{io, mem, net::{SocketAddr, TcpListener, TcpStream}, time::{Duration, Instant}};
+use miow::{
+    Overlapped,
+    iocp::{CompletionPort, CompletionStatus},
+    net::{AcceptAddrsBuf, TcpListenerExt},
+};
+use net2::TcpBuilder;
+
+/// What `GetQueuedCompletionStatusEx` fails with when the timeout is up.
+const WAIT_TIMEOUT: i32 = 258;
+
+/// How long a server stopping waits for its cancelled operations to come back before it
+/// leaks their buffers instead of freeing memory the kernel may still write to.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) const STATUS_CAPACITY: usize = 1024;
+
+/// Wait for completions, none of them if `timeout` runs out first.
+pub(crate) fn wait<'a>(port: &CompletionPort, statuses: &'a mut [CompletionStatus], timeout: Option<Duration>)
+    -> io::Result<&'a mut [CompletionStatus]>
+{
+    match port.get_many(statuses, timeout) {
+        Ok(done) => Ok(done),
+        Err(ref e) if e.raw_os_error() == Some(WAIT_TIMEOUT) || e.kind() == io::ErrorKind::TimedOut =>
+            Ok(&mut []),
+        Err(e) => Err(e),
+    }
+}
+
+/// Take `pending` completions of operations whose sockets were closed, and say how many
+/// never came back in time.
+fn drain(port: &CompletionPort, mut pending: usize) -> usize {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let mut statuses = vec![CompletionStatus::zero(); STATUS_CAPACITY];
+    while pending > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match wait(port, &mut statuses, Some(deadline - now)) {
+            Ok(done) => pending = pending.saturating_sub(done.len()),
+            Err(_) => break,
+        }
+    }
+    pending
+}
+
+struct Accept {
+    overlapped: Overlapped,
+    addrs: AcceptAddrsBuf,
+    stream: Option<TcpStream>,
+}
+
+/// A listener and its posted accepts.
+pub(crate) struct Acceptor {
+    listener: TcpListener,
+    v6: bool,
+    // allocated once, so the kernel's pointers into it stay put while the acceptor moves
+    accepts: Box<[Accept]>,
+}
+
+impl Acceptor {
+    /// Attach `listener` to `port` under `token` and post `backlog` accepts on it.
+    pub(crate) fn new(listener: TcpListener, port: &CompletionPort, token: usize, backlog: usize) -> io::Result<Self> {
+        port.add_socket(token, &listener)?;
+        let v6 = listener.local_addr()?.is_ipv6();
+        let accepts = (0..backlog)
+            .map(|_| Accept { overlapped: Overlapped::zero(), addrs: AcceptAddrsBuf::new(), stream: None })
+            .collect();
+        let mut acceptor = Acceptor { listener, v6, accepts };
+        for slot in 0..backlog {
+            acceptor.post(slot)?;
+        }
+        Ok(acceptor)
+    }
+
+    fn post(&mut self, slot: usize) -> io::Result<()> {
+        let socket = if self.v6 { TcpBuilder::new_v6()? } else { TcpBuilder::new_v4()? };
+        let accept = &mut self.accepts[slot];
+        accept.overlapped = Overlapped::zero();
+        // completes through the port even when it succeeds at once
+        let (stream, _) = unsafe {
+            self.listener.accept_overlapped(&socket, &mut accept.addrs, accept.overlapped.raw())?
+        };
+        accept.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Finish the accept `status` reports and post another in its place. A connection the
+    /// peer dropped before it was accepted gives `None`.
+    pub(crate) fn complete(&mut self, status: &CompletionStatus) -> io::Result<Option<(TcpStream, SocketAddr, SocketAddr)>> {
+        let slot = match self.accepts.iter().position(|accept| accept.overlapped.raw() == status.overlapped()) {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        let accept = &mut self.accepts[slot];
+        let stream = accept.stream.take();
+        let listener = &self.listener;
+        let finish = |stream: TcpStream| -> io::Result<(TcpStream, SocketAddr, SocketAddr)> {
+            unsafe { listener.result(accept.overlapped.raw())? };
+            listener.accept_complete(&stream)?;
+            let addrs = accept.addrs.parse(listener)?;
+            let peer_addr = match addrs.remote() {
+                Some(addr) => addr,
+                None => stream.peer_addr()?,
+            };
+            let local_addr = match addrs.local() {
+                Some(addr) => addr,
+                None => stream.local_addr()?,
+            };
+            Ok((stream, peer_addr, local_addr))
+        };
+        let accepted = stream.and_then(|stream| finish(stream).ok());
+        self.post(slot)?;
+        Ok(accepted)
+    }
+}
+
+/// Close `acceptors`, cancelling their accepts, and wait for those and `pending` other
+/// operations the caller cancelled to come back before dropping `buffers`, which the kernel
+/// may write to until then.
+pub(crate) fn shut_down<B>(port: &CompletionPort, acceptors: Vec<Acceptor>, mut pending: usize, buffers: B) {
+    let mut accepts = Vec::with_capacity(acceptors.len());
+    for Acceptor { listener, accepts: posted, .. } in acceptors {
+        drop(listener);
+        pending += posted.len();
+        accepts.push(posted);
+    }
+    if drain(port, pending) > 0 {
+        mem::forget(accepts);
+        mem::forget(buffers);
+    }
+}
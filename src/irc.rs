@@ -0,0 +1,453 @@
+use std::{
+    fmt,
+    io::{self, BufReader},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+#[cfg(feature = "backend-tokio")]
+use bytes::BytesMut;
+#[cfg(feature = "backend-tokio")]
+use tokio::codec::{Decoder, Encoder};
+use crate::{framing, reconnect::Ended, socks5::{self, Proxy}, wire::invalid_data};
+
+/// Connect with `nick` as nick, user name and real name, and run `handler` until the server closes.
+pub fn connect<A, H>(addr: A, nick: &str, handler: H) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    H: Handler
+{
+    Client::new(nick).run(addr, handler)
+}
+
+// RFC 1459: at most 512 bytes per line, CRLF included
+pub const MAX_LINE_LEN: usize = 512;
+
+// IRCv3 servers may put up to 8191 bytes of tags in front; such lines are skipped, and
+// reading them whole keeps the connection in step
+const MAX_READ_LEN: usize = 8191 + MAX_LINE_LEN;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const RPL_WELCOME: &str = "001";
+const ERR_NICKNAMEINUSE: &str = "433";
+
+/// One IRC line split into `[:prefix] COMMAND params... [:trailing]`.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Message {
+    prefix: Option<String>,
+    command: String,
+    params: Vec<String>,
+}
+
+impl Message {
+    pub fn new<I, S>(command: &str, params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>
+    {
+        Self {
+            prefix: None,
+            command: command.to_string(),
+            params: params.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// The nick part of a `nick!user@host` prefix.
+    #[inline]
+    pub fn nick(&self) -> Option<&str> {
+        self.prefix().and_then(|prefix| prefix.split('!').next())
+    }
+
+    #[inline]
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    #[inline]
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    /// Parse one line, with or without its CRLF.
+    pub fn parse(line: &str) -> io::Result<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.contains(['\r', '\n', '\0']) {
+            return Err(invalid_data("irc line has a line break or NUL inside"));
+        }
+        let prefix = if rest.starts_with(':') {
+            let end = rest.find(' ').ok_or_else(|| invalid_data("irc line has only a prefix"))?;
+            let prefix = rest[1..end].to_string();
+            rest = &rest[end + 1..];
+            Some(prefix)
+        } else {
+            None
+        };
+        let rest = rest.trim_start_matches(' ');
+        let (command, mut rest) = match rest.find(' ') {
+            Some(end) => (&rest[..end], &rest[end + 1..]),
+            None => (rest, ""),
+        };
+        if command.is_empty() || !command.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(invalid_data("irc line has no command"));
+        }
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            match rest.find(' ') {
+                Some(end) => {
+                    params.push(rest[..end].to_string());
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    params.push(rest.to_string());
+                    break;
+                }
+            }
+        }
+        Ok(Self { prefix, command: command.to_ascii_uppercase(), params })
+    }
+}
+
+/// Without the CRLF; the last parameter gets a ':' when it needs one.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        f.write_str(&self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes messages back to the server.
+#[derive(Debug)]
+pub struct Sender<'a> {
+    stream: &'a mut TcpStream,
+    nick: &'a str,
+    quit: bool,
+}
+
+impl<'a> Sender<'a> {
+    #[inline]
+    fn new(stream: &'a mut TcpStream, nick: &'a str) -> Self {
+        Self { stream, nick, quit: false }
+    }
+
+    /// The nick we are registered under, which may differ from the one asked for.
+    #[inline]
+    pub fn nick(&self) -> &str {
+        self.nick
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        framing::write_line(self.stream, &message.to_string(), MAX_LINE_LEN)
+    }
+
+    #[inline]
+    pub fn join(&mut self, channel: &str) -> io::Result<()> {
+        self.send(&Message::new("JOIN", vec![channel]))
+    }
+
+    #[inline]
+    pub fn privmsg(&mut self, target: &str, text: &str) -> io::Result<()> {
+        self.send(&Message::new("PRIVMSG", vec![target, text]))
+    }
+
+    /// Send QUIT and stop the client once this callback returns.
+    #[inline]
+    pub fn quit(&mut self, reason: &str) -> io::Result<()> {
+        self.quit = true;
+        self.send(&Message::new("QUIT", vec![reason]))
+    }
+}
+
+pub trait Handler {
+    fn on_registered(&mut self, _sender: &mut Sender) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_message(&mut self, sender: &mut Sender, message: &Message) -> io::Result<()>;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&mut Sender, &Message) -> io::Result<()>
+{
+    #[inline]
+    fn on_message(&mut self, sender: &mut Sender, message: &Message) -> io::Result<()> {
+        self(sender, message)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Client {
+    nick: String,
+    user: String,
+    realname: String,
+    password: Option<String>,
+    connect_timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Client {
+    #[inline]
+    pub fn new(nick: &str) -> Self {
+        Self {
+            nick: nick.to_string(),
+            user: nick.to_string(),
+            realname: nick.to_string(),
+            password: None,
+            connect_timeout: CONNECT_TIMEOUT,
+            proxy: None,
+        }
+    }
+
+    #[inline]
+    pub fn user(mut self, user: &str, realname: &str) -> Self {
+        self.user = user.to_string();
+        self.realname = realname.to_string();
+        self
+    }
+
+    #[inline]
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// How long connecting may take, 30 seconds by default. Once registered the client waits
+    /// for the server however long it is quiet; servers PING idle clients.
+    #[inline]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Connect through a SOCKS5 proxy.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Register, answer server PINGs, and hand every line to `handler`.
+    ///
+    /// A nick already in use gets an underscore appended and is tried again.
+    #[inline]
+    pub fn run<A, H>(&self, addr: A, mut handler: H) -> io::Result<()>
+    where
+        A: ToSocketAddrs,
+        H: Handler
+    {
+        self.session(addr, &mut handler).map(|_| ())
+    }
+
+    /// `run` with a borrowed handler, saying whether the server closed the connection or the
+    /// handler quit, for running under `reconnect::Reconnecting`:
+    ///
+    /// ```ignore
+    /// Reconnecting::new(|| client.session("irc.example.org:6667", &mut bot)).run()?;
+    /// ```
+    pub fn session<A, H>(&self, addr: A, handler: &mut H) -> io::Result<Ended>
+    where
+        A: ToSocketAddrs,
+        H: Handler
+    {
+        let mut stream = socks5::connect_to(self.proxy.as_ref(), addr, self.connect_timeout)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut nick = self.nick.clone();
+        {
+            let mut sender = Sender::new(&mut stream, &nick);
+            if let Some(password) = &self.password {
+                sender.send(&Message::new("PASS", vec![password.as_str()]))?;
+            }
+            sender.send(&Message::new("NICK", vec![nick.as_str()]))?;
+            sender.send(&Message::new("USER", vec![self.user.as_str(), "0", "*", self.realname.as_str()]))?;
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if framing::read_line(&mut reader, &mut line, MAX_READ_LEN)? == 0 {
+                return Ok(Ended::Closed);
+            }
+            let message = match Message::parse(&line) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            match message.command() {
+                "PING" => {
+                    let pong = Message::new("PONG", message.params().to_vec());
+                    Sender::new(&mut stream, &nick).send(&pong)?;
+                }
+                ERR_NICKNAMEINUSE => {
+                    nick.push('_');
+                    Sender::new(&mut stream, &nick).send(&Message::new("NICK", vec![nick.as_str()]))?;
+                }
+                "ERROR" => {
+                    let reason = message.params().last().cloned().unwrap_or_default();
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason));
+                }
+                _ => {}
+            }
+            let mut sender = Sender::new(&mut stream, &nick);
+            if message.command() == RPL_WELCOME {
+                handler.on_registered(&mut sender)?;
+            }
+            handler.on_message(&mut sender, &message)?;
+            if sender.quit {
+                return Ok(Ended::Done);
+            }
+        }
+    }
+}
+
+/// Line framing for IRC over a tokio stream. Lines longer than 512 bytes are an error.
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Codec;
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for Codec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Message>> {
+        loop {
+            let len = match framing::find_line(&buf[..], MAX_LINE_LEN)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let line = buf.split_to(len);
+            let line = std::str::from_utf8(&line).map_err(|_| invalid_data("irc line is not utf-8"))?;
+            // blank lines between messages are allowed and carry nothing
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Message::parse(line).map(Some);
+        }
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for Codec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, buf: &mut BytesMut) -> io::Result<()> {
+        let mut line = Vec::with_capacity(MAX_LINE_LEN);
+        framing::write_line(&mut line, &message.to_string(), MAX_LINE_LEN)?;
+        buf.extend_from_slice(&line);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, Write}, net::TcpListener, thread};
+
+    #[test]
+    fn parse_lines() -> io::Result<()> {
+        let message = Message::parse(":laji!~l@example.org PRIVMSG #rust :hello there\r\n")?;
+        assert_eq!(message.prefix(), Some("laji!~l@example.org"));
+        assert_eq!(message.nick(), Some("laji"));
+        assert_eq!(message.command(), "PRIVMSG");
+        assert_eq!(message.params(), &["#rust", "hello there"]);
+        assert_eq!(message.to_string(), ":laji!~l@example.org PRIVMSG #rust :hello there");
+        let message = Message::parse("ping irc.example.org")?;
+        assert_eq!((message.command(), message.params()), ("PING", &["irc.example.org".to_string()][..]));
+        assert_eq!(Message::parse(":srv 005 laji A B :are supported")?.params().len(), 4);
+        assert_eq!(Message::new("TOPIC", vec!["#rust", ""]).to_string(), "TOPIC #rust :");
+        assert!(Message::parse(":prefix-only").is_err());
+        assert!(Message::parse("").is_err());
+        assert!(Message::parse(" :not-a-command x").is_err());
+        assert!(Message::parse("PRIVMSG #a :one\r\nQUIT").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "backend-tokio")]
+    fn codec_lines() -> io::Result<()> {
+        let mut buf = BytesMut::from(&b"\r\nPING :one\r\n:srv NOTICE * :two"[..]);
+        assert_eq!(Codec.decode(&mut buf)?, Some(Message::new("PING", vec!["one"])));
+        assert_eq!(Codec.decode(&mut buf)?, None);
+        buf.extend_from_slice(b"\r\n");
+        assert_eq!(Codec.decode(&mut buf)?.unwrap().command(), "NOTICE");
+        let mut out = BytesMut::new();
+        Codec.encode(Message::new("JOIN", vec!["#laji"]), &mut out)?;
+        assert_eq!(&out[..], b"JOIN #laji\r\n");
+        let mut long = BytesMut::from(vec![b'a'; MAX_LINE_LEN + 1]);
+        assert!(Codec.decode(&mut long).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn client_loopback() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> io::Result<Vec<String>> {
+            let (mut stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut seen = Vec::new();
+            let mut expect = |reader: &mut BufReader<TcpStream>| -> io::Result<()> {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                seen.push(line.trim_end().to_string());
+                Ok(())
+            };
+            expect(&mut reader)?;
+            expect(&mut reader)?;
+            stream.write_all(b":srv 433 * laji :Nickname is already in use\r\n")?;
+            expect(&mut reader)?;
+            stream.write_all(b"PING :12345\r\n")?;
+            expect(&mut reader)?;
+            stream.write_all(b":srv 001 laji_ :Welcome\r\n")?;
+            expect(&mut reader)?;
+            stream.write_all(b":friend!f@h PRIVMSG laji_ :bye\r\n")?;
+            expect(&mut reader)?;
+            Ok(seen)
+        });
+        let mut said = Vec::new();
+        struct Bot<'v>(&'v mut Vec<String>);
+        impl Handler for Bot<'_> {
+            fn on_registered(&mut self, sender: &mut Sender) -> io::Result<()> {
+                sender.join("#laji")
+            }
+            fn on_message(&mut self, sender: &mut Sender, message: &Message) -> io::Result<()> {
+                if message.command() == "PRIVMSG" {
+                    self.0.push(format!("{} -> {}", message.nick().unwrap(), sender.nick()));
+                    sender.quit("done")?;
+                }
+                Ok(())
+            }
+        }
+        connect(addr, "laji", Bot(&mut said))?;
+        assert_eq!(said, ["friend -> laji_"]);
+        assert_eq!(server.join().unwrap()?, [
+            "NICK laji", "USER laji 0 * laji", "NICK laji_", "PONG 12345", "JOIN #laji", "QUIT done",
+        ]);
+        Ok(())
+    }
+}
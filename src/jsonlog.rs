@@ -0,0 +1,212 @@
+//! One JSON line per connection event, for feeding mio discard servers into log pipelines.
+//!
+//! ```text
+//! {"time":"2019-03-02T08:00:00.000Z","event":"open","id":0,"listener":"public","peer":"10.0.0.7:50312","local":"0.0.0.0:9"}
+//! {"time":"2019-03-02T08:00:00.002Z","event":"data","id":0,"peer":"10.0.0.7:50312","bytes":512}
+//! {"time":"2019-03-02T08:00:30.002Z","event":"close","id":0,"peer":"10.0.0.7:50312","reason":"idle timeout","bytes":512,"duration_ms":30002}
+//! {"time":"2019-03-02T08:00:31.000Z","event":"reject","id":1,"peer":"192.0.2.9:41000","local":"0.0.0.0:9","reason":"not allowed"}
+//! ```
+//!
+//! Wrap any factory with `JsonLog::factory`; a failing writer never takes the server down,
+//! the lines are simply lost.
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use chrono::{SecondsFormat, Utc};
+use crate::{
+    admin::push_json_str,
+    discard_mio::{CloseReason, ConnectionInfo, Factory, Handler, Handshake, RejectReason},
+};
+
+/// The writer every handler logs to; clones share it.
+#[derive(Clone)]
+pub struct JsonLog {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonLog {
+    #[inline]
+    pub fn new<W>(out: W) -> Self
+    where W: Write + Send + 'static
+    {
+        Self { out: Arc::new(Mutex::new(Box::new(out))) }
+    }
+
+    #[inline]
+    pub fn factory<F>(&self, factory: F) -> JsonLogFactory<F> {
+        JsonLogFactory { inner: factory, log: self.clone() }
+    }
+
+    fn emit(&self, event: &str, info: &ConnectionInfo, fields: &str) {
+        let mut line = format!("{{\"time\":\"{}\",\"event\":\"{}\",\"id\":{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), event, info.id());
+        line.push_str(fields);
+        line.push_str("}\n");
+        let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+impl fmt::Debug for JsonLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonLog").finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JsonLogFactory<F> {
+    inner: F,
+    log: JsonLog,
+}
+
+impl<F> Factory for JsonLogFactory<F>
+where F: Factory
+{
+    type Handler = JsonLogged<F::Handler>;
+
+    #[inline]
+    fn accept(&mut self, info: &ConnectionInfo) -> bool {
+        self.inner.accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &ConnectionInfo) -> Self::Handler {
+        JsonLogged {
+            inner: self.inner.connection_made(info),
+            log: self.log.clone(),
+            info: *info,
+            opened_at: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn on_reject(&mut self, info: &ConnectionInfo, reason: RejectReason) {
+        let mut fields = format!(",\"peer\":\"{}\",\"local\":\"{}\",\"reason\":", info.peer_addr(), info.local_addr());
+        push_json_str(&mut fields, &reason.to_string());
+        self.log.emit("reject", info, &fields);
+        self.inner.on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonLogged<H> {
+    inner: H,
+    log: JsonLog,
+    info: ConnectionInfo,
+    opened_at: Instant,
+    bytes: u64,
+}
+
+impl<H> JsonLogged<H> {
+    fn closed(&mut self, reason: Option<CloseReason>) {
+        let mut fields = format!(",\"peer\":\"{}\",\"reason\":", self.info.peer_addr());
+        match reason {
+            Some(reason) => push_json_str(&mut fields, &reason.to_string()),
+            None => fields.push_str("null"),
+        }
+        let elapsed = self.opened_at.elapsed();
+        let _ = write!(fields, ",\"bytes\":{},\"duration_ms\":{}", self.bytes,
+            elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()));
+        self.log.emit("close", &self.info, &fields);
+    }
+}
+
+impl<H> Handler for JsonLogged<H>
+where H: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        let mut fields = String::from(",\"listener\":");
+        match self.info.listener_tag() {
+            Some(tag) => push_json_str(&mut fields, tag),
+            None => fields.push_str("null"),
+        }
+        let _ = write!(fields, ",\"peer\":\"{}\",\"local\":\"{}\"", shake.peer_addr(), shake.local_addr());
+        self.log.emit("open", &self.info, &fields);
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        let fields = format!(",\"peer\":\"{}\",\"bytes\":{}", self.info.peer_addr(), data.len());
+        self.log.emit("data", &self.info, &fields);
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.closed(None);
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        self.closed(Some(reason));
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.inner.idle_timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discard_mio::Builder;
+    use std::{io, net::TcpStream, sync::mpsc, thread, time::Duration};
+
+    /// Sends every line it is given.
+    struct Lines(mpsc::Sender<String>, Vec<u8>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.extend_from_slice(buf);
+            while let Some(pos) = self.1.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.1.drain(..=pos).collect();
+                let _ = self.0.send(String::from_utf8_lossy(&line[..pos]).into_owned());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The line without its leading `"time"` field.
+    fn untimed(line: String) -> String {
+        let start = line.find(",\"event\"").expect("no event field");
+        format!("{{{}", &line[start + 1..])
+    }
+
+    #[test]
+    fn lifecycle_lines() {
+        let (tx, rx) = mpsc::channel();
+        let log = JsonLog::new(Lines(tx, Vec::new()));
+        let builder = Builder::new()
+            .bind_tagged("127.0.0.1:0", "public").unwrap()
+            .idle_timeout(Duration::from_millis(100));
+        let addr = builder.local_addrs().unwrap()[0];
+        let factory = log.factory(|| |_shake: Handshake| {});
+        thread::spawn(move || builder.build(factory).unwrap().run().unwrap());
+        let mut client = TcpStream::connect(addr).unwrap();
+        let peer = client.local_addr().unwrap();
+        client.write_all(b"laji").unwrap();
+        let next = || untimed(rx.recv_timeout(Duration::from_secs(2)).unwrap());
+        assert_eq!(next(), format!("{{\"event\":\"open\",\"id\":0,\"listener\":\"public\",\"peer\":\"{}\",\"local\":\"{}\"}}", peer, addr));
+        assert_eq!(next(), format!("{{\"event\":\"data\",\"id\":0,\"peer\":\"{}\",\"bytes\":4}}", peer));
+        let close = next();
+        let expected = format!("{{\"event\":\"close\",\"id\":0,\"peer\":\"{}\",\"reason\":\"idle timeout\",\"bytes\":4,\"duration_ms\":", peer);
+        assert!(close.starts_with(&expected), "{}", close);
+    }
+}
@@ -0,0 +1,888 @@
+//! KCP, the ARQ protocol that many games run over UDP.
+//!
+//! `Kcp` is the protocol without any I/O, the same state machine as the reference `ikcp.c`:
+//! feed it received datagrams with `input`, call `update` with the current time in
+//! milliseconds and it emits what to send, and exchange messages with `send` and `recv`.
+//! `KcpStream` runs one over a connected `UdpSocket` with blocking `Read` and `Write`.
+//!
+//! What makes KCP "fast" is in `Config`: `nodelay` stops the retransmission timeout from
+//! doubling and drops its floor, `resend` retransmits a segment once that many later ones
+//! were acknowledged without waiting for the timeout, and `no_congestion` ignores the
+//! congestion window. `Config::fast()` turns all of them on, as `ikcp_nodelay(1, 10, 2, 1)`.
+//! Only the message mode of the reference is implemented, not its stream mode.
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+use crate::wire::invalid_data;
+
+/// conv, cmd, frg, wnd, ts, sn, una, len
+pub const OVERHEAD: usize = 24;
+
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+const ASK_SEND: u8 = 1;
+const ASK_TELL: u8 = 2;
+const RTO_NODELAY: u32 = 30;
+const RTO_MIN: u32 = 100;
+const RTO_DEFAULT: u32 = 200;
+const RTO_MAX: u32 = 60_000;
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+const PROBE_INIT: u32 = 7_000;
+const PROBE_LIMIT: u32 = 120_000;
+const DEAD_LINK: u32 = 20;
+// fragments of one message are counted in a byte, and must fit the receive window
+const MAX_FRAGMENTS: usize = 128;
+/// The largest UDP payload over IPv4, which bounds `Config::mtu`.
+const MAX_MTU: usize = 65_507;
+
+/// `a - b` for sequence numbers and timestamps that wrap.
+#[inline]
+fn diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Config {
+    pub nodelay: bool,
+    /// Milliseconds between flushes, 10 to 5000.
+    pub interval: u32,
+    /// Retransmit after this many later segments were acknowledged; 0 for never.
+    pub resend: u32,
+    pub no_congestion: bool,
+    /// Segments in flight at most.
+    pub send_window: u16,
+    /// Segments held for reordering at most; also the longest message, in segments.
+    pub recv_window: u16,
+    /// Largest datagram sent, header included; at most 65507, the largest UDP payload.
+    pub mtu: usize,
+    /// Give up on the link after a segment was sent this many times.
+    pub dead_link: u32,
+}
+
+impl Config {
+    /// The reference defaults: cautious, TCP-like.
+    #[inline]
+    pub fn normal() -> Self {
+        Self {
+            nodelay: false,
+            interval: 100,
+            resend: 0,
+            no_congestion: false,
+            send_window: 32,
+            recv_window: 128,
+            mtu: 1400,
+            dead_link: DEAD_LINK,
+        }
+    }
+
+    /// The settings KCP is known for, trading bandwidth for latency.
+    #[inline]
+    pub fn fast() -> Self {
+        Self { nodelay: true, interval: 10, resend: 2, no_congestion: true, ..Self::normal() }
+    }
+}
+
+impl Default for Config {
+    #[inline]
+    fn default() -> Self {
+        Self::normal()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Segment {
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resend_ts: u32,
+    rto: u32,
+    fast_ack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, conv: u32, out: &mut Vec<u8>) {
+        out.extend_from_slice(&conv.to_le_bytes());
+        out.push(self.cmd);
+        out.push(self.frg);
+        out.extend_from_slice(&self.wnd.to_le_bytes());
+        out.extend_from_slice(&self.ts.to_le_bytes());
+        out.extend_from_slice(&self.sn.to_le_bytes());
+        out.extend_from_slice(&self.una.to_le_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+    }
+}
+
+/// One end of a KCP conversation. Both ends must use the same `conv`.
+#[derive(Clone, Debug)]
+pub struct Kcp {
+    conv: u32,
+    config: Config,
+    mss: usize,
+    dead: bool,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    incr: u32,
+    probe: u8,
+    current: u32,
+    ts_flush: u32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    snd_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acks: Vec<(u32, u32)>,
+    out: Vec<u8>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32, config: Config) -> Self {
+        let config = Config {
+            interval: config.interval.clamp(10, 5000),
+            mtu: config.mtu.clamp(OVERHEAD + 1, MAX_MTU),
+            send_window: config.send_window.max(1),
+            recv_window: config.recv_window.max(MAX_FRAGMENTS as u16),
+            ..config
+        };
+        Self {
+            conv,
+            config,
+            mss: config.mtu - OVERHEAD,
+            dead: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: RTO_DEFAULT,
+            rx_minrto: if config.nodelay { RTO_NODELAY } else { RTO_MIN },
+            rmt_wnd: u32::from(config.recv_window),
+            cwnd: 0,
+            incr: 0,
+            probe: 0,
+            current: 0,
+            ts_flush: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            snd_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acks: Vec::new(),
+            out: Vec::with_capacity(config.mtu * 3),
+        }
+    }
+
+    #[inline]
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    /// Largest payload of one segment.
+    #[inline]
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    /// Whether some segment went unacknowledged `dead_link` sends in a row.
+    #[inline]
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Segments queued or in flight, not yet acknowledged.
+    #[inline]
+    pub fn wait_send(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    /// Queue `msg` to be sent, cut into as many segments as it takes.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        let count = match msg.len() {
+            0 => 1,
+            len => len.div_ceil(self.mss),
+        };
+        if count > MAX_FRAGMENTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message longer than 128 segments"));
+        }
+        let mut chunks = msg.chunks(self.mss);
+        for i in 0..count {
+            let data = chunks.next().unwrap_or(&[]).to_vec();
+            self.snd_queue.push_back(Segment { frg: (count - i - 1) as u8, data, ..Segment::default() });
+        }
+        Ok(())
+    }
+
+    /// Size of the next complete message, if one has arrived.
+    pub fn peek_size(&self) -> Option<usize> {
+        let first = self.rcv_queue.front()?;
+        if first.frg == 0 {
+            return Some(first.data.len());
+        }
+        if self.rcv_queue.len() < usize::from(first.frg) + 1 {
+            return None;
+        }
+        let mut len = 0;
+        for seg in &self.rcv_queue {
+            len += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        Some(len)
+    }
+
+    /// Take the next complete message into `buf`: `WouldBlock` if there is none yet, and
+    /// `InvalidInput`, leaving it queued, if it does not fit.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.peek_size().ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "no message yet"))?;
+        if size > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer smaller than the message"));
+        }
+        let recover = self.rcv_queue.len() >= usize::from(self.config.recv_window);
+        let mut len = 0;
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            buf[len..len + seg.data.len()].copy_from_slice(&seg.data);
+            len += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        self.move_to_queue();
+        // the window just opened again; tell the peer rather than wait for it to ask
+        if recover && self.rcv_queue.len() < usize::from(self.config.recv_window) {
+            self.probe |= ASK_TELL;
+        }
+        Ok(len)
+    }
+
+    /// Take in one received datagram, which may carry several segments.
+    pub fn input(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if data.len() < OVERHEAD {
+            return Err(invalid_data("kcp datagram shorter than a header"));
+        }
+        let prev_una = self.snd_una;
+        let mut max_ack = None;
+        while data.len() >= OVERHEAD {
+            let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+            if u32_at(0) != self.conv {
+                return Err(invalid_data("kcp segment of another conversation"));
+            }
+            let (cmd, frg) = (data[4], data[5]);
+            let wnd = u16::from_le_bytes([data[6], data[7]]);
+            let (ts, sn, una, len) = (u32_at(8), u32_at(12), u32_at(16), u32_at(20) as usize);
+            if data.len() - OVERHEAD < len {
+                return Err(invalid_data("kcp segment cut short"));
+            }
+            if ![CMD_PUSH, CMD_ACK, CMD_WASK, CMD_WINS].contains(&cmd) {
+                return Err(invalid_data("unknown kcp command"));
+            }
+            self.rmt_wnd = u32::from(wnd);
+            self.parse_una(una);
+            self.shrink_buf();
+            match cmd {
+                CMD_ACK => {
+                    if diff(self.current, ts) >= 0 {
+                        self.update_ack(diff(self.current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    max_ack = match max_ack {
+                        Some(max) if diff(sn, max) <= 0 => Some(max),
+                        _ => Some(sn),
+                    };
+                }
+                CMD_PUSH => {
+                    if diff(sn, self.rcv_nxt.wrapping_add(u32::from(self.config.recv_window))) < 0 {
+                        self.acks.push((sn, ts));
+                        if diff(sn, self.rcv_nxt) >= 0 {
+                            let data = data[OVERHEAD..OVERHEAD + len].to_vec();
+                            self.parse_data(Segment { cmd, frg, wnd, ts, sn, una, data, ..Segment::default() });
+                        }
+                    }
+                }
+                CMD_WASK => self.probe |= ASK_TELL,
+                _ => {}
+            }
+            data = &data[OVERHEAD + len..];
+        }
+        if let Some(max) = max_ack {
+            self.parse_fast_ack(max);
+        }
+        if diff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr = self.incr.saturating_add(mss);
+            } else {
+                self.incr = self.incr.max(mss);
+                self.incr = self.incr.saturating_add((mss * mss) / self.incr + mss / 16);
+                if (self.cwnd + 1).saturating_mul(mss) <= self.incr {
+                    self.cwnd += 1;
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd.saturating_mul(mss);
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush if an `interval` has passed since the last flush. `current` is a millisecond
+    /// clock; only differences between its values matter.
+    pub fn update<F>(&mut self, current: u32, output: F)
+    where F: FnMut(&[u8])
+    {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+        let mut slap = diff(current, self.ts_flush);
+        if !(-10_000..10_000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.config.interval);
+            if diff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.config.interval);
+            }
+            self.flush(output);
+        }
+    }
+
+    /// When `update` next has something to do, so callers can sleep until then.
+    pub fn check(&self, current: u32) -> u32 {
+        if !self.updated {
+            return current;
+        }
+        let mut ts_flush = self.ts_flush;
+        if !(-10_000..10_000).contains(&diff(current, ts_flush)) {
+            ts_flush = current;
+        }
+        if diff(current, ts_flush) >= 0 {
+            return current;
+        }
+        let mut wait = diff(ts_flush, current);
+        for seg in &self.snd_buf {
+            let resend = diff(seg.resend_ts, current);
+            if resend <= 0 {
+                return current;
+            }
+            wait = wait.min(resend);
+        }
+        current.wrapping_add((wait as u32).min(self.config.interval))
+    }
+
+    /// Send acknowledgements, window probes and whatever data the windows allow now.
+    pub fn flush<F>(&mut self, mut output: F)
+    where F: FnMut(&[u8])
+    {
+        if !self.updated {
+            return;
+        }
+        let current = self.current;
+        let mut header = Segment {
+            cmd: CMD_ACK,
+            wnd: self.wnd_unused(),
+            una: self.rcv_nxt,
+            ..Segment::default()
+        };
+        let mtu = self.config.mtu;
+        let mut out = std::mem::take(&mut self.out);
+        out.clear();
+        let mut emit = |out: &mut Vec<u8>, next: usize| {
+            if out.len() + next > mtu {
+                output(out);
+                out.clear();
+            }
+        };
+
+        for &(sn, ts) in &self.acks {
+            emit(&mut out, OVERHEAD);
+            header.sn = sn;
+            header.ts = ts;
+            header.encode(self.conv, &mut out);
+        }
+        self.acks.clear();
+
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if diff(current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        for &(flag, cmd) in &[(ASK_SEND, CMD_WASK), (ASK_TELL, CMD_WINS)] {
+            if self.probe & flag != 0 {
+                emit(&mut out, OVERHEAD);
+                header.cmd = cmd;
+                header.sn = 0;
+                header.ts = 0;
+                header.encode(self.conv, &mut out);
+            }
+        }
+        self.probe = 0;
+
+        let mut cwnd = u32::from(self.config.send_window).min(self.rmt_wnd);
+        if !self.config.no_congestion {
+            cwnd = cwnd.min(self.cwnd);
+        }
+        while diff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let mut seg = match self.snd_queue.pop_front() {
+                Some(seg) => seg,
+                None => break,
+            };
+            seg.cmd = CMD_PUSH;
+            seg.wnd = header.wnd;
+            seg.ts = current;
+            seg.sn = self.snd_nxt;
+            seg.una = self.rcv_nxt;
+            seg.resend_ts = current;
+            seg.rto = self.rx_rto;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(seg);
+        }
+
+        let resent = if self.config.resend > 0 { self.config.resend } else { u32::MAX };
+        let rto_min = if self.config.nodelay { 0 } else { self.rx_rto >> 3 };
+        let (mut lost, mut change) = (false, false);
+        for seg in &mut self.snd_buf {
+            let send = if seg.xmit == 0 {
+                seg.rto = self.rx_rto;
+                seg.resend_ts = current.wrapping_add(seg.rto + rto_min);
+                true
+            } else if diff(current, seg.resend_ts) >= 0 {
+                seg.rto += if self.config.nodelay { self.rx_rto / 2 } else { seg.rto.max(self.rx_rto) };
+                seg.resend_ts = current.wrapping_add(seg.rto);
+                lost = true;
+                true
+            } else if seg.fast_ack >= resent {
+                seg.fast_ack = 0;
+                seg.resend_ts = current.wrapping_add(seg.rto);
+                change = true;
+                true
+            } else {
+                false
+            };
+            if send {
+                seg.xmit += 1;
+                seg.ts = current;
+                seg.wnd = header.wnd;
+                seg.una = self.rcv_nxt;
+                emit(&mut out, OVERHEAD + seg.data.len());
+                seg.encode(self.conv, &mut out);
+                if seg.xmit >= self.config.dead_link {
+                    self.dead = true;
+                }
+            }
+        }
+        if !out.is_empty() {
+            output(&out);
+        }
+        self.out = out;
+
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh + resent;
+            self.incr = self.cwnd.saturating_mul(self.mss as u32);
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = self.mss as u32;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = self.mss as u32;
+        }
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        self.config.recv_window.saturating_sub(self.rcv_queue.len() as u16)
+    }
+
+    // RFC 6298
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + self.config.interval.max(4 * self.rx_rttval);
+        self.rx_rto = rto.max(self.rx_minrto).min(RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = self.snd_buf.front().map_or(self.snd_nxt, |seg| seg.sn);
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        if let Some(i) = self.snd_buf.iter().position(|seg| seg.sn == sn) {
+            self.snd_buf.remove(i);
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while self.snd_buf.front().is_some_and(|seg| diff(una, seg.sn) > 0) {
+            self.snd_buf.pop_front();
+        }
+    }
+
+    fn parse_fast_ack(&mut self, sn: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in &mut self.snd_buf {
+            if diff(sn, seg.sn) <= 0 {
+                break;
+            }
+            seg.fast_ack += 1;
+        }
+    }
+
+    fn parse_data(&mut self, seg: Segment) {
+        let sn = seg.sn;
+        if diff(sn, self.rcv_nxt.wrapping_add(u32::from(self.config.recv_window))) >= 0 || diff(sn, self.rcv_nxt) < 0 {
+            return;
+        }
+        let mut at = self.rcv_buf.len();
+        for (i, held) in self.rcv_buf.iter().enumerate().rev() {
+            if held.sn == sn {
+                return;
+            }
+            if diff(sn, held.sn) > 0 {
+                break;
+            }
+            at = i;
+        }
+        self.rcv_buf.insert(at, seg);
+        self.move_to_queue();
+    }
+
+    fn move_to_queue(&mut self) {
+        while self.rcv_buf.front().is_some_and(|seg| seg.sn == self.rcv_nxt)
+            && self.rcv_queue.len() < usize::from(self.config.recv_window)
+        {
+            let seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_queue.push_back(seg);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+    }
+}
+
+/// A `Kcp` over a connected `UdpSocket`. Each `write` sends one message, and `read` reads
+/// messages back to back, as a byte stream.
+#[derive(Debug)]
+pub struct KcpStream {
+    socket: UdpSocket,
+    kcp: Kcp,
+    epoch: Instant,
+    read_timeout: Option<Duration>,
+    pending: Vec<u8>,
+    pending_at: usize,
+    buf: Vec<u8>,
+}
+
+impl KcpStream {
+    /// Talk to `addr` from a fresh ephemeral port.
+    pub fn connect<A>(addr: A, conv: u32, config: Config) -> io::Result<Self>
+    where A: ToSocketAddrs
+    {
+        let peer = crate::resolve::each_addr(addr)?[0];
+        let socket = crate::resolve::bind_ephemeral_for(peer)?;
+        socket.connect(peer)?;
+        Ok(Self::from_socket(socket, conv, config))
+    }
+
+    /// `socket` must be connected to its peer.
+    pub fn from_socket(socket: UdpSocket, conv: u32, config: Config) -> Self {
+        let mtu = config.mtu;
+        Self {
+            socket,
+            kcp: Kcp::new(conv, config),
+            epoch: Instant::now(),
+            read_timeout: None,
+            pending: Vec::new(),
+            pending_at: 0,
+            buf: vec![0u8; mtu.clamp(OVERHEAD + 1, MAX_MTU)],
+        }
+    }
+
+    /// How long a `read`, or a `flush`, may wait; `None`, the default, waits forever.
+    #[inline]
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    #[inline]
+    pub fn kcp(&self) -> &Kcp {
+        &self.kcp
+    }
+
+    #[inline]
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Read one whole message, however `read` calls were cut.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            match self.kcp.recv(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.pump(deadline)?,
+                ans => return ans,
+            }
+        }
+    }
+
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_millis() as u32
+    }
+
+    /// Run `update` once, then wait for a datagram until the next update is due.
+    fn pump(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        if self.kcp.is_dead() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "kcp peer stopped answering"));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "kcp read timed out"));
+        }
+        let now = self.now();
+        let socket = &self.socket;
+        let mut send_err = None;
+        self.kcp.update(now, |datagram| {
+            if let Err(e) = socket.send(datagram) {
+                send_err.get_or_insert(e);
+            }
+        });
+        if let Some(e) = send_err {
+            return Err(e);
+        }
+        let mut wait = Duration::from_millis(u64::from(diff(self.kcp.check(now), now).max(1) as u32));
+        if let Some(deadline) = deadline {
+            wait = wait.min(deadline.saturating_duration_since(Instant::now())).max(Duration::from_millis(1));
+        }
+        self.socket.set_read_timeout(Some(wait))?;
+        match self.socket.recv(&mut self.buf) {
+            // a corrupt datagram is as good as a lost one
+            Ok(len) => { let _ = self.kcp.input(&self.buf[..len]); }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            // ICMP unreachable from the peer's port; keep trying until the link is dead
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => return Err(e),
+        }
+        // acknowledge what just came in without waiting for the next interval
+        self.kcp.flush(|datagram| { let _ = socket.send(datagram); });
+        Ok(())
+    }
+}
+
+impl Read for KcpStream {
+    /// Read what is left of the current message, or wait for the next one. Empty messages
+    /// are skipped, as reading one would look like the end of the stream.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_at == self.pending.len() {
+            let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+            let size = loop {
+                match self.kcp.peek_size() {
+                    Some(0) => { self.kcp.recv(&mut [])?; }
+                    Some(size) => break size,
+                    None => self.pump(deadline)?,
+                }
+            };
+            self.pending.resize(size, 0);
+            self.kcp.recv(&mut self.pending)?;
+            self.pending_at = 0;
+        }
+        let len = buf.len().min(self.pending.len() - self.pending_at);
+        buf[..len].copy_from_slice(&self.pending[self.pending_at..self.pending_at + len]);
+        self.pending_at += len;
+        Ok(len)
+    }
+}
+
+impl Write for KcpStream {
+    /// Queue at most one message's worth of `buf` and send what the windows allow.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // `Kcp::send` would queue an empty message, which the peer cannot tell from nothing
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.kcp.mss() * MAX_FRAGMENTS);
+        self.kcp.send(&buf[..len])?;
+        let socket = &self.socket;
+        self.kcp.flush(|datagram| { let _ = socket.send(datagram); });
+        Ok(len)
+    }
+
+    /// Wait until the peer acknowledged everything written.
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        while self.kcp.wait_send() > 0 {
+            self.pump(deadline)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // deliver everything each side sends, except every `drop_every`th datagram
+    fn run_lossy(a: &mut Kcp, b: &mut Kcp, drop_every: usize, mut until: impl FnMut(&mut Kcp) -> bool) -> u32 {
+        let mut sent = 0;
+        for now in (0..60_000).step_by(10) {
+            let (mut to_b, mut to_a) = (Vec::new(), Vec::new());
+            a.update(now, |d| to_b.push(d.to_vec()));
+            b.update(now, |d| to_a.push(d.to_vec()));
+            for (datagrams, peer) in [(to_b, &mut *b), (to_a, &mut *a)] {
+                for d in datagrams {
+                    sent += 1;
+                    if sent % drop_every != 0 {
+                        peer.input(&d).unwrap();
+                    }
+                }
+            }
+            if until(b) {
+                return now;
+            }
+        }
+        panic!("not delivered within a minute");
+    }
+
+    #[test]
+    fn delivers_through_loss() {
+        let mut a = Kcp::new(7, Config::fast());
+        let mut b = Kcp::new(7, Config::fast());
+        let long: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        a.send(b"first").unwrap();
+        a.send(&long).unwrap();
+        for i in 0..50u8 {
+            a.send(&[i; 3]).unwrap();
+        }
+        let mut got = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        run_lossy(&mut a, &mut b, 4, |b| {
+            while let Ok(len) = b.recv(&mut buf) {
+                got.push(buf[..len].to_vec());
+            }
+            got.len() == 52
+        });
+        assert_eq!((&got[0][..], &got[1][..]), (&b"first"[..], &long[..]));
+        assert!(got[2..].iter().enumerate().all(|(i, msg)| msg == &[i as u8; 3]));
+    }
+
+    #[test]
+    fn mtu_clamped() {
+        let config = Config { mtu: usize::MAX / 4, no_congestion: false, ..Config::fast() };
+        let (mut a, mut b) = (Kcp::new(3, config), Kcp::new(3, config));
+        assert_eq!(a.mss(), MAX_MTU - OVERHEAD);
+        let long = vec![7u8; 4 * a.mss()];
+        a.send(&long).unwrap();
+        let mut buf = vec![0u8; long.len()];
+        run_lossy(&mut a, &mut b, 1000, |b| b.recv(&mut buf).is_ok());
+        assert_eq!(buf, long);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut a = Kcp::new(1, Config::normal());
+        let mut other = Vec::new();
+        Segment { cmd: CMD_PUSH, ..Segment::default() }.encode(2, &mut other);
+        assert!(a.input(&other).is_err());
+        assert!(a.input(&other[..10]).is_err());
+        assert!(a.send(&vec![0u8; 129 * a.mss()]).is_err());
+        let mut small = [0u8; 2];
+        assert_eq!(a.recv(&mut small).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn fast_config_recovers_sooner() {
+        let time_to_deliver = |config: Config| {
+            let (mut a, mut b) = (Kcp::new(3, config), Kcp::new(3, config));
+            for _ in 0..64 {
+                a.send(&[1; 100]).unwrap();
+            }
+            let mut received = 0;
+            let mut buf = [0u8; 100];
+            run_lossy(&mut a, &mut b, 5, |b| {
+                while b.recv(&mut buf).is_ok() {
+                    received += 1;
+                }
+                received == 64
+            })
+        };
+        assert!(time_to_deliver(Config::fast()) < time_to_deliver(Config::normal()));
+    }
+
+    #[test]
+    fn stream_loopback() -> io::Result<()> {
+        let (a, b) = (UdpSocket::bind("127.0.0.1:0")?, UdpSocket::bind("127.0.0.1:0")?);
+        a.connect(b.local_addr()?)?;
+        b.connect(a.local_addr()?)?;
+        let echo = thread::spawn(move || -> io::Result<()> {
+            let mut server = KcpStream::from_socket(b, 9, Config::fast());
+            server.set_read_timeout(Some(Duration::from_secs(5)));
+            let mut buf = [0u8; 4096];
+            let len = server.recv(&mut buf)?;
+            // as another implementation may send, ahead of the echo
+            server.kcp.send(&[])?;
+            server.write_all(&buf[..len])?;
+            server.flush()
+        });
+        let mut client = KcpStream::from_socket(a, 9, Config::fast());
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(client.write(&[])?, 0);
+        assert_eq!(client.kcp().wait_send(), 0);
+        client.write_all(&[b'x'; 3000])?;
+        let mut back = vec![0u8; 3000];
+        client.read_exact(&mut back)?;
+        assert!(back.iter().all(|&b| b == b'x'));
+        echo.join().unwrap()?;
+        client.set_read_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(client.read(&mut back).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+}
@@ -1,16 +1,111 @@
+#[cfg(feature = "discard")]
 #[path = "discard-sync.rs"]
 pub mod discard_sync;
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
 #[path = "discard-mio.rs"]
 pub mod discard_mio;
+#[cfg(all(feature = "discard", feature = "backend-tokio"))]
 #[path = "discard-tokio.rs"]
 pub mod discard_tokio;
+#[cfg(all(feature = "discard", windows, feature = "backend-iocp"))]
+#[path = "discard-iocp.rs"]
+pub mod discard_iocp;
+#[cfg(all(feature = "discard", feature = "backend-romio"))]
 #[path = "discard-romio.rs"]
 pub mod discard_romio;
 
+#[cfg(feature = "daytime")]
 #[path = "daytime-threads.rs"]
 pub mod daytime_threads;
+#[cfg(all(feature = "daytime", feature = "backend-mio"))]
 #[path = "daytime-mio.rs"]
 pub mod daytime_mio;
+#[cfg(feature = "daytime")]
+pub mod drift;
+#[cfg(feature = "daytime")]
+pub mod announce;
 
+#[cfg(all(windows, feature = "backend-iocp"))]
+mod iocp;
+pub mod clock;
+pub mod config;
+pub mod server;
+#[cfg(feature = "simtcp")]
 pub mod simtcp;
+#[cfg(feature = "kcp")]
+pub mod kcp;
+#[cfg(feature = "echo")]
+pub mod echo;
+#[cfg(feature = "echo")]
+#[path = "echo-sync.rs"]
+pub mod echo_sync;
+#[cfg(all(feature = "echo", feature = "backend-mio"))]
+#[path = "echo-mio.rs"]
+pub mod echo_mio;
+#[cfg(feature = "chargen")]
+pub mod chargen;
+#[cfg(feature = "rakping")]
 pub mod rakping;
+#[cfg(all(feature = "rakping", feature = "backend-mio"))]
+#[path = "rakping-mio.rs"]
+pub mod rakping_mio;
+#[cfg(feature = "stun")]
+pub mod stun;
+#[cfg(feature = "holepunch")]
+pub mod holepunch;
+#[cfg(feature = "nbns")]
+pub mod nbns;
+#[cfg(feature = "dhcp")]
+pub mod dhcp;
+#[cfg(feature = "modbus")]
+#[path = "modbus-tcp.rs"]
+pub mod modbus_tcp;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "irc")]
+pub mod irc;
+#[cfg(feature = "mcping")]
+pub mod mcping;
+#[cfg(feature = "gopher")]
+pub mod gopher;
+#[cfg(feature = "icmp")]
+pub mod icmp;
+#[cfg(feature = "icmp")]
+pub mod traceroute;
+
+#[path = "udp-batch.rs"]
+pub mod udp_batch;
+pub mod affinity;
+pub mod ports;
+pub mod ratelimit;
+pub mod sampling;
+pub mod framing;
+pub mod checksum;
+pub mod wire;
+pub mod timestamp;
+pub mod resolve;
+pub mod beacon;
+pub mod reconnect;
+pub mod srv;
+pub mod socks5;
+pub mod virtnet;
+pub mod chaos;
+pub mod script;
+#[cfg(any(feature = "daytime", feature = "discard"))]
+pub mod record;
+#[cfg(any(feature = "daytime", feature = "discard"))]
+pub mod reload;
+pub mod metrics;
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+pub mod middleware;
+pub mod admin;
+pub mod prometheus;
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+pub mod jsonlog;
+pub mod prelude;
+mod random;
+
+#[cfg(test)]
+mod fixture;
+#[cfg(all(test, feature = "arbitrary"))]
+mod prop;
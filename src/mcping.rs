@@ -0,0 +1,197 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use crate::{framing::Prefix, resolve, socks5::{self, Proxy}, wire::invalid_data};
+
+pub const DEFAULT_PORT: u16 = crate::ports::MINECRAFT;
+
+/// Query a Java edition server's status with the default `Pinger` settings.
+pub fn ping(host: &str, port: u16) -> io::Result<Status> {
+    Pinger::new().ping(host, port)
+}
+
+// -1 means "whatever you speak"; servers answer the status query regardless
+const PROTOCOL_VERSION: i32 = -1;
+const NEXT_STATE_STATUS: i32 = 1;
+
+const PACKET_HANDSHAKE: i32 = 0x00;
+const PACKET_STATUS: i32 = 0x00;
+const PACKET_PING: i32 = 0x01;
+
+// status responses carry a base64 favicon, so they get big
+const MAX_PACKET_LEN: usize = 1 << 20;
+
+/// What a server reported about itself.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Status {
+    json: String,
+    latency: Duration,
+}
+
+impl Status {
+    /// The status response as sent: version, players, description and so on.
+    #[inline]
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+
+    /// Round-trip time of the ping/pong after the status exchange.
+    #[inline]
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Pinger {
+    protocol_version: i32,
+    timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Pinger {
+    #[inline]
+    pub fn new() -> Self {
+        Self { protocol_version: PROTOCOL_VERSION, timeout: Duration::from_secs(5), proxy: None }
+    }
+
+    /// Protocol version number sent in the handshake; some proxies route on it.
+    #[inline]
+    pub fn protocol_version(mut self, version: i32) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connect through a SOCKS5 proxy, which also resolves `host` for `ping`.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// `host` is also sent in the handshake, so pass the name players use rather than an IP.
+    /// Its IPv6 and IPv4 addresses are raced, as `resolve::connect_happy` does.
+    pub fn ping(&self, host: &str, port: u16) -> io::Result<Status> {
+        self.ping_stream(socks5::connect(self.proxy.as_ref(), host, port, self.timeout)?, host, port)
+    }
+
+    pub fn ping_addr(&self, addr: SocketAddr, host: &str) -> io::Result<Status> {
+        self.ping_stream(socks5::connect_to(self.proxy.as_ref(), addr, self.timeout)?, host, addr.port())
+    }
+
+    fn ping_stream(&self, mut stream: TcpStream, host: &str, port: u16) -> io::Result<Status> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut handshake = Vec::new();
+        write_varint(&mut handshake, self.protocol_version);
+        write_string(&mut handshake, host)?;
+        handshake.extend_from_slice(&port.to_be_bytes());
+        write_varint(&mut handshake, NEXT_STATE_STATUS);
+        let mut out = Vec::new();
+        write_packet(&mut out, PACKET_HANDSHAKE, &handshake)?;
+        write_packet(&mut out, PACKET_STATUS, &[])?;
+        stream.write_all(&out)?;
+
+        let (id, body) = read_packet(&mut stream).map_err(resolve::timed_out)?;
+        if id != PACKET_STATUS {
+            return Err(invalid_data("expected a status response"));
+        }
+        let json = read_string(&mut &body[..])?;
+
+        let payload = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        let mut out = Vec::new();
+        write_packet(&mut out, PACKET_PING, &payload.to_be_bytes())?;
+        let start = Instant::now();
+        stream.write_all(&out)?;
+        let (id, body) = read_packet(&mut stream).map_err(resolve::timed_out)?;
+        let latency = start.elapsed();
+        if id != PACKET_PING || body[..] != payload.to_be_bytes()[..] {
+            return Err(invalid_data("pong does not match ping"));
+        }
+        Ok(Status { json, latency })
+    }
+}
+
+impl Default for Pinger {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub use crate::framing::{read_varint, write_varint};
+
+#[inline]
+fn write_string(out: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    Prefix::VarInt.write_frame(out, s.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let buf = Prefix::VarInt.read_frame(reader, MAX_PACKET_LEN)?;
+    String::from_utf8(buf).map_err(|_| invalid_data("string is not utf-8"))
+}
+
+/// Append a length-prefixed packet: VarInt length, then VarInt id and body.
+fn write_packet(out: &mut Vec<u8>, id: i32, body: &[u8]) -> io::Result<()> {
+    let mut inner = Vec::with_capacity(body.len() + 5);
+    write_varint(&mut inner, id);
+    inner.extend_from_slice(body);
+    Prefix::VarInt.write_frame(out, &inner)
+}
+
+fn read_packet<R: Read>(reader: &mut R) -> io::Result<(i32, Vec<u8>)> {
+    let buf = Prefix::VarInt.read_frame(reader, MAX_PACKET_LEN)?;
+    if buf.is_empty() {
+        return Err(invalid_data("empty packet"));
+    }
+    let mut rest = &buf[..];
+    let id = read_varint(&mut rest)?;
+    Ok((id, rest.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn ping_loopback() -> io::Result<()> {
+        const JSON: &str = r#"{"version":{"name":"1.14.4","protocol":498},"players":{"max":20,"online":0},"description":{"text":"laji"}}"#;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> io::Result<(String, u16, i32)> {
+            let (mut stream, _) = listener.accept()?;
+            let (id, handshake) = read_packet(&mut stream)?;
+            assert_eq!(id, PACKET_HANDSHAKE);
+            let mut handshake = &handshake[..];
+            assert_eq!(read_varint(&mut handshake)?, PROTOCOL_VERSION);
+            let host = read_string(&mut handshake)?;
+            let port = u16::from_be_bytes([handshake[0], handshake[1]]);
+            let next_state = read_varint(&mut &handshake[2..])?;
+            assert_eq!(read_packet(&mut stream)?, (PACKET_STATUS, Vec::new()));
+            let mut body = Vec::new();
+            write_string(&mut body, JSON)?;
+            let mut out = Vec::new();
+            write_packet(&mut out, PACKET_STATUS, &body)?;
+            stream.write_all(&out)?;
+            let (id, payload) = read_packet(&mut stream)?;
+            assert_eq!(id, PACKET_PING);
+            let mut out = Vec::new();
+            write_packet(&mut out, PACKET_PING, &payload)?;
+            stream.write_all(&out)?;
+            Ok((host, port, next_state))
+        });
+        let status = Pinger::new().ping_addr(addr, "mc.example.org")?;
+        assert_eq!(status.json(), JSON);
+        assert_eq!(server.join().unwrap()?, ("mc.example.org".to_string(), addr.port(), NEXT_STATE_STATUS));
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+
+/// Upper bounds, in seconds, of the connection duration buckets.
+pub const DURATION_BUCKETS: [f64; 8] = [0.001, 0.01, 0.1, 1.0, 10.0, 60.0, 600.0, 3600.0];
+
+/// Connection and byte counters shared by every handler reporting into them; clones share
+/// the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    opened: AtomicU64,
+    closed: AtomicU64,
+    rejected: AtomicU64,
+    bytes_received: AtomicU64,
+    // (peer, local, opened at) of every open connection
+    active: Mutex<Vec<(SocketAddr, SocketAddr, Instant)>>,
+    durations: Histogram,
+}
+
+impl Recorder {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection_opened(&self, peer_addr: SocketAddr, local_addr: SocketAddr) {
+        self.inner.active.lock().unwrap().push((peer_addr, local_addr, Instant::now()));
+        self.inner.opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, peer_addr: SocketAddr, local_addr: SocketAddr) {
+        let mut active = self.inner.active.lock().unwrap();
+        if let Some(index) = active.iter().position(|&(peer, local, _)| (peer, local) == (peer_addr, local_addr)) {
+            let (_, _, opened_at) = active.swap_remove(index);
+            self.inner.durations.observe(opened_at.elapsed());
+        }
+        self.inner.closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection refused before it was served, by the factory or the listener's limits.
+    #[inline]
+    pub fn connection_rejected(&self) {
+        self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn bytes_received(&self, len: usize) {
+        self.inner.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn opened(&self) -> u64 {
+        self.inner.opened.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn closed(&self) -> u64 {
+        self.inner.closed.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn rejected(&self) -> u64 {
+        self.inner.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Connections opened and not closed yet.
+    #[inline]
+    pub fn active(&self) -> u64 {
+        // read closed first so a close racing with this never makes it underflow
+        let closed = self.closed();
+        self.opened().saturating_sub(closed)
+    }
+
+    #[inline]
+    pub fn total_bytes_received(&self) -> u64 {
+        self.inner.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// The peer and local address of every open connection, in no particular order.
+    #[inline]
+    pub fn active_connections(&self) -> Vec<(SocketAddr, SocketAddr)> {
+        self.inner.active.lock().unwrap().iter().map(|&(peer, local, _)| (peer, local)).collect()
+    }
+
+    /// How long closed connections stayed open.
+    #[inline]
+    pub fn connection_durations(&self) -> &Histogram {
+        &self.inner.durations
+    }
+}
+
+/// Counts of durations at or below each of `DURATION_BUCKETS`, and above all of them.
+#[derive(Debug)]
+pub struct Histogram {
+    counts: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let bucket = DURATION_BUCKETS.iter().position(|&bound| secs <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// `(upper bound, durations at or below it)` for every bucket, the last bound infinite.
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        DURATION_BUCKETS.iter().cloned().chain(Some(f64::INFINITY))
+            .zip(self.counts.iter())
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for Histogram {
+    #[inline]
+    fn default() -> Self {
+        Self { counts: Default::default(), sum_micros: AtomicU64::new(0) }
+    }
+}
@@ -0,0 +1,443 @@
+//! Wrappers adding logging, metrics, timeouts and error sampling to any mio discard handler,
+//! and admission checks and metrics to any factory.
+//!
+//! ```ignore
+//! use laji_protocols::{discard_mio::Builder, metrics::Recorder, middleware::HandlerExt};
+//! let recorder = Recorder::new();
+//! Builder::new().bind("0.0.0.0:9")?.build(move || {
+//!     MyHandler::new().with_logging().with_metrics(recorder.clone())
+//! })?.run()
+//! ```
+use std::{io, time::Duration};
+use crate::{
+    discard_mio::{CloseReason, ConnectionInfo, Factory, Handler, Handshake, RejectReason},
+    metrics::Recorder,
+    sampling::ErrorSampler,
+};
+
+pub trait HandlerExt: Handler + Sized {
+    /// Print every callback to stdout.
+    #[inline]
+    fn with_logging(self) -> Logging<Self> {
+        Logging { inner: self, shake: None }
+    }
+
+    /// Count connections and received bytes in `recorder`.
+    #[inline]
+    fn with_metrics(self, recorder: Recorder) -> Metered<Self> {
+        Metered { inner: self, recorder, shake: None }
+    }
+
+    /// Close the stream after `timeout` without data, whatever the server's idle timeout is.
+    #[inline]
+    fn with_timeout(self, timeout: Duration) -> Timeout<Self> {
+        Timeout { inner: self, timeout }
+    }
+
+    /// Report error closes to `sampler`, and hide from this handler the ones it holds back:
+    /// those close with a plain `on_close`, so a logging or metrics handler inside still
+    /// sees every close but not a storm of identical errors.
+    #[inline]
+    fn with_error_sampling(self, sampler: ErrorSampler) -> Sampled<Self> {
+        Sampled { inner: self, sampler, shake: None }
+    }
+
+    /// Run `other` after this handler on every callback.
+    #[inline]
+    fn chain<B>(self, other: B) -> Chain<Self, B>
+    where B: Handler
+    {
+        Chain { first: self, second: other }
+    }
+}
+
+impl<H> HandlerExt for H
+where H: Handler {}
+
+pub trait FactoryExt: Factory + Sized {
+    /// Refuse streams `accept` says no to, before any handler is made for them.
+    #[inline]
+    fn accept_if<P>(self, accept: P) -> AcceptIf<Self, P>
+    where P: FnMut(&ConnectionInfo) -> bool
+    {
+        AcceptIf { inner: self, accept }
+    }
+
+    /// Meter every handler in `recorder`, and count the streams refused, by this factory or
+    /// by the listener.
+    #[inline]
+    fn metered(self, recorder: Recorder) -> MeteredFactory<Self> {
+        MeteredFactory { inner: self, recorder }
+    }
+}
+
+impl<F> FactoryExt for F
+where F: Factory {}
+
+#[derive(Clone, Debug)]
+pub struct AcceptIf<F, P> {
+    inner: F,
+    accept: P,
+}
+
+impl<F, P> Factory for AcceptIf<F, P>
+where
+    F: Factory,
+    P: FnMut(&ConnectionInfo) -> bool
+{
+    type Handler = F::Handler;
+
+    #[inline]
+    fn accept(&mut self, info: &ConnectionInfo) -> bool {
+        (self.accept)(info) && self.inner.accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &ConnectionInfo) -> F::Handler {
+        self.inner.connection_made(info)
+    }
+
+    #[inline]
+    fn on_reject(&mut self, info: &ConnectionInfo, reason: RejectReason) {
+        self.inner.on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MeteredFactory<F> {
+    inner: F,
+    recorder: Recorder,
+}
+
+impl<F> Factory for MeteredFactory<F>
+where F: Factory
+{
+    type Handler = Metered<F::Handler>;
+
+    #[inline]
+    fn accept(&mut self, info: &ConnectionInfo) -> bool {
+        self.inner.accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &ConnectionInfo) -> Self::Handler {
+        self.inner.connection_made(info).with_metrics(self.recorder.clone())
+    }
+
+    /// Refusals by the factory and by the listener alike count as rejected.
+    #[inline]
+    fn on_reject(&mut self, info: &ConnectionInfo, reason: RejectReason) {
+        self.recorder.connection_rejected();
+        self.inner.on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Logging<H> {
+    inner: H,
+    shake: Option<Handshake>,
+}
+
+impl<H> Handler for Logging<H>
+where H: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        println!("[{} -> {}]: Open!", shake.peer_addr(), shake.local_addr());
+        self.shake = Some(shake);
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        if let Some(shake) = self.shake {
+            println!("[{} -> {}]: {} bytes", shake.peer_addr(), shake.local_addr(), data.len());
+        }
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        if let Some(shake) = self.shake {
+            println!("[{} -> {}]: Close!", shake.peer_addr(), shake.local_addr());
+        }
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        if let Some(shake) = self.shake {
+            println!("[{} -> {}]: Close ({})!", shake.peer_addr(), shake.local_addr(), reason);
+        }
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.inner.idle_timeout()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Metered<H> {
+    inner: H,
+    recorder: Recorder,
+    shake: Option<Handshake>,
+}
+
+impl<H> Metered<H> {
+    #[inline]
+    fn closed(&mut self) {
+        if let Some(shake) = self.shake {
+            self.recorder.connection_closed(*shake.peer_addr(), *shake.local_addr());
+        }
+    }
+}
+
+impl<H> Handler for Metered<H>
+where H: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self.recorder.connection_opened(*shake.peer_addr(), *shake.local_addr());
+        self.shake = Some(shake);
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.recorder.bytes_received(data.len());
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.closed();
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        self.closed();
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.inner.idle_timeout()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Timeout<H> {
+    inner: H,
+    timeout: Duration,
+}
+
+impl<H> Handler for Timeout<H>
+where H: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        Some(self.timeout)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sampled<H> {
+    inner: H,
+    sampler: ErrorSampler,
+    shake: Option<Handshake>,
+}
+
+impl<H> Handler for Sampled<H>
+where H: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self.shake = Some(shake);
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        if let CloseReason::Error(kind) = reason {
+            let peer = self.shake.map(|shake| *shake.peer_addr());
+            if !self.sampler.report(kind, peer) {
+                return self.inner.on_close();
+            }
+        }
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.inner.idle_timeout()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Handler for Chain<A, B>
+where A: Handler, B: Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) {
+        self.first.on_open(shake);
+        self.second.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.first.on_data(data);
+        self.second.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.first.on_close();
+        self.second.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: CloseReason) {
+        self.first.on_close_with(reason);
+        self.second.on_close_with(reason)
+    }
+
+    /// The shorter of the two.
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        match (self.first.idle_timeout(), self.second.idle_timeout()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discard_mio::Builder, sampling::Report};
+    use std::{io::{self, Read, Write}, net::TcpStream, sync::{mpsc, Arc, Mutex}, thread};
+
+    #[test]
+    fn metered_timeout_chain() {
+        let recorder = Recorder::new();
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0").unwrap();
+        let addr = builder.local_addrs().unwrap()[0];
+        let server_recorder = recorder.clone();
+        struct Closing(mpsc::Sender<()>);
+        impl Handler for Closing {
+            fn on_close(&mut self) {
+                self.0.send(()).unwrap();
+            }
+        }
+        thread::spawn(move || {
+            builder.build(move || {
+                (|_shake: Handshake| {})
+                    .with_metrics(server_recorder.clone())
+                    .with_timeout(Duration::from_millis(100))
+                    .chain(Closing(tx.clone()).with_logging())
+            }).unwrap().run().unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"laji").unwrap();
+        // the server closes the idle stream on its own
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(client.read(&mut [0u8; 8]).unwrap(), 0);
+        assert_eq!((recorder.opened(), recorder.closed()), (1, 1));
+        assert_eq!((recorder.active(), recorder.total_bytes_received()), (0, 4));
+    }
+
+    #[test]
+    fn refused_by_factory() {
+        let recorder = Recorder::new();
+        let (tx, rx) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0").unwrap();
+        let addr = builder.local_addrs().unwrap()[0];
+        let factory = (move || {
+            let tx = tx.clone();
+            move |_shake: Handshake| tx.send(()).unwrap()
+        })
+            .accept_if(|info: &ConnectionInfo| info.id() % 2 == 1)
+            .metered(recorder.clone());
+        thread::spawn(move || builder.build(factory).unwrap().run().unwrap());
+        let mut refused = TcpStream::connect(addr).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(refused.read(&mut [0u8; 1]).unwrap_or(0), 0);
+        let _served = TcpStream::connect(addr).unwrap();
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(rx.try_recv().is_err());
+        assert_eq!((recorder.rejected(), recorder.opened()), (1, 1));
+    }
+
+    #[test]
+    fn sampled_errors() {
+        struct Reasons(Vec<Option<CloseReason>>);
+        impl Handler for Reasons {
+            fn on_close(&mut self) {
+                self.0.push(None);
+            }
+            fn on_close_with(&mut self, reason: CloseReason) {
+                self.0.push(Some(reason));
+            }
+        }
+        let reports = Arc::new(Mutex::new(0));
+        let counted = reports.clone();
+        let sampler = ErrorSampler::new(move |_: &Report| *counted.lock().unwrap() += 1).burst(1);
+        let mut handler = Reasons(Vec::new()).with_error_sampling(sampler);
+        let reset = CloseReason::Error(io::ErrorKind::ConnectionReset);
+        for &reason in &[reset, reset, CloseReason::Idle, reset] {
+            handler.on_close_with(reason);
+        }
+        assert_eq!(handler.inner.0, [Some(reset), None, Some(CloseReason::Idle), None]);
+        assert_eq!(*reports.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, TcpListener, TcpStream},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc, Mutex},
+    thread,
+};
+use smallvec::SmallVec;
+use crate::{affinity::{self, CoreList}, config::ConfigError, framing, ports};
+
+const INLINE_LISTENERS: usize = 4;
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+pub fn listen<A, F, B>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> B,
+    F: Clone + Send + 'static,
+    B: RegisterBank + Send + 'static
+{
+    Builder::new().bind(addr)?.build(factory).run()
+}
+
+/// Serve one register bank to every connection.
+pub fn serve<A, B>(addr: A, bank: B) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    B: RegisterBank + Send + 'static
+{
+    let bank = Arc::new(Mutex::new(bank));
+    listen(addr, move || bank.clone())
+}
+
+#[derive(Debug)]
+pub struct LajiModbus<F>
+where F: Factory
+{
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    cores: CoreList,
+    max_connections: usize,
+    factory: F
+}
+
+impl<F> LajiModbus<F>
+where
+    F: 'static + Factory + Clone + Send,
+    F::Bank: Send + 'static
+{
+    /// Serve until a listener thread cannot be pinned to its core. Each connection is
+    /// served on a thread of its own, up to `max_connections` at once; failed accepts and
+    /// connections that end in an error go to `on_error`.
+    pub fn run(self) -> io::Result<()> {
+        if self.max_connections == 0 {
+            return Err(ConfigError::Zero("max_connections").into());
+        }
+        let (err_tx, err_rx) = mpsc::channel();
+        let mut cores = self.cores;
+        let open = Arc::new(AtomicUsize::new(0));
+        for listener in self.tcp {
+            let err_tx = err_tx.clone();
+            let mut factory = self.factory.clone();
+            let open = open.clone();
+            let max_connections = self.max_connections;
+            let core = cores.next_core();
+            thread::spawn(move || {
+                if let Err(e) = affinity::pin_to(core) {
+                    let _ = err_tx.send(e);
+                    return;
+                }
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            // over the limit, the stream is closed before anything is read
+                            let slot = match Slot::take(&open, max_connections) {
+                                Some(slot) => slot,
+                                None => continue,
+                            };
+                            let bank = factory.connection_made();
+                            let mut reporter = factory.clone();
+                            // a client that resets or sends garbage only loses its own connection
+                            thread::spawn(move || {
+                                if let Err(e) = serve_connection(stream, bank) {
+                                    reporter.on_error(e);
+                                }
+                                drop(slot);
+                            });
+                        }
+                        Err(e) => factory.on_error(e),
+                    }
+                }
+            });
+        }
+        drop(err_tx);
+        match err_rx.recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// One of a server's `max_connections`, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        if open.fetch_add(1, Ordering::SeqCst) >= max {
+            open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(open.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection<B>(mut stream: TcpStream, mut bank: B) -> io::Result<()>
+where B: RegisterBank
+{
+    stream.set_nodelay(true)?;
+    let mut frame = [0u8; MAX_FRAME_LEN];
+    let mut response = [0u8; MAX_FRAME_LEN];
+    loop {
+        if !framing::read_record(&mut stream, &mut frame[..MBAP_LEN])? {
+            return Ok(());
+        }
+        let header = Header::decode(&frame[..MBAP_LEN])?;
+        let pdu = &mut frame[MBAP_LEN..MBAP_LEN + header.pdu_len()];
+        stream.read_exact(pdu)?;
+        let pdu_len = process(&mut bank, pdu, &mut response[MBAP_LEN..]);
+        header.encode(pdu_len, &mut response[..MBAP_LEN]);
+        stream.write_all(&response[..MBAP_LEN + pdu_len])?;
+    }
+}
+
+/// Answer one request PDU into `out`, returning the response PDU length.
+pub fn process<B>(bank: &mut B, pdu: &[u8], out: &mut [u8]) -> usize
+where B: RegisterBank
+{
+    let function = pdu.first().cloned().unwrap_or(0);
+    let ans = Request::decode(pdu).and_then(|request| respond(bank, &request, out));
+    match ans {
+        Ok(len) => len,
+        Err(exception) => {
+            out[0] = function | 0x80;
+            out[1] = exception as u8;
+            2
+        }
+    }
+}
+
+fn respond<B>(bank: &mut B, request: &Request, out: &mut [u8]) -> Result<usize, Exception>
+where B: RegisterBank
+{
+    let mut values = [0u16; MAX_READ_COUNT];
+    match *request {
+        Request::ReadHoldingRegisters { address, count } | Request::ReadInputRegisters { address, count } => {
+            let values = &mut values[..count as usize];
+            if let Request::ReadHoldingRegisters { .. } = request {
+                bank.read_holding(address, values)?;
+            } else {
+                bank.read_input(address, values)?;
+            }
+            out[0] = request.function();
+            out[1] = (values.len() * 2) as u8;
+            for (i, value) in values.iter().enumerate() {
+                out[2 + 2 * i..4 + 2 * i].copy_from_slice(&value.to_be_bytes());
+            }
+            Ok(2 + values.len() * 2)
+        }
+        Request::WriteSingleRegister { address, value } => {
+            bank.write_single(address, value)?;
+            Ok(request.encode(out).map_err(|_| Exception::ServerDeviceFailure)?)
+        }
+        Request::WriteMultipleRegisters { address, ref values } => {
+            bank.write_multiple(address, values)?;
+            out[0] = request.function();
+            out[1..3].copy_from_slice(&address.to_be_bytes());
+            out[3..5].copy_from_slice(&(values.len() as u16).to_be_bytes());
+            Ok(5)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Builder {
+    tcp: SmallVec<[TcpListener; INLINE_LISTENERS]>,
+    cores: CoreList,
+    max_connections: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self { tcp: SmallVec::new(), cores: CoreList::default(), max_connections: DEFAULT_MAX_CONNECTIONS }
+    }
+
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Builder>
+    where A: ToSocketAddrs
+    {
+        let new_listener = TcpListener::bind(addr)?;
+        self.tcp.push(new_listener);
+        Ok(self)
+    }
+
+    /// Bind the well-known Modbus port, 502, on every IPv4 address.
+    pub fn bind_default_ipv4(self) -> io::Result<Builder> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::MODBUS))
+    }
+
+    /// Bind the well-known Modbus port, 502, on every IPv6 address.
+    pub fn bind_default_ipv6(self) -> io::Result<Builder> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::MODBUS))
+    }
+
+    /// Pin listener threads to these cores, assigned round-robin in bind order.
+    pub fn cpu_affinity<I>(mut self, cores: I) -> Builder
+    where I: IntoIterator<Item = usize>
+    {
+        self.cores = CoreList::new(cores);
+        self
+    }
+
+    /// Serve at most `max` connections at once, each on a thread of its own, closing the
+    /// rest as soon as they are accepted; 256 by default.
+    pub fn max_connections(mut self, max: usize) -> Builder {
+        self.max_connections = max;
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tcp.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.max_connections == 0 {
+            return Err(ConfigError::Zero("max_connections"));
+        }
+        Ok(())
+    }
+
+    pub fn build<F>(self, factory: F) -> LajiModbus<F>
+    where F: Factory
+    {
+        LajiModbus {
+            tcp: self.tcp,
+            cores: self.cores,
+            max_connections: self.max_connections,
+            factory,
+        }
+    }
+}
+
+pub const FUNCTION_READ_HOLDING_REGISTERS: u8 = 3;
+pub const FUNCTION_READ_INPUT_REGISTERS: u8 = 4;
+pub const FUNCTION_WRITE_SINGLE_REGISTER: u8 = 6;
+pub const FUNCTION_WRITE_MULTIPLE_REGISTERS: u8 = 16;
+
+const MBAP_LEN: usize = 7;
+const MAX_PDU_LEN: usize = 253;
+const MAX_FRAME_LEN: usize = MBAP_LEN + MAX_PDU_LEN;
+const MAX_READ_COUNT: usize = 125;
+const MAX_WRITE_COUNT: usize = 123;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+}
+
+/// The MBAP header in front of every Modbus TCP PDU.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+struct Header {
+    transaction_id: u16,
+    length: u16,
+    unit_id: u8,
+}
+
+impl Header {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let protocol_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let length = u16::from_be_bytes([buf[4], buf[5]]);
+        // length counts the unit id as well as the pdu
+        if protocol_id != 0 || length < 2 || length as usize > MAX_PDU_LEN + 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad mbap header"));
+        }
+        Ok(Self { transaction_id: u16::from_be_bytes([buf[0], buf[1]]), length, unit_id: buf[6] })
+    }
+
+    #[inline]
+    fn pdu_len(&self) -> usize {
+        self.length as usize - 1
+    }
+
+    fn encode(&self, pdu_len: usize, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.transaction_id.to_be_bytes());
+        buf[2..4].copy_from_slice(&[0, 0]);
+        buf[4..6].copy_from_slice(&(pdu_len as u16 + 1).to_be_bytes());
+        buf[6] = self.unit_id;
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Request {
+    ReadHoldingRegisters { address: u16, count: u16 },
+    ReadInputRegisters { address: u16, count: u16 },
+    WriteSingleRegister { address: u16, value: u16 },
+    WriteMultipleRegisters { address: u16, values: Vec<u16> },
+}
+
+impl Request {
+    #[inline]
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::ReadHoldingRegisters { .. } => FUNCTION_READ_HOLDING_REGISTERS,
+            Request::ReadInputRegisters { .. } => FUNCTION_READ_INPUT_REGISTERS,
+            Request::WriteSingleRegister { .. } => FUNCTION_WRITE_SINGLE_REGISTER,
+            Request::WriteMultipleRegisters { .. } => FUNCTION_WRITE_MULTIPLE_REGISTERS,
+        }
+    }
+
+    pub fn decode(pdu: &[u8]) -> Result<Self, Exception> {
+        let word = |at: usize| pdu.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or(Exception::IllegalDataValue);
+        let function = *pdu.first().ok_or(Exception::IllegalFunction)?;
+        match function {
+            FUNCTION_READ_HOLDING_REGISTERS | FUNCTION_READ_INPUT_REGISTERS => {
+                let (address, count) = (word(1)?, word(3)?);
+                if count == 0 || count as usize > MAX_READ_COUNT {
+                    return Err(Exception::IllegalDataValue);
+                }
+                Ok(if function == FUNCTION_READ_HOLDING_REGISTERS {
+                    Request::ReadHoldingRegisters { address, count }
+                } else {
+                    Request::ReadInputRegisters { address, count }
+                })
+            }
+            FUNCTION_WRITE_SINGLE_REGISTER => Ok(Request::WriteSingleRegister { address: word(1)?, value: word(3)? }),
+            FUNCTION_WRITE_MULTIPLE_REGISTERS => {
+                let (address, count) = (word(1)?, word(3)? as usize);
+                let byte_count = *pdu.get(5).ok_or(Exception::IllegalDataValue)? as usize;
+                if count == 0 || count > MAX_WRITE_COUNT || byte_count != count * 2 || pdu.len() != 6 + byte_count {
+                    return Err(Exception::IllegalDataValue);
+                }
+                let values = (0..count).map(|i| word(6 + 2 * i)).collect::<Result<_, _>>()?;
+                Ok(Request::WriteMultipleRegisters { address, values })
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self {
+            Request::WriteMultipleRegisters { values, .. } => 6 + values.len() * 2,
+            _ => 5,
+        };
+        if buf.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "buffer too small for modbus request"));
+        }
+        let (address, second) = match *self {
+            Request::ReadHoldingRegisters { address, count } | Request::ReadInputRegisters { address, count } =>
+                (address, count),
+            Request::WriteSingleRegister { address, value } => (address, value),
+            Request::WriteMultipleRegisters { address, ref values } => (address, values.len() as u16),
+        };
+        buf[0] = self.function();
+        buf[1..3].copy_from_slice(&address.to_be_bytes());
+        buf[3..5].copy_from_slice(&second.to_be_bytes());
+        if let Request::WriteMultipleRegisters { values, .. } = self {
+            buf[5] = (values.len() * 2) as u8;
+            for (i, value) in values.iter().enumerate() {
+                buf[6 + 2 * i..8 + 2 * i].copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Where register reads and writes end up. Everything not overridden answers
+/// with an illegal function exception.
+pub trait RegisterBank {
+    fn read_holding(&mut self, _address: u16, _out: &mut [u16]) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn read_input(&mut self, _address: u16, _out: &mut [u16]) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn write_single(&mut self, _address: u16, _value: u16) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn write_multiple(&mut self, address: u16, values: &[u16]) -> Result<(), Exception> {
+        for (i, value) in values.iter().enumerate() {
+            self.write_single(address.wrapping_add(i as u16), *value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B> RegisterBank for Arc<Mutex<B>>
+where B: RegisterBank
+{
+    fn read_holding(&mut self, address: u16, out: &mut [u16]) -> Result<(), Exception> {
+        self.lock().map_err(|_| Exception::ServerDeviceFailure)?.read_holding(address, out)
+    }
+
+    fn read_input(&mut self, address: u16, out: &mut [u16]) -> Result<(), Exception> {
+        self.lock().map_err(|_| Exception::ServerDeviceFailure)?.read_input(address, out)
+    }
+
+    fn write_single(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        self.lock().map_err(|_| Exception::ServerDeviceFailure)?.write_single(address, value)
+    }
+
+    fn write_multiple(&mut self, address: u16, values: &[u16]) -> Result<(), Exception> {
+        self.lock().map_err(|_| Exception::ServerDeviceFailure)?.write_multiple(address, values)
+    }
+}
+
+/// Plain in-memory holding and input registers, both starting at address 0.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Memory {
+    pub holding: Vec<u16>,
+    pub input: Vec<u16>,
+}
+
+impl Memory {
+    #[inline]
+    pub fn new(holding_len: usize, input_len: usize) -> Self {
+        Self { holding: vec![0; holding_len], input: vec![0; input_len] }
+    }
+}
+
+#[inline]
+fn range(len: usize, address: u16, count: usize) -> Result<std::ops::Range<usize>, Exception> {
+    let start = address as usize;
+    if start + count > len {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(start..start + count)
+}
+
+impl RegisterBank for Memory {
+    fn read_holding(&mut self, address: u16, out: &mut [u16]) -> Result<(), Exception> {
+        out.copy_from_slice(&self.holding[range(self.holding.len(), address, out.len())?]);
+        Ok(())
+    }
+
+    fn read_input(&mut self, address: u16, out: &mut [u16]) -> Result<(), Exception> {
+        out.copy_from_slice(&self.input[range(self.input.len(), address, out.len())?]);
+        Ok(())
+    }
+
+    fn write_single(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        self.write_multiple(address, &[value])
+    }
+
+    fn write_multiple(&mut self, address: u16, values: &[u16]) -> Result<(), Exception> {
+        let range = range(self.holding.len(), address, values.len())?;
+        self.holding[range].copy_from_slice(values);
+        Ok(())
+    }
+}
+
+pub trait Factory {
+    type Bank: RegisterBank;
+
+    fn connection_made(&mut self) -> Self::Bank;
+
+    /// Accepting a connection failed, or serving one did, and the server carries on. Called
+    /// on a clone of the factory when the connection's own thread failed.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, B> Factory for F
+where B: RegisterBank, F: FnMut() -> B {
+    type Bank = B;
+
+    fn connection_made(&mut self) -> B {
+        self()
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Request {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let address = u.arbitrary()?;
+        Ok(match u.int_in_range(0..=3)? {
+            0 => Request::ReadHoldingRegisters { address, count: u.int_in_range(1..=MAX_READ_COUNT as u16)? },
+            1 => Request::ReadInputRegisters { address, count: u.int_in_range(1..=MAX_READ_COUNT as u16)? },
+            2 => Request::WriteSingleRegister { address, value: u.arbitrary()? },
+            _ => {
+                let count = u.int_in_range(1..=MAX_WRITE_COUNT)?;
+                let values = (0..count).map(|_| u.arbitrary()).collect::<Result<_, _>>()?;
+                Request::WriteMultipleRegisters { address, values }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_requests() {
+        assert_eq!(Request::decode(&[3, 0x00, 0x6b, 0x00, 0x03]),
+            Ok(Request::ReadHoldingRegisters { address: 0x6b, count: 3 }));
+        assert_eq!(Request::decode(&[16, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0a, 0x01, 0x02]),
+            Ok(Request::WriteMultipleRegisters { address: 1, values: vec![0x000a, 0x0102] }));
+        assert_eq!(Request::decode(&[3, 0x00, 0x00, 0x00, 0x00]), Err(Exception::IllegalDataValue));
+        assert_eq!(Request::decode(&[16, 0x00, 0x01, 0x00, 0x02, 0x03, 0x00]), Err(Exception::IllegalDataValue));
+        assert_eq!(Request::decode(&[43, 0x0e]), Err(Exception::IllegalFunction));
+        let request = Request::WriteMultipleRegisters { address: 7, values: vec![1, 2, 3] };
+        let mut buf = [0u8; MAX_PDU_LEN];
+        let len = request.encode(&mut buf).unwrap();
+        assert_eq!(Request::decode(&buf[..len]), Ok(request));
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_round_trip() {
+        crate::prop::for_all(|request: Request| {
+            let mut buf = [0u8; MAX_PDU_LEN];
+            let len = request.encode(&mut buf).unwrap();
+            assert_eq!(Request::decode(&buf[..len]), Ok(request));
+        });
+    }
+
+    #[test]
+    fn serve_loopback() -> io::Result<()> {
+        let mut memory = Memory::new(8, 2);
+        memory.input = vec![0x1234, 0x5678];
+        let builder = Builder::new().bind("127.0.0.1:0")?;
+        let addr = builder.local_addrs()?[0];
+        let bank = Arc::new(Mutex::new(memory));
+        let server = builder.build(move || bank.clone());
+        thread::spawn(move || server.run().unwrap());
+        let mut stream = TcpStream::connect(addr)?;
+        let mut exchange = |transaction_id: u16, pdu: &[u8]| -> io::Result<Vec<u8>> {
+            let mut frame = transaction_id.to_be_bytes().to_vec();
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            frame.push(0x11);
+            frame.extend_from_slice(pdu);
+            stream.write_all(&frame)?;
+            let mut header = [0u8; MBAP_LEN];
+            stream.read_exact(&mut header)?;
+            assert_eq!(&header[..2], &transaction_id.to_be_bytes());
+            assert_eq!(header[6], 0x11);
+            let mut pdu = vec![0u8; u16::from_be_bytes([header[4], header[5]]) as usize - 1];
+            stream.read_exact(&mut pdu)?;
+            Ok(pdu)
+        };
+        assert_eq!(exchange(1, &[16, 0, 2, 0, 2, 4, 0, 5, 0, 6])?, [16, 0, 2, 0, 2]);
+        assert_eq!(exchange(2, &[6, 0, 7, 0xbe, 0xef])?, [6, 0, 7, 0xbe, 0xef]);
+        assert_eq!(exchange(3, &[3, 0, 1, 0, 3])?, [3, 6, 0, 0, 0, 5, 0, 6]);
+        assert_eq!(exchange(4, &[4, 0, 0, 0, 2])?, [4, 4, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(exchange(5, &[4, 0, 1, 0, 2])?, [0x84, Exception::IllegalDataAddress as u8]);
+        assert_eq!(exchange(6, &[1, 0, 0, 0, 8])?, [0x81, Exception::IllegalFunction as u8]);
+        // a second connection sees the same registers
+        let mut other = TcpStream::connect(addr)?;
+        other.write_all(&[0, 9, 0, 0, 0, 6, 0x11, 3, 0, 7, 0, 1])?;
+        let mut reply = [0u8; MBAP_LEN + 4];
+        other.read_exact(&mut reply)?;
+        assert_eq!(&reply[MBAP_LEN..], &[3, 2, 0xbe, 0xef]);
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct Reporting(mpsc::Sender<io::ErrorKind>);
+
+    impl Factory for Reporting {
+        type Bank = Memory;
+
+        fn connection_made(&mut self) -> Memory {
+            Memory::new(1, 0)
+        }
+
+        fn on_error(&mut self, err: io::Error) {
+            let _ = self.0.send(err.kind());
+        }
+    }
+
+    #[test]
+    fn connections_capped_and_errors_reported() -> io::Result<()> {
+        let (errors, failed) = mpsc::channel();
+        let builder = Builder::new().bind("127.0.0.1:0")?.max_connections(1);
+        let addr = builder.local_addrs()?[0];
+        let server = builder.build(Reporting(errors));
+        thread::spawn(move || server.run());
+        let mut served = TcpStream::connect(addr)?;
+        served.write_all(&[0, 1, 0, 0, 0, 6, 0x11, 3, 0, 0, 0, 1])?;
+        let mut reply = [0u8; MBAP_LEN + 4];
+        served.read_exact(&mut reply)?;
+        // the one connection is open, so the next is closed unread
+        let mut refused = TcpStream::connect(addr)?;
+        assert!(matches!(refused.read(&mut reply), Ok(0) | Err(_)));
+        // a protocol id other than 0
+        served.write_all(&[0, 2, 0, 1, 0, 6, 0x11, 3, 0, 0, 0, 1])?;
+        assert_eq!(failed.recv_timeout(std::time::Duration::from_secs(2)), Ok(io::ErrorKind::InvalidData));
+        drop(served);
+        assert_eq!(Builder::new().validate(), Err(ConfigError::NoListeners));
+        assert_eq!(Builder::new().bind("127.0.0.1:0")?.max_connections(0).validate(),
+            Err(ConfigError::Zero("max_connections")));
+        Ok(())
+    }
+}
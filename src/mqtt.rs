@@ -0,0 +1,362 @@
+use std::{
+    io::{self, Read, Write},
+    net::ToSocketAddrs,
+    time::{Duration, Instant},
+};
+use crate::{resolve, socks5::{self, Proxy}, wire::invalid_data};
+
+/// CONNECT to a broker with the default `Probe` settings, ping it once and disconnect.
+pub fn probe<A>(addr: A, client_id: &str) -> io::Result<Report>
+where
+    A: ToSocketAddrs
+{
+    Probe::new(client_id).run(addr)
+}
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_USERNAME: u8 = 0x80;
+
+// CONNACK and PINGRESP are tiny; anything bigger is only v5 properties we skip
+const MAX_PACKET_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Version {
+    /// MQTT 3.1.1, protocol level 4.
+    V311,
+    /// MQTT 5.0, protocol level 5.
+    V5,
+}
+
+impl Version {
+    #[inline]
+    fn level(self) -> u8 {
+        match self {
+            Version::V311 => 4,
+            Version::V5 => 5,
+        }
+    }
+}
+
+/// What the broker said to our CONNECT.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct ConnAck {
+    version: Version,
+    session_present: bool,
+    reason_code: u8,
+}
+
+impl ConnAck {
+    #[inline]
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// The 3.1.1 return code or the v5 reason code; zero means accepted for both.
+    #[inline]
+    pub fn reason_code(&self) -> u8 {
+        self.reason_code
+    }
+
+    #[inline]
+    pub fn accepted(&self) -> bool {
+        self.reason_code == 0
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match (self.version, self.reason_code) {
+            (_, 0x00) => "accepted",
+            (Version::V311, 0x01) => "unacceptable protocol version",
+            (Version::V311, 0x02) => "identifier rejected",
+            (Version::V311, 0x03) => "server unavailable",
+            (Version::V311, 0x04) => "bad user name or password",
+            (Version::V311, 0x05) => "not authorized",
+            (Version::V5, 0x80) => "unspecified error",
+            (Version::V5, 0x81) => "malformed packet",
+            (Version::V5, 0x82) => "protocol error",
+            (Version::V5, 0x83) => "implementation specific error",
+            (Version::V5, 0x84) => "unsupported protocol version",
+            (Version::V5, 0x85) => "client identifier not valid",
+            (Version::V5, 0x86) => "bad user name or password",
+            (Version::V5, 0x87) => "not authorized",
+            (Version::V5, 0x88) => "server unavailable",
+            (Version::V5, 0x89) => "server busy",
+            (Version::V5, 0x8a) => "banned",
+            (Version::V5, 0x8c) => "bad authentication method",
+            (Version::V5, 0x9c) => "use another server",
+            (Version::V5, 0x9d) => "server moved",
+            (Version::V5, 0x9f) => "connection rate exceeded",
+            _ => "unknown reason",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Report {
+    connack: ConnAck,
+    connect_time: Duration,
+    ping_rtt: Option<Duration>,
+}
+
+impl Report {
+    #[inline]
+    pub fn connack(&self) -> &ConnAck {
+        &self.connack
+    }
+
+    /// From starting the TCP connect until the CONNACK arrived.
+    #[inline]
+    pub fn connect_time(&self) -> Duration {
+        self.connect_time
+    }
+
+    /// `None` if the broker refused the connection, so no ping was sent.
+    #[inline]
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.ping_rtt
+    }
+
+    /// Accepted and answered the ping.
+    #[inline]
+    pub fn healthy(&self) -> bool {
+        self.connack.accepted() && self.ping_rtt.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Probe {
+    version: Version,
+    client_id: String,
+    credentials: Option<(String, Vec<u8>)>,
+    keep_alive: u16,
+    timeout: Duration,
+    proxy: Option<Proxy>,
+}
+
+impl Probe {
+    #[inline]
+    pub fn new(client_id: &str) -> Self {
+        Self {
+            version: Version::V311,
+            client_id: client_id.to_string(),
+            credentials: None,
+            keep_alive: 30,
+            timeout: Duration::from_secs(5),
+            proxy: None,
+        }
+    }
+
+    #[inline]
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    #[inline]
+    pub fn credentials(mut self, username: &str, password: &[u8]) -> Self {
+        self.credentials = Some((username.to_string(), password.to_vec()));
+        self
+    }
+
+    /// Keep-alive interval announced in CONNECT, in seconds.
+    #[inline]
+    pub fn keep_alive(mut self, secs: u16) -> Self {
+        self.keep_alive = secs;
+        self
+    }
+
+    /// Limit for the TCP connect and for each answer from the broker.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Reach the broker through a SOCKS5 proxy.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn run<A>(&self, addr: A) -> io::Result<Report>
+    where
+        A: ToSocketAddrs
+    {
+        let start = Instant::now();
+        let mut stream = socks5::connect_to(self.proxy.as_ref(), addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&self.encode_connect()?)?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = read_packet(&mut stream, CONNACK, &mut buf).map_err(resolve::timed_out)?;
+        let connack = decode_connack(self.version, &buf[..len])?;
+        let connect_time = start.elapsed();
+        if !connack.accepted() {
+            return Ok(Report { connack, connect_time, ping_rtt: None });
+        }
+        let ping_start = Instant::now();
+        stream.write_all(&[PINGREQ, 0])?;
+        read_packet(&mut stream, PINGRESP, &mut buf).map_err(resolve::timed_out)?;
+        let ping_rtt = ping_start.elapsed();
+        // best effort, the answer we wanted is already in
+        let _ = stream.write_all(&[DISCONNECT, 0]);
+        Ok(Report { connack, connect_time, ping_rtt: Some(ping_rtt) })
+    }
+
+    fn encode_connect(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        push_string(&mut body, b"MQTT")?;
+        body.push(self.version.level());
+        let mut flags = FLAG_CLEAN_SESSION;
+        if self.credentials.is_some() {
+            flags |= FLAG_USERNAME | FLAG_PASSWORD;
+        }
+        body.push(flags);
+        body.extend_from_slice(&self.keep_alive.to_be_bytes());
+        if self.version == Version::V5 {
+            // no properties
+            body.push(0);
+        }
+        push_string(&mut body, self.client_id.as_bytes())?;
+        if let Some((username, password)) = &self.credentials {
+            push_string(&mut body, username.as_bytes())?;
+            push_string(&mut body, password)?;
+        }
+        let mut packet = vec![CONNECT];
+        push_remaining_length(&mut packet, body.len())?;
+        packet.extend_from_slice(&body);
+        Ok(packet)
+    }
+}
+
+#[inline]
+fn push_string(out: &mut Vec<u8>, s: &[u8]) -> io::Result<()> {
+    if s.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "mqtt string longer than 65535 bytes"));
+    }
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+    Ok(())
+}
+
+/// The variable byte integer in front of every packet body: 7 bits per byte, low bits first.
+fn push_remaining_length(out: &mut Vec<u8>, mut len: usize) -> io::Result<()> {
+    if len > 268_435_455 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "mqtt packet too long"));
+    }
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn read_remaining_length<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut len = 0;
+    for shift in (0..4).map(|i| i * 7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(invalid_data("mqtt remaining length longer than 4 bytes"))
+}
+
+/// Read one packet of type `expected` into `buf`, returning its body length.
+fn read_packet<R: Read>(reader: &mut R, expected: u8, buf: &mut [u8]) -> io::Result<usize> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    if kind[0] & 0xf0 != expected {
+        if kind[0] & 0xf0 == DISCONNECT {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "broker sent DISCONNECT"));
+        }
+        return Err(invalid_data("unexpected mqtt packet type"));
+    }
+    let len = read_remaining_length(reader)?;
+    if len > buf.len() {
+        return Err(invalid_data("mqtt packet too long"));
+    }
+    reader.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+fn decode_connack(version: Version, body: &[u8]) -> io::Result<ConnAck> {
+    // v5 brokers may put properties after these two bytes
+    if body.len() < 2 {
+        return Err(invalid_data("mqtt connack too short"));
+    }
+    Ok(ConnAck { version, session_present: body[0] & 0x01 != 0, reason_code: body[1] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn encode_connect() {
+        let packet = Probe::new("laji").keep_alive(60).encode_connect().unwrap();
+        assert_eq!(packet, [
+            0x10, 16, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 4, b'l', b'a', b'j', b'i',
+        ]);
+        let packet = Probe::new("").version(Version::V5).credentials("u", b"p").encode_connect().unwrap();
+        assert_eq!(&packet[8..13], &[5, 0xc2, 0, 30, 0]);
+        assert_eq!(&packet[13..], &[0, 0, 0, 1, b'u', 0, 1, b'p']);
+    }
+
+    #[test]
+    fn remaining_length() {
+        for &len in &[0, 127, 128, 16_383, 16_384, 268_435_455] {
+            let mut buf = Vec::new();
+            push_remaining_length(&mut buf, len).unwrap();
+            assert_eq!(read_remaining_length(&mut &buf[..]).unwrap(), len);
+        }
+        let mut buf = Vec::new();
+        push_remaining_length(&mut buf, 321).unwrap();
+        assert_eq!(buf, [0xc1, 0x02]);
+    }
+
+    fn fake_broker(reason_code: u8) -> io::Result<std::net::SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            read_packet(&mut stream, CONNECT, &mut buf).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, reason_code]).unwrap();
+            if reason_code == 0 {
+                read_packet(&mut stream, PINGREQ, &mut buf).unwrap();
+                stream.write_all(&[PINGRESP, 0]).unwrap();
+                read_packet(&mut stream, DISCONNECT, &mut buf).unwrap();
+            }
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn probe_loopback() -> io::Result<()> {
+        let report = probe(fake_broker(0)?, "laji")?;
+        assert!(report.healthy());
+        assert_eq!(report.connack().reason(), "accepted");
+        let report = probe(fake_broker(5)?, "laji")?;
+        assert!(!report.healthy());
+        assert_eq!(report.ping_rtt(), None);
+        assert_eq!(report.connack().reason(), "not authorized");
+        Ok(())
+    }
+}
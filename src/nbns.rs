@@ -0,0 +1,418 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+use crate::{random::random, wire::invalid_data};
+
+pub const PORT: u16 = crate::ports::NBNS;
+
+/// Broadcast a name query on the local network and collect every address that answers.
+pub fn resolve(name: &str, suffix: u8, wait: Duration) -> io::Result<Vec<Ipv4Addr>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    let question = Question::Name(Name::new(name, suffix)?);
+    let mut ans = Vec::new();
+    query(&socket, SocketAddrV4::new(Ipv4Addr::BROADCAST, PORT), &question, wait, &mut |_, response: &Response| {
+        if let Some(Answer::Addresses { addresses, .. }) = response.answer() {
+            ans.extend(addresses.iter().map(|&(_, addr)| addr));
+        }
+    })?;
+    Ok(ans)
+}
+
+/// Ask one host for its name table and MAC address.
+pub fn node_status(host: Ipv4Addr, wait: Duration) -> io::Result<NodeStatus> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let mut ans = None;
+    query(&socket, SocketAddrV4::new(host, PORT), &Question::NodeStatus, wait, &mut |_, response: &Response| {
+        if let Some(Answer::NodeStatus(status)) = response.answer() {
+            ans.get_or_insert_with(|| status.clone());
+        }
+    })?;
+    ans.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no node status response"))
+}
+
+/// Send `question` to `target` once, then hand every matching response that
+/// arrives within `wait` to the handler. Returns how many there were.
+///
+/// Broadcast queries can be answered by several hosts, so this always waits the
+/// full duration. The query carries the broadcast flag when `socket` has
+/// `SO_BROADCAST` set.
+pub fn query<A, H>(socket: &UdpSocket, target: A, question: &Question, wait: Duration, handler: &mut H) -> io::Result<usize>
+where
+    A: ToSocketAddrs,
+    H: Handler
+{
+    let target = target.to_socket_addrs()?.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+    let id: u16 = random();
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    let len = question.encode(id, socket.broadcast()?, &mut buf)?;
+    let old_timeout = socket.read_timeout()?;
+    socket.send_to(&buf[..len], target)?;
+    let ans = collect(socket, id, Instant::now() + wait, handler);
+    socket.set_read_timeout(old_timeout)?;
+    ans
+}
+
+fn collect<H>(socket: &UdpSocket, id: u16, deadline: Instant, handler: &mut H) -> io::Result<usize>
+where
+    H: Handler
+{
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    let mut count = 0;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(count);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(ans) => ans,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => return Ok(count),
+            Err(e) => return Err(e),
+        };
+        match Response::decode(&buf[..len]) {
+            Ok(ref response) if response.transaction_id() == id => {
+                count += 1;
+                handler.on_response(from, response);
+            }
+            _ => continue,
+        }
+    }
+}
+
+pub trait Handler {
+    fn on_response(&mut self, from: SocketAddr, response: &Response);
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(SocketAddr, &Response)
+{
+    #[inline]
+    fn on_response(&mut self, from: SocketAddr, response: &Response) {
+        self(from, response)
+    }
+}
+
+const MAX_PACKET_LEN: usize = 576;
+const HEADER_LEN: usize = 12;
+const ENCODED_NAME_LEN: usize = 34;
+const NAME_LEN: usize = 15;
+
+const TYPE_NB: u16 = 0x0020;
+const TYPE_NBSTAT: u16 = 0x0021;
+const CLASS_IN: u16 = 0x0001;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_BROADCAST: u16 = 0x0010;
+
+/// A NetBIOS name: up to 15 characters plus the suffix byte naming the service
+/// (0x00 workstation, 0x20 file server, ...).
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Name {
+    name: String,
+    suffix: u8,
+}
+
+impl Name {
+    pub fn new(name: &str, suffix: u8) -> io::Result<Self> {
+        if name.len() > NAME_LEN || !name.is_ascii() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "netbios names are at most 15 ascii characters"));
+        }
+        Ok(Self { name: name.to_ascii_uppercase(), suffix })
+    }
+
+    #[inline]
+    fn wildcard() -> Self {
+        Self { name: "*".to_string(), suffix: 0 }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn suffix(&self) -> u8 {
+        self.suffix
+    }
+
+    /// Padded to 16 bytes; the wildcard name is padded with NULs instead of spaces.
+    fn raw(&self) -> [u8; 16] {
+        let pad = if self.name == "*" { 0 } else { b' ' };
+        let mut raw = [pad; 16];
+        raw[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        raw[15] = self.suffix;
+        raw
+    }
+
+    #[inline]
+    fn from_raw(raw: &[u8]) -> Self {
+        let name = String::from_utf8_lossy(&raw[..NAME_LEN]);
+        Self {
+            name: name.trim_end_matches([' ', '\0']).to_string(),
+            suffix: raw[NAME_LEN],
+        }
+    }
+
+    /// RFC 1001 first-level encoding: each nibble becomes a letter from 'A' to 'P'.
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = 32;
+        for (i, byte) in self.raw().iter().enumerate() {
+            buf[1 + 2 * i] = b'A' + (byte >> 4);
+            buf[2 + 2 * i] = b'A' + (byte & 0x0f);
+        }
+        buf[33] = 0;
+    }
+
+    /// Decode the name at `offset`, following one compression pointer; returns the name and the offset after it.
+    fn decode(packet: &[u8], offset: usize) -> io::Result<(Self, usize)> {
+        let first = *packet.get(offset).ok_or_else(|| invalid_data("netbios name truncated"))?;
+        if first & 0xc0 == 0xc0 {
+            let low = *packet.get(offset + 1).ok_or_else(|| invalid_data("netbios name truncated"))?;
+            let target = ((first as usize & 0x3f) << 8) | low as usize;
+            let (name, _) = Self::decode_labels(packet, target)?;
+            return Ok((name, offset + 2));
+        }
+        Self::decode_labels(packet, offset)
+    }
+
+    fn decode_labels(packet: &[u8], offset: usize) -> io::Result<(Self, usize)> {
+        let encoded = packet.get(offset..offset + ENCODED_NAME_LEN).ok_or_else(|| invalid_data("netbios name truncated"))?;
+        if encoded[0] != 32 || encoded[33] != 0 {
+            // scope ids would show up as extra labels here; nobody uses them
+            return Err(invalid_data("unsupported netbios name"));
+        }
+        let mut raw = [0u8; 16];
+        for (i, pair) in encoded[1..33].chunks(2).enumerate() {
+            let (high, low) = (pair[0].wrapping_sub(b'A'), pair[1].wrapping_sub(b'A'));
+            if high > 0x0f || low > 0x0f {
+                return Err(invalid_data("bad netbios name encoding"));
+            }
+            raw[i] = (high << 4) | low;
+        }
+        Ok((Self::from_raw(&raw), offset + ENCODED_NAME_LEN))
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Question {
+    /// Who has this name? Answered with addresses.
+    Name(Name),
+    /// What names do you have? Answered with the node's name table.
+    NodeStatus,
+}
+
+impl Question {
+    pub fn encode(&self, transaction_id: u16, broadcast: bool, buf: &mut [u8]) -> io::Result<usize> {
+        let len = HEADER_LEN + ENCODED_NAME_LEN + 4;
+        if buf.len() < len {
+            return Err(invalid_data("buffer too small for netbios query"));
+        }
+        let (name, qtype, mut flags) = match self {
+            Question::Name(name) => (name.clone(), TYPE_NB, FLAG_RECURSION_DESIRED),
+            Question::NodeStatus => (Name::wildcard(), TYPE_NBSTAT, 0),
+        };
+        if broadcast {
+            flags |= FLAG_BROADCAST;
+        }
+        buf[0..2].copy_from_slice(&transaction_id.to_be_bytes());
+        buf[2..4].copy_from_slice(&flags.to_be_bytes());
+        buf[4..6].copy_from_slice(&1u16.to_be_bytes());
+        buf[6..12].copy_from_slice(&[0; 6]);
+        name.encode(&mut buf[HEADER_LEN..]);
+        let rest = HEADER_LEN + ENCODED_NAME_LEN;
+        buf[rest..rest + 2].copy_from_slice(&qtype.to_be_bytes());
+        buf[rest + 2..rest + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+        Ok(len)
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct NodeStatus {
+    names: Vec<(Name, u16)>,
+    mac: [u8; 6],
+}
+
+impl NodeStatus {
+    /// Each registered name with its name flags (group bit, node type, state).
+    #[inline]
+    pub fn names(&self) -> &[(Name, u16)] {
+        &self.names
+    }
+
+    #[inline]
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Answer {
+    /// Owners of a name, each with its flags (group bit and node type).
+    Addresses { name: Name, ttl: u32, addresses: Vec<(u16, Ipv4Addr)> },
+    NodeStatus(NodeStatus),
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Response {
+    transaction_id: u16,
+    rcode: u8,
+    answer: Option<Answer>,
+}
+
+impl Response {
+    #[inline]
+    pub fn transaction_id(&self) -> u16 {
+        self.transaction_id
+    }
+
+    /// Zero for a positive response.
+    #[inline]
+    pub fn rcode(&self) -> u8 {
+        self.rcode
+    }
+
+    #[inline]
+    pub fn answer(&self) -> Option<&Answer> {
+        self.answer.as_ref()
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            return Err(invalid_data("netbios packet too short"));
+        }
+        let word = |at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+        let flags = word(2);
+        if flags & FLAG_RESPONSE == 0 {
+            return Err(invalid_data("netbios packet is not a response"));
+        }
+        let (questions, answers) = (word(4), word(6));
+        let mut offset = HEADER_LEN;
+        for _ in 0..questions {
+            offset = Name::decode(buf, offset)?.1 + 4;
+        }
+        let answer = if answers == 0 {
+            None
+        } else {
+            Some(decode_record(buf, offset)?)
+        };
+        Ok(Self { transaction_id: word(0), rcode: (flags & 0x000f) as u8, answer })
+    }
+}
+
+fn decode_record(buf: &[u8], offset: usize) -> io::Result<Answer> {
+    let (name, offset) = Name::decode(buf, offset)?;
+    let fixed = buf.get(offset..offset + 10).ok_or_else(|| invalid_data("netbios record truncated"))?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    let rdata = buf.get(offset + 10..offset + 10 + rdlength).ok_or_else(|| invalid_data("netbios record truncated"))?;
+    match rtype {
+        TYPE_NB => {
+            let addresses = rdata.chunks_exact(6)
+                .map(|entry| (u16::from_be_bytes([entry[0], entry[1]]),
+                    Ipv4Addr::new(entry[2], entry[3], entry[4], entry[5])))
+                .collect();
+            Ok(Answer::Addresses { name, ttl, addresses })
+        }
+        TYPE_NBSTAT => {
+            let count = *rdata.first().ok_or_else(|| invalid_data("node status truncated"))? as usize;
+            let table = rdata.get(1..1 + count * 18).ok_or_else(|| invalid_data("node status truncated"))?;
+            let names = table.chunks_exact(18)
+                .map(|entry| (Name::from_raw(&entry[..16]), u16::from_be_bytes([entry[16], entry[17]])))
+                .collect();
+            let mac_bytes = rdata.get(1 + count * 18..1 + count * 18 + 6).ok_or_else(|| invalid_data("node status truncated"))?;
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(mac_bytes);
+            Ok(Answer::NodeStatus(NodeStatus { names, mac }))
+        }
+        _ => Err(invalid_data("unexpected netbios record type")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn first_level_encoding() {
+        // the example from RFC 1001, section 14.1
+        let mut buf = [0u8; ENCODED_NAME_LEN];
+        Name::new("fred", 0x20).unwrap().encode(&mut buf);
+        assert_eq!(&buf[1..33], &b"EGFCEFEECACACACACACACACACACACACA"[..]);
+        let (name, end) = Name::decode(&buf, 0).unwrap();
+        assert_eq!((name.name(), name.suffix(), end), ("FRED", 0x20, ENCODED_NAME_LEN));
+        assert!(Name::new("a-name-that-is-too-long", 0).is_err());
+    }
+
+    fn node_status_response(id: u16) -> Vec<u8> {
+        let mut ans = vec![0; HEADER_LEN];
+        ans[0..2].copy_from_slice(&id.to_be_bytes());
+        ans[2..4].copy_from_slice(&0x8400u16.to_be_bytes());
+        ans[6..8].copy_from_slice(&1u16.to_be_bytes());
+        let mut name = [0u8; ENCODED_NAME_LEN];
+        Name::wildcard().encode(&mut name);
+        ans.extend_from_slice(&name);
+        ans.extend_from_slice(&[0x00, 0x21, 0x00, 0x01, 0, 0, 0, 0, 0x00, 0x2b]);
+        ans.push(2);
+        ans.extend_from_slice(b"LAJI           \x00\x04\x00");
+        ans.extend_from_slice(b"WORKGROUP      \x00\x84\x00");
+        ans.extend_from_slice(&[0x02, 0x42, 0xac, 0x11, 0x00, 0x02]);
+        ans
+    }
+
+    #[test]
+    fn decode_node_status() {
+        let response = Response::decode(&node_status_response(7)).unwrap();
+        assert_eq!(response.transaction_id(), 7);
+        assert_eq!(response.rcode(), 0);
+        let status = match response.answer() {
+            Some(Answer::NodeStatus(status)) => status,
+            other => panic!("unexpected answer {:?}", other),
+        };
+        assert_eq!(status.names(), &[
+            (Name::new("LAJI", 0).unwrap(), 0x0400),
+            (Name::new("WORKGROUP", 0).unwrap(), 0x8400),
+        ][..]);
+        assert_eq!(status.mac(), [0x02, 0x42, 0xac, 0x11, 0x00, 0x02]);
+        assert!(Response::decode(&node_status_response(7)[..60]).is_err());
+    }
+
+    #[test]
+    fn query_loopback() -> io::Result<()> {
+        let responder = UdpSocket::bind("127.0.0.1:0")?;
+        let target = responder.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            let (len, from) = responder.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 50);
+            // echo the question back as a positive name query response
+            let mut reply = buf[..HEADER_LEN + ENCODED_NAME_LEN].to_vec();
+            reply[2..4].copy_from_slice(&0x8500u16.to_be_bytes());
+            reply[4..8].copy_from_slice(&[0, 0, 0, 1]);
+            reply.extend_from_slice(&[0x00, 0x20, 0x00, 0x01, 0, 0, 0x0e, 0x10, 0x00, 0x06]);
+            reply.extend_from_slice(&[0x00, 0x00, 192, 168, 1, 42]);
+            responder.send_to(&reply, from).unwrap();
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let question = Question::Name(Name::new("laji", 0x20)?);
+        let mut seen = Vec::new();
+        let count = query(&socket, target, &question, Duration::from_millis(300), &mut |from, response: &Response| {
+            seen.push((from, response.answer().cloned()));
+        })?;
+        assert_eq!(count, 1);
+        assert_eq!(seen, [(target, Some(Answer::Addresses {
+            name: Name::new("LAJI", 0x20)?,
+            ttl: 3600,
+            addresses: vec![(0, Ipv4Addr::new(192, 168, 1, 42))],
+        }))]);
+        Ok(())
+    }
+}
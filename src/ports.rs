@@ -0,0 +1,30 @@
+//! IANA-assigned ports of the protocols in this crate.
+//!
+//! Ports below 1024 need root or `CAP_NET_BIND_SERVICE` to bind on most systems.
+
+pub const ECHO: u16 = 7;
+pub const DISCARD: u16 = 9;
+pub const DAYTIME: u16 = 13;
+pub const QOTD: u16 = 17;
+pub const CHARGEN: u16 = 19;
+pub const TIME: u16 = 37;
+pub const DHCP_SERVER: u16 = 67;
+pub const DHCP_CLIENT: u16 = 68;
+pub const GOPHER: u16 = 70;
+pub const FINGER: u16 = 79;
+pub const NTP: u16 = 123;
+pub const NBNS: u16 = 137;
+pub const MODBUS: u16 = 502;
+pub const MQTT: u16 = 1883;
+pub const STUN: u16 = 3478;
+pub const IRC: u16 = 6667;
+/// Where traceroute probes start counting up from.
+pub const TRACEROUTE: u16 = 33434;
+/// Bedrock edition servers, whose unconnected pings rakping speaks.
+pub const RAKNET: u16 = 19132;
+/// Java edition servers, as queried by mcping.
+pub const MINECRAFT: u16 = 25565;
+
+/// The simple services that answer any datagram. Nothing arriving from these ports is
+/// answered, so two such servers cannot be set to bounce datagrams at each other forever.
+pub const REFLECTION_PORTS: [u16; 4] = [ECHO, DAYTIME, CHARGEN, TIME];
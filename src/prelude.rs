@@ -0,0 +1,200 @@
+//! The traits and builders most servers and clients need, in one import.
+//!
+//! ```ignore
+//! use laji_protocols::prelude::*;
+//! ```
+//!
+//! Every protocol calls its traits `Factory` and `Handler`, so here they carry the protocol's
+//! name: `discard_mio::Handler` is `MioDiscardHandler`, `daytime_threads::Sender` is
+//! `DaytimeSender`. Only what the enabled features build is exported.
+pub use crate::{clock::Clock, config::ConfigError, server::{ServerHandle, Stopper}, virtnet::Datagram};
+pub use crate::reconnect::{
+    Events as ReconnectEvents,
+    Reconnecting,
+    Session as ReconnectSession,
+};
+pub use crate::srv::Resolver as SrvResolver;
+pub use crate::socks5::Proxy as Socks5Proxy;
+
+#[cfg(feature = "echo")]
+pub use crate::echo::{
+    Factory as EchoFactory,
+    Handler as EchoHandler,
+    LajiEcho,
+    Prober as EchoProber,
+    ProbeStats as EchoProbeStats,
+};
+
+#[cfg(feature = "echo")]
+pub use crate::echo_sync::{
+    Builder as SyncEchoBuilder,
+    Factory as SyncEchoFactory,
+    Handler as SyncEchoHandler,
+    Handshake as SyncEchoHandshake,
+    Sender as SyncEchoSender,
+};
+
+#[cfg(all(feature = "echo", feature = "backend-mio"))]
+pub use crate::echo_mio::{
+    Builder as MioEchoBuilder,
+    Factory as MioEchoFactory,
+    Handler as MioEchoHandler,
+    Handshake as MioEchoHandshake,
+    Sender as MioEchoSender,
+};
+
+#[cfg(feature = "chargen")]
+pub use crate::chargen::{
+    Client as ChargenClient,
+    Factory as ChargenFactory,
+    Flood,
+    Handler as ChargenHandler,
+    LajiChargen,
+    Report as ChargenReport,
+    Verifier as ChargenVerifier,
+};
+
+#[cfg(feature = "discard")]
+pub use crate::discard_sync::{
+    Blast as DiscardBlast,
+    BlastReport as DiscardBlastReport,
+    Builder as SyncDiscardBuilder,
+    Factory as SyncDiscardFactory,
+    Handler as SyncDiscardHandler,
+    Handshake as SyncDiscardHandshake,
+    Sender as DiscardSender,
+};
+
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+pub use crate::{
+    discard_mio::{
+        Builder as MioDiscardBuilder,
+        CloseReason,
+        ConnectionInfo,
+        Factory as MioDiscardFactory,
+        Handler as MioDiscardHandler,
+        Handshake as MioDiscardHandshake,
+        ListenerConfig,
+        RejectReason,
+    },
+    middleware::{FactoryExt, HandlerExt},
+};
+
+#[cfg(all(feature = "discard", feature = "backend-tokio"))]
+pub use crate::discard_tokio::{
+    AsyncFactory as TokioDiscardAsyncFactory,
+    AsyncHandler as TokioDiscardAsyncHandler,
+    Builder as TokioDiscardBuilder,
+    Factory as TokioDiscardFactory,
+    Handler as TokioDiscardHandler,
+    Handshake as TokioDiscardHandshake,
+};
+
+#[cfg(feature = "daytime")]
+pub use crate::daytime_threads::{
+    Client as DaytimeClient,
+    ClientHandler as DaytimeClientHandler,
+    Factory as DaytimeFactory,
+    Handler as DaytimeHandler,
+    Handshake as DaytimeHandshake,
+    LajiDaytime,
+    Locale as DaytimeLocale,
+    Sender as DaytimeSender,
+    SharedSender as DaytimeSharedSender,
+    TimeFormat,
+};
+
+#[cfg(feature = "daytime")]
+pub use crate::drift::Poller as DriftPoller;
+
+#[cfg(feature = "daytime")]
+pub use crate::announce::{Announcer as TimeAnnouncer, Listener as TimeListener};
+
+pub use crate::beacon::{Advertiser as BeaconAdvertiser, Beacon, Discovery as BeaconDiscovery};
+
+#[cfg(feature = "rakping")]
+pub use crate::rakping::{
+    Advertiser as RakPingAdvertiser,
+    BedrockMotd,
+    Factory as RakPingFactory,
+    Handler as RakPingHandler,
+    Motd as RakPingMotd,
+    Pinger as RakPinger,
+    PingKind,
+    RetryPolicy,
+    Sender as RakPingSender,
+    Throttle as RakPingThrottle,
+};
+
+#[cfg(all(feature = "rakping", feature = "backend-mio"))]
+pub use crate::rakping_mio::{Builder as MioRakPingBuilder, Responder as RakPingResponder};
+
+#[cfg(feature = "stun")]
+pub use crate::stun::{
+    Factory as StunFactory,
+    Handler as StunHandler,
+    LajiStun,
+};
+
+#[cfg(feature = "holepunch")]
+pub use crate::holepunch::{
+    Handler as HolePunchHandler,
+    Puncher as HolePuncher,
+    Rendezvous,
+};
+
+#[cfg(feature = "nbns")]
+pub use crate::nbns::Handler as NbnsHandler;
+
+#[cfg(feature = "dhcp")]
+pub use crate::dhcp::Handler as DhcpHandler;
+
+#[cfg(feature = "modbus")]
+pub use crate::modbus_tcp::{
+    Builder as ModbusBuilder,
+    Factory as ModbusFactory,
+    RegisterBank,
+};
+
+#[cfg(feature = "irc")]
+pub use crate::irc::{
+    Handler as IrcHandler,
+    Sender as IrcSender,
+};
+
+#[cfg(feature = "kcp")]
+pub use crate::kcp::{
+    Config as KcpConfig,
+    Kcp,
+    KcpStream,
+};
+
+#[cfg(feature = "simtcp")]
+pub use crate::simtcp::{
+    Connector as SimTcpConnector,
+    Factory as SimTcpFactory,
+    Handler as SimTcpHandler,
+    Handshake as SimTcpHandshake,
+    LajiSimTcp,
+    Observer as SimTcpObserver,
+    Oversize as SimTcpOversize,
+    Stats as SimTcpStats,
+    Stream as SimTcpStream,
+    Trace as SimTcpTrace,
+};
+#[cfg(all(feature = "simtcp", feature = "backend-tokio"))]
+pub use crate::simtcp::tokio::SimTcpStream as TokioSimTcpStream;
+
+#[cfg(feature = "gopher")]
+pub use crate::gopher::{
+    Client as GopherClient,
+    Item as GopherItem,
+    ItemType as GopherItemType,
+    Menu as GopherMenu,
+};
+
+#[cfg(feature = "icmp")]
+pub use crate::{
+    icmp::Handler as IcmpHandler,
+    traceroute::Handler as TracerouteHandler,
+};
@@ -0,0 +1,98 @@
+//! Renders a status page's recorders in the Prometheus text exposition format, for the admin
+//! listener's `/metrics` or for a host application to serve itself.
+use std::fmt::Write;
+use crate::{admin::Status, metrics::Recorder};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// A per-listener series: name, metric type, help text and how to read it off a recorder.
+type Series = (&'static str, &'static str, &'static str, fn(&Recorder) -> u64);
+
+pub fn render(status: &Status) -> String {
+    let mut out = String::new();
+    header(&mut out, "laji_uptime_seconds", "gauge", "Seconds since the status page was created.");
+    let _ = writeln!(out, "laji_uptime_seconds {}", status.uptime().as_secs());
+
+    let counters: [Series; 5] = [
+        ("laji_connections_opened_total", "counter", "Connections accepted.", Recorder::opened),
+        ("laji_connections_closed_total", "counter", "Connections closed.", Recorder::closed),
+        ("laji_connections_rejected_total", "counter", "Connections refused before being served.", Recorder::rejected),
+        ("laji_connections_active", "gauge", "Connections open right now.", Recorder::active),
+        ("laji_received_bytes_total", "counter", "Bytes read from connections.", Recorder::total_bytes_received),
+    ];
+    for &(name, kind, help, value) in counters.iter() {
+        header(&mut out, name, kind, help);
+        for (listener, recorder) in status.listeners() {
+            let _ = writeln!(out, "{}{{listener={}}} {}", name, label(listener), value(recorder));
+        }
+    }
+
+    let name = "laji_connection_duration_seconds";
+    header(&mut out, name, "histogram", "How long closed connections stayed open.");
+    for (listener, recorder) in status.listeners() {
+        let listener = label(listener);
+        let durations = recorder.connection_durations();
+        for (bound, count) in durations.cumulative() {
+            let le = if bound.is_infinite() { "+Inf".to_string() } else { bound.to_string() };
+            let _ = writeln!(out, "{}_bucket{{listener={},le=\"{}\"}} {}", name, listener, le, count);
+        }
+        let sum = durations.sum();
+        let sum = sum.as_secs() as f64 + f64::from(sum.subsec_micros()) / 1e6;
+        let _ = writeln!(out, "{}_sum{{listener={}}} {}", name, listener, sum);
+        let _ = writeln!(out, "{}_count{{listener={}}} {}", name, listener, durations.count());
+    }
+    out
+}
+
+#[inline]
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// A quoted label value.
+fn label(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn exposition_format() {
+        let discard = Recorder::new();
+        let peer: SocketAddr = "127.0.0.1:50312".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        discard.connection_opened(peer, local);
+        discard.connection_closed(peer, local);
+        discard.connection_opened(peer, local);
+        discard.bytes_received(12);
+        discard.connection_rejected();
+        let text = render(&Status::new().listener("discard", discard).listener("a\"b", Recorder::new()));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(&lines[..3], ["# HELP laji_uptime_seconds Seconds since the status page was created.",
+            "# TYPE laji_uptime_seconds gauge", "laji_uptime_seconds 0"]);
+        for line in &["laji_connections_opened_total{listener=\"discard\"} 2",
+            "laji_connections_rejected_total{listener=\"discard\"} 1",
+            "laji_connections_active{listener=\"discard\"} 1",
+            "laji_received_bytes_total{listener=\"a\\\"b\"} 0",
+            "# TYPE laji_connection_duration_seconds histogram",
+            "laji_connection_duration_seconds_bucket{listener=\"discard\",le=\"0.001\"} 1",
+            "laji_connection_duration_seconds_bucket{listener=\"discard\",le=\"+Inf\"} 1",
+            "laji_connection_duration_seconds_count{listener=\"discard\"} 1"] {
+            assert!(lines.contains(line), "missing {:?} in\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Property test driver for the `Arbitrary` impls behind the "arbitrary" feature.
+use arbitrary::{Arbitrary, Unstructured};
+
+const CASES: usize = 2000;
+const MAX_INPUT_LEN: usize = 1024;
+
+/// Run `check` on values built from `CASES` pseudo-random inputs.
+///
+/// The inputs come from a fixed seed so a failing case shows up again on the next run.
+pub(crate) fn for_all<T, F>(mut check: F)
+where
+    T: Arbitrary + std::fmt::Debug,
+    F: FnMut(T)
+{
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut input = Vec::with_capacity(MAX_INPUT_LEN);
+    for _ in 0..CASES {
+        input.clear();
+        let len = (next(&mut state) as usize) % MAX_INPUT_LEN;
+        while input.len() < len {
+            input.extend_from_slice(&next(&mut state).to_le_bytes());
+        }
+        input.truncate(len);
+        if let Ok(value) = T::arbitrary(&mut Unstructured::new(&input)) {
+            check(value);
+        }
+    }
+}
+
+#[inline]
+fn next(state: &mut u64) -> u64 {
+    // xorshift64*
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
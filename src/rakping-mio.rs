@@ -0,0 +1,276 @@
+//! A rakping responder on one mio event loop, for servers on public lists that crawlers
+//! ping far more often than players join.
+//!
+//! Every ping is answered with the same pong, the server GUID and the current `Motd`, with
+//! no handler made per packet. The loop takes up to `batch_size` datagrams off a socket at
+//! a time, checks each against the `Throttle`, and sends their pongs together with one
+//! `udp_batch::Batch` flush, a single `sendmmsg` on Linux. A batch the socket has no room
+//! for is lost like any other datagram, and the loop goes on reading.
+use mio::{Poll, PollOpt, Ready, Registration, Token, Events, net::UdpSocket};
+use std::{borrow::Cow, io, net::{self, Ipv4Addr, SocketAddr, ToSocketAddrs}};
+use smallvec::SmallVec;
+use crate::{
+    config::ConfigError,
+    ports,
+    rakping::{Motd, Packet, Pong, Throttle, MAX_PACKET_LEN},
+    server::{ServerHandle, Stopper},
+    udp_batch::Batch,
+};
+
+const EVENTS_CAPACITY: usize = 64;
+const INLINE_SOCKETS: usize = 2;
+const DEFAULT_BATCH_SIZE: usize = 32;
+const STOP_TOKEN: Token = Token(usize::MAX - 1);
+
+/// Answer pings on `addr` as `server_guid`, named by `motd`, on a thread of its own;
+/// binding errors are returned here.
+pub fn listen_spawned<A>(addr: A, server_guid: u64, motd: Motd) -> io::Result<ServerHandle>
+where A: ToSocketAddrs
+{
+    Builder::new(server_guid, motd).bind(addr)?.build()?.run_detached()
+}
+
+pub struct Responder {
+    poll: Poll,
+    // each socket as mio polls it, and a clone of it for batches to be sent through
+    sockets: Vec<(UdpSocket, net::UdpSocket)>,
+    server_guid: u64,
+    motd: Motd,
+    throttle: Throttle,
+    batch: Batch,
+    buf: Vec<u8>,
+}
+
+impl Responder {
+    fn from_builder(builder: Builder) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut sockets = Vec::with_capacity(builder.udp.len());
+        for (index, socket) in builder.udp.into_iter().enumerate() {
+            let sender = socket.try_clone()?;
+            let socket = UdpSocket::from_socket(socket)?;
+            poll.register(&socket, Token(index), Ready::readable(), PollOpt::edge())?;
+            sockets.push((socket, sender));
+        }
+        Ok(Self {
+            poll,
+            sockets,
+            server_guid: builder.server_guid,
+            motd: builder.motd,
+            throttle: builder.throttle,
+            batch: Batch::with_capacity(builder.batch_size),
+            buf: vec![0u8; MAX_PACKET_LEN],
+        })
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(|(socket, _)| socket.local_addr()).collect()
+    }
+
+    /// Serve until polling or receiving fails; see `run_until`.
+    #[inline]
+    pub fn run(self) -> io::Result<()> {
+        self.run_until(&Stopper::new())
+    }
+
+    /// Serve until `stopper` is stopped, or polling or receiving fails. Failed sends only
+    /// lose the batch of pongs that failed.
+    pub fn run_until(mut self, stopper: &Stopper) -> io::Result<()> {
+        let (registration, readiness) = Registration::new2();
+        self.poll.register(&registration, STOP_TOKEN, Ready::readable(), PollOpt::edge())?;
+        stopper.on_stop(move || {
+            let _ = readiness.set_readiness(Ready::readable());
+        });
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in &events {
+                if event.token() == STOP_TOKEN {
+                    return Ok(());
+                }
+                self.answer_all(event.token().0)?;
+            }
+        }
+    }
+
+    /// `run_until` on a thread of its own, stopped through the returned handle.
+    pub fn run_detached(self) -> io::Result<ServerHandle> {
+        let local_addrs = self.local_addrs()?;
+        let stopper = Stopper::new();
+        let serving = stopper.clone();
+        ServerHandle::spawn_stoppable("laji-rakping", local_addrs, stopper, move || self.run_until(&serving))
+    }
+
+    /// Answer what is waiting on socket `index`, a batch at a time, until it would block.
+    fn answer_all(&mut self, index: usize) -> io::Result<()> {
+        let mut drained = false;
+        while !drained {
+            let name = self.motd.get();
+            let (socket, sender) = &self.sockets[index];
+            while !self.batch.is_full() {
+                let (len, origin) = match socket.recv_from(&mut self.buf) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        drained = true;
+                        break;
+                    }
+                    // Windows reports an earlier pong's ICMP unreachable here
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset
+                        || e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                let request = &self.buf[..len];
+                let ping = match Packet::decode(request) {
+                    Ok(Packet::Ping(ping)) => ping,
+                    _ => continue,
+                };
+                if self.throttle.check(origin, request).is_err() {
+                    continue;
+                }
+                let mut pong = [0u8; MAX_PACKET_LEN];
+                let pong_len = Pong::new(ping.ping_time(), self.server_guid, Cow::Borrowed(&*name)).encode(&mut pong)?;
+                self.batch.push(origin, &pong[..pong_len]);
+            }
+            // a full send buffer, or a source that cannot be sent to, loses only this batch
+            let _ = self.batch.flush(sender);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Builder {
+    udp: SmallVec<[net::UdpSocket; INLINE_SOCKETS]>,
+    server_guid: u64,
+    motd: Motd,
+    throttle: Throttle,
+    batch_size: usize,
+}
+
+impl Builder {
+    /// A responder that answers as `server_guid`, with the name `motd` holds when each
+    /// batch is answered.
+    #[inline]
+    pub fn new(server_guid: u64, motd: Motd) -> Self {
+        Self { udp: SmallVec::new(), server_guid, motd, throttle: Throttle::new(), batch_size: DEFAULT_BATCH_SIZE }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Self>
+    where A: ToSocketAddrs
+    {
+        self.udp.push(net::UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the Bedrock port, 19132, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Self> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::RAKNET))
+    }
+
+    /// What a ping is checked against before it is answered; nothing by default.
+    #[inline]
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Take up to `n` pings off a socket before their pongs are sent together; 32 by default.
+    #[inline]
+    pub fn batch_size(mut self, n: usize) -> Self {
+        self.batch_size = n;
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.udp.iter().map(net::UdpSocket::local_addr).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.udp.is_empty() {
+            return Err(ConfigError::NoListeners);
+        }
+        if self.batch_size == 0 {
+            return Err(ConfigError::Zero("batch_size"));
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration first; its errors are `InvalidInput` wrapping a
+    /// `ConfigError`.
+    #[inline]
+    pub fn build(self) -> io::Result<Responder> {
+        self.validate()?;
+        Responder::from_builder(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::rakping::Ping;
+
+    #[test]
+    fn answers_every_ping_in_batches() -> io::Result<()> {
+        let motd = Motd::new("MCPE;laji;390;1.14.60;0;10;13253860892328930865;;Survival;1;19132;19133;");
+        let server = Builder::new(0xb7ef_2e35_f0a7_a231, motd.clone()).bind("127.0.0.1:0")?.batch_size(4).build()?.run_detached()?;
+        let client = net::UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        for ping_time in 0..10 {
+            let len = Ping::new(ping_time, 0x5f7a_3b62_c10e_990d).encode(&mut buf)?;
+            client.send_to(&buf[..len], server.local_addrs()[0])?;
+        }
+        // not a ping, so not answered
+        client.send_to(&[0x1c, 1, 2, 3], server.local_addrs()[0])?;
+        let mut ping_times = Vec::new();
+        for _ in 0..10 {
+            let len = client.recv(&mut buf)?;
+            match Packet::decode(&buf[..len])? {
+                Packet::Pong(pong) => {
+                    assert_eq!((pong.server_guid(), pong.server_name()), (0xb7ef_2e35_f0a7_a231, &*motd.get()));
+                    ping_times.push(pong.ping_time());
+                }
+                other => panic!("decoded {:?}", other),
+            }
+        }
+        ping_times.sort_unstable();
+        assert_eq!(ping_times, (0..10).collect::<Vec<_>>());
+
+        motd.set("MCPE;renamed;390;1.14.60;0;10;1;;Survival;1;19132;19133;");
+        let len = Ping::new(99, 1).encode(&mut buf)?;
+        client.send_to(&buf[..len], server.local_addrs()[0])?;
+        let len = client.recv(&mut buf)?;
+        match Packet::decode(&buf[..len])? {
+            Packet::Pong(pong) => assert!(pong.server_name().starts_with("MCPE;renamed;")),
+            other => panic!("decoded {:?}", other),
+        }
+        client.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert!(client.recv(&mut buf).is_err());
+        server.stop()
+    }
+
+    #[test]
+    fn throttled_and_misconfigured() -> io::Result<()> {
+        let throttle = Throttle::new().per_source(2, Duration::from_secs(60));
+        let server = Builder::new(1, Motd::new("MCPE;laji;")).bind("127.0.0.1:0")?.throttle(throttle).build()?.run_detached()?;
+        let client = net::UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = Ping::new(7, 2).encode(&mut buf)?;
+        for _ in 0..5 {
+            client.send_to(&buf[..len], server.local_addrs()[0])?;
+        }
+        let mut answered = 0;
+        while client.recv(&mut buf).is_ok() {
+            answered += 1;
+        }
+        assert_eq!(answered, 2);
+        server.stop()?;
+        assert_eq!(Builder::new(1, Motd::new("")).validate(), Err(ConfigError::NoListeners));
+        assert_eq!(Builder::new(1, Motd::new("")).bind("127.0.0.1:0")?.batch_size(0).validate(),
+            Err(ConfigError::Zero("batch_size")));
+        Ok(())
+    }
+}
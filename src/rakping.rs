@@ -1,95 +1,745 @@
-use std::{io, net};
+use std::{fmt, io, net, thread};
 use std::borrow::Cow;
-use core::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::{ports, random, ratelimit::PerSource, server::ServerHandle, wire::{invalid_data, ByteReader, ByteWriter}};
+#[cfg(feature = "backend-tokio")]
+use bytes::BytesMut;
+#[cfg(feature = "backend-tokio")]
+use tokio::codec::{Decoder, Encoder};
 
+/// Answer pings on `addr`, with a handler from `factory` for each one.
 pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()> 
 where
     A: net::ToSocketAddrs,
     F: FnMut(Sender) -> H,
     H: Handler
 {
-    Ok(())
+    LajiRakPing::bind(addr, factory)?.run()
 }
 
+/// Talk to the server at `addr`: `factory` gets a sender to ping it with, and the handler
+/// it makes hears every pong.
 pub fn connect<A, F, H>(addr: A, factory: F) -> io::Result<()>
 where
     A: net::ToSocketAddrs,
     F: FnMut(Sender) -> H,
     H: Handler
 {
-    Ok(())
+    LajiRakPing::connect(addr, factory)?.run()
 }
 
-pub struct LajiRakPing<'a, F> 
+/// One socket, either answering the pings that come to it or talking to one server.
+pub struct LajiRakPing<F> 
 where F: Factory
 {
-    remote_socket: &'a [net::UdpSocket],
-    local_socket: Option<net::UdpSocket>,
+    socket: Arc<net::UdpSocket>,
+    // the server, when connected to one
+    remote_addr: Option<net::SocketAddr>,
+    throttle: Throttle,
     factory: F
 }
 
+impl<F> LajiRakPing<F>
+where F: Factory
+{
+    pub fn bind<A>(addr: A, factory: F) -> io::Result<Self>
+    where A: net::ToSocketAddrs
+    {
+        let socket = net::UdpSocket::bind(addr)?;
+        Ok(Self { socket: Arc::new(socket), remote_addr: None, throttle: Throttle::new(), factory })
+    }
+
+    /// Bind an ephemeral port and take packets from `addr` only.
+    pub fn connect<A>(addr: A, factory: F) -> io::Result<Self>
+    where A: net::ToSocketAddrs
+    {
+        let socket = crate::resolve::connect_udp(addr)?;
+        let remote_addr = socket.peer_addr()?;
+        Ok(Self { socket: Arc::new(socket), remote_addr: Some(remote_addr), throttle: Throttle::new(), factory })
+    }
+
+    /// What a bound server checks before a ping reaches its handler; nothing by default.
+    #[inline]
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Receive until the socket fails. A bound server makes a handler per packet and
+    /// drops it afterwards, its errors going to the factory's `on_error`, so a send to one
+    /// peer failing leaves the rest answered; a connected client has one handler, and its
+    /// errors end the run.
+    /// Packets other than pings and pongs, and ones that do not decode, are skipped.
+    pub fn run(mut self) -> io::Result<()> {
+        match self.remote_addr {
+            Some(remote_addr) => self.run_client(remote_addr),
+            None => self.run_server(),
+        }
+    }
+
+    fn run_server(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        loop {
+            let (len, origin) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                // Windows reports an earlier send's ICMP unreachable here
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset
+                    || e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let request = &buf[..len];
+            match Packet::decode(request) {
+                Ok(Packet::Ping(ping)) => {
+                    if self.throttle.check(origin, request).is_err() {
+                        continue;
+                    }
+                    let sender = Sender::new(self.socket.clone(), origin);
+                    if let Err(e) = self.factory.client_connected(sender).on_ping(&ping) {
+                        self.factory.on_error(e);
+                    }
+                }
+                Ok(Packet::Pong(pong)) => {
+                    let sender = Sender::new(self.socket.clone(), origin);
+                    if let Err(e) = self.factory.client_connected(sender).on_pong(&pong, pong.motd().as_ref()) {
+                        self.factory.on_error(e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn run_client(&mut self, remote_addr: net::SocketAddr) -> io::Result<()> {
+        let mut handler = self.factory.server_connected(Sender::connected(self.socket.clone(), remote_addr));
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            match Packet::decode(&buf[..len]) {
+                Ok(Packet::Ping(ping)) => handler.on_ping(&ping)?,
+                Ok(Packet::Pong(pong)) => handler.on_pong(&pong, pong.motd().as_ref())?,
+                _ => {}
+            }
+        }
+    }
+}
+
 pub trait Factory {
     type Handler: Handler;
 
     fn connection_made(&mut self, sender: Sender) -> Self::Handler;
 
+    /// A packet came from a client of a bound server.
     #[inline]
     fn client_connected(&mut self, sender: Sender) -> Self::Handler {
         self.connection_made(sender)
     }
 
+    /// A client connected to its server.
     #[inline]
     fn server_connected(&mut self, sender: Sender) -> Self::Handler {
         self.connection_made(sender)
     }
+
+    /// A bound server's handler failed on a packet, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler,
+    F: FnMut(Sender) -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self, sender: Sender) -> H {
+        self(sender)
+    }
 }
 
 pub trait Handler {
     fn on_ping(&mut self, ping: &Ping) -> io::Result<()>;
 
-    fn on_pong(&mut self, pong: &Pong) -> io::Result<()>;
+    /// `motd` is the server name parsed by `BedrockMotd::parse`, if it could be.
+    fn on_pong(&mut self, pong: &Pong, motd: Option<&BedrockMotd>) -> io::Result<()>;
 }
 
-#[derive(Clone)]
-pub struct Sender<'a> {
-    socket: &'a net::UdpSocket,
+/// Sends to the one peer a handler was made for; clones share the socket.
+#[derive(Clone, Debug)]
+pub struct Sender {
+    socket: Arc<net::UdpSocket>,
     addr: net::SocketAddr,
+    connected: bool,
 }
 
-impl<'a> Sender<'a> {
-    fn new(socket: &'a net::UdpSocket, addr: net::SocketAddr) -> Self {
-        Self { socket, addr }
+impl Sender {
+    #[inline]
+    fn new(socket: Arc<net::UdpSocket>, addr: net::SocketAddr) -> Self {
+        Self { socket, addr, connected: false }
+    }
+
+    #[inline]
+    fn connected(socket: Arc<net::UdpSocket>, addr: net::SocketAddr) -> Self {
+        Self { socket, addr, connected: true }
     }
-}
 
-impl Sender<'_> {
-    pub fn send_ping(&self, ping: &Ping) -> io::Result<usize> {
-        let mut buf = [0u8; 17];
-        buf[0] = 0x01;
-        unsafe { 
-            *(buf.as_ptr().offset(1) as *mut u64) = ping.ping_time.to_be();
-            *(buf.as_ptr().offset(9) as *mut u64) = ping.client_guid.to_be(); 
+    // some systems refuse `send_to` on a connected socket, even to the peer
+    #[inline]
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        if self.connected {
+            self.socket.send(packet)
+        } else {
+            self.socket.send_to(packet, self.addr)
         }
-        self.socket.send_to(&buf, self.addr)
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    pub fn send_ping(&self, ping: &Ping) -> io::Result<usize> {
+        let mut buf = [0u8; PING_LEN];
+        let len = ping.encode(&mut buf)?;
+        self.send(&buf[..len])
     }
 
     pub fn send_pong(&self, pong: &Pong) -> io::Result<usize> {
-        let mut buf = [0u8; 1024];
-        buf[0] = 0x1c;
-        let len_server_name = pong.server_name.len();
-        unsafe {
-            let buf_ptr = buf.as_ptr();
-            *(buf_ptr.offset(1) as *mut u64) = pong.ping_time.to_be();
-            *(buf_ptr.offset(9) as *mut u64) = pong.server_guid.to_be(); 
-            *(buf_ptr.offset(17) as *mut u16) = len_server_name as u16;
-            ptr::copy_nonoverlapping(pong.server_name.as_ptr(), buf_ptr.offset(19) as *mut u8, len_server_name);
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = pong.encode(&mut buf)?;
+        self.send(&buf[..len])
+    }
+
+    /// Tell a client whose Open Connection Request named another protocol version that
+    /// this server speaks `protocol`.
+    pub fn send_incompatible_version(&self, protocol: u8, server_guid: u64) -> io::Result<usize> {
+        let mut buf = [0u8; INCOMPATIBLE_VERSION_LEN];
+        let len = IncompatibleProtocolVersion::new(protocol, server_guid).encode(&mut buf)?;
+        self.send(&buf[..len])
+    }
+}
+
+/// Broadcasts Unconnected Pongs nobody asked for, the way Bedrock announces LAN worlds, so
+/// clients on the network list the server without pinging it first.
+#[derive(Debug)]
+pub struct Advertiser {
+    socket: net::UdpSocket,
+    target: net::SocketAddr,
+    interval: Duration,
+    server_guid: u64,
+    motd: Motd,
+    started: Instant,
+}
+
+impl Advertiser {
+    /// Advertise from a clone of `socket`, so the same port can go on answering pings.
+    pub fn new<S>(socket: &net::UdpSocket, server_guid: u64, motd: S) -> io::Result<Self>
+    where S: Into<String>
+    {
+        let socket = socket.try_clone()?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            target: (net::Ipv4Addr::BROADCAST, ports::RAKNET).into(),
+            interval: Duration::from_millis(1500),
+            server_guid,
+            motd: Motd::new(motd),
+            started: Instant::now(),
+        })
+    }
+
+    /// Where announcements go; the limited broadcast address on the Bedrock port by default.
+    #[inline]
+    pub fn target<A>(mut self, addr: A) -> Self
+    where A: Into<net::SocketAddr>
+    {
+        self.target = addr.into();
+        self
+    }
+
+    #[inline]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// A handle to change the advertised server name while the advertiser runs.
+    #[inline]
+    pub fn motd(&self) -> Motd {
+        self.motd.clone()
+    }
+
+    /// Send one announcement now.
+    pub fn advertise(&self) -> io::Result<usize> {
+        let ping_time = self.started.elapsed().as_millis() as u64;
+        let name = self.motd.get();
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = Pong::new(ping_time, self.server_guid, name).encode(&mut buf)?;
+        self.socket.send_to(&buf[..len], self.target)
+    }
+
+    /// Announce every `interval` until sending fails.
+    pub fn run(self) -> io::Result<()> {
+        loop {
+            self.advertise()?;
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// `run` on a thread of its own.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let local_addrs = vec![self.socket.local_addr()?];
+        ServerHandle::spawn("laji-rakping-advertiser", local_addrs, move || self.run())
+    }
+}
+
+/// The server name an `Advertiser` sends; clones update the same name.
+#[derive(Clone, Debug)]
+pub struct Motd(Arc<Mutex<String>>);
+
+impl Motd {
+    #[inline]
+    pub fn new<S>(name: S) -> Self
+    where S: Into<String>
+    {
+        Motd(Arc::new(Mutex::new(name.into())))
+    }
+
+    #[inline]
+    pub fn set<S>(&self, name: S)
+    where S: Into<String>
+    {
+        *self.0.lock().unwrap() = name.into();
+    }
+
+    #[inline]
+    pub fn get(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Ping `addr` once with the default `Pinger` settings, retrying as `RetryPolicy::new` does.
+pub fn ping<A>(addr: A) -> io::Result<Reply>
+where A: net::ToSocketAddrs
+{
+    Pinger::new().ping(addr)
+}
+
+/// How a `Pinger` spaces out its pings while no pong comes back.
+///
+/// Attempt `n` waits `initial_delay * multiplier^(n - 1)` for an answer before the next
+/// ping goes out, each wait moved by up to `jitter` of itself either way so clients
+/// started together do not stay in step. No attempt starts after the `deadline`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    attempts: u32,
+    initial_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.0,
+            deadline: None,
+        }
+    }
+
+    /// Pings sent at most, the first one included.
+    #[inline]
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    #[inline]
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    #[inline]
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// A fraction of each wait, from 0 for none to 1.
+    #[inline]
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up this long after the first ping, however many attempts are left.
+    #[inline]
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// How long attempt `attempt`, counting from 1, waits for its pong.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let mut secs = self.initial_delay.as_secs_f64() * factor;
+        if self.jitter > 0.0 {
+            secs = random::jittered(secs, self.jitter);
+        }
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What became of one ping a `Pinger` sent.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Outcome {
+    /// The server answered this ping after this long.
+    Answered(Duration),
+    /// Nothing came back while the attempt waited.
+    TimedOut,
+    /// Sending or receiving failed.
+    Failed(io::ErrorKind),
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Attempt {
+    /// Counting from 1.
+    pub number: u32,
+    pub outcome: Outcome,
+}
+
+/// The pong a `Pinger` got, and what it took.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Reply {
+    pong: Pong<'static>,
+    latency: Duration,
+    attempts: Vec<Attempt>,
+}
+
+impl Reply {
+    #[inline]
+    pub fn pong(&self) -> &Pong<'static> {
+        &self.pong
+    }
+
+    #[inline]
+    pub fn motd(&self) -> Option<BedrockMotd> {
+        self.pong.motd()
+    }
+
+    /// From sending the ping that was answered to receiving its pong.
+    #[inline]
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Every attempt made, the answered one last.
+    #[inline]
+    pub fn attempts(&self) -> &[Attempt] {
+        &self.attempts
+    }
+}
+
+/// Queries one server's pong, sending pings until one is answered or the `RetryPolicy`
+/// gives up. A late pong to an earlier ping still counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pinger {
+    retry: RetryPolicy,
+    client_guid: u64,
+}
+
+impl Pinger {
+    #[inline]
+    pub fn new() -> Self {
+        Self { retry: RetryPolicy::new(), client_guid: random::random() }
+    }
+
+    #[inline]
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The guid pings are sent with; random by default.
+    #[inline]
+    pub fn client_guid(mut self, guid: u64) -> Self {
+        self.client_guid = guid;
+        self
+    }
+
+    pub fn ping<A>(&self, addr: A) -> io::Result<Reply>
+    where A: net::ToSocketAddrs
+    {
+        self.ping_with(addr, |_attempt| {})
+    }
+
+    /// `ping`, telling `on_attempt` how each attempt ended as soon as it has.
+    pub fn ping_with<A, F>(&self, addr: A, mut on_attempt: F) -> io::Result<Reply>
+    where
+        A: net::ToSocketAddrs,
+        F: FnMut(&Attempt)
+    {
+        // each attempt goes to the next address, so one that never answers only costs its turns
+        let targets = crate::resolve::each_addr(addr)?;
+        let mut sockets: Vec<(bool, net::UdpSocket)> = Vec::new();
+        let started = Instant::now();
+        let deadline = self.retry.deadline.map(|deadline| started + deadline);
+        let mut sent: Vec<(u64, Instant)> = Vec::new();
+        let mut attempts = Vec::new();
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        for number in 1..=self.retry.attempts {
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                break;
+            }
+            let epoch_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let ping_time = sent.last().map_or(epoch_ms, |&(last, _)| epoch_ms.max(last + 1));
+            let mut wait_until = now + self.retry.delay(number);
+            if let Some(deadline) = deadline {
+                wait_until = wait_until.min(deadline);
+            }
+            let len = Ping::new(ping_time, self.client_guid).encode(&mut buf)?;
+            let target = targets[(number as usize - 1) % targets.len()];
+            let outcome = socket_for(&mut sockets, target).and_then(|socket| {
+                socket.send_to(&buf[..len], target)?;
+                sent.push((ping_time, now));
+                wait_for_pong(socket, &targets, &sent, wait_until, &mut buf)
+            });
+            let (outcome, answer) = match outcome {
+                Ok(Some((pong, latency))) => (Outcome::Answered(latency), Some((pong, latency))),
+                Ok(None) => (Outcome::TimedOut, None),
+                Err(e) => (Outcome::Failed(e.kind()), None),
+            };
+            let attempt = Attempt { number, outcome };
+            on_attempt(&attempt);
+            attempts.push(attempt);
+            if let Some((pong, latency)) = answer {
+                return Ok(Reply { pong, latency, attempts });
+            }
+            if let Outcome::Failed(_) = outcome {
+                // don't spin through the remaining attempts on an immediate error
+                thread::sleep(wait_until.saturating_duration_since(Instant::now()));
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no pong after all attempts"))
+    }
+}
+
+impl Default for Pinger {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The socket for `target`'s IP version, bound the first time that version is pinged.
+fn socket_for(sockets: &mut Vec<(bool, net::UdpSocket)>, target: net::SocketAddr) -> io::Result<&net::UdpSocket> {
+    let index = match sockets.iter().position(|&(v6, _)| v6 == target.is_ipv6()) {
+        Some(index) => index,
+        None => {
+            sockets.push((target.is_ipv6(), crate::resolve::bind_ephemeral_for(target)?));
+            sockets.len() - 1
+        }
+    };
+    Ok(&sockets[index].1)
+}
+
+fn wait_for_pong(
+    socket: &net::UdpSocket,
+    targets: &[net::SocketAddr],
+    sent: &[(u64, Instant)],
+    until: Instant,
+    buf: &mut [u8],
+) -> io::Result<Option<(Pong<'static>, Duration)>> {
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (len, from) = match socket.recv_from(buf) {
+            Ok(ans) => ans,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !targets.contains(&from) {
+            continue;
+        }
+        let pong = match Pong::decode(&buf[..len]) {
+            Ok(pong) => pong,
+            Err(_) => continue,
+        };
+        if let Some(&(_, sent_at)) = sent.iter().find(|&&(ping_time, _)| ping_time == pong.ping_time()) {
+            return Ok(Some((pong.into_owned(), sent_at.elapsed())));
+        }
+    }
+}
+
+/// Why a ping got no pong.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Dropped {
+    /// It was shorter than the minimum request size.
+    TooShort,
+    /// Its source IP has had all the pongs it gets for now.
+    RateLimited,
+    /// It was a `PingKind::OpenConnections` ping, and those are not answered.
+    OpenConnections,
+}
+
+/// What a responder checks before answering a ping.
+///
+/// A pong carries the whole server name and is several times the size of the ping it
+/// answers, so a flood of pings with spoofed sources would make the server an amplifier
+/// aimed at whoever the sources name. Nothing is limited until configured.
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    limit: Option<PerSource>,
+    min_request_len: usize,
+    ignore_open_connections: bool,
+}
+
+impl Throttle {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to answer `PingKind::OpenConnections` pings, which clients send to find
+    /// servers with free slots; a full server may want to stay quiet. They are answered
+    /// by default.
+    #[inline]
+    pub fn answer_open_connections(mut self, answer: bool) -> Self {
+        self.ignore_open_connections = !answer;
+        self
+    }
+
+    /// Answer each source IP at most `pongs` times per `interval`.
+    #[inline]
+    pub fn per_source(mut self, pongs: u32, interval: Duration) -> Self {
+        self.limit = Some(PerSource::new(pongs, interval));
+        self
+    }
+
+    /// Ignore pings shorter than `len` bytes. Padding pings up to the pong size is how
+    /// a client shows it is not asking for more than it sends.
+    #[inline]
+    pub fn min_request_len(mut self, len: usize) -> Self {
+        self.min_request_len = len;
+        self
+    }
+
+    /// Whether the ping in `request` from `origin` should be answered. Each `Ok` counts
+    /// towards the source's limit.
+    pub fn check(&mut self, origin: net::SocketAddr, request: &[u8]) -> Result<(), Dropped> {
+        if request.len() < self.min_request_len {
+            return Err(Dropped::TooShort);
+        }
+        if self.ignore_open_connections && request.first() == Some(&ID_UNCONNECTED_PING_OPEN_CONNECTIONS) {
+            return Err(Dropped::OpenConnections);
+        }
+        if let Some(limit) = &mut self.limit {
+            if !limit.allow(origin.ip()) {
+                return Err(Dropped::RateLimited);
+            }
+        }
+        Ok(())
+    }
+}
+
+const ID_UNCONNECTED_PING: u8 = 0x01;
+const ID_UNCONNECTED_PING_OPEN_CONNECTIONS: u8 = 0x02;
+const ID_UNCONNECTED_PONG: u8 = 0x1c;
+const ID_OPEN_CONNECTION_REQUEST_1: u8 = 0x05;
+const ID_INCOMPATIBLE_PROTOCOL_VERSION: u8 = 0x19;
+/// Marks offline messages, the ones sent before a connection exists.
+pub const MAGIC: [u8; 16] = [0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe,
+    0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78];
+const PING_LEN: usize = 33;
+const OPEN_CONNECTION_REQUEST_1_MIN_LEN: usize = 18;
+const INCOMPATIBLE_VERSION_LEN: usize = 26;
+// the IPv4 and UDP headers, which count towards the MTU a request is padded to
+const UDP_HEADERS_LEN: usize = 28;
+const PONG_HEADER_LEN: usize = 35;
+pub(crate) const MAX_PACKET_LEN: usize = 1024;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Packet<'a> {
+    Ping(Ping),
+    Pong(Pong<'a>),
+    OpenConnectionRequest1(OpenConnectionRequest1),
+    IncompatibleProtocolVersion(IncompatibleProtocolVersion),
+}
+
+impl<'a> Packet<'a> {
+    pub fn decode(buf: &'a [u8]) -> io::Result<Self> {
+        match buf.first() {
+            Some(&ID_UNCONNECTED_PING) | Some(&ID_UNCONNECTED_PING_OPEN_CONNECTIONS) =>
+                Ping::decode(buf).map(Packet::Ping),
+            Some(&ID_UNCONNECTED_PONG) => Pong::decode(buf).map(Packet::Pong),
+            Some(&ID_OPEN_CONNECTION_REQUEST_1) =>
+                OpenConnectionRequest1::decode(buf).map(Packet::OpenConnectionRequest1),
+            Some(&ID_INCOMPATIBLE_PROTOCOL_VERSION) =>
+                IncompatibleProtocolVersion::decode(buf).map(Packet::IncompatibleProtocolVersion),
+            Some(_) => Err(invalid_data("unknown packet id")),
+            None => Err(invalid_data("empty packet")),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Packet::Ping(ping) => ping.encode(buf),
+            Packet::Pong(pong) => pong.encode(buf),
+            Packet::OpenConnectionRequest1(request) => request.encode(buf),
+            Packet::IncompatibleProtocolVersion(reply) => reply.encode(buf),
+        }
+    }
+
+    #[inline]
+    pub fn into_owned(self) -> Packet<'static> {
+        match self {
+            Packet::Ping(ping) => Packet::Ping(ping),
+            Packet::Pong(pong) => Packet::Pong(pong.into_owned()),
+            Packet::OpenConnectionRequest1(request) => Packet::OpenConnectionRequest1(request),
+            Packet::IncompatibleProtocolVersion(reply) => Packet::IncompatibleProtocolVersion(reply),
         }
-        let len = len_server_name + 19;
-        self.socket.send_to(&buf[..len], self.addr)
     }
 }
 
+/// Which of the two ping ids a ping was sent with.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum PingKind {
+    /// `0x01`, answered by every server.
+    Unconnected,
+    /// `0x02`, meant only for servers with connection slots left.
+    OpenConnections,
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub struct Ping {
+    kind: PingKind,
     ping_time: u64,
     client_guid: u64,
 }
@@ -97,10 +747,64 @@ pub struct Ping {
 impl Ping {
     #[inline]
     pub fn new(ping_time: u64, client_guid: u64) -> Self {
-        Self { ping_time, client_guid }
+        Self { kind: PingKind::Unconnected, ping_time, client_guid }
+    }
+
+    #[inline]
+    pub fn open_connections(ping_time: u64, client_guid: u64) -> Self {
+        Self { kind: PingKind::OpenConnections, ping_time, client_guid }
+    }
+
+    #[inline]
+    pub fn kind(&self) -> PingKind {
+        self.kind
+    }
+
+    #[inline]
+    pub fn ping_time(&self) -> u64 {
+        self.ping_time
+    }
+
+    #[inline]
+    pub fn client_guid(&self) -> u64 {
+        self.client_guid
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < PING_LEN {
+            return Err(invalid_data("ping packet too short"));
+        }
+        let mut reader = ByteReader::new(buf);
+        let kind = match reader.read_u8()? {
+            ID_UNCONNECTED_PING => PingKind::Unconnected,
+            ID_UNCONNECTED_PING_OPEN_CONNECTIONS => PingKind::OpenConnections,
+            _ => return Err(invalid_data("not a ping packet")),
+        };
+        let ping_time = reader.read_u64_be()?;
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("bad offline message magic"));
+        }
+        // clients may pad the ping past the guid, see `Throttle::min_request_len`
+        Ok(Self { kind, ping_time, client_guid: reader.read_u64_be()? })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() < PING_LEN {
+            return Err(invalid_data("buffer too small for ping packet"));
+        }
+        let mut writer = ByteWriter::new(buf);
+        writer.write_u8(match self.kind {
+            PingKind::Unconnected => ID_UNCONNECTED_PING,
+            PingKind::OpenConnections => ID_UNCONNECTED_PING_OPEN_CONNECTIONS,
+        })?;
+        writer.write_u64_be(self.ping_time)?;
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u64_be(self.client_guid)?;
+        Ok(writer.position())
     }
 }
 
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Pong<'a> {
     ping_time: u64,
     server_guid: u64,
@@ -113,4 +817,637 @@ impl<'a> Pong<'a> {
     where S: Into<Cow<'a, str>> {
         Self { ping_time, server_guid, server_name: server_name.into() }
     }
+
+    #[inline]
+    pub fn ping_time(&self) -> u64 {
+        self.ping_time
+    }
+
+    #[inline]
+    pub fn server_guid(&self) -> u64 {
+        self.server_guid
+    }
+
+    #[inline]
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// The server name split into the fields Bedrock servers fill it with.
+    #[inline]
+    pub fn motd(&self) -> Option<BedrockMotd> {
+        BedrockMotd::parse(&self.server_name)
+    }
+
+    #[inline]
+    pub fn into_owned(self) -> Pong<'static> {
+        Pong {
+            ping_time: self.ping_time,
+            server_guid: self.server_guid,
+            server_name: Cow::Owned(self.server_name.into_owned()),
+        }
+    }
+
+    pub fn decode(buf: &'a [u8]) -> io::Result<Self> {
+        if buf.len() < PONG_HEADER_LEN {
+            return Err(invalid_data("pong packet too short"));
+        }
+        let mut reader = ByteReader::new(buf);
+        if reader.read_u8()? != ID_UNCONNECTED_PONG {
+            return Err(invalid_data("not a pong packet"));
+        }
+        let ping_time = reader.read_u64_be()?;
+        let server_guid = reader.read_u64_be()?;
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("bad offline message magic"));
+        }
+        let len_server_name = reader.read_u16_be()? as usize;
+        let name_bytes = reader.take(len_server_name)
+            .map_err(|_| invalid_data("pong server name truncated"))?;
+        let server_name = std::str::from_utf8(name_bytes)
+            .map_err(|_| invalid_data("pong server name is not utf-8"))?;
+        Ok(Self { ping_time, server_guid, server_name: Cow::Borrowed(server_name) })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len_server_name = self.server_name.len();
+        if len_server_name > u16::MAX as usize {
+            return Err(invalid_data("pong server name too long"));
+        }
+        let len = PONG_HEADER_LEN + len_server_name;
+        if buf.len() < len {
+            return Err(invalid_data("buffer too small for pong packet"));
+        }
+        let mut writer = ByteWriter::new(buf);
+        writer.write_u8(ID_UNCONNECTED_PONG)?;
+        writer.write_u64_be(self.ping_time)?;
+        writer.write_u64_be(self.server_guid)?;
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u16_be(len_server_name as u16)?;
+        writer.write_bytes(self.server_name.as_bytes())?;
+        Ok(writer.position())
+    }
+}
+
+/// The semicolon-separated server name of a Bedrock pong:
+/// `MCPE;Dedicated Server;390;1.14.60;0;10;13253860892328930865;Bedrock level;Survival;1;19132;19133;`.
+///
+/// Servers older than 1.13 stop after the player counts, so the fields after them are
+/// optional. Displaying a `BedrockMotd` gives the server name back.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct BedrockMotd {
+    /// `MCPE`, or `MCEE` for Education Edition.
+    pub edition: String,
+    pub motd: String,
+    pub protocol: u32,
+    pub version: String,
+    pub players_online: u32,
+    pub max_players: u32,
+    pub server_id: Option<u64>,
+    /// The second MOTD line, usually the world name.
+    pub level_name: Option<String>,
+    pub game_mode: Option<String>,
+    pub game_mode_id: Option<u32>,
+    pub port_v4: Option<u16>,
+    pub port_v6: Option<u16>,
+}
+
+impl BedrockMotd {
+    /// `None` when `server_name` lacks one of the six fields every server sends, or a number
+    /// does not parse.
+    pub fn parse(server_name: &str) -> Option<Self> {
+        let mut fields = server_name.split(';');
+        let mut next = || fields.next().filter(|field| !field.is_empty());
+        let edition = next()?.to_string();
+        let motd = next()?.to_string();
+        let protocol = next()?.parse().ok()?;
+        let version = next()?.to_string();
+        let players_online = next()?.parse().ok()?;
+        let max_players = next()?.parse().ok()?;
+        let server_id = match next() { Some(id) => Some(id.parse().ok()?), None => None };
+        let level_name = next().map(str::to_string);
+        let game_mode = next().map(str::to_string);
+        let game_mode_id = match next() { Some(id) => Some(id.parse().ok()?), None => None };
+        let port_v4 = match next() { Some(port) => Some(port.parse().ok()?), None => None };
+        let port_v6 = match next() { Some(port) => Some(port.parse().ok()?), None => None };
+        Some(Self {
+            edition, motd, protocol, version, players_online, max_players,
+            server_id, level_name, game_mode, game_mode_id, port_v4, port_v6,
+        })
+    }
+}
+
+impl fmt::Display for BedrockMotd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{};{};{};{};{};", self.edition, self.motd, self.protocol, self.version,
+            self.players_online, self.max_players)?;
+        if let Some(id) = self.server_id {
+            write!(f, "{};", id)?;
+        }
+        if let Some(name) = &self.level_name {
+            write!(f, "{};", name)?;
+        }
+        if let Some(mode) = &self.game_mode {
+            write!(f, "{};", mode)?;
+        }
+        if let Some(id) = self.game_mode_id {
+            write!(f, "{};", id)?;
+        }
+        if let Some(port) = self.port_v4 {
+            write!(f, "{};", port)?;
+        }
+        if let Some(port) = self.port_v6 {
+            write!(f, "{};", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first packet of a connection attempt, padded with zeroes to the MTU the client
+/// wants to try.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct OpenConnectionRequest1 {
+    protocol: u8,
+    mtu: u16,
+}
+
+impl OpenConnectionRequest1 {
+    #[inline]
+    pub fn new(protocol: u8, mtu: u16) -> Self {
+        Self { protocol, mtu }
+    }
+
+    /// The RakNet protocol version the client speaks.
+    #[inline]
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// The datagram size probed, IP and UDP headers included.
+    #[inline]
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// The reply for a server speaking `protocol`, unless the client speaks it too.
+    #[inline]
+    pub fn incompatible_with(&self, protocol: u8, server_guid: u64) -> Option<IncompatibleProtocolVersion> {
+        if self.protocol == protocol {
+            return None;
+        }
+        Some(IncompatibleProtocolVersion::new(protocol, server_guid))
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < OPEN_CONNECTION_REQUEST_1_MIN_LEN {
+            return Err(invalid_data("open connection request too short"));
+        }
+        let mut reader = ByteReader::new(buf);
+        if reader.read_u8()? != ID_OPEN_CONNECTION_REQUEST_1 {
+            return Err(invalid_data("not an open connection request"));
+        }
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("bad offline message magic"));
+        }
+        if buf.len() + UDP_HEADERS_LEN > u16::MAX as usize {
+            return Err(invalid_data("open connection request too long"));
+        }
+        Ok(Self { protocol: reader.read_u8()?, mtu: (buf.len() + UDP_HEADERS_LEN) as u16 })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (self.mtu as usize).saturating_sub(UDP_HEADERS_LEN);
+        if len < OPEN_CONNECTION_REQUEST_1_MIN_LEN {
+            return Err(invalid_data("mtu too small for open connection request"));
+        }
+        if buf.len() < len {
+            return Err(invalid_data("buffer too small for open connection request"));
+        }
+        let mut writer = ByteWriter::new(buf);
+        writer.write_u8(ID_OPEN_CONNECTION_REQUEST_1)?;
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u8(self.protocol)?;
+        writer.fill(0, len - OPEN_CONNECTION_REQUEST_1_MIN_LEN)?;
+        Ok(writer.position())
+    }
+}
+
+/// A server's answer to an `OpenConnectionRequest1` naming a protocol it does not speak.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct IncompatibleProtocolVersion {
+    protocol: u8,
+    server_guid: u64,
+}
+
+impl IncompatibleProtocolVersion {
+    #[inline]
+    pub fn new(protocol: u8, server_guid: u64) -> Self {
+        Self { protocol, server_guid }
+    }
+
+    /// The protocol version the server speaks.
+    #[inline]
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    #[inline]
+    pub fn server_guid(&self) -> u64 {
+        self.server_guid
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < INCOMPATIBLE_VERSION_LEN {
+            return Err(invalid_data("incompatible protocol version packet too short"));
+        }
+        let mut reader = ByteReader::new(buf);
+        if reader.read_u8()? != ID_INCOMPATIBLE_PROTOCOL_VERSION {
+            return Err(invalid_data("not an incompatible protocol version packet"));
+        }
+        let protocol = reader.read_u8()?;
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("bad offline message magic"));
+        }
+        Ok(Self { protocol, server_guid: reader.read_u64_be()? })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() < INCOMPATIBLE_VERSION_LEN {
+            return Err(invalid_data("buffer too small for incompatible protocol version packet"));
+        }
+        let mut writer = ByteWriter::new(buf);
+        writer.write_u8(ID_INCOMPATIBLE_PROTOCOL_VERSION)?;
+        writer.write_u8(self.protocol)?;
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u64_be(self.server_guid)?;
+        Ok(writer.position())
+    }
+}
+
+/// One packet per datagram, for `UdpFramed`. A datagram that does not decode is skipped, so
+/// stray traffic on the port does not end the stream.
+#[cfg(feature = "backend-tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Codec;
+
+#[cfg(feature = "backend-tokio")]
+impl Decoder for Codec {
+    type Item = Packet<'static>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let packet = Packet::decode(buf).ok().map(Packet::into_owned);
+        buf.clear();
+        Ok(packet)
+    }
+}
+
+#[cfg(feature = "backend-tokio")]
+impl Encoder for Codec {
+    type Item = Packet<'static>;
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let mut bytes = [0u8; MAX_PACKET_LEN];
+        let len = packet.encode(&mut bytes)?;
+        buf.extend_from_slice(&bytes[..len]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Ping {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let (ping_time, client_guid) = (u.arbitrary()?, u.arbitrary()?);
+        Ok(if u.arbitrary()? {
+            Self::new(ping_time, client_guid)
+        } else {
+            Self::open_connections(ping_time, client_guid)
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Pong<'static> {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?, u.arbitrary()?, u.arbitrary::<String>()?))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Packet<'static> {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => Packet::Ping(u.arbitrary()?),
+            1 => Packet::Pong(u.arbitrary()?),
+            2 => Packet::OpenConnectionRequest1(u.arbitrary()?),
+            _ => Packet::IncompatibleProtocolVersion(u.arbitrary()?),
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for OpenConnectionRequest1 {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let mtu = u.int_in_range((UDP_HEADERS_LEN + OPEN_CONNECTION_REQUEST_1_MIN_LEN) as u16..=1492)?;
+        Ok(Self::new(u.arbitrary()?, mtu))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for IncompatibleProtocolVersion {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?, u.arbitrary()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_round_trip() {
+        let ping = Ping::new(0x0102030405060708, 0xdeadbeef);
+        let mut buf = [0u8; PING_LEN];
+        let len = ping.encode(&mut buf).unwrap();
+        assert_eq!(len, PING_LEN);
+        assert_eq!(Ping::decode(&buf[..len]).unwrap(), ping);
+    }
+
+    #[test]
+    fn open_connections_ping() {
+        let ping = Ping::open_connections(1, 2);
+        let mut buf = [0u8; PING_LEN];
+        ping.encode(&mut buf).unwrap();
+        assert_eq!(buf[0], ID_UNCONNECTED_PING_OPEN_CONNECTIONS);
+        assert_eq!(Packet::decode(&buf).unwrap(), Packet::Ping(ping));
+        assert_eq!(Ping::decode(&buf).unwrap().kind(), PingKind::OpenConnections);
+        let mut full = Throttle::new().answer_open_connections(false);
+        let origin = "192.0.2.1:19132".parse().unwrap();
+        assert_eq!(full.check(origin, &buf), Err(Dropped::OpenConnections));
+        Ping::new(1, 2).encode(&mut buf).unwrap();
+        assert_eq!(full.check(origin, &buf), Ok(()));
+    }
+
+    #[test]
+    fn pong_round_trip() {
+        let pong = Pong::new(1, 2, "MCPE;Laji;137;1.11.0;0;20");
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = pong.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..len]).unwrap(), Packet::Pong(pong));
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert!(Packet::decode(&[]).is_err());
+        assert!(Packet::decode(&[ID_UNCONNECTED_PING, 0, 0]).is_err());
+        let pong = Pong::new(1, 2, "Laji");
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = pong.encode(&mut buf).unwrap();
+        assert!(Pong::decode(&buf[..len - 1]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "backend-tokio")]
+    fn codec_skips_undecodable() {
+        let mut buf = BytesMut::from(&b"laji"[..]);
+        assert_eq!(Codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+        let ping = Ping::new(1, 2);
+        let mut bytes = [0u8; PING_LEN];
+        ping.encode(&mut bytes).unwrap();
+        buf.extend_from_slice(&bytes);
+        assert_eq!(Codec.decode(&mut buf).unwrap(), Some(Packet::Ping(ping)));
+    }
+
+    #[test]
+    fn raknet_fixtures() {
+        let ping_bytes = crate::fixture::load("raknet/synthesized-unconnected-ping.hex");
+        let ping = Ping::decode(&ping_bytes).unwrap();
+        assert_eq!((ping.kind(), ping.ping_time(), ping.client_guid()),
+            (PingKind::Unconnected, 0x2b_3a6f, 0x5f7a_3b62_c10e_990d));
+        crate::fixture::assert_encodes(&ping_bytes, |buf| ping.encode(buf));
+
+        let pong_bytes = crate::fixture::load("raknet/synthesized-unconnected-pong.hex");
+        let pong = match Packet::decode(&pong_bytes).unwrap() {
+            Packet::Pong(pong) => pong,
+            other => panic!("decoded {:?}", other),
+        };
+        assert_eq!((pong.ping_time(), pong.server_guid()), (ping.ping_time(), 13253860892328930865));
+        assert_eq!(pong.motd().unwrap().server_id, Some(pong.server_guid()));
+        crate::fixture::assert_encodes(&pong_bytes, |buf| pong.encode(buf));
+
+        for len in 0..ping_bytes.len() {
+            assert!(Ping::decode(&ping_bytes[..len]).is_err(), "ping cut to {} bytes", len);
+        }
+        for len in 0..pong_bytes.len() {
+            assert!(Pong::decode(&pong_bytes[..len]).is_err(), "pong cut to {} bytes", len);
+        }
+        let mut damaged = ping_bytes.clone();
+        damaged[9] ^= 1;
+        assert!(Ping::decode(&damaged).is_err());
+    }
+
+    struct Responder(Sender);
+
+    impl Handler for Responder {
+        fn on_ping(&mut self, ping: &Ping) -> io::Result<()> {
+            self.0.send_pong(&Pong::new(ping.ping_time(), 42, "MCPE;Laji;389;1.14.0;0;10")).map(drop)
+        }
+
+        fn on_pong(&mut self, _pong: &Pong, _motd: Option<&BedrockMotd>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Collector(std::sync::mpsc::Sender<(u64, Option<u32>)>);
+
+    impl Handler for Collector {
+        fn on_ping(&mut self, _ping: &Ping) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn on_pong(&mut self, pong: &Pong, motd: Option<&BedrockMotd>) -> io::Result<()> {
+            let _ = self.0.send((pong.ping_time(), motd.map(|motd| motd.max_players)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn listen_and_connect() -> io::Result<()> {
+        let server = LajiRakPing::bind("127.0.0.1:0", Responder)?
+            .throttle(Throttle::new().per_source(1, Duration::from_secs(60)));
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run());
+        let (pongs, heard) = std::sync::mpsc::channel();
+        thread::spawn(move || connect(addr, move |sender: Sender| {
+            assert_eq!(sender.peer_addr(), addr);
+            sender.send_ping(&Ping::new(1, 7)).unwrap();
+            // over the limit, so not answered
+            sender.send_ping(&Ping::new(2, 7)).unwrap();
+            Collector(pongs.clone())
+        }));
+        assert_eq!(heard.recv_timeout(Duration::from_secs(2)).unwrap(), (1, Some(10)));
+        assert!(heard.recv_timeout(Duration::from_millis(200)).is_err());
+        Ok(())
+    }
+
+    struct Refusing(std::sync::mpsc::Sender<io::ErrorKind>);
+
+    impl Factory for Refusing {
+        type Handler = Refusing;
+
+        fn connection_made(&mut self, _sender: Sender) -> Refusing {
+            Refusing(self.0.clone())
+        }
+
+        fn on_error(&mut self, err: io::Error) {
+            let _ = self.0.send(err.kind());
+        }
+    }
+
+    impl Handler for Refusing {
+        fn on_ping(&mut self, _ping: &Ping) -> io::Result<()> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+
+        fn on_pong(&mut self, _pong: &Pong, _motd: Option<&BedrockMotd>) -> io::Result<()> {
+            Err(io::ErrorKind::InvalidData.into())
+        }
+    }
+
+    #[test]
+    fn handler_errors_reach_on_error() -> io::Result<()> {
+        let (errors, failed) = std::sync::mpsc::channel();
+        let server = LajiRakPing::bind("127.0.0.1:0", Refusing(errors))?;
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run());
+        let client = net::UdpSocket::bind("127.0.0.1:0")?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let len = Ping::new(1, 7).encode(&mut buf)?;
+        client.send_to(&buf[..len], addr)?;
+        let len = Pong::new(1, 7, "MCPE;Laji;").encode(&mut buf)?;
+        client.send_to(&buf[..len], addr)?;
+        let timeout = Duration::from_secs(2);
+        assert_eq!(failed.recv_timeout(timeout), Ok(io::ErrorKind::PermissionDenied));
+        assert_eq!(failed.recv_timeout(timeout), Ok(io::ErrorKind::InvalidData));
+        Ok(())
+    }
+
+    #[test]
+    fn advertiser() -> io::Result<()> {
+        let lan = net::UdpSocket::bind("127.0.0.1:0")?;
+        lan.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let server = net::UdpSocket::bind("127.0.0.1:0")?;
+        let advertiser = Advertiser::new(&server, 42, "MCPE;Laji;389;1.14.0;0;10")?
+            .target(lan.local_addr()?)
+            .interval(Duration::from_millis(20));
+        let motd = advertiser.motd();
+        advertiser.spawn()?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let (len, from) = lan.recv_from(&mut buf)?;
+        assert_eq!(from, server.local_addr()?);
+        assert_eq!(Pong::decode(&buf[..len])?.server_name(), "MCPE;Laji;389;1.14.0;0;10");
+        motd.set("MCPE;Laji;389;1.14.0;1;10");
+        let updated = (0..50).any(|_| {
+            let len = lan.recv(&mut buf).unwrap();
+            Pong::decode(&buf[..len]).unwrap().server_name() == "MCPE;Laji;389;1.14.0;1;10"
+        });
+        assert!(updated);
+        Ok(())
+    }
+
+    #[test]
+    fn incompatible_protocol_version() {
+        let request = OpenConnectionRequest1::new(9, 1492);
+        let mut buf = [0u8; 1500];
+        let len = request.encode(&mut buf).unwrap();
+        assert_eq!(len, 1492 - UDP_HEADERS_LEN);
+        assert_eq!(Packet::decode(&buf[..len]).unwrap(), Packet::OpenConnectionRequest1(request));
+        assert_eq!(request.incompatible_with(9, 42), None);
+        let reply = request.incompatible_with(10, 42).unwrap();
+        let len = reply.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..2], &[ID_INCOMPATIBLE_PROTOCOL_VERSION, 10]);
+        assert_eq!(&buf[2..18], &MAGIC);
+        assert_eq!(Packet::decode(&buf[..len]).unwrap(), Packet::IncompatibleProtocolVersion(reply));
+        buf[5] ^= 1;
+        assert!(Packet::decode(&buf[..len]).is_err());
+    }
+
+    #[test]
+    fn bedrock_motd() {
+        const NAME: &str = "MCPE;Dedicated Server;390;1.14.60;0;10;13253860892328930865;Bedrock level;Survival;1;19132;19133;";
+        let motd = Pong::new(1, 2, NAME).motd().unwrap();
+        assert_eq!((motd.motd.as_str(), motd.protocol, motd.max_players), ("Dedicated Server", 390, 10));
+        assert_eq!((motd.level_name.as_ref().map(|s| &s[..]), motd.port_v6), (Some("Bedrock level"), Some(19133)));
+        assert_eq!(motd.to_string(), NAME);
+        let old = BedrockMotd::parse("MCPE;Laji;137;1.11.0;0;20").unwrap();
+        assert_eq!((old.players_online, old.server_id), (0, None));
+        assert_eq!(old.to_string(), "MCPE;Laji;137;1.11.0;0;20;");
+        assert_eq!(BedrockMotd::parse("Laji"), None);
+        assert_eq!(BedrockMotd::parse("MCPE;Laji;new;1.11.0;0;20"), None);
+    }
+
+    #[test]
+    fn pinger_retries() -> io::Result<()> {
+        let server = net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = server.local_addr()?;
+        thread::spawn(move || -> io::Result<()> {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            // the first ping is lost
+            server.recv_from(&mut buf)?;
+            let (len, client) = server.recv_from(&mut buf)?;
+            let ping = Ping::decode(&buf[..len])?;
+            let len = Pong::new(ping.ping_time(), 42, "MCPE;Laji;389;1.14.0;0;10").encode(&mut buf)?;
+            server.send_to(&buf[..len], client)?;
+            Ok(())
+        });
+        let retry = RetryPolicy::new().initial_delay(Duration::from_millis(100)).multiplier(1.5);
+        let mut seen = Vec::new();
+        let reply = Pinger::new().retry(retry).ping_with(addr, |attempt| seen.push(attempt.number))?;
+        assert_eq!(reply.pong().server_guid(), 42);
+        assert_eq!(reply.motd().unwrap().max_players, 10);
+        assert_eq!(reply.attempts()[0], Attempt { number: 1, outcome: Outcome::TimedOut });
+        assert_eq!(reply.attempts()[1].outcome, Outcome::Answered(reply.latency()));
+        assert_eq!(seen, [1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_deadline() -> io::Result<()> {
+        let silent = net::UdpSocket::bind("127.0.0.1:0")?;
+        let retry = RetryPolicy::new().attempts(100).initial_delay(Duration::from_millis(20))
+            .multiplier(1.0).jitter(0.5).deadline(Duration::from_millis(150));
+        let started = Instant::now();
+        let ans = Pinger::new().retry(retry).ping(silent.local_addr()?);
+        assert_eq!(ans.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(RetryPolicy::new().delay(3), Duration::from_secs(2));
+        let jittered = retry.delay(1);
+        assert!(jittered >= Duration::from_millis(10) && jittered <= Duration::from_millis(30), "{:?}", jittered);
+        Ok(())
+    }
+
+    #[test]
+    fn throttle() {
+        let mut throttle = Throttle::new().per_source(2, Duration::from_secs(60)).min_request_len(64);
+        let (spoofed, other) = ("192.0.2.1:19132".parse().unwrap(), "192.0.2.2:19132".parse().unwrap());
+        assert_eq!(throttle.check(spoofed, &[ID_UNCONNECTED_PING; PING_LEN]), Err(Dropped::TooShort));
+        let padded = [ID_UNCONNECTED_PING; 64];
+        assert_eq!(throttle.check(spoofed, &padded), Ok(()));
+        assert_eq!(throttle.check(spoofed, &padded), Ok(()));
+        assert_eq!(throttle.check(spoofed, &padded), Err(Dropped::RateLimited));
+        assert_eq!(throttle.check(other, &padded), Ok(()));
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_round_trip() {
+        crate::prop::for_all(|packet: Packet<'static>| {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            if let Ok(len) = packet.encode(&mut buf) {
+                assert_eq!(Packet::decode(&buf[..len]).unwrap(), packet);
+            }
+        });
+    }
 }
@@ -0,0 +1,43 @@
+//! Random protocol fields: transaction ids, initial sequence numbers, backoff jitter.
+//!
+//! Everything comes from `rand::thread_rng`, a CSPRNG seeded from the OS, because the
+//! defences against off-path spoofing in RFC 5389 (STUN transaction ids), RFC 5452 (DNS
+//! query ids) and RFC 6528 (initial sequence numbers) rely on these being unguessable.
+use rand::{distributions::{Distribution, Standard, Uniform}, Rng};
+
+/// A uniformly random value.
+#[inline]
+pub(crate) fn random<T>() -> T
+where Standard: Distribution<T>
+{
+    rand::thread_rng().gen()
+}
+
+/// Uniform in `0..=max`.
+#[inline]
+pub(crate) fn up_to(max: u64) -> u64 {
+    Uniform::new_inclusive(0, max).sample(&mut rand::thread_rng())
+}
+
+/// `secs` moved by up to `jitter` of itself either way.
+#[inline]
+pub(crate) fn jittered(secs: f64, jitter: f64) -> f64 {
+    let unit: f64 = random();
+    secs * (1.0 + jitter * (2.0 * unit - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        for _ in 0..1000 {
+            assert!(up_to(3) <= 3);
+            let secs = jittered(2.0, 0.25);
+            assert!((1.5..=2.5).contains(&secs), "{}", secs);
+        }
+        assert_eq!(up_to(0), 0);
+        assert_ne!(random::<[u8; 12]>(), random::<[u8; 12]>());
+    }
+}
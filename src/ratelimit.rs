@@ -0,0 +1,121 @@
+//! Per-source rate limits for UDP services, which answer whoever a datagram claims to come
+//! from and so must not answer any one address too often, and a token bucket for clients
+//! pacing what they send.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Sources tracked at most; expired ones are swept out once per window when there are this
+/// many, and new sources are refused while all of them are still current.
+const MAX_SOURCES: usize = 4096;
+
+/// At most `limit` events per source IP in each `window`, counted in fixed windows starting
+/// at a source's first event.
+#[derive(Clone, Debug)]
+pub struct PerSource {
+    limit: u32,
+    window: Duration,
+    seen: HashMap<IpAddr, (Instant, u32)>,
+    next_sweep: Option<Instant>,
+}
+
+impl PerSource {
+    #[inline]
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window, seen: HashMap::new(), next_sweep: None }
+    }
+
+    /// Count an event from `source`, and say whether it is within the limit.
+    #[inline]
+    pub fn allow(&mut self, source: IpAddr) -> bool {
+        self.allow_at(source, Instant::now())
+    }
+
+    pub fn allow_at(&mut self, source: IpAddr, now: Instant) -> bool {
+        let window = self.window;
+        if self.seen.len() >= MAX_SOURCES && !self.seen.contains_key(&source) {
+            if self.next_sweep.is_none_or(|at| now >= at) {
+                self.seen.retain(|_, &mut (start, _)| now.duration_since(start) < window);
+                self.next_sweep = Some(now + window);
+            }
+            if self.seen.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+        let (start, count) = self.seen.entry(source).or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// Lets through `rate` units per second on average, and up to `burst` at once.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so the first `burst` units go through at once.
+    #[inline]
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst, tokens: burst, last: Instant::now() }
+    }
+
+    /// Take `n` units, and say how long to wait before using them to stay within the rate.
+    /// The units are taken either way; waiting pays back the debt.
+    pub fn take_at(&mut self, n: f64, now: Instant) -> Duration {
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst) - n;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    #[inline]
+    pub fn take(&mut self, n: f64) -> Duration {
+        self.take_at(n, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_windows() {
+        let mut limit = PerSource::new(2, Duration::from_secs(1));
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let start = Instant::now();
+        assert!(limit.allow_at(a, start));
+        assert!(limit.allow_at(a, start + Duration::from_millis(10)));
+        assert!(!limit.allow_at(a, start + Duration::from_millis(20)));
+        assert!(limit.allow_at(b, start + Duration::from_millis(20)));
+        assert!(limit.allow_at(a, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(1000.0, 500.0);
+        let start = Instant::now();
+        assert_eq!(bucket.take_at(500.0, start), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(250.0, start), Duration::from_millis(250));
+        // the 250 owed are paid back after a quarter second, and then it refills
+        assert_eq!(bucket.take_at(100.0, start + Duration::from_millis(350)), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(0.0, start + Duration::from_secs(10)), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(600.0, start + Duration::from_secs(10)), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,222 @@
+//! Keeping a long-lived TCP client connected.
+//!
+//! `Reconnecting` runs a `Session` over and over: whenever the connection drops or cannot
+//! be made, it waits and dials again, each wait `multiplier` times the last up to
+//! `max_delay`. A session that stayed up for `healthy_after` starts the backoff over, so a
+//! client that was fine for a day does not wait a minute after its first hiccup.
+use std::{
+    io,
+    thread,
+    time::{Duration, Instant},
+};
+use crate::random;
+
+/// How one session ended, when it ended without an error.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Ended {
+    /// The peer closed the connection; dial again.
+    Closed,
+    /// The client is finished, as after an IRC `QUIT`; do not dial again.
+    Done,
+}
+
+/// One connection, from dialing until it ends.
+pub trait Session {
+    fn session(&mut self) -> io::Result<Ended>;
+}
+
+impl<F> Session for F
+where
+    F: FnMut() -> io::Result<Ended>
+{
+    #[inline]
+    fn session(&mut self) -> io::Result<Ended> {
+        self()
+    }
+}
+
+/// What `Reconnecting` tells about its reconnects.
+pub trait Events {
+    /// The `failures`th disconnect in a row happened, with `cause` unless the peer just
+    /// closed, and the next dial comes after `delay`.
+    fn on_reconnect(&mut self, _failures: u32, _delay: Duration, _cause: Option<&io::Error>) {}
+
+    /// `give_up_after` was reached; `run` returns `err` next.
+    fn on_give_up(&mut self, _err: &io::Error) {}
+}
+
+impl Events for () {}
+
+#[derive(Clone, Debug)]
+pub struct Reconnecting<C> {
+    client: C,
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    healthy_after: Duration,
+    give_up_after: Option<u32>,
+}
+
+impl<C> Reconnecting<C>
+where
+    C: Session
+{
+    #[inline]
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            healthy_after: Duration::from_secs(30),
+            give_up_after: None,
+        }
+    }
+
+    #[inline]
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    #[inline]
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    #[inline]
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// A fraction of each wait, from 0 for none to 1, to move it by either way, so clients
+    /// dropped together do not all dial again at once.
+    #[inline]
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// A session lasting this long resets the backoff.
+    #[inline]
+    pub fn healthy_after(mut self, after: Duration) -> Self {
+        self.healthy_after = after;
+        self
+    }
+
+    /// Stop after this many disconnects in a row, instead of never.
+    #[inline]
+    pub fn give_up_after(mut self, failures: u32) -> Self {
+        self.give_up_after = Some(failures);
+        self
+    }
+
+    #[inline]
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+
+    /// How long to wait after the `failures`th disconnect in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = self.multiplier.powi(failures.saturating_sub(1) as i32);
+        let mut secs = (self.initial_delay.as_secs_f64() * factor).min(self.max_delay.as_secs_f64());
+        if self.jitter > 0.0 {
+            secs = random::jittered(secs, self.jitter);
+        }
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+
+    /// Run sessions until one ends with `Ended::Done`, or until giving up.
+    #[inline]
+    pub fn run(&mut self) -> io::Result<()> {
+        self.run_with(&mut ())
+    }
+
+    pub fn run_with<E>(&mut self, events: &mut E) -> io::Result<()>
+    where E: Events
+    {
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let cause = match self.client.session() {
+                Ok(Ended::Done) => return Ok(()),
+                Ok(Ended::Closed) => None,
+                Err(e) => Some(e),
+            };
+            if started.elapsed() >= self.healthy_after {
+                failures = 0;
+            }
+            failures += 1;
+            if self.give_up_after.is_some_and(|max| failures > max) {
+                let err = cause.unwrap_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"));
+                events.on_give_up(&err);
+                return Err(err);
+            }
+            let delay = self.delay(failures);
+            events.on_reconnect(failures, delay, cause.as_ref());
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Events for Log {
+        fn on_reconnect(&mut self, failures: u32, _delay: Duration, cause: Option<&io::Error>) {
+            self.0.push(format!("{} {:?}", failures, cause.map(io::Error::kind)));
+        }
+
+        fn on_give_up(&mut self, err: &io::Error) {
+            self.0.push(format!("give up {:?}", err.kind()));
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = Reconnecting::new(|| Ok(Ended::Done)).jitter(0.0).max_delay(Duration::from_secs(3));
+        let delays: Vec<_> = (1..=5).map(|n| retry.delay(n).as_millis()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+        let jittered = retry.jitter(0.5).delay(1);
+        assert!(jittered >= Duration::from_millis(250) && jittered <= Duration::from_millis(750));
+    }
+
+    #[test]
+    fn redials() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let mut sessions = 0;
+        let mut retry = Reconnecting::new(move || {
+            sessions += 1;
+            match sessions {
+                1 => TcpStream::connect(addr).map(|_| Ended::Closed),
+                2 => Ok(Ended::Closed),
+                _ => Ok(Ended::Done),
+            }
+        }).initial_delay(Duration::from_millis(1)).jitter(0.0);
+        let mut log = Log::default();
+        retry.run_with(&mut log)?;
+        assert_eq!(log.0, ["1 Some(ConnectionRefused)", "2 None"]);
+
+        let mut log = Log::default();
+        let mut closing = Reconnecting::new(|| Ok(Ended::Closed)).initial_delay(Duration::from_millis(1)).give_up_after(1);
+        assert_eq!(closing.run_with(&mut log).unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(log.0, ["1 None", "give up ConnectionAborted"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+//! Handler and factory wrappers that log every callback, for asserting on the lifecycle a
+//! backend drives.
+//!
+//! Implemented for the discard (sync and mio) and daytime handlers; the log is typed by the
+//! backend's `Handshake`, so `Open` carries what that backend reported.
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "daytime")]
+use crate::daytime_threads;
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+use crate::discard_mio;
+#[cfg(feature = "discard")]
+use crate::discard_sync;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Event<S> {
+    Open(S),
+    Request,
+    Data(Vec<u8>),
+    /// A handler was told of a failure, by its kind.
+    Error(io::ErrorKind),
+    Close,
+}
+
+/// The ordered events of every handler recording into it; clones share the same log.
+#[derive(Debug)]
+pub struct EventLog<S> {
+    inner: Arc<(Mutex<Vec<Event<S>>>, Condvar)>,
+}
+
+impl<S> EventLog<S> {
+    #[inline]
+    pub fn new() -> Self {
+        Self { inner: Arc::new((Mutex::new(Vec::new()), Condvar::new())) }
+    }
+
+    /// Wrap `factory` so every handler it makes records here.
+    #[inline]
+    pub fn factory<F>(&self, factory: F) -> RecordingFactory<F, S> {
+        RecordingFactory { inner: factory, log: self.clone() }
+    }
+
+    /// Wrap one handler.
+    #[inline]
+    pub fn handler<H>(&self, handler: H) -> RecordingHandler<H, S> {
+        RecordingHandler { inner: handler, log: self.clone() }
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        (self.inner.0).lock().unwrap().clear();
+    }
+
+    fn push(&self, event: Event<S>) {
+        let (events, changed) = &*self.inner;
+        events.lock().unwrap().push(event);
+        changed.notify_all();
+    }
+}
+
+impl<S> EventLog<S>
+where
+    S: Clone
+{
+    #[inline]
+    pub fn events(&self) -> Vec<Event<S>> {
+        (self.inner.0).lock().unwrap().clone()
+    }
+
+    /// Wait until at least `n` events were recorded, for servers running on other threads.
+    /// Returns whatever was recorded when `timeout` ran out.
+    pub fn wait_for(&self, n: usize, timeout: Duration) -> Vec<Event<S>> {
+        let deadline = Instant::now() + timeout;
+        let (events, changed) = &*self.inner;
+        let mut events = events.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if events.len() >= n || now >= deadline {
+                return events.clone();
+            }
+            events = changed.wait_timeout(events, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl<S> Clone for EventLog<S> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<S> Default for EventLog<S> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Passes every callback on to the wrapped handler after logging it.
+#[derive(Debug)]
+pub struct RecordingHandler<H, S> {
+    inner: H,
+    log: EventLog<S>,
+}
+
+impl<H, S> RecordingHandler<H, S> {
+    #[inline]
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordingFactory<F, S> {
+    inner: F,
+    log: EventLog<S>,
+}
+
+impl<F, S> Clone for RecordingFactory<F, S>
+where
+    F: Clone
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), log: self.log.clone() }
+    }
+}
+
+#[cfg(feature = "discard")]
+impl<H> discard_sync::Handler for RecordingHandler<H, discard_sync::Handshake>
+where
+    H: discard_sync::Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: discard_sync::Handshake) {
+        self.log.push(Event::Open(shake));
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.log.push(Event::Close);
+        self.inner.on_close()
+    }
+}
+
+#[cfg(feature = "discard")]
+impl<F> discard_sync::Factory for RecordingFactory<F, discard_sync::Handshake>
+where
+    F: discard_sync::Factory
+{
+    type Handler = RecordingHandler<F::Handler, discard_sync::Handshake>;
+
+    #[inline]
+    fn accept(&mut self, shake: &discard_sync::Handshake) -> bool {
+        self.inner.accept(shake)
+    }
+
+    #[inline]
+    fn connection_made(&mut self) -> Self::Handler {
+        self.log.handler(self.inner.connection_made())
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+impl<H> discard_mio::Handler for RecordingHandler<H, discard_mio::Handshake>
+where
+    H: discard_mio::Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: discard_mio::Handshake) {
+        self.log.push(Event::Open(shake));
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_data(&mut self, data: &[u8]) {
+        self.log.push(Event::Data(data.to_vec()));
+        self.inner.on_data(data)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.log.push(Event::Close);
+        self.inner.on_close()
+    }
+
+    #[inline]
+    fn on_close_with(&mut self, reason: discard_mio::CloseReason) {
+        self.log.push(Event::Close);
+        self.inner.on_close_with(reason)
+    }
+
+    #[inline]
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.inner.idle_timeout()
+    }
+}
+
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+impl<F> discard_mio::Factory for RecordingFactory<F, discard_mio::Handshake>
+where
+    F: discard_mio::Factory
+{
+    type Handler = RecordingHandler<F::Handler, discard_mio::Handshake>;
+
+    #[inline]
+    fn accept(&mut self, info: &discard_mio::ConnectionInfo) -> bool {
+        self.inner.accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &discard_mio::ConnectionInfo) -> Self::Handler {
+        self.log.handler(self.inner.connection_made(info))
+    }
+
+    #[inline]
+    fn on_reject(&mut self, info: &discard_mio::ConnectionInfo, reason: discard_mio::RejectReason) {
+        self.inner.on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[cfg(feature = "daytime")]
+impl<H> daytime_threads::Handler for RecordingHandler<H, daytime_threads::Handshake>
+where
+    H: daytime_threads::Handler
+{
+    #[inline]
+    fn on_open(&mut self, shake: daytime_threads::Handshake) {
+        self.log.push(Event::Open(shake));
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_request(&mut self) {
+        self.log.push(Event::Request);
+        self.inner.on_request()
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.log.push(Event::Error(err.kind()));
+        self.inner.on_error(err)
+    }
+
+    #[inline]
+    fn on_close(&mut self) {
+        self.log.push(Event::Close);
+        self.inner.on_close()
+    }
+}
+
+#[cfg(feature = "daytime")]
+impl<F> daytime_threads::Factory for RecordingFactory<F, daytime_threads::Handshake>
+where
+    F: daytime_threads::Factory
+{
+    type Handler = RecordingHandler<F::Handler, daytime_threads::Handshake>;
+
+    #[inline]
+    fn connection_made(&mut self, sender: daytime_threads::Sender) -> Self::Handler {
+        self.log.handler(self.inner.connection_made(sender))
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.inner.on_error(err)
+    }
+}
+
+#[cfg(all(test, any(feature = "discard", feature = "daytime")))]
+mod tests {
+    use super::*;
+    use std::{net::TcpStream, thread};
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    #[test]
+    #[cfg(feature = "discard")]
+    fn discard_sync_lifecycle() {
+        let log = EventLog::new();
+        let builder = discard_sync::Builder::new().bind("127.0.0.1:0").unwrap();
+        let addr = builder.local_addrs().unwrap()[0];
+        let server = builder.build(log.factory(|| |_shake| {}));
+        thread::spawn(move || server.run().unwrap());
+        let client = TcpStream::connect(addr).unwrap();
+        let events = log.wait_for(2, WAIT);
+        match &events[..] {
+            [Event::Open(shake), Event::Close] => assert_eq!(*shake.peer_addr(), client.local_addr().unwrap()),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "discard", feature = "backend-mio"))]
+    fn discard_mio_lifecycle() {
+        use std::io::Write;
+        let log = EventLog::new();
+        let server = discard_mio::Builder::new().bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addrs().unwrap()[0];
+        let factory = log.factory(|| |_shake| {});
+        thread::spawn(move || server.build(factory).unwrap().run().unwrap());
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"laji").unwrap();
+        assert_eq!(log.wait_for(2, WAIT)[1], Event::Data(b"laji".to_vec()));
+        drop(client);
+        let events = log.wait_for(3, WAIT);
+        assert_eq!(&events[1..], [Event::Data(b"laji".to_vec()), Event::Close]);
+    }
+
+    #[test]
+    #[cfg(feature = "daytime")]
+    fn daytime_lifecycle() {
+        use std::io::Read;
+        let log = EventLog::new();
+        let server = daytime_threads::LajiDaytime::new(log.factory(|_sender| || {}))
+            .bind_tcp("127.0.0.1:0").unwrap();
+        let addr = server.local_addrs().unwrap()[0];
+        thread::spawn(move || server.run().unwrap());
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.read_to_end(&mut Vec::new()).unwrap();
+        let open = Event::Open(daytime_threads::Handshake::Tcp {
+            peer_addr: client.local_addr().unwrap(),
+            local_addr: client.peer_addr().unwrap(),
+        });
+        assert_eq!(log.wait_for(3, WAIT), [open, Event::Request, Event::Close]);
+    }
+
+    #[test]
+    #[cfg(feature = "daytime")]
+    fn daytime_error_forwarded() {
+        use daytime_threads::Handler as _;
+        struct Failing(Arc<Mutex<Option<io::ErrorKind>>>);
+        impl daytime_threads::Handler for Failing {
+            fn on_error(&mut self, err: io::Error) {
+                *self.0.lock().unwrap() = Some(err.kind());
+            }
+        }
+        let log = EventLog::new();
+        let seen = Arc::new(Mutex::new(None));
+        let mut handler = log.handler(Failing(seen.clone()));
+        handler.on_error(io::ErrorKind::BrokenPipe.into());
+        assert_eq!(log.events(), [Event::Error(io::ErrorKind::BrokenPipe)]);
+        assert_eq!(*seen.lock().unwrap(), Some(io::ErrorKind::BrokenPipe));
+    }
+}
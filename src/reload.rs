@@ -0,0 +1,150 @@
+//! Swap a running server's factory without rebinding its listeners.
+//!
+//! Both halves share one slot: the server keeps the `ReloadableFactory`, the application keeps
+//! the `ReloadHandle`, and connections accepted after a swap get handlers from the new factory.
+//! To swap in a different closure, make `F` a boxed one such as
+//! `Box<dyn FnMut() -> H + Send>`.
+use std::{io, sync::{Arc, Mutex}};
+#[cfg(feature = "daytime")]
+use crate::daytime_threads;
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+use crate::discard_mio;
+#[cfg(feature = "discard")]
+use crate::discard_sync;
+
+pub fn reloadable<F>(factory: F) -> (ReloadableFactory<F>, ReloadHandle<F>) {
+    let current = Arc::new(Mutex::new(factory));
+    (ReloadableFactory { current: current.clone() }, ReloadHandle { current })
+}
+
+#[derive(Debug)]
+pub struct ReloadableFactory<F> {
+    current: Arc<Mutex<F>>,
+}
+
+impl<F> Clone for ReloadableFactory<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { current: self.current.clone() }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReloadHandle<F> {
+    current: Arc<Mutex<F>>,
+}
+
+impl<F> ReloadHandle<F> {
+    /// Install `factory` for every connection from now on, returning the one it replaced.
+    /// Handlers already made keep running.
+    #[inline]
+    pub fn swap(&self, factory: F) -> F {
+        std::mem::replace(&mut *self.current.lock().unwrap(), factory)
+    }
+}
+
+impl<F> Clone for ReloadHandle<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { current: self.current.clone() }
+    }
+}
+
+#[cfg(feature = "discard")]
+impl<F> discard_sync::Factory for ReloadableFactory<F>
+where
+    F: discard_sync::Factory
+{
+    type Handler = F::Handler;
+
+    #[inline]
+    fn accept(&mut self, shake: &discard_sync::Handshake) -> bool {
+        self.current.lock().unwrap().accept(shake)
+    }
+
+    #[inline]
+    fn connection_made(&mut self) -> F::Handler {
+        self.current.lock().unwrap().connection_made()
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.current.lock().unwrap().on_error(err)
+    }
+}
+
+#[cfg(all(feature = "discard", feature = "backend-mio"))]
+impl<F> discard_mio::Factory for ReloadableFactory<F>
+where
+    F: discard_mio::Factory
+{
+    type Handler = F::Handler;
+
+    #[inline]
+    fn accept(&mut self, info: &discard_mio::ConnectionInfo) -> bool {
+        self.current.lock().unwrap().accept(info)
+    }
+
+    #[inline]
+    fn connection_made(&mut self, info: &discard_mio::ConnectionInfo) -> F::Handler {
+        self.current.lock().unwrap().connection_made(info)
+    }
+
+    #[inline]
+    fn on_reject(&mut self, info: &discard_mio::ConnectionInfo, reason: discard_mio::RejectReason) {
+        self.current.lock().unwrap().on_reject(info, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.current.lock().unwrap().on_error(err)
+    }
+}
+
+#[cfg(feature = "daytime")]
+impl<F> daytime_threads::Factory for ReloadableFactory<F>
+where
+    F: daytime_threads::Factory
+{
+    type Handler = F::Handler;
+
+    #[inline]
+    fn connection_made(&mut self, sender: daytime_threads::Sender) -> F::Handler {
+        self.current.lock().unwrap().connection_made(sender)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: io::Error) {
+        self.current.lock().unwrap().on_error(err)
+    }
+}
+
+#[cfg(all(test, feature = "discard"))]
+mod tests {
+    use super::*;
+    use std::{net::TcpStream, sync::mpsc, thread, time::Duration};
+
+    type Tagging = Box<dyn FnMut() -> Box<dyn FnMut(discard_sync::Handshake)> + Send>;
+
+    fn tagging(tag: &'static str, tx: mpsc::Sender<&'static str>) -> Tagging {
+        Box::new(move || {
+            let tx = tx.clone();
+            Box::new(move |_shake| tx.send(tag).unwrap())
+        })
+    }
+
+    #[test]
+    fn swap_keeps_listening() {
+        let (tx, rx) = mpsc::channel();
+        let (factory, handle) = reloadable(tagging("old", tx.clone()));
+        let builder = discard_sync::Builder::new().bind("127.0.0.1:0").unwrap();
+        let addr = builder.local_addrs().unwrap()[0];
+        let server = builder.build(factory);
+        thread::spawn(move || server.run().unwrap());
+        TcpStream::connect(addr).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok("old"));
+        drop(handle.swap(tagging("new", tx)));
+        TcpStream::connect(addr).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok("new"));
+    }
+}
@@ -0,0 +1,285 @@
+//! Every address a host name resolves to, tried in turn, for clients that connect more than
+//! once.
+//!
+//! Each connect starts one address further along than the last, so repeated connections
+//! spread over all of a host's addresses, and a failing address is followed by the next
+//! instead of failing the connect. With `refresh_every`, the name is resolved again once the
+//! addresses are that old, so clients follow DNS changes without restarting.
+//!
+//! `connect_happy` is what the TCP clients use for single connections: it races a host's
+//! IPv6 and IPv4 addresses as RFC 8305 describes, so a broken IPv6 path costs a quarter
+//! second instead of a connect timeout.
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long an attempt gets before the next address is tried alongside it (RFC 8305, 5).
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to whichever of `host`'s addresses answers first, giving up after `timeout`.
+#[inline]
+pub fn connect_happy(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    connect_happy_to((host, port), timeout)
+}
+
+/// Make a read that ran past its `set_read_timeout` fail with `TimedOut`, as it does on
+/// Windows, instead of the `WouldBlock` Unix reports; the clients pass their read errors
+/// through this so a timeout looks the same everywhere.
+pub fn timed_out(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "read timed out"),
+        _ => err,
+    }
+}
+
+/// A UDP socket on an ephemeral port of the unspecified address in `peer`'s family, for
+/// sending to `peer` whichever IP version it speaks.
+pub fn bind_ephemeral_for(peer: SocketAddr) -> io::Result<UdpSocket> {
+    let local: SocketAddr = match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    UdpSocket::bind(local)
+}
+
+/// `connect_happy` for anything that resolves to socket addresses.
+///
+/// Addresses are tried alternating between IPv6 and IPv4, IPv6 first. A new attempt starts
+/// when the previous one failed or has been pending for `CONNECTION_ATTEMPT_DELAY`; the
+/// first to connect wins and the rest are closed once they finish.
+pub fn connect_happy_to<A>(addr: A, timeout: Duration) -> io::Result<TcpStream>
+where A: ToSocketAddrs
+{
+    let addrs = interleave(addr.to_socket_addrs()?.collect());
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    let (mut started, mut pending) = (0, 0);
+    let mut last_err = None;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+        }
+        if started < addrs.len() {
+            let (addr, left, tx) = (addrs[started], deadline - now, tx.clone());
+            // a losing attempt's stream is dropped when the send finds nobody listening
+            thread::spawn(move || { let _ = tx.send(TcpStream::connect_timeout(&addr, left)); });
+            started += 1;
+            pending += 1;
+        }
+        if pending == 0 {
+            return Err(last_err.unwrap_or_else(no_addresses));
+        }
+        let wait = if started < addrs.len() { CONNECTION_ATTEMPT_DELAY } else { deadline - now };
+        match rx.recv_timeout(wait.min(deadline - now)) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_err = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// IPv6 and IPv4 addresses taking turns, each family in resolver order.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ans = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ans,
+            (a, b) => ans.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    host: String,
+    port: u16,
+    addrs: Vec<SocketAddr>,
+    next: usize,
+    resolved_at: Instant,
+    refresh: Option<Duration>,
+}
+
+impl Rotation {
+    pub fn new(host: &str, port: u16) -> io::Result<Self> {
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            addrs: resolve(host, port)?,
+            next: 0,
+            resolved_at: Instant::now(),
+            refresh: None,
+        })
+    }
+
+    /// Resolve the host again when the addresses are older than `interval`.
+    #[inline]
+    pub fn refresh_every(mut self, interval: Duration) -> Self {
+        self.refresh = Some(interval);
+        self
+    }
+
+    /// All addresses, starting with the one after where the last call started.
+    pub fn addrs(&mut self) -> io::Result<Vec<SocketAddr>> {
+        if self.refresh.is_some_and(|interval| self.resolved_at.elapsed() >= interval) {
+            // keep the old addresses if the name stopped resolving for a moment
+            if let Ok(addrs) = resolve(&self.host, self.port) {
+                self.addrs = addrs;
+            }
+            self.resolved_at = Instant::now();
+        }
+        let start = self.next % self.addrs.len();
+        self.next = start + 1;
+        Ok(self.addrs[start..].iter().chain(&self.addrs[..start]).cloned().collect())
+    }
+
+    /// Connect to the first address, in rotation order, that accepts; the error is the last
+    /// address's if none does.
+    pub fn connect_tcp(&mut self, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        self.try_each(|addr| match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        })
+    }
+
+    /// Run `attempt` on each address in rotation order until one succeeds.
+    pub fn try_each<T, F>(&mut self, mut attempt: F) -> io::Result<T>
+    where F: FnMut(SocketAddr) -> io::Result<T>
+    {
+        let mut last_err = None;
+        for addr in self.addrs()? {
+            match attempt(addr) {
+                Ok(ans) => return Ok(ans),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(no_addresses))
+    }
+}
+
+fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(no_addresses());
+    }
+    Ok(addrs)
+}
+
+/// Every address `addr` resolves to, each once, in resolver order.
+pub(crate) fn each_addr<A>(addr: A) -> io::Result<Vec<SocketAddr>>
+where A: ToSocketAddrs
+{
+    let mut addrs = Vec::new();
+    for addr in addr.to_socket_addrs()? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(no_addresses());
+    }
+    Ok(addrs)
+}
+
+/// Run `attempt` on each of `addr`'s addresses until one succeeds; the error is the last
+/// address's if none does.
+pub(crate) fn try_each<A, T, F>(addr: A, mut attempt: F) -> io::Result<T>
+where
+    A: ToSocketAddrs,
+    F: FnMut(SocketAddr) -> io::Result<T>
+{
+    let mut last_err = None;
+    for addr in each_addr(addr)? {
+        match attempt(addr) {
+            Ok(ans) => return Ok(ans),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+/// A UDP socket connected to the first of `addr`'s addresses that can be bound for and
+/// connected to, so a host whose IPv6 network is unreachable is reached over IPv4.
+#[cfg(any(feature = "chargen", feature = "echo", feature = "rakping", feature = "simtcp"))]
+pub(crate) fn connect_udp<A>(addr: A) -> io::Result<UdpSocket>
+where A: ToSocketAddrs
+{
+    try_each(addr, |peer| {
+        let socket = bind_ephemeral_for(peer)?;
+        socket.connect(peer)?;
+        Ok(socket)
+    })
+}
+
+#[inline]
+fn no_addresses() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn rotation(addrs: &[&str]) -> Rotation {
+        let mut rotation = Rotation::new("127.0.0.1", 0).unwrap();
+        rotation.addrs = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+        rotation
+    }
+
+    #[test]
+    fn rotates_and_skips_failures() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        // a port nobody listens on, taken from a listener dropped right away
+        let closed = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let open = listener.local_addr()?.to_string();
+        let mut rotation = rotation(&[&closed.to_string(), &open]);
+        let first = rotation.addrs()?;
+        assert_eq!(rotation.addrs()?, [first[1], first[0]]);
+        assert_eq!(rotation.connect_tcp(Some(Duration::from_secs(1)))?.peer_addr()?, listener.local_addr()?);
+        let err = rotation.try_each(|_addr| -> io::Result<()> {
+            Err(io::Error::other("down"))
+        }).unwrap_err();
+        assert_eq!(err.to_string(), "down");
+        Ok(())
+    }
+
+    #[test]
+    fn refreshes() -> io::Result<()> {
+        let mut rotation = rotation(&["192.0.2.1:7"]).refresh_every(Duration::from_secs(0));
+        assert_eq!(rotation.addrs()?, ["127.0.0.1:0".parse().unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "10.0.0.3:1", "[::2]:1"]
+            .iter().map(|addr| addr.parse().unwrap()).collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "10.0.0.3:1"]
+            .iter().map(|addr| addr.parse().unwrap()).collect();
+        assert_eq!(interleave(addrs), expected);
+    }
+
+    #[test]
+    fn happy_connect() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let closed = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        // the refused address fails fast, so the second one is tried without waiting
+        let start = Instant::now();
+        let stream = connect_happy_to(&[closed, listener.local_addr()?][..], Duration::from_secs(2))?;
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+        assert_eq!(connect_happy_to(closed, Duration::from_secs(2)).unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Sampling for error reports, so a storm of the same error (thousands of resets during a
+//! load test, say) reaches the logs as a few examples and a count instead of one line each.
+//!
+//! `ErrorSampler` passes the first `burst` errors of each kind in a window on to its sink,
+//! and counts the rest. When the window is over it reports the count as a `Summary`, so a
+//! kind that keeps failing stays visible without drowning out the kinds that do not.
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// What an `ErrorSampler` hands to its sink.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Report {
+    /// One of the first errors of its kind in the window, reported as it happened.
+    Error { kind: io::ErrorKind, peer: Option<SocketAddr> },
+    /// How many more errors of `kind` a finished window held than were reported.
+    Summary { kind: io::ErrorKind, suppressed: u64, window: Duration },
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Report::Error { kind, peer: Some(peer) } => write!(f, "error: {:?} from {}", kind, peer),
+            Report::Error { kind, peer: None } => write!(f, "error: {:?}", kind),
+            Report::Summary { kind, suppressed, window } =>
+                write!(f, "error: {:?} {} more times in {:?}", kind, suppressed, window),
+        }
+    }
+}
+
+struct Window {
+    started: Instant,
+    reported: u32,
+    suppressed: u64,
+}
+
+struct State {
+    burst: u32,
+    window: Duration,
+    windows: HashMap<io::ErrorKind, Window>,
+    sink: Box<dyn FnMut(&Report) + Send>,
+}
+
+/// Reports errors to a sink, at most `burst` of each kind per window; clones share the
+/// counts and the sink, so one sampler can serve every connection of a server.
+#[derive(Clone)]
+pub struct ErrorSampler {
+    state: Arc<Mutex<State>>,
+}
+
+impl ErrorSampler {
+    /// Let through 5 errors of each kind every 10 seconds.
+    ///
+    /// The sink runs with the sampler locked, so it must not report to the same sampler.
+    pub fn new<S>(sink: S) -> Self
+    where S: FnMut(&Report) + Send + 'static
+    {
+        let state = State {
+            burst: 5,
+            window: Duration::from_secs(10),
+            windows: HashMap::new(),
+            sink: Box::new(sink),
+        };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    #[inline]
+    pub fn burst(self, burst: u32) -> Self {
+        self.state.lock().unwrap().burst = burst;
+        self
+    }
+
+    #[inline]
+    pub fn window(self, window: Duration) -> Self {
+        self.state.lock().unwrap().window = window;
+        self
+    }
+
+    /// Count an error, and say whether the sink was told about it.
+    #[inline]
+    pub fn report(&self, kind: io::ErrorKind, peer: Option<SocketAddr>) -> bool {
+        self.report_at(kind, peer, Instant::now())
+    }
+
+    pub fn report_at(&self, kind: io::ErrorKind, peer: Option<SocketAddr>, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.flush(now);
+        let burst = state.burst;
+        let window = state.windows.entry(kind)
+            .or_insert(Window { started: now, reported: 0, suppressed: 0 });
+        if window.reported >= burst {
+            window.suppressed += 1;
+            return false;
+        }
+        window.reported += 1;
+        (state.sink)(&Report::Error { kind, peer });
+        true
+    }
+
+    /// Summarise the windows that are over. Reporting does this too, so call it on a timer
+    /// only to hear about a storm that stopped before the next error came.
+    #[inline]
+    pub fn flush(&self) {
+        self.flush_at(Instant::now())
+    }
+
+    #[inline]
+    pub fn flush_at(&self, now: Instant) {
+        self.state.lock().unwrap().flush(now)
+    }
+}
+
+impl State {
+    fn flush(&mut self, now: Instant) {
+        let length = self.window;
+        let over: Vec<io::ErrorKind> = self.windows.iter()
+            .filter(|(_, window)| now.saturating_duration_since(window.started) >= length)
+            .map(|(&kind, _)| kind)
+            .collect();
+        for kind in over {
+            let window = self.windows.remove(&kind).unwrap();
+            if window.suppressed > 0 {
+                (self.sink)(&Report::Summary { kind, suppressed: window.suppressed, window: length });
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ErrorSampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("ErrorSampler")
+            .field("burst", &state.burst)
+            .field("window", &state.window)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn bursts_and_summaries() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let sampler = ErrorSampler::new(move |report: &Report| seen.lock().unwrap().push(*report))
+            .burst(2)
+            .window(Duration::from_secs(1));
+        let peer: SocketAddr = "10.0.0.7:50312".parse().unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert!(sampler.report_at(ErrorKind::ConnectionReset, Some(peer), at(0)));
+        assert!(sampler.report_at(ErrorKind::ConnectionReset, None, at(10)));
+        for ms in 20..1000 {
+            assert!(!sampler.report_at(ErrorKind::ConnectionReset, Some(peer), at(ms)));
+        }
+        // another kind has a window of its own
+        assert!(sampler.report_at(ErrorKind::TimedOut, None, at(500)));
+        assert_eq!(reports.lock().unwrap().len(), 3);
+
+        // the next reset ends the first window, and starts a new one
+        assert!(sampler.report_at(ErrorKind::ConnectionReset, Some(peer), at(1000)));
+        sampler.flush_at(at(1499));
+        sampler.flush_at(at(1500));
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports[..], [
+            Report::Error { kind: ErrorKind::ConnectionReset, peer: Some(peer) },
+            Report::Error { kind: ErrorKind::ConnectionReset, peer: None },
+            Report::Error { kind: ErrorKind::TimedOut, peer: None },
+            Report::Summary { kind: ErrorKind::ConnectionReset, suppressed: 980, window: Duration::from_secs(1) },
+            Report::Error { kind: ErrorKind::ConnectionReset, peer: Some(peer) },
+        ]);
+        assert_eq!(reports[3].to_string(), "error: ConnectionReset 980 more times in 1s");
+        assert_eq!(reports[0].to_string(), "error: ConnectionReset from 10.0.0.7:50312");
+    }
+}
@@ -0,0 +1,214 @@
+//! Expect-style scripting of line-based text protocols over TCP, for integration tests
+//! against finger, whois, qotd or telnet-like servers.
+//!
+//! Every `expect_*` call waits, up to the script's timeout, for the output it wants and
+//! consumes what it skipped over, so banners and prompts in between need no matching.
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use regex::Regex;
+use crate::resolve;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn connect<A>(addr: A) -> io::Result<Script>
+where
+    A: ToSocketAddrs
+{
+    Ok(Script::new(resolve::connect_happy_to(addr, DEFAULT_TIMEOUT)?))
+}
+
+#[derive(Debug)]
+pub struct Script {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    eof: bool,
+    timeout: Duration,
+}
+
+impl Script {
+    #[inline]
+    pub fn new(stream: TcpStream) -> Self {
+        Self { stream, pending: Vec::new(), eof: false, timeout: DEFAULT_TIMEOUT }
+    }
+
+    /// How long each `expect_*` call waits before failing with `TimedOut`.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[inline]
+    pub fn send(&mut self, text: &str) -> io::Result<&mut Self> {
+        self.stream.write_all(text.as_bytes())?;
+        Ok(self)
+    }
+
+    /// Send `line` followed by CRLF.
+    #[inline]
+    pub fn send_line(&mut self, line: &str) -> io::Result<&mut Self> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes())?;
+        Ok(self)
+    }
+
+    /// Wait for `text` anywhere in the output, including prompts not ended by a newline.
+    pub fn expect(&mut self, text: &str) -> io::Result<&mut Self> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(pos) = find(&self.pending, text.as_bytes()) {
+                self.pending.drain(..pos + text.len());
+                return Ok(self);
+            }
+            if !self.fill(deadline)? {
+                return Err(closed_before(format!("{:?}", text)));
+            }
+        }
+    }
+
+    /// Wait for a line that is exactly `line`, without its line ending.
+    pub fn expect_line(&mut self, line: &str) -> io::Result<&mut Self> {
+        let deadline = Instant::now() + self.timeout;
+        while let Some(got) = self.next_line(deadline)? {
+            if got == line {
+                return Ok(self);
+            }
+        }
+        Err(closed_before(format!("line {:?}", line)))
+    }
+
+    /// Wait for a line matching `pattern`, returning its capture groups; group 0 is the match.
+    /// Groups that took no part in the match come back empty.
+    pub fn expect_regex(&mut self, pattern: &str) -> io::Result<Vec<String>> {
+        let regex = Regex::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let deadline = Instant::now() + self.timeout;
+        while let Some(line) = self.next_line(deadline)? {
+            if let Some(captures) = regex.captures(&line) {
+                return Ok(captures.iter()
+                    .map(|group| group.map_or_else(String::new, |group| group.as_str().to_string()))
+                    .collect());
+            }
+        }
+        Err(closed_before(format!("a line matching {:?}", pattern)))
+    }
+
+    /// Wait for the server to close the connection, ignoring anything it sends first.
+    pub fn expect_eof(&mut self) -> io::Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        while self.fill(deadline)? {}
+        Ok(())
+    }
+
+    /// The next complete line, or the unterminated rest at end of stream; `None` once drained.
+    fn next_line(&mut self, deadline: Instant) -> io::Result<Option<String>> {
+        loop {
+            if let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=pos).collect();
+                return Ok(Some(trim_line(&line)));
+            }
+            if !self.fill(deadline)? {
+                if self.pending.is_empty() {
+                    return Ok(None);
+                }
+                let line: Vec<u8> = self.pending.drain(..).collect();
+                return Ok(Some(trim_line(&line)));
+            }
+        }
+    }
+
+    /// Read more output; false at end of stream.
+    fn fill(&mut self, deadline: Instant) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(self.timed_out());
+        }
+        self.stream.set_read_timeout(Some(deadline - now))?;
+        let mut buf = [0u8; 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => {
+                self.eof = true;
+                Ok(false)
+            }
+            Ok(len) => {
+                self.pending.extend_from_slice(&buf[..len]);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => Err(self.timed_out()),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[inline]
+    fn timed_out(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, format!("timed out with {:?} unread",
+            String::from_utf8_lossy(&self.pending)))
+    }
+}
+
+#[inline]
+fn closed_before(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, format!("connection closed before {}", what))
+}
+
+#[inline]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[inline]
+fn trim_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).trim_end_matches(['\r', '\n']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::BufRead, net::TcpListener, thread};
+
+    fn finger_server() -> io::Result<std::net::SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || -> io::Result<()> {
+            let (stream, _) = listener.accept()?;
+            let mut out = stream.try_clone()?;
+            out.write_all(b"laji fingerd ready\r\nlogin: ")?;
+            let mut user = String::new();
+            io::BufReader::new(stream).read_line(&mut user)?;
+            write!(out, "Login: {}\r\nName: Laji Protocols\r\nShell: /bin/sh", user.trim_end())?;
+            Ok(())
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn finger_session() -> io::Result<()> {
+        let mut script = connect(finger_server()?)?;
+        script.expect("login: ")?.send_line("laji")?.expect_line("Login: laji")?;
+        assert_eq!(script.expect_regex(r"^Name: (\w+) (\w+)$")?, ["Name: Laji Protocols", "Laji", "Protocols"]);
+        // the last line has no line ending
+        assert_eq!(script.expect_regex(r"^Shell: (.*)$")?[1], "/bin/sh");
+        script.expect_eof()?;
+        assert_eq!(script.expect_line("anything").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn silent_server_times_out() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut script = connect(listener.local_addr()?)?.timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        assert_eq!(script.expect_line("hello").unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(script.expect_regex("(").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
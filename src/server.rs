@@ -0,0 +1,149 @@
+use std::{
+    fmt,
+    io,
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+/// A server running on a thread of its own, as started by the `listen_spawned` functions.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    thread: JoinHandle<io::Result<()>>,
+    stopper: Option<Stopper>,
+}
+
+impl ServerHandle {
+    pub(crate) fn spawn<F>(name: &str, local_addrs: Vec<SocketAddr>, run: F) -> io::Result<Self>
+    where F: FnOnce() -> io::Result<()> + Send + 'static
+    {
+        let thread = thread::Builder::new().name(name.to_string()).spawn(run)?;
+        Ok(Self { local_addrs, thread, stopper: None })
+    }
+
+    /// `spawn` for a server that returns once `stopper` is stopped.
+    #[cfg(any(feature = "daytime", feature = "discard", feature = "simtcp", all(feature = "rakping", feature = "backend-mio")))]
+    pub(crate) fn spawn_stoppable<F>(name: &str, local_addrs: Vec<SocketAddr>, stopper: Stopper, run: F) -> io::Result<Self>
+    where F: FnOnce() -> io::Result<()> + Send + 'static
+    {
+        let mut ans = Self::spawn(name, local_addrs, run)?;
+        ans.stopper = Some(stopper);
+        Ok(ans)
+    }
+
+    /// Where the server was bound, with the ports the system picked for port 0.
+    #[inline]
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Something to stop the server with from elsewhere, or `None` for servers that run
+    /// until they fail.
+    #[inline]
+    pub fn stopper(&self) -> Option<Stopper> {
+        self.stopper.clone()
+    }
+
+    /// Stop the server and wait for its threads to finish. Servers without a `stopper` are
+    /// left running, and this returns an error.
+    pub fn stop(self) -> io::Result<()> {
+        match &self.stopper {
+            Some(stopper) => stopper.stop(),
+            None => return Err(io::Error::other("server cannot be stopped")),
+        }
+        self.join()
+    }
+
+    /// Wait for the server to stop, returning the error that stopped it, if any.
+    pub fn join(self) -> io::Result<()> {
+        match self.thread.join() {
+            Ok(ans) => ans,
+            Err(_) => Err(io::Error::other("server thread panicked")),
+        }
+    }
+}
+
+/// Tells a running server to stop, from any thread. Clones stop the same servers; a server
+/// given a stopper that was already stopped returns right away.
+#[derive(Clone, Default)]
+pub struct Stopper {
+    inner: Arc<StopperInner>,
+}
+
+#[derive(Default)]
+struct StopperInner {
+    stopped: AtomicBool,
+    wakers: Mutex<Vec<Box<dyn Fn() + Send>>>,
+}
+
+impl Stopper {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every server run with this stopper to close its sockets and return.
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        for wake in self.inner.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Call `wake` when stopped, to get a server blocked in a call out of it; if already
+    /// stopped, it is called now.
+    #[cfg(any(feature = "daytime", feature = "discard", feature = "simtcp", all(feature = "rakping", feature = "backend-mio")))]
+    pub(crate) fn on_stop<W>(&self, wake: W)
+    where W: Fn() + Send + 'static
+    {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        if self.is_stopped() {
+            wake();
+        }
+        wakers.push(Box::new(wake));
+    }
+
+    /// Wake a thread blocked accepting on `local_addr` by connecting to it.
+    #[cfg(any(feature = "daytime", feature = "discard"))]
+    pub(crate) fn wake_tcp(&self, local_addr: SocketAddr) {
+        let addr = reachable(local_addr);
+        self.on_stop(move || {
+            let _ = std::net::TcpStream::connect_timeout(&addr, WAKE_TIMEOUT);
+        });
+    }
+
+    /// Wake a thread blocked receiving on `local_addr` with an empty datagram.
+    #[cfg(any(feature = "daytime", feature = "simtcp"))]
+    pub(crate) fn wake_udp(&self, local_addr: SocketAddr) {
+        let addr = reachable(local_addr);
+        self.on_stop(move || {
+            let _ = crate::resolve::bind_ephemeral_for(addr).and_then(|socket| socket.send_to(&[], addr));
+        });
+    }
+}
+
+impl fmt::Debug for Stopper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stopper").field("stopped", &self.is_stopped()).finish()
+    }
+}
+
+#[cfg(any(feature = "daytime", feature = "discard"))]
+const WAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// a socket bound to every address is woken through loopback
+#[cfg(any(feature = "daytime", feature = "discard", feature = "simtcp"))]
+fn reachable(local_addr: SocketAddr) -> SocketAddr {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, local_addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, local_addr.port()).into(),
+        _ => local_addr,
+    }
+}
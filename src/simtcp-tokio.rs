@@ -0,0 +1,229 @@
+//! A simtcp connection driven by a tokio reactor instead of blocking a thread, read and
+//! written as `AsyncRead` and `AsyncWrite`, so code written against tokio's sockets runs
+//! over the simulator unchanged.
+//!
+//! `SimTcpStream::from_std` takes over a client's `Stream` once its handshake is done. The
+//! connection only gets on while it is polled: its retransmission, delayed ACK and
+//! keep-alive timers wake the task that last read, wrote or flushed it, so a task that only
+//! writes should flush before it waits on something else. A datagram the socket is not
+//! ready to take is dropped, as one lost on the way, for the retransmission timer to resend.
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::SocketAddr,
+    time::Instant,
+};
+use tokio::{net::UdpSocket, prelude::*, reactor::Handle, timer::Delay};
+use super::{drain, receive, Link, Observer, Options, State, Stats, Stream, Tcb, MAX_DATAGRAM_LEN};
+
+#[derive(Debug)]
+pub struct SimTcpStream {
+    socket: UdpSocket,
+    // the same socket, for the FIN sent on drop, outside any task
+    std_socket: std::net::UdpSocket,
+    tcb: Tcb,
+    timer: Option<Delay>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    options: Options,
+    observer: Option<Box<dyn Observer + Send>>,
+    buf: Vec<u8>,
+}
+
+impl SimTcpStream {
+    /// Drive `stream` from a reactor, its socket registered with `handle`; with
+    /// `Handle::default()`, that of the runtime it is first polled on. Only a stream with a
+    /// socket of its own, one that connected, can be: a server's streams share its socket.
+    pub fn from_std(mut stream: Stream, handle: &Handle) -> io::Result<Self> {
+        let std_socket = match stream.link {
+            Link::Connected(ref socket) => socket.try_clone()?,
+            Link::Shared { .. } =>
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a simtcp server's streams share its socket")),
+        };
+        let socket = UdpSocket::from_std(std_socket.try_clone()?, handle)?;
+        // what is left of `stream` is closed, so dropping it sends nothing
+        let tcb = mem::replace(&mut stream.tcb, Tcb::new(State::Closed, 0, Instant::now()));
+        Ok(Self {
+            socket,
+            std_socket,
+            tcb,
+            timer: None,
+            local_addr: stream.local_addr,
+            peer_addr: stream.peer_addr,
+            options: stream.options,
+            observer: stream.observer.take(),
+            buf: vec![0u8; MAX_DATAGRAM_LEN],
+        })
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        self.tcb.stats
+    }
+
+    /// Send what is queued, take in every datagram that arrived and run the timers that are
+    /// due, until the socket and the next timer both have the task to wake.
+    fn pump(&mut self) -> io::Result<()> {
+        loop {
+            self.send_output()?;
+            loop {
+                match self.socket.poll_recv(&mut self.buf) {
+                    Ok(Async::Ready(len)) => receive(&mut self.tcb, &self.buf[..len], Instant::now()),
+                    Ok(Async::NotReady) => break,
+                    // ICMP unreachable for an earlier datagram; the retransmission timer deals with it
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.tcb.on_timeout(Instant::now());
+            self.send_output()?;
+            let deadline = match self.tcb.timeout() {
+                Some(deadline) => deadline,
+                None => {
+                    self.timer = None;
+                    return Ok(());
+                }
+            };
+            let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+            if Delay::deadline(timer) != deadline {
+                timer.reset(deadline);
+            }
+            match timer.poll() {
+                Ok(Async::NotReady) => return Ok(()),
+                // due already, so run it and wait for the next
+                Ok(Async::Ready(())) => {}
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let socket = &mut self.socket;
+        drain(&mut self.tcb, self.options, &mut self.observer, |datagram| match socket.poll_send(datagram) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+#[inline]
+fn would_block() -> io::Error {
+    io::ErrorKind::WouldBlock.into()
+}
+
+impl Read for SimTcpStream {
+    /// Read what arrived in order, `WouldBlock` until at least one byte did; 0 after the
+    /// peer's FIN.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.pump()?;
+        let len = self.tcb.read(buf, Instant::now());
+        if len > 0 {
+            self.send_output()?;
+            return Ok(len);
+        }
+        if self.tcb.at_eof() {
+            return Ok(0);
+        }
+        Err(self.tcb.error().unwrap_or_else(would_block))
+    }
+}
+
+impl AsyncRead for SimTcpStream {}
+
+impl Write for SimTcpStream {
+    /// Queue what fits the send buffer, `WouldBlock` while it is full, and send what the
+    /// peer's window allows.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.tcb.error() {
+            return Err(e);
+        }
+        if self.tcb.fin_queued {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "simtcp stream was shut down"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.tcb.send_space() == 0 {
+            self.pump()?;
+            if self.tcb.send_space() == 0 {
+                return Err(would_block());
+            }
+        }
+        let len = self.tcb.write(buf, Instant::now());
+        self.send_output()?;
+        Ok(len)
+    }
+
+    /// `WouldBlock` until the peer acknowledged everything written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.pump()?;
+        if let Some(e) = self.tcb.error() {
+            return Err(e);
+        }
+        if self.tcb.all_acked() { Ok(()) } else { Err(would_block()) }
+    }
+}
+
+impl AsyncWrite for SimTcpStream {
+    /// Send FIN after everything written, ready once the peer acknowledged it all. Reading
+    /// goes on until the peer's FIN.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.tcb.shutdown(Instant::now());
+        match self.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for SimTcpStream {
+    /// Send FIN once, as `Stream` does, without waiting for it to arrive.
+    fn drop(&mut self) {
+        if self.tcb.is_established() && !self.tcb.fin_queued {
+            self.tcb.shutdown(Instant::now());
+            let socket = &self.std_socket;
+            let _ = drain(&mut self.tcb, self.options, &mut self.observer, |datagram| socket.send(datagram).map(drop));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+    use crate::simtcp::{connect, listen_spawned};
+
+    #[test]
+    fn read_write_through_tokio() -> io::Result<()> {
+        let upper = || |stream: &mut Stream| -> io::Result<()> {
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request)?;
+            stream.write_all(&request.to_ascii_uppercase())
+        };
+        let server = listen_spawned("127.0.0.1:0", upper)?;
+        let stream = SimTcpStream::from_std(connect(server.local_addrs()[0])?, &Handle::default())?;
+        let exchange = tokio::io::write_all(stream, vec![b'a'; 100_000])
+            .and_then(|(stream, _)| tokio::io::shutdown(stream))
+            .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()));
+        let (stream, response) = Runtime::new()?.block_on(exchange)?;
+        assert!(response == vec![b'A'; 100_000]);
+        assert!(stream.stats().segments_sent > 0);
+        drop(stream);
+        server.stop()
+    }
+}
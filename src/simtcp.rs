@@ -0,0 +1,2675 @@
+//! A simplified TCP over UDP, for seeing how TCP makes a reliable byte stream out of
+//! datagrams that may be lost.
+//!
+//! Every datagram is one `Segment`, with the flags, sequence and acknowledgment numbers,
+//! receive window and Internet checksum of a TCP header, but no ports. UDP has a
+//! checksum of its own, but simtcp's covers what a link corrupts on the way: with
+//! `Connector::corrupt` or `LajiSimTcp::corrupt`, datagrams have a bit flipped before they
+//! are sent, the receiver finds the checksum off and drops them, and `Stats` counts both.
+//!
+//! On the wire, a segment is a version byte, 2 for this layout, then the flags, the window,
+//! the checksum, the sequence and acknowledgment numbers, big-endian in `HEADER_LEN` bytes,
+//! and the payload to the end of the datagram. The checksum covers the whole datagram, taken
+//! with its own field zero. A receiver drops datagrams of any other version, so two builds
+//! of the crate talk to each other as long as the version matches; the fixtures under
+//! `fixtures/simtcp` pin the layout down. A SYN's payload holds options, laid out as TCP's,
+//! and a receiver skips those it does not know.
+//!
+//! Each direction buffers 65535 bytes unless `buffer_len` says otherwise, and a larger
+//! buffer is advertised in full with the window scale option of RFC 7323: both SYNs offer a
+//! shift, and every later window is shifted by it. Without it, a round trip carries at most
+//! 64 KiB, which is what bounds a long, fast link.
+//!
+//! Both SYNs also carry the MSS option, each end's `mss`, and segments are no longer than
+//! the smaller of the two. `path_mtu` makes the link in between carry datagrams no longer
+//! than its MTU, and `Oversize` says what becomes of those that are: fragmented and
+//! delivered, dropped with "fragmentation needed" so the sender lowers its MSS as path MTU
+//! discovery does, or dropped without a word, the PMTU blackhole.
+//!
+//! A connection opens with the three-way handshake, SYN, SYN-ACK, ACK, from random initial
+//! sequence numbers, and SYN and FIN each take a sequence number as in TCP. Data is
+//! acknowledged cumulatively and resent go-back-N, from the oldest unacknowledged byte, when
+//! the retransmission timeout runs out or on the third duplicate ACK; the timeout is
+//! estimated from round trips as in RFC 6298, doubling on each expiry. Segments that arrive
+//! ahead of a gap are dropped rather than queued, there is no congestion control, only the
+//! peer's window, which is probed while it is zero, and no TIME-WAIT after closing.
+//!
+//! Small writes are coalesced with Nagle's algorithm (RFC 896): while anything sent is
+//! unacknowledged, data short of a full segment waits for more to be written or for the
+//! ACK. `Stream::set_nodelay` turns it off, as `TCP_NODELAY` does, and `Stream::stats`
+//! counts the writes it held back. The receiver delays its ACKs (RFC 1122, 4.2.3.2): data
+//! that arrives in order is acknowledged with the second segment, with anything sent back,
+//! or once `Stream::set_delayed_ack`'s delay is up, 40 ms by default. Together the two make
+//! the classic stall of a write, write, read exchange: the second write waits for an ACK the
+//! receiver is holding back.
+//!
+//! Segments that belong to no connection are answered with RST, as RFC 793 does, and a
+//! connection that receives a believable RST is over, failing with `ConnectionReset` and
+//! telling the server's handler through `on_reset`. With `Stream::set_keepalive`, an idle
+//! connection probes its peer with the keep-alive of RFC 1122, 4.2.3.6, an empty segment
+//! one sequence number back that the peer has to acknowledge, and gives up on a half-open
+//! peer, one that crashed or lost the connection without telling, after four unanswered
+//! probes.
+//!
+//! An `Observer` is told every state transition, every timer started, stopped or expired,
+//! and every change of the window the sender may fill, each with the time since that end of
+//! the connection was created, so a frontend can animate what the stack does. There being no
+//! congestion control, that window is the peer's receive window, in place of a cwnd. Clients
+//! attach one with `Connector::observer`, servers with `Factory::observer`. It is told each
+//! segment sent and received as well, and `Trace` keeps those to render the exchange as a
+//! sequence diagram, in plain text or mermaid, for teaching material or a bug report.
+//!
+//! `connect` opens a `Stream`, read and written like a `TcpStream`; `tokio::SimTcpStream`
+//! drives one from a tokio reactor instead. `listen` serves every peer of one socket,
+//! handing each handler its `Stream` on a thread of its own. SYNs beyond
+//! `LajiSimTcp::max_half_open` handshakes in progress, or `max_connections` in all, are
+//! answered with RST, so a flood of them costs the server no more than that many threads.
+//! Each connection's thread is handed at most a receive window of datagrams at a time, and
+//! the demux drops any more, as a peer sending past the window would have them dropped.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use smallvec::SmallVec;
+use crate::{
+    checksum::checksum,
+    config::ConfigError,
+    random::random,
+    server::{ServerHandle, Stopper},
+    wire::{invalid_data, ByteReader},
+};
+
+#[cfg(feature = "backend-tokio")]
+#[path = "simtcp-tokio.rs"]
+pub mod tokio;
+
+/// version, flags, window, checksum, seq, ack
+pub const HEADER_LEN: usize = 14;
+/// Payload bytes in one segment at most, keeping datagrams within 1200 bytes, and the
+/// maximum segment size of a connection that sets none lower.
+pub const MSS: usize = 1200 - HEADER_LEN;
+
+pub const SYN: u8 = 0x01;
+pub const ACK: u8 = 0x02;
+pub const FIN: u8 = 0x04;
+pub const RST: u8 = 0x08;
+
+const VERSION: u8 = 2;
+// SYN option kinds, numbered as TCP's
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const MAX_WINDOW_SCALE: u8 = 14;
+// IPv4's smallest MTU, which every link has to carry (RFC 791)
+const MIN_PATH_MTU: usize = 68;
+// each direction buffers, by default, what the window field can advertise unscaled
+const DEFAULT_BUFFER_LEN: usize = u16::MAX as usize;
+const MAX_BUFFER_LEN: usize = DEFAULT_BUFFER_LEN << MAX_WINDOW_SCALE;
+const MAX_DATAGRAM_LEN: usize = 2048;
+const RTO_INITIAL: Duration = Duration::from_millis(300);
+const RTO_MIN: Duration = Duration::from_millis(50);
+const RTO_MAX: Duration = Duration::from_secs(4);
+// timeouts in a row before the peer is given up on, some 25 seconds
+const MAX_RETRIES: u32 = 8;
+const DUP_ACKS: u32 = 3;
+// Linux's delayed ACK timeout at its shortest; RFC 1122 allows up to 500 ms
+const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(40);
+// keep-alive probes in a row that may go unanswered
+const KEEPALIVE_PROBES: u32 = 4;
+const INLINE_SOCKETS: usize = 2;
+const DEFAULT_MAX_HALF_OPEN: usize = 64;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// The smallest window scale that advertises a free buffer of `len` in full.
+#[inline]
+fn scale_for(len: usize) -> u8 {
+    (0..MAX_WINDOW_SCALE).find(|&shift| len >> shift <= DEFAULT_BUFFER_LEN).unwrap_or(MAX_WINDOW_SCALE)
+}
+
+/// `a - b` for sequence numbers that wrap.
+#[inline]
+fn diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+pub fn listen<A, F>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: Factory + Send + 'static,
+    F::Handler: Send + 'static
+{
+    LajiSimTcp::new(factory).bind(addr)?.run()
+}
+
+/// `listen` on a thread of its own; binding errors are returned here.
+pub fn listen_spawned<A, F>(addr: A, factory: F) -> io::Result<ServerHandle>
+where
+    A: ToSocketAddrs,
+    F: Factory + Send + 'static,
+    F::Handler: Send + 'static
+{
+    LajiSimTcp::new(factory).bind(addr)?.run_detached()
+}
+
+/// Open a connection to `addr`; see `Stream::connect`.
+#[inline]
+pub fn connect<A>(addr: A) -> io::Result<Stream>
+where A: ToSocketAddrs
+{
+    Stream::connect(addr)
+}
+
+/// Opens connections with what has to be in place before the SYN goes out.
+#[derive(Debug, Default)]
+pub struct Connector {
+    observer: Option<Box<dyn Observer + Send>>,
+    options: Options,
+}
+
+impl Connector {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tell `observer` everything the connection does, the handshake included.
+    #[inline]
+    pub fn observer<O>(mut self, observer: O) -> Self
+    where O: Observer + Send + 'static
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Chance from 0 to 1 that a datagram sent has one of its bits flipped, for the peer's
+    /// checksum to catch.
+    #[inline]
+    pub fn corrupt(mut self, chance: f64) -> Self {
+        self.options.corrupt = chance;
+        self
+    }
+
+    /// Bytes buffered in each direction, 65535 by default. The window only advertises more
+    /// than 65535 with window scaling, which both ends have to offer.
+    #[inline]
+    pub fn buffer_len(mut self, len: usize) -> Self {
+        self.options.buffer_len = len;
+        self
+    }
+
+    /// Whether to offer window scaling (RFC 7323) in the SYN; on by default. The shift is
+    /// the smallest that advertises all of `buffer_len`, and applies once the peer offers
+    /// one of its own.
+    #[inline]
+    pub fn window_scaling(mut self, on: bool) -> Self {
+        self.options.window_scaling = on;
+        self
+    }
+
+    /// The most payload to take in one segment, offered to the peer in the SYN; `MSS`, the
+    /// most there can be, by default. Segments sent are no longer than either end's.
+    #[inline]
+    pub fn mss(mut self, mss: usize) -> Self {
+        self.options.mss = mss;
+        self
+    }
+
+    /// Treat datagrams sent longer than `mtu` bytes, the simtcp header included, as
+    /// `oversize` says, as if they went over a link with that MTU.
+    #[inline]
+    pub fn path_mtu(mut self, mtu: usize, oversize: Oversize) -> Self {
+        self.options.path_mtu = Some((mtu, oversize));
+        self
+    }
+
+    /// Connect to the first of `addr`'s addresses that can be sent to, from a fresh
+    /// ephemeral port, returning once the handshake is done.
+    pub fn connect<A>(self, addr: A) -> io::Result<Stream>
+    where A: ToSocketAddrs
+    {
+        self.options.check()?;
+        let socket = crate::resolve::connect_udp(addr)?;
+        let (local_addr, peer) = (socket.local_addr()?, socket.peer_addr()?);
+        let now = Instant::now();
+        let tcb = Tcb::connect(random(), self.options, now);
+        Stream::handshake(Link::Connected(socket), tcb, self.options, self.observer, local_addr, peer)
+    }
+}
+
+/// One datagram's worth of the protocol.
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Segment {
+    /// `SYN`, `ACK`, `FIN` and `RST`, or'd together.
+    pub flags: u8,
+    pub seq: u32,
+    /// The next sequence number expected from the peer, when `flags` has `ACK`.
+    pub ack: u32,
+    /// How many more bytes the sender can buffer, shifted right by the scale it offered,
+    /// except in a SYN.
+    pub window: u16,
+    /// The maximum segment size option, in a SYN: the most payload the sender takes in one
+    /// segment.
+    pub mss: Option<u16>,
+    /// The window scale option of RFC 7323, in a SYN: the shift the sender applies to every
+    /// window it advertises after its SYN, if the peer offers one too.
+    pub window_scale: Option<u8>,
+    /// The data; in a SYN, the options after those decoded into fields.
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    /// Whether every flag in `flags` is set.
+    #[inline]
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// Sequence numbers the segment takes up: one per payload byte, and one each for SYN and FIN.
+    #[inline]
+    pub fn seq_len(&self) -> u32 {
+        self.payload.len() as u32 + u32::from(self.has(SYN)) + u32::from(self.has(FIN))
+    }
+
+    /// Append the segment to `out`, which may hold other data before it.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.push(VERSION);
+        out.push(self.flags);
+        out.extend_from_slice(&self.window.to_be_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.ack.to_be_bytes());
+        if self.has(SYN) {
+            if let Some(mss) = self.mss {
+                out.extend_from_slice(&[OPTION_MSS, 4]);
+                out.extend_from_slice(&mss.to_be_bytes());
+            }
+            if let Some(shift) = self.window_scale {
+                out.extend_from_slice(&[OPTION_WINDOW_SCALE, 3, shift]);
+            }
+        }
+        out.extend_from_slice(&self.payload);
+        let sum = checksum(&out[start..]);
+        out[start + 4..start + 6].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    pub fn decode(datagram: &[u8]) -> io::Result<Self> {
+        let mut reader = ByteReader::new(datagram);
+        if reader.read_u8()? != VERSION {
+            return Err(invalid_data("not a simtcp segment"));
+        }
+        let flags = reader.read_u8()?;
+        if flags & !(SYN | ACK | FIN | RST) != 0 {
+            return Err(invalid_data("unknown simtcp flags"));
+        }
+        let window = reader.read_u16_be()?;
+        reader.read_u16_be()?;
+        if !checksum_ok(datagram) {
+            return Err(invalid_data("simtcp checksum mismatch"));
+        }
+        let seq = reader.read_u32_be()?;
+        let ack = reader.read_u32_be()?;
+        let payload = reader.take_rest();
+        if payload.len() > MSS {
+            return Err(invalid_data("simtcp segment longer than MSS"));
+        }
+        let mut ans = Self { flags, seq, ack, window, payload: payload.to_vec(), ..Self::default() };
+        if ans.has(SYN) {
+            ans.decode_options()?;
+        }
+        Ok(ans)
+    }
+
+    /// Take the options TCP's way out of a SYN's payload: a kind byte, then for any kind but
+    /// end and no-op, a length byte that counts both and the value. Kinds not known here are
+    /// skipped, and what follows the end of the list is left in `payload`.
+    fn decode_options(&mut self) -> io::Result<()> {
+        let options = std::mem::take(&mut self.payload);
+        let mut reader = ByteReader::new(&options);
+        while reader.remaining() > 0 {
+            let kind = reader.read_u8()?;
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => continue,
+                _ => {}
+            }
+            let len = usize::from(reader.read_u8()?);
+            if len < 2 {
+                return Err(invalid_data("simtcp option shorter than its header"));
+            }
+            let value = reader.take(len - 2)?;
+            if kind == OPTION_MSS {
+                self.mss = match *value {
+                    [high, low] => Some(u16::from_be_bytes([high, low])),
+                    _ => return Err(invalid_data("simtcp MSS option of the wrong length")),
+                };
+            } else if kind == OPTION_WINDOW_SCALE {
+                let shift = match *value {
+                    [shift] => shift,
+                    _ => return Err(invalid_data("simtcp window scale option of the wrong length")),
+                };
+                // RFC 7323, 2.3: larger shifts are taken as the largest
+                self.window_scale = Some(shift.min(MAX_WINDOW_SCALE));
+            }
+        }
+        self.payload = reader.take_rest().to_vec();
+        Ok(())
+    }
+}
+
+/// Whether `datagram`'s checksum adds up, as it does over a segment sent intact.
+#[inline]
+fn checksum_ok(datagram: &[u8]) -> bool {
+    checksum(datagram) == 0
+}
+
+/// What one end of a connection has done so far.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Stats {
+    /// Segments sent, retransmissions and bare ACKs included.
+    pub segments_sent: u64,
+    /// Writes that Nagle's algorithm held back, joining a short segment that was already
+    /// waiting instead of going out in one of their own.
+    pub coalesced: u64,
+    /// Datagrams sent with a bit flipped on purpose, by `corrupt`.
+    pub corrupted: u64,
+    /// Segments received whose checksum did not add up, dropped as if lost.
+    pub bad_checksums: u64,
+    /// Datagrams longer than the path MTU that went out in fragments, by `Oversize::Fragment`.
+    pub fragmented: u64,
+    /// Datagrams longer than the path MTU dropped with "fragmentation needed", by
+    /// `Oversize::NeedsFrag`.
+    pub needs_frag: u64,
+    /// Datagrams longer than the path MTU dropped without a word, by `Oversize::Blackhole`.
+    pub blackholed: u64,
+}
+
+/// What a link does with a datagram longer than its MTU.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Oversize {
+    /// Fragment it, as IPv4 does without the don't-fragment bit, and deliver it whole.
+    Fragment,
+    /// Drop it and tell the sender the MTU, as an ICMP "fragmentation needed" does for path
+    /// MTU discovery (RFC 1191); the sender lowers its MSS to fit, and sends again.
+    NeedsFrag,
+    /// Drop it and tell no one, as a path whose ICMP is filtered does: the PMTU blackhole,
+    /// where the handshake gets through and full segments never do.
+    Blackhole,
+}
+
+/// How a connection buffers and sends, set up by `Connector` and `LajiSimTcp`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Options {
+    corrupt: f64,
+    buffer_len: usize,
+    window_scaling: bool,
+    mss: usize,
+    path_mtu: Option<(usize, Oversize)>,
+}
+
+impl Default for Options {
+    #[inline]
+    fn default() -> Self {
+        Self { corrupt: 0.0, buffer_len: DEFAULT_BUFFER_LEN, window_scaling: true, mss: MSS, path_mtu: None }
+    }
+}
+
+impl Options {
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.buffer_len == 0 {
+            return Err(ConfigError::Zero("buffer_len"));
+        }
+        if self.buffer_len > MAX_BUFFER_LEN {
+            return Err(ConfigError::Conflict("`buffer_len` is more than a scaled window can advertise"));
+        }
+        if self.mss == 0 {
+            return Err(ConfigError::Zero("mss"));
+        }
+        if self.mss > MSS {
+            return Err(ConfigError::Conflict("`mss` is more than a simtcp segment carries"));
+        }
+        if self.path_mtu.is_some_and(|(mtu, _)| mtu < MIN_PATH_MTU) {
+            return Err(ConfigError::Conflict("`path_mtu` is below the 68 bytes every link carries"));
+        }
+        Ok(())
+    }
+
+    /// Datagrams waiting for a connection's thread, a receive window of full segments; a
+    /// peer that sends past the window has the rest dropped, as if lost.
+    #[inline]
+    fn peer_queue_len(&self) -> usize {
+        self.buffer_len / self.mss + 1
+    }
+}
+
+/// Where one end of a connection is, as far as an `Observer` is told. Each end starts out
+/// `Closed`, before its SYN, and is `Closed` again once reset or given up on.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Timer {
+    Retransmission,
+    DelayedAck,
+    Keepalive,
+}
+
+const TIMERS: [Timer; 3] = [Timer::Retransmission, Timer::DelayedAck, Timer::Keepalive];
+
+/// Something an end of a connection did, for an `Observer`.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Event {
+    State { from: State, to: State },
+    /// The timer was set, or set again, to go off `expires` after the end was created.
+    TimerStarted { timer: Timer, expires: Duration },
+    TimerStopped(Timer),
+    TimerExpired(Timer),
+    /// The window the sender may fill, the peer's receive window, changed.
+    Window { from: u32, to: u32 },
+    /// A segment went out, or is about to, retransmissions included.
+    Sent(Summary),
+    /// A segment arrived intact; those that failed the checksum are dropped unseen.
+    Received(Summary),
+}
+
+/// A segment's header and payload length, as an `Observer` is told of it.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Summary {
+    pub flags: u8,
+    pub seq: u32,
+    pub ack: u32,
+    /// The window field as sent, before any scaling.
+    pub window: u16,
+    pub len: usize,
+}
+
+impl From<&Segment> for Summary {
+    #[inline]
+    fn from(seg: &Segment) -> Self {
+        Self { flags: seg.flags, seq: seg.seq, ack: seg.ack, window: seg.window, len: seg.payload.len() }
+    }
+}
+
+impl fmt::Display for Summary {
+    /// As in `SYN,ACK seq=1 ack=2 win=512 len=3`, leaving out `ack` without the ACK flag
+    /// and `len` without payload.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [(SYN, "SYN"), (FIN, "FIN"), (RST, "RST"), (ACK, "ACK")];
+        let mut flags = names.iter().filter(|&&(flag, _)| self.flags & flag != 0).map(|&(_, name)| name);
+        match flags.next() {
+            Some(name) => f.write_str(name)?,
+            None => f.write_str("-")?,
+        }
+        for name in flags {
+            write!(f, ",{}", name)?;
+        }
+        write!(f, " seq={}", self.seq)?;
+        if self.flags & ACK != 0 {
+            write!(f, " ack={}", self.ack)?;
+        }
+        write!(f, " win={}", self.window)?;
+        if self.len > 0 {
+            write!(f, " len={}", self.len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Told what one end of a connection does, with `at` the time since the end was created.
+pub trait Observer {
+    fn on_event(&mut self, at: Duration, event: &Event);
+}
+
+impl fmt::Debug for dyn Observer + Send {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+impl<F> Observer for F
+where F: FnMut(Duration, &Event) {
+    #[inline]
+    fn on_event(&mut self, at: Duration, event: &Event) {
+        self(at, event)
+    }
+}
+
+/// An `Observer` that keeps what one end of a connection sends and receives, and what it
+/// goes through, to render as a sequence diagram between that end and its peer. Clones share
+/// what was kept, so one can go to `Connector::observer` or `Factory::observer` and another
+/// render the diagram once the connection is done.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    local: String,
+    peer: String,
+    events: Arc<Mutex<Vec<(Duration, Event)>>>,
+}
+
+impl Trace {
+    /// A trace of the end called `local` talking to `peer`, names that appear as the
+    /// diagram's participants.
+    #[inline]
+    pub fn new<L, P>(local: L, peer: P) -> Self
+    where L: Into<String>, P: Into<String>
+    {
+        Self { local: local.into(), peer: peer.into(), events: Arc::default() }
+    }
+
+    /// Everything the observed end did so far.
+    #[inline]
+    pub fn events(&self) -> Vec<(Duration, Event)> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The diagram as text, one line per segment, state change or timer that ran out, each
+    /// with its time in seconds; segments are arrows between the two ends' lifelines.
+    pub fn to_text(&self) -> String {
+        const ARROW_LEN: usize = 48;
+        let mut ans = format!("{:>9}  {:<width$}{}\n", "", self.local, self.peer, width = ARROW_LEN + 1);
+        for (at, line) in self.lines() {
+            let at = at.as_secs_f64();
+            match line {
+                Line::Sent(summary) => {
+                    let label = format!("-- {} ", summary);
+                    ans += &format!("{:>8.3}s  |{:-<width$}>|\n", at, label, width = ARROW_LEN - 1);
+                }
+                Line::Received(summary) => {
+                    let label = format!("<- {} ", summary);
+                    ans += &format!("{:>8.3}s  |{:-<width$}|\n", at, label, width = ARROW_LEN);
+                }
+                Line::Note(note) => ans += &format!("{:>8.3}s  |  {:<width$}|\n", at, note, width = ARROW_LEN - 2),
+            }
+        }
+        ans
+    }
+
+    /// The diagram in mermaid's `sequenceDiagram` syntax, times at the start of each message.
+    pub fn to_mermaid(&self) -> String {
+        let mut ans = format!("sequenceDiagram\n    participant {}\n    participant {}\n", self.local, self.peer);
+        for (at, line) in self.lines() {
+            let at = at.as_secs_f64();
+            ans += &match line {
+                Line::Sent(summary) => format!("    {}->>{}: {:.3}s {}\n", self.local, self.peer, at, summary),
+                Line::Received(summary) => format!("    {}->>{}: {:.3}s {}\n", self.peer, self.local, at, summary),
+                Line::Note(note) => format!("    Note over {}: {:.3}s {}\n", self.local, at, note),
+            };
+        }
+        ans
+    }
+
+    // the events a diagram shows; timers started and stopped, and windows, are left out
+    fn lines(&self) -> Vec<(Duration, Line)> {
+        self.events.lock().unwrap().iter().filter_map(|&(at, event)| {
+            let line = match event {
+                Event::Sent(summary) => Line::Sent(summary),
+                Event::Received(summary) => Line::Received(summary),
+                Event::State { to, .. } => Line::Note(format!("{:?}", to)),
+                Event::TimerExpired(timer) => Line::Note(format!("{:?} timeout", timer)),
+                _ => return None,
+            };
+            Some((at, line))
+        }).collect()
+    }
+}
+
+enum Line {
+    Sent(Summary),
+    Received(Summary),
+    Note(String),
+}
+
+impl Observer for Trace {
+    #[inline]
+    fn on_event(&mut self, at: Duration, event: &Event) {
+        self.events.lock().unwrap().push((at, *event));
+    }
+}
+
+/// What an `Observer` was last told, to tell it what changed since.
+#[derive(Clone, Copy, Debug)]
+struct Seen {
+    state: State,
+    timers: [Option<Instant>; 3],
+    window: u32,
+    // segments at the front of `out`
+    sent: usize,
+}
+
+/// The transmission control block, one end of a connection without any I/O: segments go in
+/// through `input`, the timer through `on_timeout`, and what to send comes out of `take_output`.
+#[derive(Debug)]
+struct Tcb {
+    state: State,
+    iss: u32,
+    // the oldest unacknowledged sequence number, and the next one to send
+    snd_una: u32,
+    snd_nxt: u32,
+    // the highest sent, which is past `snd_nxt` after a go-back-N rewind
+    snd_max: u32,
+    snd_wnd: u32,
+    buffer_len: usize,
+    // whether the SYN offers window scaling, and once both did, the shifts of the windows
+    // advertised and received; 0 without it
+    scaling: bool,
+    rcv_scale: u8,
+    snd_scale: u8,
+    // the most payload sent in one segment, and what the SYN offers to take
+    mss: usize,
+    mss_offer: usize,
+    // bytes from `snd_una` on, sent or not
+    send_buf: VecDeque<u8>,
+    fin_queued: bool,
+    fin_acked: bool,
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+    peer_fin: bool,
+    // the window last advertised, as the peer reads it
+    advertised: usize,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    rto_deadline: Option<Instant>,
+    retries: u32,
+    // one segment at a time is timed, by the ack that covers it; Karn's rule drops the
+    // sample once anything is resent
+    rtt_sample: Option<(u32, Instant)>,
+    dup_acks: u32,
+    reset: bool,
+    nodelay: bool,
+    // a short segment is waiting on Nagle's algorithm
+    nagle_held: bool,
+    ack_delay: Option<Duration>,
+    // when the delayed ACK is due, and how many segments it acknowledges so far
+    ack_deadline: Option<Instant>,
+    unacked_segments: u32,
+    keepalive: Option<Duration>,
+    // when the peer has been quiet long enough to be probed, and how often it was already
+    keepalive_deadline: Option<Instant>,
+    probes: u32,
+    stats: Stats,
+    out: Vec<Segment>,
+    born: Instant,
+    // what an observer was told, while there is one
+    seen: Option<Seen>,
+    events: Vec<(Duration, Event)>,
+}
+
+impl Tcb {
+    fn new(state: State, iss: u32, now: Instant) -> Self {
+        Self {
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            buffer_len: DEFAULT_BUFFER_LEN,
+            scaling: false,
+            rcv_scale: 0,
+            snd_scale: 0,
+            mss: MSS,
+            mss_offer: MSS,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_acked: false,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            peer_fin: false,
+            advertised: 0,
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: RTO_INITIAL,
+            rto_deadline: None,
+            retries: 0,
+            rtt_sample: None,
+            dup_acks: 0,
+            reset: false,
+            nodelay: false,
+            nagle_held: false,
+            ack_delay: Some(DEFAULT_ACK_DELAY),
+            ack_deadline: None,
+            unacked_segments: 0,
+            keepalive: None,
+            keepalive_deadline: None,
+            probes: 0,
+            stats: Stats::default(),
+            out: Vec::new(),
+            born: now,
+            seen: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Open actively, sending SYN.
+    fn connect(iss: u32, options: Options, now: Instant) -> Self {
+        let mut ans = Self::new(State::SynSent, iss, now);
+        ans.buffer_len = options.buffer_len;
+        ans.mss = options.mss;
+        ans.mss_offer = options.mss;
+        ans.scaling = options.window_scaling;
+        if ans.scaling {
+            ans.rcv_scale = scale_for(ans.buffer_len);
+        }
+        ans.push_sequenced(SYN, Vec::new(), now);
+        ans
+    }
+
+    /// Answer the peer's `syn` with SYN-ACK.
+    fn accept(syn: &Segment, iss: u32, options: Options, now: Instant) -> Self {
+        let mut ans = Self::new(State::SynReceived, iss, now);
+        ans.buffer_len = options.buffer_len;
+        ans.mss_offer = options.mss;
+        ans.mss = options.mss;
+        ans.limit_mss(syn.mss);
+        if let Some(shift) = syn.window_scale.filter(|_| options.window_scaling) {
+            ans.scaling = true;
+            ans.rcv_scale = scale_for(ans.buffer_len);
+            ans.snd_scale = shift;
+        }
+        ans.rcv_nxt = syn.seq.wrapping_add(1);
+        ans.snd_wnd = u32::from(syn.window);
+        ans.push_sequenced(SYN | ACK, Vec::new(), now);
+        ans
+    }
+
+    #[inline]
+    fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    /// Why the connection is over, if it is.
+    fn error(&self) -> Option<io::Error> {
+        if self.reset {
+            Some(io::Error::new(io::ErrorKind::ConnectionReset, "simtcp peer reset the connection"))
+        } else if self.state == State::Closed {
+            Some(io::Error::new(io::ErrorKind::TimedOut, "simtcp peer stopped answering"))
+        } else {
+            None
+        }
+    }
+
+    /// The peer sent FIN and everything before it was read.
+    #[inline]
+    fn at_eof(&self) -> bool {
+        self.peer_fin && self.recv_buf.is_empty()
+    }
+
+    /// Nothing written, nor FIN once queued, is waiting for the peer's acknowledgment.
+    #[inline]
+    fn all_acked(&self) -> bool {
+        self.send_buf.is_empty() && self.fin_queued == self.fin_acked
+    }
+
+    #[inline]
+    fn send_space(&self) -> usize {
+        self.buffer_len - self.send_buf.len()
+    }
+
+    /// Room left in the receive buffer.
+    #[inline]
+    fn window(&self) -> usize {
+        self.buffer_len - self.recv_buf.len()
+    }
+
+    /// When `on_timeout` is next due, if anything is waiting on a timer.
+    #[inline]
+    fn timeout(&self) -> Option<Instant> {
+        [self.rto_deadline, self.ack_deadline, self.keepalive_deadline].iter().flatten().min().copied()
+    }
+
+    #[inline]
+    fn take_output(&mut self) -> Vec<Segment> {
+        if let Some(ref mut seen) = self.seen {
+            seen.sent = 0;
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    /// Record events from here on, starting with how the end got from `Closed` to now.
+    fn observe(&mut self, now: Instant) {
+        self.seen = Some(Seen { state: State::Closed, timers: [None; 3], window: 0, sent: 0 });
+        self.note_changes(now);
+    }
+
+    #[inline]
+    fn take_events(&mut self) -> Vec<(Duration, Event)> {
+        std::mem::take(&mut self.events)
+    }
+
+    #[inline]
+    fn timers(&self) -> [Option<Instant>; 3] {
+        [self.rto_deadline, self.ack_deadline, self.keepalive_deadline]
+    }
+
+    /// Record what changed since the last call, if anyone is observing.
+    fn note_changes(&mut self, now: Instant) {
+        let timers = self.timers();
+        let seen = match self.seen {
+            Some(ref mut seen) => seen,
+            None => return,
+        };
+        let at = now.saturating_duration_since(self.born);
+        if self.state != seen.state {
+            self.events.push((at, Event::State { from: seen.state, to: self.state }));
+        }
+        for ((&timer, deadline), was) in TIMERS.iter().zip(timers).zip(seen.timers) {
+            if deadline != was {
+                let event = match deadline {
+                    Some(deadline) => Event::TimerStarted { timer, expires: deadline.saturating_duration_since(self.born) },
+                    None => Event::TimerStopped(timer),
+                };
+                self.events.push((at, event));
+            }
+        }
+        if self.snd_wnd != seen.window {
+            self.events.push((at, Event::Window { from: seen.window, to: self.snd_wnd }));
+        }
+        for seg in &self.out[seen.sent..] {
+            self.events.push((at, Event::Sent(seg.into())));
+        }
+        *seen = Seen { state: self.state, timers, window: self.snd_wnd, sent: self.out.len() };
+    }
+
+    /// Queue as much of `data` as fits the send buffer, and send what the peer's window allows.
+    fn write(&mut self, data: &[u8], now: Instant) -> usize {
+        let len = data.len().min(self.send_space());
+        if self.nagle_held && len > 0 {
+            self.stats.coalesced += 1;
+        }
+        self.send_buf.extend(&data[..len]);
+        self.transmit(now);
+        self.note_changes(now);
+        len
+    }
+
+    /// Turn Nagle's algorithm off, sending what it holds back right away, or on again.
+    fn set_nodelay(&mut self, nodelay: bool, now: Instant) {
+        self.nodelay = nodelay;
+        self.transmit(now);
+        self.note_changes(now);
+    }
+
+    /// Probe the peer after `idle` without a segment from it, or never with `None`.
+    fn set_keepalive(&mut self, idle: Option<Duration>, now: Instant) {
+        self.keepalive = idle;
+        self.probes = 0;
+        self.keepalive_deadline = idle.filter(|_| self.is_established()).map(|idle| now + idle);
+        self.note_changes(now);
+    }
+
+    /// Delay ACKs by up to `delay`, or with `None` acknowledge every segment right away,
+    /// starting with one that is being held back.
+    fn set_ack_delay(&mut self, delay: Option<Duration>, now: Instant) {
+        self.ack_delay = delay;
+        if delay.is_none() && self.ack_deadline.is_some() {
+            self.push_ack();
+        }
+        self.note_changes(now);
+    }
+
+    fn read(&mut self, buf: &mut [u8], now: Instant) -> usize {
+        let len = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..len)) {
+            *dst = src;
+        }
+        // a window that reopens is announced, or the peer would wait for its probe timer
+        if self.advertised < MSS && self.window() >= MSS && self.is_established() {
+            self.push_ack();
+        }
+        self.note_changes(now);
+        len
+    }
+
+    /// Send FIN after everything written.
+    fn shutdown(&mut self, now: Instant) {
+        if !self.fin_queued {
+            self.fin_queued = true;
+            self.transmit(now);
+            self.note_changes(now);
+        }
+    }
+
+    #[inline]
+    fn input(&mut self, seg: Segment, now: Instant) {
+        if self.seen.is_some() {
+            self.events.push((now.saturating_duration_since(self.born), Event::Received((&seg).into())));
+        }
+        self.receive(seg, now);
+        self.note_changes(now);
+    }
+
+    fn receive(&mut self, seg: Segment, now: Instant) {
+        if seg.has(RST) {
+            self.on_reset(&seg);
+            return;
+        }
+        if let Some(idle) = self.keepalive.filter(|_| self.is_established()) {
+            // anything from the peer shows it still has the connection
+            self.keepalive_deadline = Some(now + idle);
+            self.probes = 0;
+        }
+        match self.state {
+            State::SynSent => {
+                if seg.has(SYN | ACK) && seg.ack == self.snd_nxt {
+                    self.limit_mss(seg.mss);
+                    match seg.window_scale.filter(|_| self.scaling) {
+                        Some(shift) => self.snd_scale = shift,
+                        // the peer has no scaling, so neither end scales
+                        None => self.rcv_scale = 0,
+                    }
+                    self.rcv_nxt = seg.seq.wrapping_add(1);
+                    self.synchronize(seg.ack, now);
+                    self.snd_wnd = u32::from(seg.window);
+                    self.push_ack();
+                }
+                return;
+            }
+            State::SynReceived => {
+                if seg.has(SYN) {
+                    // our SYN-ACK was lost, so the peer sent SYN again
+                    if !seg.has(ACK) && seg.seq.wrapping_add(1) == self.rcv_nxt {
+                        self.push(SYN | ACK, self.iss, Vec::new());
+                    }
+                    return;
+                }
+                // the final ACK of the handshake may have been lost, and this is data after it
+                if !seg.has(ACK) || seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.synchronize(seg.ack, now);
+            }
+            State::Established => {
+                // the peer missed our ACK of its SYN-ACK, or is checking we are still here
+                if seg.has(SYN) || seg.seq_len() == 0 && seg.seq == self.rcv_nxt.wrapping_sub(1) {
+                    self.push_ack();
+                    return;
+                }
+            }
+            // the connection is gone, so the peer should stop sending for it
+            State::Closed => {
+                self.out.push(reset_for(&seg));
+                return;
+            }
+        }
+        if seg.has(ACK) {
+            self.on_ack(&seg, now);
+        }
+        self.on_payload(seg, now);
+        self.transmit(now);
+    }
+
+    /// Send no more in a segment than the peer's SYN said it takes.
+    #[inline]
+    fn limit_mss(&mut self, peer_mss: Option<u16>) {
+        if let Some(peer_mss) = peer_mss {
+            self.mss = self.mss.min(usize::from(peer_mss).max(1));
+        }
+    }
+
+    /// A datagram was dropped for being longer than the path's `mtu`: shrink segments to
+    /// fit, and send what was in flight again.
+    fn on_needs_frag(&mut self, mtu: usize, now: Instant) {
+        let mss = mtu.saturating_sub(HEADER_LEN).max(1);
+        if mss >= self.mss {
+            return;
+        }
+        self.mss = mss;
+        if self.is_established() {
+            self.snd_nxt = self.snd_una;
+            self.rtt_sample = None;
+            self.transmit(now);
+        }
+        self.note_changes(now);
+    }
+
+    fn on_timeout(&mut self, now: Instant) {
+        let timers = self.timers();
+        if let Some(ref mut seen) = self.seen {
+            let at = now.saturating_duration_since(self.born);
+            for ((&timer, deadline), was) in TIMERS.iter().zip(timers).zip(seen.timers.iter_mut()) {
+                if deadline.is_some_and(|deadline| deadline <= now) {
+                    self.events.push((at, Event::TimerExpired(timer)));
+                    *was = None;
+                }
+            }
+        }
+        self.expire(now);
+        self.note_changes(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if self.ack_deadline.is_some_and(|deadline| deadline <= now) {
+            self.push_ack();
+        }
+        self.on_keepalive(now);
+        match self.rto_deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.state = State::Closed;
+            self.rto_deadline = None;
+            return;
+        }
+        self.rto = (self.rto * 2).min(RTO_MAX);
+        self.rto_deadline = Some(now + self.rto);
+        self.rtt_sample = None;
+        match self.state {
+            State::SynSent => self.push(SYN, self.iss, Vec::new()),
+            State::SynReceived => self.push(SYN | ACK, self.iss, Vec::new()),
+            State::Established => {
+                self.snd_nxt = self.snd_una;
+                // a zero window is probed with one byte, whose ACK tells when it reopens
+                if self.snd_wnd == 0 && !self.send_buf.is_empty() {
+                    let probe = vec![self.send_buf[0]];
+                    self.push_sequenced(0, probe, now);
+                }
+                self.transmit(now);
+            }
+            State::Closed => {}
+        }
+    }
+
+    fn on_keepalive(&mut self, now: Instant) {
+        let idle = match (self.keepalive, self.keepalive_deadline) {
+            (Some(idle), Some(deadline)) if deadline <= now => idle,
+            _ => return,
+        };
+        self.keepalive_deadline = Some(now + idle);
+        // the retransmission timer already finds out whether the peer answers
+        if self.snd_una != self.snd_max {
+            self.probes = 0;
+            return;
+        }
+        if self.probes == KEEPALIVE_PROBES {
+            self.state = State::Closed;
+            self.rto_deadline = None;
+            self.keepalive_deadline = None;
+            return;
+        }
+        self.probes += 1;
+        self.push(ACK, self.snd_nxt.wrapping_sub(1), Vec::new());
+    }
+
+    fn on_reset(&mut self, seg: &Segment) {
+        // only a reset that answers something of ours is believed
+        let believed = match self.state {
+            State::SynSent => seg.has(ACK) && seg.ack == self.snd_nxt,
+            State::SynReceived | State::Established => seg.seq == self.rcv_nxt,
+            State::Closed => false,
+        };
+        // once both ends are done, a reset for a late retransmission changes nothing
+        if believed && !(self.at_eof() && self.fin_acked) {
+            self.state = State::Closed;
+            self.reset = true;
+            self.rto_deadline = None;
+        }
+    }
+
+    /// The handshake is done, with `ack` covering our SYN.
+    fn synchronize(&mut self, ack: u32, now: Instant) {
+        self.state = State::Established;
+        self.snd_una = ack;
+        self.retries = 0;
+        self.rto_deadline = None;
+        self.keepalive_deadline = self.keepalive.map(|idle| now + idle);
+        if let Some((_, sent)) = self.rtt_sample.take() {
+            self.update_rto(now - sent);
+        }
+    }
+
+    fn on_ack(&mut self, seg: &Segment, now: Instant) {
+        let acked = diff(seg.ack, self.snd_una);
+        if acked < 0 || diff(seg.ack, self.snd_max) > 0 {
+            return;
+        }
+        let window = u32::from(seg.window) << self.snd_scale;
+        let window_changed = window != self.snd_wnd;
+        if self.snd_wnd == 0 && window_changed {
+            // a window that reopens takes the probe byte again, and what follows it
+            self.snd_nxt = self.snd_una;
+        }
+        self.snd_wnd = window;
+        // the peer answered, so it is alive even if nothing new was acknowledged
+        self.retries = 0;
+        if acked == 0 {
+            // the same ACK again, for a segment that came after a lost one
+            if seg.seq_len() == 0 && !window_changed && self.snd_una != self.snd_max {
+                self.dup_acks += 1;
+                if self.dup_acks == DUP_ACKS {
+                    self.snd_nxt = self.snd_una;
+                    self.rtt_sample = None;
+                }
+            }
+            return;
+        }
+        self.dup_acks = 0;
+        if let Some((seq, sent)) = self.rtt_sample {
+            if diff(seg.ack, seq) >= 0 {
+                self.rtt_sample = None;
+                self.update_rto(now - sent);
+            }
+        }
+        let acked = acked as usize;
+        let data = acked.min(self.send_buf.len());
+        self.send_buf.drain(..data);
+        if acked > data {
+            self.fin_acked = true;
+        }
+        self.snd_una = seg.ack;
+        if diff(self.snd_nxt, self.snd_una) < 0 {
+            self.snd_nxt = self.snd_una;
+        }
+        self.rto = self.rto_estimate();
+        self.rto_deadline = if self.snd_una == self.snd_max { None } else { Some(now + self.rto) };
+    }
+
+    fn on_payload(&mut self, seg: Segment, now: Instant) {
+        if seg.payload.is_empty() && !seg.has(FIN) {
+            return;
+        }
+        // bytes of it already received, negative when it came ahead of a gap
+        let behind = diff(self.rcv_nxt, seg.seq);
+        let mut delayable = false;
+        if behind >= 0 && !self.peer_fin && behind as usize <= seg.payload.len() {
+            let fresh = &seg.payload[behind as usize..];
+            let len = fresh.len().min(self.window());
+            self.recv_buf.extend(&fresh[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            if len == fresh.len() && seg.has(FIN) {
+                self.peer_fin = true;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            }
+            delayable = behind == 0 && len == fresh.len() && !seg.has(FIN);
+        }
+        match self.ack_delay {
+            // new data in order waits for a second segment, or for the delay to run out
+            Some(delay) if delayable => {
+                self.unacked_segments += 1;
+                if self.unacked_segments >= 2 {
+                    self.push_ack();
+                } else if self.ack_deadline.is_none() {
+                    self.ack_deadline = Some(now + delay);
+                }
+            }
+            // what was dropped is acknowledged right away, as the duplicate ACK that asks for
+            // it again
+            _ => self.push_ack(),
+        }
+    }
+
+    /// Send what the peer's window allows from `snd_nxt` on, then FIN if it is due.
+    fn transmit(&mut self, now: Instant) {
+        if !self.is_established() {
+            return;
+        }
+        self.nagle_held = false;
+        loop {
+            let offset = diff(self.snd_nxt, self.snd_una) as usize;
+            let unsent = self.send_buf.len().saturating_sub(offset);
+            // the window counts from `snd_una`
+            let room = (self.snd_wnd as usize).saturating_sub(offset);
+            let len = unsent.min(room).min(self.mss);
+            if len > 0 {
+                // new data short of a segment waits while anything is unacknowledged; what
+                // goes back out after a rewind was sent before, and is not held again
+                if len < self.mss && !self.nodelay && self.snd_una != self.snd_max && self.snd_nxt == self.snd_max {
+                    self.nagle_held = true;
+                    break;
+                }
+                let payload = self.send_buf.range(offset..offset + len).copied().collect();
+                self.push_sequenced(0, payload, now);
+                continue;
+            }
+            if self.fin_queued && !self.fin_acked && offset == self.send_buf.len() {
+                self.push_sequenced(FIN, Vec::new(), now);
+            }
+            break;
+        }
+        // data held back by a closed window keeps the timer running, to probe it
+        if self.rto_deadline.is_none() && !self.send_buf.is_empty() {
+            self.rto_deadline = Some(now + self.rto);
+        }
+    }
+
+    /// Send a segment that takes sequence numbers from `snd_nxt`, and time it.
+    fn push_sequenced(&mut self, flags: u8, payload: Vec<u8>, now: Instant) {
+        let flags = if self.state == State::SynSent { flags } else { flags | ACK };
+        let seq = self.snd_nxt;
+        self.push(flags, seq, payload);
+        let len = self.out.last().map_or(0, Segment::seq_len);
+        self.snd_nxt = seq.wrapping_add(len);
+        if diff(self.snd_nxt, self.snd_max) > 0 {
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((self.snd_nxt, now));
+            }
+            self.snd_max = self.snd_nxt;
+        }
+        if self.rto_deadline.is_none() {
+            self.rto_deadline = Some(now + self.rto);
+        }
+    }
+
+    #[inline]
+    fn push_ack(&mut self) {
+        self.push(ACK, self.snd_nxt, Vec::new());
+    }
+
+    fn push(&mut self, flags: u8, seq: u32, payload: Vec<u8>) {
+        // the window in a SYN is never scaled (RFC 7323, 2.2)
+        let (shift, mss, window_scale) = if flags & SYN == 0 {
+            (self.rcv_scale, None, None)
+        } else {
+            (0, Some(self.mss_offer as u16), self.scaling.then_some(self.rcv_scale))
+        };
+        let window = (self.window() >> shift).min(usize::from(u16::MAX)) as u16;
+        self.advertised = usize::from(window) << shift;
+        self.stats.segments_sent += 1;
+        // every segment carries the ACK a delayed one was waiting to send
+        self.ack_deadline = None;
+        self.unacked_segments = 0;
+        self.out.push(Segment { flags, seq, ack: self.rcv_nxt, window, mss, window_scale, payload });
+    }
+
+    // RFC 6298, section 2
+    fn update_rto(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.max(rtt) - srtt.min(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = self.rto_estimate();
+    }
+
+    #[inline]
+    fn rto_estimate(&self) -> Duration {
+        self.srtt.map_or(RTO_INITIAL, |srtt| (srtt + self.rttvar * 4).max(RTO_MIN).min(RTO_MAX))
+    }
+}
+
+#[derive(Debug)]
+enum Link {
+    /// A socket of the stream's own, connected to the peer.
+    Connected(UdpSocket),
+    /// The server's socket, with the peer's datagrams passed on by the server.
+    Shared { socket: UdpSocket, peer: SocketAddr, rx: mpsc::Receiver<Vec<u8>> },
+}
+
+impl Link {
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        let sent = match self {
+            Link::Connected(socket) => socket.send(datagram),
+            Link::Shared { socket, peer, .. } => socket.send_to(datagram, *peer),
+        };
+        match sent {
+            // ICMP unreachable for an earlier datagram; the retransmission timer deals with it
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            ans => ans.map(|_| ()),
+        }
+    }
+
+    /// Wait up to `wait`, or forever for `None`, for a datagram.
+    fn recv(&self, buf: &mut [u8], wait: Option<Duration>) -> io::Result<Option<usize>> {
+        match self {
+            Link::Connected(socket) => {
+                socket.set_read_timeout(wait)?;
+                match socket.recv(buf) {
+                    Ok(len) => Ok(Some(len)),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            Link::Shared { rx, .. } => {
+                let stopped = || io::Error::new(io::ErrorKind::ConnectionAborted, "simtcp server stopped");
+                let datagram = match wait {
+                    Some(wait) => match rx.recv_timeout(wait) {
+                        Ok(datagram) => datagram,
+                        Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return Err(stopped()),
+                    },
+                    None => rx.recv().map_err(|_| stopped())?,
+                };
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(Some(len))
+            }
+        }
+    }
+}
+
+/// One end of a connection, read and written like a `TcpStream`. Dropping it without
+/// `close` sends FIN once, without waiting for it to arrive.
+#[derive(Debug)]
+pub struct Stream {
+    link: Link,
+    tcb: Tcb,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    read_timeout: Option<Duration>,
+    buf: Vec<u8>,
+    options: Options,
+    observer: Option<Box<dyn Observer + Send>>,
+}
+
+impl Stream {
+    /// Connect to `addr` from a fresh ephemeral port, returning once the handshake is done;
+    /// `Connector` sets up a connection first.
+    #[inline]
+    pub fn connect<A>(addr: A) -> io::Result<Self>
+    where A: ToSocketAddrs
+    {
+        Connector::new().connect(addr)
+    }
+
+    fn accept(
+        link: Link,
+        syn: &Segment,
+        shake: Handshake,
+        options: Options,
+        observer: Option<Box<dyn Observer + Send>>,
+    ) -> io::Result<Self> {
+        let tcb = Tcb::accept(syn, random(), options, Instant::now());
+        Self::handshake(link, tcb, options, observer, shake.local_addr, shake.peer_addr)
+    }
+
+    fn handshake(
+        link: Link,
+        mut tcb: Tcb,
+        options: Options,
+        observer: Option<Box<dyn Observer + Send>>,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> io::Result<Self> {
+        if observer.is_some() {
+            tcb.observe(tcb.born);
+        }
+        let buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let mut ans = Self { link, tcb, local_addr, peer_addr, read_timeout: None, buf, options, observer };
+        while !ans.tcb.is_established() {
+            ans.pump(None)?;
+        }
+        Ok(ans)
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// How long a `read`, `flush` or `close` may wait; `None`, the default, waits until the
+    /// peer is given up on.
+    #[inline]
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Send short segments without waiting for the ACK of what is in flight, turning off
+    /// Nagle's algorithm; off by default.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.tcb.set_nodelay(nodelay, Instant::now());
+        self.send_output()
+    }
+
+    #[inline]
+    pub fn nodelay(&self) -> bool {
+        self.tcb.nodelay
+    }
+
+    /// Probe the peer once nothing came from it for `idle`, as `SO_KEEPALIVE` does, and
+    /// again each `idle` after; four probes unanswered, the connection fails with
+    /// `TimedOut`. Off, `None`, by default.
+    pub fn set_keepalive(&mut self, idle: Option<Duration>) {
+        self.tcb.set_keepalive(idle, Instant::now());
+    }
+
+    /// Hold back the ACK of data that arrives in order for up to `delay`, unless a second
+    /// segment or something to send back comes first; `None` acknowledges every segment
+    /// right away. 40 ms by default.
+    pub fn set_delayed_ack(&mut self, delay: Option<Duration>) -> io::Result<()> {
+        self.tcb.set_ack_delay(delay, Instant::now());
+        self.send_output()
+    }
+
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        self.tcb.stats
+    }
+
+    /// The most payload sent in one segment, as the handshake settled it and "fragmentation
+    /// needed" lowered it since.
+    #[inline]
+    pub fn mss(&self) -> usize {
+        self.tcb.mss
+    }
+
+    /// Send FIN after everything written, so the peer reads to its end. Reading goes on
+    /// until the peer's FIN.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.tcb.shutdown(Instant::now());
+        self.send_output()
+    }
+
+    /// `shutdown`, then wait until the peer acknowledged everything, FIN included.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.shutdown()?;
+        self.flush()
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.read_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Send what the connection has queued, then wait for one datagram or the timer.
+    fn pump(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        self.send_output()?;
+        if let Some(e) = self.tcb.error() {
+            return Err(e);
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "simtcp read timed out"));
+        }
+        let until = match (self.tcb.timeout(), deadline) {
+            (Some(timer), Some(deadline)) => Some(timer.min(deadline)),
+            (timer, deadline) => timer.or(deadline),
+        };
+        let wait = until.map(|until| until.saturating_duration_since(now).max(Duration::from_millis(1)));
+        if let Some(len) = self.link.recv(&mut self.buf, wait)? {
+            receive(&mut self.tcb, &self.buf[..len], Instant::now());
+        }
+        self.tcb.on_timeout(Instant::now());
+        self.send_output()
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let link = &self.link;
+        drain(&mut self.tcb, self.options, &mut self.observer, |datagram| link.send(datagram))
+    }
+}
+
+/// Hand a datagram from the peer to `tcb`; a corrupt one is as good as a lost one.
+fn receive(tcb: &mut Tcb, datagram: &[u8], now: Instant) {
+    match Segment::decode(datagram) {
+        Ok(seg) => tcb.input(seg, now),
+        Err(_) if !checksum_ok(datagram) => tcb.stats.bad_checksums += 1,
+        Err(_) => {}
+    }
+}
+
+/// Tell `observer` what `tcb` did, then `send` each segment it queued, through a path MTU
+/// and corrupted as `options` wants, until it queues no more.
+fn drain<F>(tcb: &mut Tcb, options: Options, observer: &mut Option<Box<dyn Observer + Send>>, mut send: F) -> io::Result<()>
+where F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut datagram = Vec::with_capacity(HEADER_LEN + MSS);
+    loop {
+        if let Some(observer) = observer {
+            for (at, event) in tcb.take_events() {
+                observer.on_event(at, &event);
+            }
+        }
+        let output = tcb.take_output();
+        if output.is_empty() {
+            return Ok(());
+        }
+        for seg in output {
+            datagram.clear();
+            seg.encode(&mut datagram);
+            if let Some((mtu, oversize)) = options.path_mtu.filter(|&(mtu, _)| datagram.len() > mtu) {
+                match oversize {
+                    Oversize::Fragment => tcb.stats.fragmented += 1,
+                    Oversize::NeedsFrag => {
+                        tcb.stats.needs_frag += 1;
+                        tcb.on_needs_frag(mtu, Instant::now());
+                        continue;
+                    }
+                    Oversize::Blackhole => {
+                        tcb.stats.blackholed += 1;
+                        continue;
+                    }
+                }
+            }
+            if options.corrupt > 0.0 && f64::from(random::<u32>()) < options.corrupt * 4_294_967_296.0 {
+                // rand 0.6 fills 64-bit values with an unaligned read, so only u32s are drawn
+                let bit = random::<u32>() as usize % (datagram.len() * 8);
+                datagram[bit / 8] ^= 1 << (bit % 8);
+                tcb.stats.corrupted += 1;
+            }
+            send(&datagram)?;
+        }
+    }
+}
+
+impl Read for Stream {
+    /// Read what arrived in order, waiting for at least one byte; 0 after the peer's FIN.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.deadline();
+        loop {
+            let len = self.tcb.read(buf, Instant::now());
+            if len > 0 {
+                self.send_output()?;
+                return Ok(len);
+            }
+            if self.tcb.at_eof() {
+                return Ok(0);
+            }
+            self.pump(deadline)?;
+        }
+    }
+}
+
+impl Write for Stream {
+    /// Queue what fits the send buffer, waiting for room if it is full, and send what the
+    /// peer's window allows.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.tcb.error() {
+            return Err(e);
+        }
+        if self.tcb.fin_queued {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "simtcp stream was shut down"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.deadline();
+        while self.tcb.send_space() == 0 {
+            self.pump(deadline)?;
+        }
+        let len = self.tcb.write(buf, Instant::now());
+        self.send_output()?;
+        Ok(len)
+    }
+
+    /// Wait until the peer acknowledged everything written.
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = self.deadline();
+        while !self.tcb.all_acked() {
+            self.pump(deadline)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.tcb.is_established() && !self.tcb.fin_queued {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+/// Serves simtcp on any number of sockets, each read by a thread that passes datagrams
+/// on to the connection they belong to.
+pub struct LajiSimTcp<F> {
+    udp: SmallVec<[UdpSocket; INLINE_SOCKETS]>,
+    factory: F,
+    limits: Limits,
+    options: Options,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    max_half_open: usize,
+    max_connections: usize,
+}
+
+impl<F> LajiSimTcp<F> {
+    #[inline]
+    pub fn new(factory: F) -> Self {
+        let limits = Limits { max_half_open: DEFAULT_MAX_HALF_OPEN, max_connections: DEFAULT_MAX_CONNECTIONS };
+        Self { udp: SmallVec::new(), factory, limits, options: Options::default() }
+    }
+
+    /// Reset SYNs while this many handshakes per socket are still waiting for the peer's ACK;
+    /// 64 by default.
+    #[inline]
+    pub fn max_half_open(mut self, max: usize) -> Self {
+        self.limits.max_half_open = max;
+        self
+    }
+
+    /// Reset SYNs while this many connections per socket, open or opening, are being
+    /// served; 1024 by default.
+    #[inline]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = max;
+        self
+    }
+
+    /// Chance from 0 to 1 that a datagram a connection sends has one of its bits flipped;
+    /// see `Connector::corrupt`.
+    #[inline]
+    pub fn corrupt(mut self, chance: f64) -> Self {
+        self.options.corrupt = chance;
+        self
+    }
+
+    /// Bytes each connection buffers in each direction; see `Connector::buffer_len`.
+    #[inline]
+    pub fn buffer_len(mut self, len: usize) -> Self {
+        self.options.buffer_len = len;
+        self
+    }
+
+    /// Whether to accept window scaling from peers that offer it; see
+    /// `Connector::window_scaling`.
+    #[inline]
+    pub fn window_scaling(mut self, on: bool) -> Self {
+        self.options.window_scaling = on;
+        self
+    }
+
+    /// The most payload each connection takes in one segment; see `Connector::mss`.
+    #[inline]
+    pub fn mss(mut self, mss: usize) -> Self {
+        self.options.mss = mss;
+        self
+    }
+
+    /// The MTU of the path connections send over; see `Connector::path_mtu`.
+    #[inline]
+    pub fn path_mtu(mut self, mtu: usize, oversize: Oversize) -> Self {
+        self.options.path_mtu = Some((mtu, oversize));
+        self
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Self>
+    where A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.udp.iter().map(UdpSocket::local_addr).collect()
+    }
+}
+
+impl<F> LajiSimTcp<F>
+where
+    F: Factory + Send + 'static,
+    F::Handler: Send + 'static
+{
+    /// Serve until a socket fails; see `run_until`.
+    #[inline]
+    pub fn run(self) -> io::Result<()> {
+        self.run_until(&Stopper::new())
+    }
+
+    /// Serve until `stopper` is stopped or receiving fails, then wait for every connection's
+    /// thread. Open streams fail their next wait with `ConnectionAborted` once the server
+    /// stops, so handlers that read or write them return.
+    pub fn run_until(self, stopper: &Stopper) -> io::Result<()> {
+        if self.udp.is_empty() {
+            return Err(ConfigError::NoListeners.into());
+        }
+        if self.limits.max_half_open == 0 {
+            return Err(ConfigError::Zero("max_half_open").into());
+        }
+        if self.limits.max_connections == 0 {
+            return Err(ConfigError::Zero("max_connections").into());
+        }
+        self.options.check()?;
+        for socket in &self.udp {
+            stopper.wake_udp(socket.local_addr()?);
+        }
+        let factory = Arc::new(Mutex::new(self.factory));
+        let (limits, options) = (self.limits, self.options);
+        let (err_tx, err_rx) = mpsc::channel();
+        let mut threads = Vec::new();
+        for socket in self.udp {
+            let err_tx = err_tx.clone();
+            let stopper = stopper.clone();
+            let factory = factory.clone();
+            threads.push(thread::spawn(move || {
+                if let Err(e) = demux(socket, &factory, limits, options, &stopper) {
+                    stopper.stop();
+                    err_tx.send(e).unwrap();
+                }
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        drop(err_tx);
+        match err_rx.try_recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// `run_until` on a thread of its own, stopped through the returned handle.
+    pub fn run_detached(self) -> io::Result<ServerHandle> {
+        let local_addrs = self.local_addrs()?;
+        let stopper = Stopper::new();
+        let serving = stopper.clone();
+        ServerHandle::spawn_stoppable("laji-simtcp", local_addrs, stopper, move || self.run_until(&serving))
+    }
+}
+
+/// What a connection's thread tells the demux about its peer.
+enum Progress {
+    Established,
+    Done { established: bool },
+}
+
+fn demux<F>(socket: UdpSocket, factory: &Mutex<F>, limits: Limits, options: Options, stopper: &Stopper) -> io::Result<()>
+where
+    F: Factory,
+    F::Handler: Send + 'static
+{
+    let local_addr = socket.local_addr()?;
+    // each peer's connection, by the id it was started with
+    let mut peers: HashMap<SocketAddr, (u64, mpsc::SyncSender<Vec<u8>>)> = HashMap::new();
+    let (done_tx, done_rx) = mpsc::channel();
+    let (mut serving, mut half_open, mut next_id) = (0, 0, 0u64);
+    let mut buf = [0u8; MAX_DATAGRAM_LEN];
+    let ans = loop {
+        let received = socket.recv_from(&mut buf);
+        if stopper.is_stopped() {
+            break Ok(());
+        }
+        for (peer, id, progress) in done_rx.try_iter() {
+            match progress {
+                Progress::Established => half_open -= 1,
+                Progress::Done { established } => {
+                    // the peer may have opened a new connection since, which keeps its entry
+                    if peers.get(&peer).is_some_and(|&(current, _)| current == id) {
+                        peers.remove(&peer);
+                    }
+                    serving -= 1;
+                    if !established {
+                        half_open -= 1;
+                    }
+                }
+            }
+        }
+        let (len, peer) = match received {
+            Ok(received) => received,
+            // ICMP unreachable for an earlier send_to, on Windows
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset || e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if let Some((_, tx)) = peers.get(&peer) {
+            match tx.try_send(buf[..len].to_vec()) {
+                Ok(()) | Err(mpsc::TrySendError::Full(_)) => continue,
+                // its thread is finishing; whatever the peer sends now is a new connection's
+                Err(mpsc::TrySendError::Disconnected(_)) => peers.remove(&peer),
+            };
+        }
+        let seg = match Segment::decode(&buf[..len]) {
+            Ok(seg) => seg,
+            Err(_) => continue,
+        };
+        if seg.has(RST) {
+            continue;
+        }
+        let shake = Handshake { peer_addr: peer, local_addr };
+        let full = half_open >= limits.max_half_open || serving >= limits.max_connections;
+        if seg.flags != SYN || full || !factory.lock().unwrap().accept(&shake) {
+            let mut datagram = Vec::with_capacity(HEADER_LEN);
+            reset_for(&seg).encode(&mut datagram);
+            let _ = socket.send_to(&datagram, peer);
+            continue;
+        }
+        let link = match socket.try_clone() {
+            Ok(shared) => {
+                let (tx, rx) = mpsc::sync_channel(options.peer_queue_len());
+                peers.insert(peer, (next_id, tx));
+                Link::Shared { socket: shared, peer, rx }
+            }
+            Err(e) => { factory.lock().unwrap().on_error(e); continue }
+        };
+        let (handler, observer) = {
+            let mut factory = factory.lock().unwrap();
+            (factory.connection_made(), factory.observer(&shake))
+        };
+        let done_tx = done_tx.clone();
+        let id = next_id;
+        next_id += 1;
+        serving += 1;
+        half_open += 1;
+        thread::spawn(move || {
+            let established = serve(link, &seg, shake, options, observer, handler, ||
+                drop(done_tx.send((peer, id, Progress::Established))));
+            let _ = done_tx.send((peer, id, Progress::Done { established }));
+        });
+    };
+    // with their channels closed, the streams fail their next wait
+    drop(peers);
+    while serving > 0 {
+        if let Ok((_, _, Progress::Done { .. })) = done_rx.recv() {
+            serving -= 1;
+        }
+    }
+    ans
+}
+
+/// RST for a segment that belongs to no connection, as RFC 793 answers one.
+fn reset_for(seg: &Segment) -> Segment {
+    if seg.has(ACK) {
+        Segment { flags: RST, seq: seg.ack, ..Segment::default() }
+    } else {
+        Segment { flags: RST | ACK, ack: seg.seq.wrapping_add(seg.seq_len()), ..Segment::default() }
+    }
+}
+
+/// Complete the handshake and hand the stream to `handler`, calling `established` in
+/// between; whether the handshake completed.
+fn serve<H>(
+    link: Link,
+    syn: &Segment,
+    shake: Handshake,
+    options: Options,
+    observer: Option<Box<dyn Observer + Send>>,
+    mut handler: H,
+    established: impl FnOnce(),
+) -> bool
+where H: Handler
+{
+    let mut stream = match Stream::accept(link, syn, shake, options, observer) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    established();
+    handler.on_open(shake);
+    if let Err(e) = handler.on_stream(&mut stream).and_then(|()| stream.close()) {
+        if stream.tcb.reset {
+            handler.on_reset();
+        } else {
+            handler.on_error(e);
+        }
+    }
+    handler.on_close();
+    true
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Handshake {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl Handshake {
+    #[inline]
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+}
+
+pub trait Handler {
+    fn on_open(&mut self, _shake: Handshake) {}
+
+    /// Serve the connection. Once this returns `Ok`, the stream is closed, waiting until the
+    /// peer acknowledged everything written.
+    fn on_stream(&mut self, stream: &mut Stream) -> io::Result<()>;
+
+    /// `on_stream`, or the close after it, failed.
+    fn on_error(&mut self, _err: io::Error) {}
+
+    /// The peer reset the connection, failing `on_stream` or the close after it; called
+    /// instead of `on_error`.
+    fn on_reset(&mut self) {}
+
+    fn on_close(&mut self) {}
+}
+
+impl<F> Handler for F
+where F: FnMut(&mut Stream) -> io::Result<()> {
+    #[inline]
+    fn on_stream(&mut self, stream: &mut Stream) -> io::Result<()> {
+        self(stream)
+    }
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    /// Whether to answer this SYN at all; refused peers are sent RST.
+    #[inline]
+    fn accept(&mut self, _shake: &Handshake) -> bool {
+        true
+    }
+
+    /// A handler for the peer whose SYN just came in. If the handshake never completes, the
+    /// handler is dropped without any of its methods called.
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// Someone to tell what the connection just made does, its handshake included.
+    #[inline]
+    fn observer(&mut self, _shake: &Handshake) -> Option<Box<dyn Observer + Send>> {
+        None
+    }
+
+    /// A connection could not be set up, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where H: Handler, F: FnMut() -> H {
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deliver what each side sends through the codec on 1 ms ticks, except every `drop_every`th
+    fn run_lossy(a: &mut Tcb, b: &mut Tcb, drop_every: usize, mut until: impl FnMut(&mut Tcb, &mut Tcb, Instant) -> bool) -> Duration {
+        let start = Instant::now();
+        let mut sent = 0;
+        for tick in 0..60_000 {
+            let now = start + Duration::from_millis(tick);
+            a.on_timeout(now);
+            b.on_timeout(now);
+            let (to_b, to_a) = (a.take_output(), b.take_output());
+            for (segments, peer) in [(to_b, &mut *b), (to_a, &mut *a)] {
+                for seg in segments {
+                    sent += 1;
+                    if sent % drop_every != 0 {
+                        let mut datagram = Vec::new();
+                        seg.encode(&mut datagram);
+                        peer.input(Segment::decode(&datagram).unwrap(), now);
+                    }
+                }
+            }
+            if until(a, b, now) {
+                return now - start;
+            }
+        }
+        panic!("not delivered within a minute");
+    }
+
+    // what each side sends arrives `one_way` later, through the codec, on 1 ms ticks
+    fn run_delayed(a: &mut Tcb, b: &mut Tcb, one_way: Duration, mut until: impl FnMut(&mut Tcb, &mut Tcb) -> bool) -> Duration {
+        let start = Instant::now();
+        let mut in_flight = VecDeque::new();
+        for tick in 0..60_000 {
+            let now = start + Duration::from_millis(tick);
+            while in_flight.front().is_some_and(|&(at, _, _)| at <= now) {
+                let (_, to_b, datagram): (_, bool, Vec<u8>) = in_flight.pop_front().unwrap();
+                let peer = if to_b { &mut *b } else { &mut *a };
+                peer.input(Segment::decode(&datagram).unwrap(), now);
+            }
+            if until(a, b) {
+                return now - start;
+            }
+            a.on_timeout(now);
+            b.on_timeout(now);
+            for (to_b, segments) in [(true, a.take_output()), (false, b.take_output())] {
+                for seg in segments {
+                    let mut datagram = Vec::new();
+                    seg.encode(&mut datagram);
+                    in_flight.push_back((now + one_way, to_b, datagram));
+                }
+            }
+        }
+        panic!("not delivered within a minute");
+    }
+
+    // both ends of a connection, handshake done
+    fn established(now: Instant) -> (Tcb, Tcb) {
+        established_with(Options::default(), Options::default(), now)
+    }
+
+    fn established_with(a_options: Options, b_options: Options, now: Instant) -> (Tcb, Tcb) {
+        let mut a = Tcb::connect(1000, a_options, now);
+        let syn = a.take_output().remove(0);
+        let mut b = Tcb::accept(&syn, 5000, b_options, now);
+        for seg in b.take_output() {
+            a.input(seg, now);
+        }
+        for seg in a.take_output() {
+            b.input(seg, now);
+        }
+        assert!(a.is_established() && b.is_established());
+        (a, b)
+    }
+
+    #[test]
+    fn segment_codec() {
+        let seg = Segment { flags: FIN | ACK, seq: 0xdead_beef, ack: 7, window: 512, mss: None, window_scale: None, payload: b"hi".to_vec() };
+        let mut datagram = Vec::new();
+        seg.encode(&mut datagram);
+        assert_eq!(datagram.len(), HEADER_LEN + 2);
+        assert_eq!(Segment::decode(&datagram).unwrap(), seg);
+        assert_eq!(seg.seq_len(), 3);
+        assert!(Segment::decode(&datagram[..HEADER_LEN - 1]).is_err());
+        let mut unknown = datagram.clone();
+        unknown[1] |= 0x80;
+        assert!(Segment::decode(&unknown).is_err());
+        let mut flipped = datagram.clone();
+        flipped[HEADER_LEN] ^= 0x10;
+        assert!(!checksum_ok(&flipped) && Segment::decode(&flipped).is_err());
+        datagram[0] = VERSION + 1;
+        assert!(Segment::decode(&datagram).is_err());
+        let mut long = Vec::new();
+        Segment { payload: vec![0; MSS + 1], ..Segment::default() }.encode(&mut long);
+        assert!(Segment::decode(&long).is_err());
+    }
+
+    #[test]
+    fn wire_format_fixtures() {
+        fn encoded(seg: &Segment) -> impl FnOnce(&mut [u8]) -> io::Result<usize> + '_ {
+            move |buf| {
+                let mut datagram = Vec::new();
+                seg.encode(&mut datagram);
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                Ok(datagram.len())
+            }
+        }
+        let syn_bytes = crate::fixture::load("simtcp/synthesized-syn.hex");
+        let syn = Segment::decode(&syn_bytes).unwrap();
+        assert_eq!(syn, Segment { flags: SYN, seq: 0x1a2b_3c4d, ack: 0, window: 65535, mss: None, window_scale: None, payload: Vec::new() });
+        crate::fixture::assert_encodes(&syn_bytes, encoded(&syn));
+
+        let data_bytes = crate::fixture::load("simtcp/synthesized-data.hex");
+        let data = Segment::decode(&data_bytes).unwrap();
+        assert_eq!((data.flags, data.seq, data.ack, data.window), (ACK, 0x1a2b_3c4e, 0x00a0_b0c1, 65000));
+        assert_eq!(data.payload, b"hello, simtcp");
+        crate::fixture::assert_encodes(&data_bytes, encoded(&data));
+
+        let rst_bytes = crate::fixture::load("simtcp/synthesized-rst.hex");
+        assert_eq!(Segment::decode(&rst_bytes).unwrap(), reset_for(&syn));
+        crate::fixture::assert_encodes(&rst_bytes, encoded(&reset_for(&syn)));
+
+        for len in 0..HEADER_LEN {
+            assert!(Segment::decode(&syn_bytes[..len]).is_err(), "segment cut to {} bytes", len);
+        }
+        // options a newer build puts in its SYN are skipped
+        let mut datagram = Vec::new();
+        Segment { payload: vec![OPTION_NOP, 0xfe, 4, 1, 2, OPTION_END], ..syn.clone() }.encode(&mut datagram);
+        assert_eq!(Segment::decode(&datagram).unwrap(), syn);
+    }
+
+    #[test]
+    fn syn_options() {
+        let syn = Segment { flags: SYN, seq: 9, window: 65535, mss: Some(536), window_scale: Some(7), ..Segment::default() };
+        let mut datagram = Vec::new();
+        syn.encode(&mut datagram);
+        assert_eq!(&datagram[HEADER_LEN..], &[OPTION_MSS, 4, 0x02, 0x18, OPTION_WINDOW_SCALE, 3, 7]);
+        assert_eq!(Segment::decode(&datagram).unwrap(), syn);
+        // only a SYN has options
+        datagram.clear();
+        Segment { flags: ACK, ..syn.clone() }.encode(&mut datagram);
+        assert_eq!(datagram.len(), HEADER_LEN);
+        let bad: [&[u8]; 5] = [&[OPTION_WINDOW_SCALE, 2], &[OPTION_WINDOW_SCALE, 4, 1, 1], &[OPTION_MSS, 3, 5], &[0xfe, 1], &[0xfe, 9, 0]];
+        for options in bad {
+            datagram.clear();
+            Segment { payload: options.to_vec(), mss: None, window_scale: None, ..syn.clone() }.encode(&mut datagram);
+            assert!(Segment::decode(&datagram).is_err(), "options {:?}", options);
+        }
+        assert_eq!(scale_for(DEFAULT_BUFFER_LEN), 0);
+        assert_eq!(scale_for(DEFAULT_BUFFER_LEN + 1), 1);
+        assert_eq!(scale_for(1 << 20), 5);
+        assert_eq!(scale_for(MAX_BUFFER_LEN), MAX_WINDOW_SCALE);
+    }
+
+    #[test]
+    fn segments_fit_both_ends_mss() {
+        let now = Instant::now();
+        let small = Options { mss: 500, ..Options::default() };
+        for (a_options, b_options) in [(small, Options::default()), (Options::default(), small)] {
+            let (mut a, mut b) = established_with(a_options, b_options, now);
+            assert_eq!((a.mss, b.mss), (500, 500));
+            a.write(&[7; 2000], now);
+            b.write(&[7; 2000], now);
+            for seg in a.take_output().into_iter().chain(b.take_output()) {
+                assert!(seg.payload.len() <= 500);
+            }
+        }
+        // "fragmentation needed" lowers the MSS, and what was in flight goes again at the new size
+        let (mut a, _) = established(now);
+        a.set_nodelay(true, now);
+        a.write(&[7; 3000], now);
+        assert_eq!(a.take_output().len(), 3);
+        a.on_needs_frag(576, now);
+        assert_eq!(a.mss, 576 - HEADER_LEN);
+        let resent = a.take_output();
+        assert_eq!((resent.len(), resent[0].seq, resent[0].payload.len()), (6, 1001, 576 - HEADER_LEN));
+        a.on_needs_frag(1500, now);
+        assert_eq!(a.mss, 576 - HEADER_LEN);
+    }
+
+    #[test]
+    fn delivers_through_loss_in_order() {
+        let start = Instant::now();
+        // sequence numbers wrap within the first kilobyte
+        let mut a = Tcb::connect(u32::MAX - 1000, Options::default(), start);
+        let syn = a.take_output().remove(0);
+        let mut b = Tcb::accept(&syn, 42, Options::default(), start);
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let (mut written, mut got) = (0, Vec::new());
+        let mut buf = [0u8; 700];
+        run_lossy(&mut a, &mut b, 7, |a, b, now| {
+            if a.is_established() && written < data.len() {
+                written += a.write(&data[written..], now);
+                if written == data.len() {
+                    a.shutdown(now);
+                }
+            }
+            // read slower than the sender writes, so the window closes now and then
+            let len = b.read(&mut buf, now);
+            got.extend_from_slice(&buf[..len]);
+            b.at_eof() && a.all_acked()
+        });
+        assert!(got == data);
+        assert!(a.error().is_none() && b.error().is_none());
+    }
+
+    #[test]
+    fn window_scaling_fills_long_fat_pipe() {
+        const LEN: usize = 2 << 20;
+        let big = Options { buffer_len: 1 << 20, ..Options::default() };
+        // a 100 ms round trip, with `big`'s buffers on both ends
+        let transfer = |a_options: Options, b_options: Options| {
+            let (mut a, mut b) = established_with(a_options, b_options, Instant::now());
+            let (mut written, mut received, mut most_in_flight) = (0, 0, 0);
+            let mut buf = vec![0x5a; big.buffer_len];
+            let elapsed = run_delayed(&mut a, &mut b, Duration::from_millis(50), |a, b| {
+                while written < LEN && a.send_space() > 0 {
+                    written += a.write(&buf[..(LEN - written).min(a.send_space())], Instant::now());
+                }
+                most_in_flight = most_in_flight.max(diff(a.snd_max, a.snd_una) as usize);
+                received += b.read(&mut buf, Instant::now());
+                received == LEN
+            });
+            (a, b, elapsed, most_in_flight)
+        };
+
+        let (a, b, scaled, most_in_flight) = transfer(big, big);
+        assert_eq!((a.rcv_scale, a.snd_scale, b.rcv_scale, b.snd_scale), (5, 5, 5, 5));
+        assert!(most_in_flight > DEFAULT_BUFFER_LEN * 4, "at most {} bytes in flight", most_in_flight);
+
+        // without scaling on either end, the 16-bit window caps a round trip at 64 KiB
+        let no_scaling = Options { window_scaling: false, ..big };
+        for (a_options, b_options) in [(no_scaling, big), (big, no_scaling)] {
+            let (a, b, unscaled, most_in_flight) = transfer(a_options, b_options);
+            assert_eq!((a.rcv_scale, a.snd_scale, b.rcv_scale, b.snd_scale), (0, 0, 0, 0));
+            assert!(most_in_flight <= DEFAULT_BUFFER_LEN, "{} bytes in flight", most_in_flight);
+            assert!(unscaled > scaled * 4, "{:?} unscaled against {:?} scaled", unscaled, scaled);
+        }
+    }
+
+    #[test]
+    fn timer_backs_off_then_gives_up() {
+        let start = Instant::now();
+        let mut a = Tcb::connect(5, Options::default(), start);
+        let mut syns = vec![start];
+        a.take_output();
+        let mut now = start;
+        while a.error().is_none() {
+            now = a.timeout().unwrap();
+            a.on_timeout(now);
+            if !a.take_output().is_empty() {
+                syns.push(now);
+            }
+        }
+        assert_eq!(syns.len(), MAX_RETRIES as usize + 1);
+        assert_eq!((syns[1] - syns[0], syns[2] - syns[1]), (RTO_INITIAL, RTO_INITIAL * 2));
+        assert_eq!(a.error().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert!(a.timeout().is_none() && now - start < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn nagle_coalesces_small_writes() {
+        for nodelay in [false, true] {
+            let now = Instant::now();
+            let (mut a, mut b) = established(now);
+            a.set_nodelay(nodelay, now);
+            b.set_ack_delay(None, now);
+            let before = a.stats.segments_sent;
+            for byte in 0..100u8 {
+                assert_eq!(a.write(&[byte], now), 1);
+            }
+            let first = a.take_output();
+            for seg in first.iter().cloned() {
+                b.input(seg, now);
+            }
+            for seg in b.take_output() {
+                a.input(seg, now);
+            }
+            // the ACK of the first byte lets the other 99 out in one segment
+            let rest = a.take_output();
+            if nodelay {
+                assert_eq!((first.len(), rest.len(), a.stats.coalesced), (100, 0, 0));
+            } else {
+                assert_eq!((first.len(), rest.len(), a.stats.coalesced), (1, 1, 98));
+                assert_eq!(rest[0].payload.len(), 99);
+            }
+            assert_eq!(a.stats.segments_sent - before, (first.len() + rest.len()) as u64);
+        }
+    }
+
+    #[test]
+    fn delayed_ack_every_other_segment() {
+        let now = Instant::now();
+        let (mut a, mut b) = established(now);
+        a.set_nodelay(true, now);
+        a.write(b"one", now);
+        for seg in a.take_output() {
+            b.input(seg, now);
+        }
+        assert!(b.take_output().is_empty());
+        assert_eq!(b.timeout(), Some(now + DEFAULT_ACK_DELAY));
+        // the second segment is acknowledged along with the first
+        a.write(b"two", now);
+        for seg in a.take_output() {
+            b.input(seg, now);
+        }
+        let acks = b.take_output();
+        assert_eq!((acks.len(), acks[0].ack), (1, a.snd_nxt));
+        assert!(b.timeout().is_none());
+        // a lone segment's ACK goes out when the delay is up
+        a.write(b"three", now);
+        for seg in a.take_output() {
+            b.input(seg, now);
+        }
+        b.on_timeout(now + DEFAULT_ACK_DELAY);
+        assert_eq!(b.take_output()[0].ack, a.snd_nxt);
+        b.set_ack_delay(None, now);
+        a.write(b"four", now);
+        for seg in a.take_output() {
+            b.input(seg, now);
+        }
+        assert_eq!(b.take_output().len(), 1);
+    }
+
+    #[test]
+    fn nagle_waits_for_delayed_ack() {
+        // write, write, then wait for both: how long until the receiver has the second?
+        let second_arrives = |nodelay: bool, ack_delay: Option<Duration>| {
+            let start = Instant::now();
+            let (mut a, mut b) = established(start);
+            a.set_nodelay(nodelay, start);
+            b.set_ack_delay(ack_delay, start);
+            a.write(b"header", start);
+            a.write(b"body", start);
+            let mut got = Vec::new();
+            let mut buf = [0u8; 16];
+            run_lossy(&mut a, &mut b, usize::MAX, |_a, b, now| {
+                let len = b.read(&mut buf, now);
+                got.extend_from_slice(&buf[..len]);
+                got.len() == 10
+            })
+        };
+        // the delay stays under RTO_MIN, or the retransmission timer would end the wait instead
+        let delay = DEFAULT_ACK_DELAY;
+        assert!(second_arrives(false, Some(delay)) >= delay);
+        assert!(second_arrives(true, Some(delay)) < Duration::from_millis(10));
+        assert!(second_arrives(false, None) < Duration::from_millis(10));
+    }
+
+    #[test]
+    fn keepalive_finds_half_open_peer() {
+        let start = Instant::now();
+        let (mut a, mut b) = established(start);
+        let idle = Duration::from_secs(10);
+        a.set_keepalive(Some(idle), start);
+        assert_eq!(a.timeout(), Some(start + idle));
+        a.on_timeout(start + idle);
+        let probe = a.take_output().remove(0);
+        assert_eq!((probe.seq, probe.payload.len()), (a.snd_nxt.wrapping_sub(1), 0));
+        b.input(probe, start + idle);
+        let ack = b.take_output().remove(0);
+        a.input(ack, start + idle);
+        assert_eq!((a.timeout(), a.probes), (Some(start + idle * 2), 0));
+        // b is gone: four probes, then a gives up
+        let mut probes = 0;
+        while a.error().is_none() {
+            let now = a.timeout().unwrap();
+            a.on_timeout(now);
+            probes += a.take_output().len();
+        }
+        assert_eq!(probes, KEEPALIVE_PROBES as usize);
+        assert_eq!(a.error().unwrap().kind(), io::ErrorKind::TimedOut);
+        // and answers what b still sends with RST
+        b.write(b"late", start);
+        a.input(b.take_output().remove(0), start);
+        let reset = a.take_output().remove(0);
+        b.input(reset, start);
+        assert_eq!(b.error().unwrap().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn observer_sees_timers_states_and_window() {
+        let start = Instant::now();
+        let zero = Duration::from_millis(0);
+        let mut a = Tcb::connect(1000, Options::default(), start);
+        a.observe(start);
+        let syn = a.take_output().remove(0);
+        assert_eq!(a.take_events(), vec![
+            (zero, Event::State { from: State::Closed, to: State::SynSent }),
+            (zero, Event::TimerStarted { timer: Timer::Retransmission, expires: RTO_INITIAL }),
+            (zero, Event::Sent((&syn).into())),
+        ]);
+        // the SYN is lost and sent again
+        let rto = RTO_INITIAL;
+        a.on_timeout(start + rto);
+        assert_eq!(a.take_events(), vec![
+            (rto, Event::TimerExpired(Timer::Retransmission)),
+            (rto, Event::TimerStarted { timer: Timer::Retransmission, expires: rto * 3 }),
+            (rto, Event::Sent((&syn).into())),
+        ]);
+        let syn = a.take_output().remove(0);
+        let mut b = Tcb::accept(&syn, 5000, Options::default(), start + rto);
+        let answered = rto + Duration::from_millis(10);
+        let syn_ack = b.take_output().remove(0);
+        a.input(syn_ack.clone(), start + answered);
+        let ack = Summary { flags: ACK, seq: 1001, ack: 5001, window: u16::MAX, len: 0 };
+        assert_eq!(a.take_events(), vec![
+            (answered, Event::Received((&syn_ack).into())),
+            (answered, Event::State { from: State::SynSent, to: State::Established }),
+            (answered, Event::TimerStopped(Timer::Retransmission)),
+            (answered, Event::Window { from: 0, to: u32::from(u16::MAX) }),
+            (answered, Event::Sent(ack)),
+        ]);
+        assert_eq!(Summary::from(&a.take_output()[0]), ack);
+        assert!(b.take_events().is_empty());
+    }
+
+    #[test]
+    fn trace_renders_sequence_diagram() {
+        let start = Instant::now();
+        let mut observer = Trace::new("client", "server");
+        let trace = observer.clone();
+        let mut a = Tcb::connect(1000, Options::default(), start);
+        a.observe(start);
+        let mut b = Tcb::accept(&a.take_output()[0], 5000, Options::default(), start);
+        let mut at = start + Duration::from_millis(10);
+        for seg in b.take_output() {
+            a.input(seg, at);
+        }
+        b.input(a.take_output().remove(0), at);
+        a.write(b"hi", at);
+        at += Duration::from_millis(10);
+        b.input(a.take_output().remove(0), at);
+        // b's ACK is lost, so a sends the data again
+        a.on_timeout(at + RTO_INITIAL);
+        for (at, event) in a.take_events() {
+            observer.on_event(at, &event);
+        }
+        assert_eq!(trace.to_text(), concat!(
+            "           client                                           server\n",
+            "   0.000s  |  SynSent                                       |\n",
+            "   0.000s  |-- SYN seq=1000 win=65535 --------------------->|\n",
+            "   0.010s  |<- SYN,ACK seq=5000 ack=1001 win=65535 ---------|\n",
+            "   0.010s  |  Established                                   |\n",
+            "   0.010s  |-- ACK seq=1001 ack=5001 win=65535 ------------>|\n",
+            "   0.010s  |-- ACK seq=1001 ack=5001 win=65535 len=2 ------>|\n",
+            "   0.320s  |  Retransmission timeout                        |\n",
+            "   0.320s  |-- ACK seq=1001 ack=5001 win=65535 len=2 ------>|\n",
+        ));
+        assert_eq!(trace.to_mermaid(), concat!(
+            "sequenceDiagram\n",
+            "    participant client\n",
+            "    participant server\n",
+            "    Note over client: 0.000s SynSent\n",
+            "    client->>server: 0.000s SYN seq=1000 win=65535\n",
+            "    server->>client: 0.010s SYN,ACK seq=5000 ack=1001 win=65535\n",
+            "    Note over client: 0.010s Established\n",
+            "    client->>server: 0.010s ACK seq=1001 ack=5001 win=65535\n",
+            "    client->>server: 0.010s ACK seq=1001 ack=5001 win=65535 len=2\n",
+            "    Note over client: 0.320s Retransmission timeout\n",
+            "    client->>server: 0.320s ACK seq=1001 ack=5001 win=65535 len=2\n",
+        ));
+    }
+
+    struct Observed(mpsc::Sender<(bool, Event)>);
+
+    impl Factory for Observed {
+        type Handler = fn(&mut Stream) -> io::Result<()>;
+
+        fn connection_made(&mut self) -> Self::Handler {
+            |stream| stream.read_to_end(&mut Vec::new()).map(drop)
+        }
+
+        fn observer(&mut self, _shake: &Handshake) -> Option<Box<dyn Observer + Send>> {
+            let tx = self.0.clone();
+            Some(Box::new(move |_at: Duration, event: &Event| tx.send((true, *event)).unwrap()))
+        }
+    }
+
+    #[test]
+    fn observers_see_both_ends() -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let server = listen_spawned("127.0.0.1:0", Observed(tx.clone()))?;
+        let mut stream = Connector::new()
+            .observer(move |_at: Duration, event: &Event| tx.send((false, *event)).unwrap())
+            .connect(server.local_addrs()[0])?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)));
+        stream.shutdown()?;
+        stream.read_to_end(&mut Vec::new())?;
+        stream.close()?;
+        drop(stream);
+        server.stop()?;
+        let events: Vec<_> = rx.try_iter().collect();
+        let states = |server| events.iter().filter_map(|&(at_server, event)| match event {
+            Event::State { to, .. } if at_server == server => Some(to),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(states(false)[..2], [State::SynSent, State::Established]);
+        assert_eq!(states(true)[..2], [State::SynReceived, State::Established]);
+        Ok(())
+    }
+
+    struct Resets(mpsc::Sender<&'static str>);
+
+    impl Handler for Resets {
+        fn on_stream(&mut self, stream: &mut Stream) -> io::Result<()> {
+            stream.read(&mut [0u8; 16]).map(drop)
+        }
+
+        fn on_error(&mut self, _err: io::Error) {
+            self.0.send("error").unwrap();
+        }
+
+        fn on_reset(&mut self) {
+            self.0.send("reset").unwrap();
+        }
+    }
+
+    #[test]
+    fn reset_reaches_handler() -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let server = listen_spawned("127.0.0.1:0", move || Resets(tx.clone()))?;
+        let addr = server.local_addrs()[0];
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let send = |seg: Segment| -> io::Result<()> {
+            let mut datagram = Vec::new();
+            seg.encode(&mut datagram);
+            client.send_to(&datagram, addr).map(drop)
+        };
+        let recv = || -> io::Result<Segment> {
+            let mut buf = [0u8; MAX_DATAGRAM_LEN];
+            let (len, _) = client.recv_from(&mut buf)?;
+            Segment::decode(&buf[..len])
+        };
+        // an ACK for a connection the server never had
+        send(Segment { flags: ACK, seq: 3, ack: 99, window: u16::MAX, ..Segment::default() })?;
+        assert_eq!(recv()?, Segment { flags: RST, seq: 99, ..Segment::default() });
+        send(Segment { flags: SYN, seq: 7, window: u16::MAX, ..Segment::default() })?;
+        let syn_ack = recv()?;
+        let ack = syn_ack.seq.wrapping_add(1);
+        send(Segment { flags: ACK, seq: 8, ack, window: u16::MAX, ..Segment::default() })?;
+        send(Segment { flags: RST, seq: 8, ..Segment::default() })?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), "reset");
+        server.stop()
+    }
+
+    struct Refusing;
+
+    impl Factory for Refusing {
+        type Handler = fn(&mut Stream) -> io::Result<()>;
+
+        fn accept(&mut self, _shake: &Handshake) -> bool {
+            false
+        }
+
+        fn connection_made(&mut self) -> Self::Handler {
+            |_stream| Ok(())
+        }
+    }
+
+    #[test]
+    fn listen_and_connect() -> io::Result<()> {
+        let upper = || |stream: &mut Stream| -> io::Result<()> {
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request)?;
+            stream.write_all(&request.to_ascii_uppercase())
+        };
+        let server = listen_spawned("127.0.0.1:0", upper)?;
+        let addr = server.local_addrs()[0];
+        let mut clients = Vec::new();
+        for i in 0..3 {
+            clients.push(thread::spawn(move || -> io::Result<Vec<u8>> {
+                let mut stream = connect(addr)?;
+                stream.set_read_timeout(Some(Duration::from_secs(5)));
+                let request = vec![b'a' + i; 100_000];
+                stream.write_all(&request)?;
+                stream.shutdown()?;
+                let mut response = Vec::new();
+                stream.read_to_end(&mut response)?;
+                stream.close()?;
+                Ok(response)
+            }));
+        }
+        for (i, client) in clients.into_iter().enumerate() {
+            assert!(client.join().unwrap()? == vec![b'A' + i as u8; 100_000]);
+        }
+        server.stop()?;
+        let refusing = listen_spawned("127.0.0.1:0", Refusing)?;
+        let err = connect(refusing.local_addrs()[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        refusing.stop()
+    }
+
+    #[test]
+    fn corrupt_segments_are_dropped_and_resent() -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let upper = move || {
+            let tx = tx.clone();
+            move |stream: &mut Stream| -> io::Result<()> {
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request)?;
+                stream.write_all(&request.to_ascii_uppercase())?;
+                stream.flush()?;
+                tx.send(stream.stats()).unwrap();
+                Ok(())
+            }
+        };
+        // enough to corrupt some of both sides' segments, not so much that one is corrupted
+        // (or its ACK is) so many times running that the backoff outlasts the read timeout
+        let server = LajiSimTcp::new(upper).corrupt(0.1).bind("127.0.0.1:0")?.run_detached()?;
+        let mut stream = Connector::new().corrupt(0.1).connect(server.local_addrs()[0])?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)));
+        stream.write_all(&[b'x'; 50_000])?;
+        stream.shutdown()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        assert!(response == [b'X'; 50_000]);
+        let (client, server_stats) = (stream.stats(), rx.recv().unwrap());
+        assert!(client.corrupted > 0 && server_stats.corrupted > 0);
+        assert!(client.bad_checksums > 0 && server_stats.bad_checksums > 0);
+        drop(stream);
+        server.stop()
+    }
+
+    #[test]
+    fn oversize_datagrams_on_a_small_path_mtu() -> io::Result<()> {
+        let echo = || |stream: &mut Stream| -> io::Result<()> {
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request)?;
+            stream.write_all(&request)
+        };
+        let server = listen_spawned("127.0.0.1:0", echo)?;
+        for oversize in [Oversize::Fragment, Oversize::NeedsFrag] {
+            let mut stream = Connector::new().path_mtu(576, oversize).connect(server.local_addrs()[0])?;
+            stream.set_read_timeout(Some(Duration::from_secs(10)));
+            stream.write_all(&[b'x'; 10_000])?;
+            stream.shutdown()?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response)?;
+            assert!(response == [b'x'; 10_000]);
+            let stats = stream.stats();
+            match oversize {
+                Oversize::Fragment => assert!(stats.fragmented > 0 && stream.mss() == MSS),
+                _ => assert!(stats.needs_frag > 0 && stream.mss() == 576 - HEADER_LEN),
+            }
+        }
+        // the handshake gets through the blackhole, full segments never do
+        let mut stream = Connector::new().path_mtu(576, Oversize::Blackhole).connect(server.local_addrs()[0])?;
+        stream.set_read_timeout(Some(Duration::from_millis(500)));
+        stream.write_all(&[b'x'; 10_000])?;
+        assert_eq!(stream.flush().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(stream.stats().blackholed > 0 && stream.mss() == MSS);
+        drop(stream);
+        assert!(Connector::new().path_mtu(60, Oversize::Fragment).connect(server.local_addrs()[0]).is_err());
+        assert!(Connector::new().mss(MSS + 1).connect(server.local_addrs()[0]).is_err());
+        server.stop()
+    }
+
+    #[test]
+    fn half_open_limit() -> io::Result<()> {
+        let server = LajiSimTcp::new(|| |_stream: &mut Stream| Ok(()))
+            .bind("127.0.0.1:0")?.max_half_open(1).run_detached()?;
+        let addr = server.local_addrs()[0];
+        let syn = |client: &UdpSocket| -> io::Result<Segment> {
+            let mut datagram = Vec::new();
+            Segment { flags: SYN, seq: 7, window: u16::MAX, ..Segment::default() }.encode(&mut datagram);
+            client.set_read_timeout(Some(Duration::from_secs(2)))?;
+            client.send_to(&datagram, addr)?;
+            let mut buf = [0u8; MAX_DATAGRAM_LEN];
+            let (len, _) = client.recv_from(&mut buf)?;
+            Segment::decode(&buf[..len])
+        };
+        let first = UdpSocket::bind("127.0.0.1:0")?;
+        assert_eq!(syn(&first)?.flags, SYN | ACK);
+        let second = UdpSocket::bind("127.0.0.1:0")?;
+        let reset = syn(&second)?;
+        assert_eq!((reset.flags, reset.ack), (RST | ACK, 8));
+        let err = LajiSimTcp::new(|| |_stream: &mut Stream| Ok(()))
+            .bind("127.0.0.1:0")?.max_connections(0).run().unwrap_err();
+        assert_eq!(err.to_string(), "`max_connections` must not be zero");
+        server.stop()
+    }
+}
@@ -0,0 +1,265 @@
+//! Dialing out through a SOCKS5 proxy (RFC 1928), with username and password
+//! authentication (RFC 1929) if the proxy wants it.
+//!
+//! The TCP clients take a `Proxy` with their `proxy` setting. Clients given a host name pass
+//! it to the proxy to resolve, so names that only resolve on the far side still work;
+//! clients given socket addresses resolve them locally and pass the address.
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use crate::{resolve, wire::invalid_data};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Where the proxy should connect to.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Destination<'a> {
+    Addr(SocketAddr),
+    /// A host name, resolved by the proxy.
+    Domain(&'a str, u16),
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Proxy {
+    addr: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    #[inline]
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, auth: None }
+    }
+
+    /// Log in with `user` and `password`, each at most 255 bytes, if the proxy asks.
+    #[inline]
+    pub fn auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    #[inline]
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Connect to `host`, which the proxy resolves unless it is an IP address already.
+    #[inline]
+    pub fn connect(&self, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.connect_to(Destination::Addr((ip, port).into()), timeout),
+            Err(_) => self.connect_to(Destination::Domain(host, port), timeout),
+        }
+    }
+
+    /// Reach the proxy within `timeout`, then give its handshake as long again.
+    pub fn connect_to(&self, dest: Destination, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = resolve::connect_happy_to(self.addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        self.handshake(&mut stream, &dest).map_err(resolve::timed_out)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, dest: &Destination) -> io::Result<()> {
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[METHOD_NONE, METHOD_PASSWORD],
+            None => &[METHOD_NONE],
+        };
+        let mut hello = vec![VERSION, methods.len() as u8];
+        hello.extend_from_slice(methods);
+        stream.write_all(&hello)?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice)?;
+        if choice[0] != VERSION {
+            return Err(invalid_data("not a socks5 proxy"));
+        }
+        match (choice[1], &self.auth) {
+            (METHOD_NONE, _) => {}
+            (METHOD_PASSWORD, Some((user, password))) => login(stream, user, password)?,
+            (METHOD_UNACCEPTABLE, _) => return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                "socks5 proxy accepts none of our authentication methods")),
+            _ => return Err(invalid_data("socks5 proxy chose a method we did not offer")),
+        }
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        let port = match *dest {
+            Destination::Addr(addr) => {
+                match addr.ip() {
+                    IpAddr::V4(ip) => { request.push(ATYP_IPV4); request.extend_from_slice(&ip.octets()); }
+                    IpAddr::V6(ip) => { request.push(ATYP_IPV6); request.extend_from_slice(&ip.octets()); }
+                }
+                addr.port()
+            }
+            Destination::Domain(host, port) => {
+                if host.is_empty() || host.len() > 255 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "host name must be 1 to 255 bytes"));
+                }
+                request.extend_from_slice(&[ATYP_DOMAIN, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+                port
+            }
+        };
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(invalid_data("not a socks5 reply"));
+        }
+        if reply[1] != 0 {
+            return Err(reply_error(reply[1]));
+        }
+        // the address the proxy connected from, which nobody needs
+        let bound_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                usize::from(len[0])
+            }
+            _ => return Err(invalid_data("socks5 reply has an unknown address type")),
+        };
+        stream.read_exact(&mut vec![0u8; bound_len + 2])
+    }
+}
+
+fn login(stream: &mut TcpStream, user: &str, password: &str) -> io::Result<()> {
+    if user.len() > 255 || password.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "socks5 user and password must be at most 255 bytes"));
+    }
+    let mut request = vec![AUTH_VERSION, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request)?;
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status)?;
+    match status[1] {
+        0 => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "socks5 proxy rejected the login")),
+    }
+}
+
+fn reply_error(code: u8) -> io::Error {
+    let (kind, msg) = match code {
+        2 => (io::ErrorKind::PermissionDenied, "socks5 proxy does not allow this connection"),
+        3 => (io::ErrorKind::Other, "socks5 proxy: network unreachable"),
+        4 => (io::ErrorKind::Other, "socks5 proxy: host unreachable"),
+        5 => (io::ErrorKind::ConnectionRefused, "socks5 proxy: connection refused"),
+        6 => (io::ErrorKind::TimedOut, "socks5 proxy: ttl expired"),
+        7 | 8 => (io::ErrorKind::InvalidInput, "socks5 proxy does not support the request"),
+        _ => (io::ErrorKind::Other, "socks5 proxy failed"),
+    };
+    io::Error::new(kind, msg)
+}
+
+/// Connect to `host` directly, racing its addresses as `resolve::connect_happy` does, or
+/// through `proxy`; how the clients dial.
+pub fn connect(proxy: Option<&Proxy>, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(host, port, timeout),
+        None => resolve::connect_happy(host, port, timeout),
+    }
+}
+
+/// `connect` for anything that resolves to socket addresses; through a proxy each address
+/// is asked for in turn until the proxy reaches one.
+pub fn connect_to<A>(proxy: Option<&Proxy>, addr: A, timeout: Duration) -> io::Result<TcpStream>
+where A: ToSocketAddrs
+{
+    match proxy {
+        Some(proxy) => resolve::try_each(addr, |addr| proxy.connect_to(Destination::Addr(addr), timeout)),
+        None => resolve::connect_happy_to(addr, timeout),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// A proxy answering one connection: it checks the login if `auth` is given, records the
+    /// destination, then plays `serve` as the far end.
+    pub(crate) fn fake_proxy<F>(auth: Option<(&'static str, &'static str)>, serve: F) -> io::Result<(Proxy, thread::JoinHandle<io::Result<Vec<u8>>>)>
+    where F: FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut proxy = Proxy::new(listener.local_addr()?);
+        if let Some((user, password)) = auth {
+            proxy = proxy.auth(user, password);
+        }
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept()?;
+            let mut hello = [0u8; 2];
+            stream.read_exact(&mut hello)?;
+            stream.read_exact(&mut vec![0u8; usize::from(hello[1])])?;
+            match auth {
+                Some((user, password)) => {
+                    stream.write_all(&[VERSION, METHOD_PASSWORD])?;
+                    let mut login = [0u8; 2];
+                    stream.read_exact(&mut login)?;
+                    let mut given = vec![0u8; usize::from(login[1])];
+                    stream.read_exact(&mut given)?;
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len)?;
+                    let mut given_password = vec![0u8; usize::from(len[0])];
+                    stream.read_exact(&mut given_password)?;
+                    let ok = given == user.as_bytes() && given_password == password.as_bytes();
+                    stream.write_all(&[AUTH_VERSION, if ok { 0 } else { 1 }])?;
+                    if !ok {
+                        return Ok(Vec::new());
+                    }
+                }
+                None => stream.write_all(&[VERSION, METHOD_NONE])?,
+            }
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request)?;
+            let rest = match request[3] {
+                ATYP_IPV4 => 3 + 2,
+                ATYP_IPV6 => 15 + 2,
+                _ => usize::from(request[4]) + 2,
+            };
+            let mut dest = request[3..].to_vec();
+            let mut tail = vec![0u8; rest];
+            stream.read_exact(&mut tail)?;
+            dest.extend_from_slice(&tail);
+            stream.write_all(&[VERSION, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0x1f, 0x90])?;
+            serve(&mut stream)?;
+            Ok(dest)
+        });
+        Ok((proxy, handle))
+    }
+
+    #[test]
+    fn connects_through_proxy() -> io::Result<()> {
+        let (proxy, handle) = fake_proxy(Some(("laji", "hunter2")), |stream| stream.write_all(b"hi"))?;
+        let mut stream = proxy.connect("far.example", 13, Duration::from_secs(2))?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        assert_eq!(reply, "hi");
+        assert_eq!(handle.join().unwrap()?, b"\x03\x0bfar.example\x00\x0d");
+
+        let (proxy, handle) = fake_proxy(None, |_stream| Ok(()))?;
+        proxy.connect("192.0.2.7", 70, Duration::from_secs(2))?;
+        assert_eq!(handle.join().unwrap()?, [ATYP_IPV4, 192, 0, 2, 7, 0, 70]);
+
+        let (proxy, _handle) = fake_proxy(Some(("laji", "hunter2")), |_stream| Ok(()))?;
+        let err = proxy.auth("laji", "wrong").connect("far.example", 13, Duration::from_secs(2)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(reply_error(5).kind(), io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+//! DNS SRV lookups (RFC 2782), for clients given a service name such as
+//! `_minecraft._tcp.example.org` instead of a host and port.
+//!
+//! The standard library resolves only addresses, so `Resolver` asks a name server itself,
+//! by default the first one in `/etc/resolv.conf`, over UDP and again over TCP if the answer
+//! was truncated. `order` sorts the targets the way RFC 2782 says to try them: lowest
+//! priority first, and within a priority a weighted random shuffle.
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use crate::{random, resolve, wire::invalid_data};
+
+pub const DNS_PORT: u16 = 53;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const HEADER_LEN: usize = 12;
+const MAX_UDP_LEN: usize = 512;
+// compression pointers may chain, but not this often in any sane message
+const MAX_POINTERS: usize = 32;
+
+/// Look `name` up with the system's name server and default settings, in the order to try.
+pub fn lookup(name: &str) -> io::Result<Vec<Target>> {
+    Resolver::system()?.lookup(name).map(order)
+}
+
+/// Connect to the first of `name`'s targets, tried in RFC 2782 order, that accepts.
+pub fn connect(name: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for target in lookup(name)? {
+        match resolve::connect_happy(target.host(), target.port(), timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no srv targets")))
+}
+
+/// One SRV record.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Target {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    host: String,
+}
+
+impl Target {
+    #[inline]
+    pub fn new(priority: u16, weight: u16, port: u16, host: &str) -> Self {
+        Self { priority, weight, port, host: host.to_string() }
+    }
+
+    /// Lower is tried first.
+    #[inline]
+    pub fn priority(&self) -> u16 {
+        self.priority
+    }
+
+    /// Among targets of one priority, how often this one should come first.
+    #[inline]
+    pub fn weight(&self) -> u16 {
+        self.weight
+    }
+
+    #[inline]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The host name, without the trailing dot.
+    #[inline]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+/// Sort `targets` into the order to try them in: by priority, and within one priority by a
+/// weighted draw, so a target of weight 20 comes first twice as often as one of weight 10.
+/// Zero-weight targets are drawn only rarely ahead of weighted ones.
+pub fn order(mut targets: Vec<Target>) -> Vec<Target> {
+    targets.sort_by_key(|target| (target.priority, target.weight));
+    let mut ans = Vec::with_capacity(targets.len());
+    while !targets.is_empty() {
+        let priority = targets[0].priority;
+        let end = targets.iter().position(|target| target.priority != priority).unwrap_or(targets.len());
+        let mut group: Vec<Target> = targets.drain(..end).collect();
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|target| u64::from(target.weight)).sum();
+            let pick = random::up_to(total);
+            let mut running = 0;
+            let index = group.iter().position(|target| {
+                running += u64::from(target.weight);
+                running >= pick
+            }).unwrap_or(0);
+            ans.push(group.remove(index));
+        }
+    }
+    ans
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Resolver {
+    nameserver: SocketAddr,
+    timeout: Duration,
+}
+
+impl Resolver {
+    #[inline]
+    pub fn new(nameserver: SocketAddr) -> Self {
+        Self { nameserver, timeout: Duration::from_secs(5) }
+    }
+
+    /// The first `nameserver` in `/etc/resolv.conf`.
+    pub fn system() -> io::Result<Self> {
+        let conf = fs::read_to_string("/etc/resolv.conf")?;
+        nameserver(&conf).map(Self::new)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+    }
+
+    /// How long the name server has to answer.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The SRV records of `name`, as the name server sent them. A name with no records, or
+    /// only the `.` record that says the service is not offered, is `NotFound`.
+    pub fn lookup(&self, name: &str) -> io::Result<Vec<Target>> {
+        let id: u16 = random::random();
+        let query = encode_query(id, name)?;
+        let socket = resolve::bind_ephemeral_for(self.nameserver)?;
+        socket.connect(self.nameserver)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.send(&query)?;
+        let mut buf = [0u8; MAX_UDP_LEN];
+        let response = loop {
+            let len = socket.recv(&mut buf).map_err(resolve::timed_out)?;
+            // anything else is a stray answer to some earlier query
+            if len >= 2 && buf[..2] == id.to_be_bytes() {
+                break buf[..len].to_vec();
+            }
+        };
+        let response = match decode_response(id, &response)? {
+            Some(targets) => return Ok(targets),
+            None => self.lookup_tcp(&query)?,
+        };
+        decode_response(id, &response)?.ok_or_else(|| invalid_data("dns response truncated over tcp"))
+    }
+
+    fn lookup_tcp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = resolve::connect_happy_to(self.nameserver, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut out = (query.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(query);
+        stream.write_all(&out)?;
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).map_err(resolve::timed_out)?;
+        let mut response = vec![0u8; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut response).map_err(resolve::timed_out)?;
+        Ok(response)
+    }
+}
+
+fn nameserver(conf: &str) -> Option<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => Some(addr),
+                _ => None,
+            }
+        })
+        .filter_map(|addr| (addr, DNS_PORT).to_socket_addrs().ok()?.next())
+        .next()
+}
+
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    out.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    out.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad label in srv name"));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(out)
+}
+
+/// The targets, or `None` if the response was truncated and should be asked for over TCP.
+fn decode_response(id: u16, msg: &[u8]) -> io::Result<Option<Vec<Target>>> {
+    if msg.len() < HEADER_LEN || msg[..2] != id.to_be_bytes() || msg[2] & 0x80 == 0 {
+        return Err(invalid_data("not a response to the srv query"));
+    }
+    if msg[2] & 0x02 != 0 {
+        return Ok(None);
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "no such srv name")),
+        _ => return Err(io::Error::other("name server failed the srv query")),
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut targets = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let fixed = msg.get(pos..pos + 10).ok_or_else(|| invalid_data("dns record cut short"))?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let data = pos + 10;
+        pos = data + len;
+        if pos > msg.len() {
+            return Err(invalid_data("dns record cut short"));
+        }
+        // CNAMEs the server followed come along too
+        if kind != TYPE_SRV || len < 7 {
+            continue;
+        }
+        let field = |i: usize| u16::from_be_bytes([msg[data + i], msg[data + i + 1]]);
+        let (host, _) = read_name(msg, data + 6)?;
+        if host.is_empty() {
+            continue;
+        }
+        targets.push(Target { priority: field(0), weight: field(2), port: field(4), host });
+    }
+    if targets.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no srv records"));
+    }
+    Ok(Some(targets))
+}
+
+/// The dotted name at `pos`, following compression pointers, and where the bytes after it
+/// start.
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *msg.get(pos).ok_or_else(|| invalid_data("dns name cut short"))?;
+            match len {
+                0 => return Ok((name, end.unwrap_or(pos + 1))),
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *msg.get(pos + 1).ok_or_else(|| invalid_data("dns name cut short"))?;
+                    end.get_or_insert(pos + 2);
+                    pos = usize::from(len & 0x3f) << 8 | usize::from(low);
+                    break;
+                }
+                len => {
+                    let label = msg.get(pos + 1..pos + 1 + usize::from(len))
+                        .ok_or_else(|| invalid_data("dns name cut short"))?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.push_str(&String::from_utf8_lossy(label));
+                    pos += 1 + usize::from(len);
+                }
+            }
+        }
+    }
+    Err(invalid_data("dns name has too many pointers"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::UdpSocket, thread};
+
+    // an answer to `encode_query(7, "_mc._tcp.example.org")` with one compressed and one
+    // uncompressed target, and the "." record of a service that is not offered
+    fn response(truncated: bool) -> Vec<u8> {
+        let mut msg = encode_query(7, "_mc._tcp.example.org").unwrap();
+        msg[2] = if truncated { 0x83 } else { 0x81 };
+        msg[3] = 0x80;
+        msg[7] = 3;
+        let record = |msg: &mut Vec<u8>, priority: u16, weight: u16, port: u16, target: &[u8]| {
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            for field in &[priority, weight, port] {
+                msg.extend_from_slice(&field.to_be_bytes());
+            }
+            msg.extend_from_slice(target);
+        };
+        // 0xc0 0x15 points at "example.org" in the question
+        record(&mut msg, 10, 5, 25565, &[2, b'm', b'c', 0xc0, 0x15]);
+        record(&mut msg, 20, 0, 25566, b"\x06backup\x03net\x00");
+        record(&mut msg, 30, 0, 0, b"\x00");
+        msg
+    }
+
+    #[test]
+    fn decodes_records() -> io::Result<()> {
+        let targets = decode_response(7, &response(false))?.unwrap();
+        assert_eq!(targets, [Target::new(10, 5, 25565, "mc.example.org"), Target::new(20, 0, 25566, "backup.net")]);
+        assert_eq!(decode_response(7, &response(true))?, None);
+        assert!(decode_response(8, &response(false)).is_err());
+        let mut nxdomain = response(false);
+        nxdomain[3] = 0x83;
+        assert_eq!(decode_response(7, &nxdomain).unwrap_err().kind(), io::ErrorKind::NotFound);
+        let mut looped = encode_query(7, "a").unwrap();
+        looped.truncate(HEADER_LEN);
+        looped.extend_from_slice(&[0xc0, 12]);
+        assert!(read_name(&looped, HEADER_LEN).is_err());
+        assert_eq!(nameserver("# local\nsearch lan\nnameserver 192.0.2.53\n"), Some("192.0.2.53:53".parse().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn orders_by_priority_then_weight() {
+        let targets = vec![
+            Target::new(20, 0, 3, "c"),
+            Target::new(10, 0, 2, "b"),
+            Target::new(10, 60000, 1, "a"),
+        ];
+        let mut first_a = 0;
+        for _ in 0..100 {
+            let ordered = order(targets.clone());
+            assert_eq!(ordered[2].host(), "c");
+            first_a += (ordered[0].host() == "a") as u32;
+        }
+        assert!(first_a > 95, "{}", first_a);
+    }
+
+    #[test]
+    fn looks_up_over_udp_and_tcp() -> io::Result<()> {
+        let udp = UdpSocket::bind("127.0.0.1:0")?;
+        let addr = udp.local_addr()?;
+        let tcp = std::net::TcpListener::bind(addr)?;
+        thread::spawn(move || -> io::Result<()> {
+            let mut buf = [0u8; MAX_UDP_LEN];
+            let (len, client) = udp.recv_from(&mut buf)?;
+            let mut answer = response(true);
+            answer[..2].copy_from_slice(&buf[..2]);
+            assert_eq!(&buf[2..len], &encode_query(0, "_mc._tcp.example.org").unwrap()[2..]);
+            udp.send_to(&answer, client)?;
+            let (mut stream, _) = tcp.accept()?;
+            let mut query = vec![0u8; 2 + len];
+            stream.read_exact(&mut query)?;
+            let mut answer = response(false);
+            answer[..2].copy_from_slice(&query[2..4]);
+            stream.write_all(&(answer.len() as u16).to_be_bytes())?;
+            stream.write_all(&answer)
+        });
+        let targets = Resolver::new(addr).timeout(Duration::from_secs(2)).lookup("_mc._tcp.example.org.")?;
+        assert_eq!(targets.len(), 2);
+        let silent = UdpSocket::bind("127.0.0.1:0")?;
+        let err = Resolver::new(silent.local_addr()?).timeout(Duration::from_millis(50)).lookup("_mc._tcp.a").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+}
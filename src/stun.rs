@@ -0,0 +1,676 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+use smallvec::SmallVec;
+use crate::{ports, random::random, virtnet::Datagram, wire::{invalid_data, too_small}};
+
+pub fn listen<A, F, H>(addr: A, factory: F) -> io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FnMut() -> H,
+    F: 'static + Clone + Send,
+    H: Handler
+{
+    LajiStun::new(factory).bind(addr)?.run()
+}
+
+/// Ask a STUN server which address `socket` is seen from.
+pub fn query<S, A>(socket: &S, server: A) -> io::Result<SocketAddr>
+where
+    S: Datagram,
+    A: ToSocketAddrs
+{
+    let server = server.to_socket_addrs()?.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))?;
+    let request = Message::binding_request(random());
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    let len = request.encode(&mut buf)?;
+    let old_timeout = socket.read_timeout()?;
+    let ans = exchange(socket, server, &buf[..len], &request);
+    socket.set_read_timeout(old_timeout)?;
+    ans
+}
+
+/// Discover the reflexive address of a fresh ephemeral UDP socket.
+pub fn discover<A>(server: A) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs
+{
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    query(&socket, server)
+}
+
+fn exchange<S>(socket: &S, server: SocketAddr, request_buf: &[u8], request: &Message) -> io::Result<SocketAddr>
+where
+    S: Datagram
+{
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    let mut rto = INITIAL_RTO;
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send_to(request_buf, server)?;
+        let deadline = Instant::now() + rto;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(ans) => ans,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            };
+            if from != server {
+                continue;
+            }
+            let response = match Message::decode(&buf[..len]) {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+            if response.transaction_id() != request.transaction_id() {
+                continue;
+            }
+            if response.class() != Class::SuccessResponse {
+                return Err(io::Error::other("binding request failed"));
+            }
+            return response.mapped_address().ok_or_else(||
+                io::Error::new(io::ErrorKind::InvalidData, "binding response has no mapped address"));
+        }
+        rto *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no binding response"))
+}
+
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: usize = 3;
+
+pub struct LajiStun<F>
+where
+    F: Factory
+{
+    udp: SmallVec<[UdpSocket; 4]>,
+    factory: F,
+}
+
+impl<F> LajiStun<F>
+where
+    F: Factory
+{
+    #[inline]
+    pub fn new(factory: F) -> Self {
+        Self { udp: SmallVec::new(), factory }
+    }
+
+    #[inline]
+    pub fn bind<A>(mut self, addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs
+    {
+        self.udp.push(UdpSocket::bind(addr)?);
+        Ok(self)
+    }
+
+    /// Bind the well-known STUN port, 3478, on every IPv4 address.
+    #[inline]
+    pub fn bind_default_ipv4(self) -> io::Result<Self> {
+        self.bind((Ipv4Addr::UNSPECIFIED, ports::STUN))
+    }
+
+    /// Bind the well-known STUN port, 3478, on every IPv6 address.
+    #[inline]
+    pub fn bind_default_ipv6(self) -> io::Result<Self> {
+        self.bind((Ipv6Addr::UNSPECIFIED, ports::STUN))
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.udp.iter().map(UdpSocket::local_addr).collect()
+    }
+}
+
+impl<F> LajiStun<F>
+where
+    F: Factory + Clone + Send + 'static
+{
+    /// Serve every socket on a thread of its own. A datagram that cannot be received or
+    /// answered goes to `on_error`, and the socket goes on serving.
+    pub fn run(self) -> io::Result<()> {
+        let mut threads = Vec::with_capacity(self.udp.len());
+        for socket in self.udp {
+            let mut factory = self.factory.clone();
+            threads.push(thread::spawn(move || {
+                let mut buf = [0u8; MAX_MESSAGE_LEN];
+                loop {
+                    serve_one(&mut factory, &socket, &mut buf)
+                        .unwrap_or_else(|e| factory.on_error(e))
+                }
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+/// Answer binding requests arriving on `socket` from the calling thread, until an error.
+pub fn serve<S, F>(socket: &S, mut factory: F) -> io::Result<()>
+where
+    S: Datagram,
+    F: Factory
+{
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    loop {
+        serve_one(&mut factory, socket, &mut buf)?;
+    }
+}
+
+fn serve_one<S, F>(factory: &mut F, socket: &S, buf: &mut [u8]) -> io::Result<()>
+where
+    S: Datagram,
+    F: Factory
+{
+    let (len, origin) = socket.recv_from(buf)?;
+    // anything that is not a well-formed binding request is dropped, as RFC 5389 asks
+    let request = match Message::decode(&buf[..len]) {
+        Ok(request) => request,
+        Err(_) => return Ok(()),
+    };
+    if request.class() != Class::Request || request.method() != METHOD_BINDING {
+        return Ok(());
+    }
+    let mut handler = factory.connection_made();
+    handler.on_binding(origin, &request);
+    let response = Message::binding_response(request.transaction_id, origin);
+    let len = response.encode(buf)?;
+    socket.send_to(&buf[..len], origin)?;
+    Ok(())
+}
+
+pub trait Factory {
+    type Handler: Handler;
+
+    fn connection_made(&mut self) -> Self::Handler;
+
+    /// A request could not be received or answered, and the server carries on.
+    fn on_error(&mut self, _err: io::Error) {}
+}
+
+impl<F, H> Factory for F
+where
+    H: Handler,
+    F: FnMut() -> H
+{
+    type Handler = H;
+
+    #[inline]
+    fn connection_made(&mut self) -> H {
+        self()
+    }
+}
+
+pub trait Handler {
+    fn on_binding(&mut self, _origin: SocketAddr, _request: &Message) {}
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(SocketAddr)
+{
+    #[inline]
+    fn on_binding(&mut self, origin: SocketAddr, _request: &Message) {
+        self(origin)
+    }
+}
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub const METHOD_BINDING: u16 = 0x001;
+
+const HEADER_LEN: usize = 20;
+const MAX_MESSAGE_LEN: usize = 548;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_SOFTWARE: u16 = 0x8022;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+const SOFTWARE: &str = "laji-protocols";
+
+#[inline]
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Class {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+impl Class {
+    #[inline]
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0x000,
+            Class::Indication => 0x010,
+            Class::SuccessResponse => 0x100,
+            Class::ErrorResponse => 0x110,
+        }
+    }
+
+    #[inline]
+    fn from_type(message_type: u16) -> Self {
+        match message_type & 0x110 {
+            0x000 => Class::Request,
+            0x010 => Class::Indication,
+            0x100 => Class::SuccessResponse,
+            _ => Class::ErrorResponse,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    XorMappedAddress(SocketAddr),
+    Software(String),
+    ErrorCode { code: u16, reason: String },
+    Unknown { attr_type: u16, value: Vec<u8> },
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Message {
+    class: Class,
+    method: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<Attribute>,
+}
+
+impl Message {
+    #[inline]
+    pub fn new(class: Class, method: u16, transaction_id: [u8; 12]) -> Self {
+        Self { class, method, transaction_id, attributes: Vec::new() }
+    }
+
+    #[inline]
+    pub fn binding_request(transaction_id: [u8; 12]) -> Self {
+        Self::new(Class::Request, METHOD_BINDING, transaction_id)
+    }
+
+    #[inline]
+    pub fn binding_response(transaction_id: [u8; 12], mapped: SocketAddr) -> Self {
+        let mut ans = Self::new(Class::SuccessResponse, METHOD_BINDING, transaction_id);
+        ans.push(Attribute::XorMappedAddress(mapped));
+        ans.push(Attribute::Software(SOFTWARE.to_string()));
+        ans
+    }
+
+    #[inline]
+    pub fn push(&mut self, attribute: Attribute) {
+        self.attributes.push(attribute);
+    }
+
+    #[inline]
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    #[inline]
+    pub fn method(&self) -> u16 {
+        self.method
+    }
+
+    #[inline]
+    pub fn transaction_id(&self) -> &[u8; 12] {
+        &self.transaction_id
+    }
+
+    #[inline]
+    pub fn attributes(&self) -> &[Attribute] {
+        &self.attributes
+    }
+
+    /// The XOR-MAPPED-ADDRESS, or the legacy MAPPED-ADDRESS if that is all there is.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let mut legacy = None;
+        for attribute in &self.attributes {
+            match attribute {
+                Attribute::XorMappedAddress(addr) => return Some(*addr),
+                Attribute::MappedAddress(addr) => legacy = Some(*addr),
+                _ => {}
+            }
+        }
+        legacy
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            return Err(invalid_data("stun message too short"));
+        }
+        let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+        if message_type & 0xc000 != 0 {
+            return Err(invalid_data("not a stun message"));
+        }
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if !len.is_multiple_of(4) || HEADER_LEN + len != buf.len() {
+            return Err(invalid_data("stun message length mismatch"));
+        }
+        if u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) != MAGIC_COOKIE {
+            return Err(invalid_data("stun magic cookie mismatch"));
+        }
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..20]);
+        let method = (message_type & 0x000f) | ((message_type & 0x00e0) >> 1) | ((message_type & 0x3e00) >> 2);
+        let mut ans = Self::new(Class::from_type(message_type), method, transaction_id);
+        let mut offset = HEADER_LEN;
+        while offset < buf.len() {
+            if buf.len() - offset < 4 {
+                return Err(invalid_data("stun attribute header truncated"));
+            }
+            let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let value = buf.get(offset + 4..offset + 4 + attr_len)
+                .ok_or_else(|| invalid_data("stun attribute truncated"))?;
+            ans.push(decode_attribute(attr_type, value, &transaction_id)?);
+            offset += 4 + padded(attr_len);
+        }
+        Ok(ans)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() < HEADER_LEN {
+            return Err(too_small());
+        }
+        let method = self.method;
+        let message_type = (method & 0x000f) | ((method & 0x0070) << 1) | ((method & 0x0f80) << 2)
+            | self.class.bits();
+        buf[0..2].copy_from_slice(&message_type.to_be_bytes());
+        buf[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf[8..20].copy_from_slice(&self.transaction_id);
+        let mut offset = HEADER_LEN;
+        for attribute in &self.attributes {
+            offset += encode_attribute(attribute, &mut buf[offset..], &self.transaction_id)?;
+        }
+        let len = offset - HEADER_LEN;
+        if len > u16::MAX as usize {
+            return Err(invalid_data("stun message too long"));
+        }
+        buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(offset)
+    }
+}
+
+fn decode_attribute(attr_type: u16, value: &[u8], transaction_id: &[u8; 12]) -> io::Result<Attribute> {
+    Ok(match attr_type {
+        ATTR_MAPPED_ADDRESS => Attribute::MappedAddress(decode_address(value, None)?),
+        ATTR_XOR_MAPPED_ADDRESS => Attribute::XorMappedAddress(decode_address(value, Some(transaction_id))?),
+        ATTR_SOFTWARE => Attribute::Software(decode_text(value)?),
+        ATTR_ERROR_CODE => {
+            if value.len() < 4 {
+                return Err(invalid_data("stun error code truncated"));
+            }
+            if value[3] >= 100 {
+                return Err(invalid_data("stun error code number out of range"));
+            }
+            let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+            Attribute::ErrorCode { code, reason: decode_text(&value[4..])? }
+        }
+        attr_type => Attribute::Unknown { attr_type, value: value.to_vec() },
+    })
+}
+
+fn encode_attribute(attribute: &Attribute, buf: &mut [u8], transaction_id: &[u8; 12]) -> io::Result<usize> {
+    let mut value = [0u8; MAX_MESSAGE_LEN];
+    let (attr_type, len) = match attribute {
+        Attribute::MappedAddress(addr) =>
+            (ATTR_MAPPED_ADDRESS, encode_address(addr, None, &mut value)),
+        Attribute::XorMappedAddress(addr) =>
+            (ATTR_XOR_MAPPED_ADDRESS, encode_address(addr, Some(transaction_id), &mut value)),
+        Attribute::Software(text) => (ATTR_SOFTWARE, copy_value(text.as_bytes(), &mut value)?),
+        Attribute::ErrorCode { code, reason } => {
+            if !(300..=699).contains(code) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "stun error code out of range"));
+            }
+            value[2] = (code / 100) as u8;
+            value[3] = (code % 100) as u8;
+            (ATTR_ERROR_CODE, 4 + copy_value(reason.as_bytes(), &mut value[4..])?)
+        }
+        Attribute::Unknown { attr_type, value: raw } => (*attr_type, copy_value(raw, &mut value)?),
+    };
+    let total = 4 + padded(len);
+    if buf.len() < total {
+        return Err(too_small());
+    }
+    buf[0..2].copy_from_slice(&attr_type.to_be_bytes());
+    buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[4..4 + len].copy_from_slice(&value[..len]);
+    for b in &mut buf[4 + len..total] {
+        *b = 0;
+    }
+    Ok(total)
+}
+
+#[inline]
+fn copy_value(src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+    if src.len() > dst.len() {
+        return Err(invalid_data("stun attribute too long"));
+    }
+    dst[..src.len()].copy_from_slice(src);
+    Ok(src.len())
+}
+
+#[inline]
+fn decode_text(value: &[u8]) -> io::Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| invalid_data("stun text attribute is not utf-8"))
+}
+
+fn decode_address(value: &[u8], xor: Option<&[u8; 12]>) -> io::Result<SocketAddr> {
+    if value.len() < 4 {
+        return Err(invalid_data("stun address truncated"));
+    }
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    if xor.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match (value[1], value.len()) {
+        (FAMILY_IPV4, 8) => {
+            let mut octets = [value[4], value[5], value[6], value[7]];
+            if xor.is_some() {
+                for (b, k) in octets.iter_mut().zip(cookie.iter()) {
+                    *b ^= k;
+                }
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (FAMILY_IPV6, 20) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            if let Some(transaction_id) = xor {
+                let key = cookie.iter().chain(transaction_id.iter());
+                for (b, k) in octets.iter_mut().zip(key) {
+                    *b ^= k;
+                }
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(invalid_data("stun address family unknown")),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn encode_address(addr: &SocketAddr, xor: Option<&[u8; 12]>, value: &mut [u8]) -> usize {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = addr.port();
+    if xor.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    value[0] = 0;
+    value[2..4].copy_from_slice(&port.to_be_bytes());
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value[1] = FAMILY_IPV4;
+            let mut octets = ip.octets();
+            if xor.is_some() {
+                for (b, k) in octets.iter_mut().zip(cookie.iter()) {
+                    *b ^= k;
+                }
+            }
+            value[4..8].copy_from_slice(&octets);
+            8
+        }
+        IpAddr::V6(ip) => {
+            value[1] = FAMILY_IPV6;
+            let mut octets = ip.octets();
+            if let Some(transaction_id) = xor {
+                let key = cookie.iter().chain(transaction_id.iter());
+                for (b, k) in octets.iter_mut().zip(key) {
+                    *b ^= k;
+                }
+            }
+            value[4..20].copy_from_slice(&octets);
+            20
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Class {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(*u.choose(&[Class::Request, Class::Indication, Class::SuccessResponse, Class::ErrorResponse])?)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_addr(u: &mut arbitrary::Unstructured) -> arbitrary::Result<SocketAddr> {
+    // flow info and scope id are not on the wire, so leave them zero
+    let ip = if u.arbitrary()? {
+        IpAddr::V4(Ipv4Addr::from(u.arbitrary::<u32>()?))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(u.arbitrary::<u128>()?))
+    };
+    Ok(SocketAddr::new(ip, u.arbitrary()?))
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Attribute {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=4)? {
+            0 => Attribute::MappedAddress(arbitrary_addr(u)?),
+            1 => Attribute::XorMappedAddress(arbitrary_addr(u)?),
+            2 => Attribute::Software(u.arbitrary()?),
+            3 => Attribute::ErrorCode { code: u.int_in_range(300..=699)?, reason: u.arbitrary()? },
+            // 0x4000 keeps the type clear of the attributes decoded above
+            _ => Attribute::Unknown { attr_type: u.arbitrary::<u16>()? | 0x4000, value: u.arbitrary()? },
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl arbitrary::Arbitrary for Message {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        let mut transaction_id = [0u8; 12];
+        u.fill_buffer(&mut transaction_id)?;
+        let mut ans = Self::new(u.arbitrary()?, u.int_in_range(0..=0x0fff)?, transaction_id);
+        for _ in 0..u.int_in_range(0..=4)? {
+            ans.push(u.arbitrary()?);
+        }
+        Ok(ans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5769, 2.2. Sample IPv4 Response
+    fn sample_ipv4_response() -> Vec<u8> {
+        crate::fixture::load("stun/rfc5769-ipv4-response.hex")
+    }
+
+    #[test]
+    fn decode_rfc5769_response() {
+        let msg = Message::decode(&sample_ipv4_response()).unwrap();
+        assert_eq!(msg.class(), Class::SuccessResponse);
+        assert_eq!(msg.method(), METHOD_BINDING);
+        assert_eq!(msg.mapped_address(), Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(msg.attributes()[0], Attribute::Software("test vector".to_string()));
+        assert_eq!(msg.attributes().len(), 4);
+    }
+
+    #[test]
+    fn round_trip() {
+        let id = random();
+        for addr in &["192.0.2.1:32853", "[2001:db8:1234:5678:11:2233:4455:6677]:32853"] {
+            let msg = Message::binding_response(id, addr.parse().unwrap());
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            let len = msg.encode(&mut buf).unwrap();
+            assert_eq!(len % 4, 0);
+            assert_eq!(Message::decode(&buf[..len]).unwrap(), msg);
+        }
+        let mut msg = Message::new(Class::ErrorResponse, METHOD_BINDING, id);
+        msg.push(Attribute::ErrorCode { code: 420, reason: "Unknown Attribute".to_string() });
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+        assert_eq!(Message::decode(&buf[..len]).unwrap(), msg);
+    }
+
+    #[test]
+    fn encode_rejects_bad_input() {
+        let kind = |msg: &Message, buf: &mut [u8]| msg.encode(buf).unwrap_err().kind();
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let msg = Message::binding_response([0; 12], "192.0.2.1:32853".parse().unwrap());
+        assert_eq!(kind(&msg, &mut buf[..HEADER_LEN - 1]), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(&msg, &mut buf[..HEADER_LEN + 4]), io::ErrorKind::InvalidInput);
+        for &code in &[0, 299, 700, 1000] {
+            let mut msg = Message::new(Class::ErrorResponse, METHOD_BINDING, [0; 12]);
+            msg.push(Attribute::ErrorCode { code, reason: String::new() });
+            assert_eq!(kind(&msg, &mut buf), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(Message::decode(&[]).is_err());
+        let sample = sample_ipv4_response();
+        assert!(Message::decode(&sample[..40]).is_err());
+        let mut bad_cookie = sample;
+        bad_cookie[4] = 0;
+        assert!(Message::decode(&bad_cookie).is_err());
+        let mut msg = Message::new(Class::ErrorResponse, METHOD_BINDING, [0; 12]);
+        msg.push(Attribute::ErrorCode { code: 420, reason: String::new() });
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+        buf[HEADER_LEN + 7] = 100;
+        assert!(Message::decode(&buf[..len]).is_err());
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_round_trip() {
+        crate::prop::for_all(|msg: Message| {
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            if let Ok(len) = msg.encode(&mut buf) {
+                assert_eq!(Message::decode(&buf[..len]).unwrap(), msg);
+            }
+        });
+    }
+
+    #[test]
+    fn discover_loopback() {
+        let server = LajiStun::new(|| |_origin| {}).bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addrs().unwrap()[0];
+        thread::spawn(move || server.run().unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mapped = query(&socket, addr).unwrap();
+        assert_eq!(mapped, socket.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,166 @@
+//! The time protocol's and NTP's timestamps, converted to and from `SystemTime`.
+//!
+//! Both count from 1900-01-01 with 32 bits of seconds, so they wrap every 136 years; the
+//! first wrap, into era 1, comes on 2036-02-07. A timestamp alone cannot say which era it
+//! is in, so converting one back takes a `pivot`, a time known to be within 68 years of
+//! it, usually the local clock. Times before 1970 and offsets that come out negative work
+//! the same as any other.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds from 1900-01-01, where both formats count from, to the Unix epoch.
+pub const UNIX_OFFSET: i64 = 2_208_988_800;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Nanoseconds since the Unix epoch, negative before it.
+fn unix_nanos(time: SystemTime) -> i128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(e) => -(e.duration().as_nanos() as i128),
+    }
+}
+
+fn from_unix_nanos(nanos: i128) -> SystemTime {
+    let abs = nanos.unsigned_abs();
+    let duration = Duration::new((abs / NANOS_PER_SEC as u128) as u64, (abs % NANOS_PER_SEC as u128) as u32);
+    if nanos < 0 {
+        UNIX_EPOCH - duration
+    } else {
+        UNIX_EPOCH + duration
+    }
+}
+
+/// `time` as the time protocol (RFC 868) sends it: whole seconds since 1900, in its era.
+#[inline]
+pub fn to_rfc868(time: SystemTime) -> u32 {
+    let secs = unix_nanos(time).div_euclid(NANOS_PER_SEC) + i128::from(UNIX_OFFSET);
+    secs as u32
+}
+
+/// The time protocol's `secs`, in whichever era puts it nearest `pivot`.
+pub fn from_rfc868(secs: u32, pivot: SystemTime) -> SystemTime {
+    let pivot = unix_nanos(pivot).div_euclid(NANOS_PER_SEC) + i128::from(UNIX_OFFSET);
+    let secs = pivot + i128::from(secs.wrapping_sub(pivot as u32) as i32);
+    from_unix_nanos((secs - i128::from(UNIX_OFFSET)) * NANOS_PER_SEC)
+}
+
+/// An NTP timestamp (RFC 5905): 32 bits of seconds since 1900, in its era, and 32 bits of
+/// fraction.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct NtpTimestamp(u64);
+
+impl NtpTimestamp {
+    /// The timestamp as read off the wire, seconds in the high half.
+    #[inline]
+    pub fn from_bits(bits: u64) -> Self {
+        NtpTimestamp(bits)
+    }
+
+    #[inline]
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn seconds(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    /// The fraction of a second, in units of 2^-32 seconds.
+    #[inline]
+    pub fn fraction(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        NtpTimestamp(fixed_point(time) as u64)
+    }
+
+    /// The time this stands for, in whichever era puts it nearest `pivot`.
+    pub fn to_system_time(self, pivot: SystemTime) -> SystemTime {
+        let pivot = fixed_point(pivot);
+        let fixed = pivot + i128::from(self.0.wrapping_sub(pivot as u64) as i64);
+        // round to the nearest nanosecond, so a conversion there and back is exact
+        let nanos = (fixed * NANOS_PER_SEC + (1 << 31)).div_euclid(1 << 32);
+        from_unix_nanos(nanos - i128::from(UNIX_OFFSET) * NANOS_PER_SEC)
+    }
+
+    /// `self - earlier`, negative if `earlier` is later, counted across an era rollover as
+    /// long as the two are within 68 years of each other.
+    pub fn signed_since(self, earlier: NtpTimestamp) -> chrono::Duration {
+        let diff = i128::from(self.0.wrapping_sub(earlier.0) as i64);
+        let nanos = (diff * NANOS_PER_SEC).div_euclid(1 << 32);
+        chrono::Duration::nanoseconds(nanos as i64)
+    }
+}
+
+/// `time` in 2^-32 seconds since 1900-01-01 of era 0, negative before it.
+fn fixed_point(time: SystemTime) -> i128 {
+    let nanos = unix_nanos(time) + i128::from(UNIX_OFFSET) * NANOS_PER_SEC;
+    (nanos << 32).div_euclid(NANOS_PER_SEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(secs: i64) -> SystemTime {
+        from_unix_nanos(i128::from(secs) * NANOS_PER_SEC)
+    }
+
+    // 2036-02-07T06:28:16Z, when the 32-bit seconds wrap to era 1
+    const ROLLOVER: i64 = 2_085_978_496;
+
+    #[test]
+    fn rfc868_fixed_dates() {
+        assert_eq!(to_rfc868(unix(0)), 2_208_988_800);
+        // 2003-07-01T08:52:37Z
+        assert_eq!(to_rfc868(unix(1_057_049_557)), 3_266_038_357);
+        assert_eq!(to_rfc868(unix(ROLLOVER - 1)), u32::MAX);
+        assert_eq!(to_rfc868(unix(ROLLOVER)), 0);
+        assert_eq!(to_rfc868(unix(ROLLOVER + 5)), 5);
+        // 1900-01-01 itself, and a second before 1970
+        assert_eq!(to_rfc868(unix(-UNIX_OFFSET)), 0);
+        assert_eq!(to_rfc868(unix(-1)), 2_208_988_799);
+        assert_eq!(to_rfc868(unix(-1) + Duration::from_millis(999)), 2_208_988_799);
+
+        let now = unix(1_057_049_557);
+        assert_eq!(from_rfc868(3_266_038_357, now), now);
+        assert_eq!(from_rfc868(3_266_038_327, now), unix(1_057_049_527));
+        assert_eq!(from_rfc868(2_208_988_799, now), unix(-1));
+        // just past the rollover, judged from just before it and the other way round
+        assert_eq!(from_rfc868(5, unix(ROLLOVER - 60)), unix(ROLLOVER + 5));
+        assert_eq!(from_rfc868(u32::MAX, unix(ROLLOVER + 60)), unix(ROLLOVER - 1));
+        assert_eq!(from_rfc868(0, unix(-UNIX_OFFSET)), unix(-UNIX_OFFSET));
+    }
+
+    #[test]
+    fn ntp_fixed_dates() {
+        let time = unix(1_057_049_557) + Duration::from_millis(250);
+        let ntp = NtpTimestamp::from_system_time(time);
+        assert_eq!((ntp.seconds(), ntp.fraction()), (3_266_038_357, 1 << 30));
+        assert_eq!(ntp.to_system_time(time), time);
+        assert_eq!(NtpTimestamp::from_bits(ntp.to_bits()), ntp);
+
+        let after = unix(ROLLOVER) + Duration::from_nanos(123_456_789);
+        let ntp = NtpTimestamp::from_system_time(after);
+        assert_eq!(ntp.seconds(), 0);
+        assert_eq!(ntp.to_system_time(unix(ROLLOVER - 3600)), after);
+        assert_eq!(ntp.to_system_time(unix(ROLLOVER + 50 * 365 * 86400)), after);
+        let before = unix(-1) + Duration::from_nanos(1);
+        assert_eq!(NtpTimestamp::from_system_time(before).to_system_time(unix(0)), before);
+        // 1970 is more than 68 years after 1900, so zero there already means 2036
+        assert_eq!(NtpTimestamp::default().to_system_time(unix(0)), unix(ROLLOVER));
+        assert_eq!(NtpTimestamp::default().to_system_time(unix(-UNIX_OFFSET + 86400)), unix(-UNIX_OFFSET));
+    }
+
+    #[test]
+    fn signed_offsets() {
+        let before = NtpTimestamp::from_system_time(unix(ROLLOVER - 1));
+        let after = NtpTimestamp::from_system_time(unix(ROLLOVER + 1) + Duration::from_millis(500));
+        assert!(after < before);
+        assert_eq!(after.signed_since(before), chrono::Duration::milliseconds(2500));
+        assert_eq!(before.signed_since(after), chrono::Duration::milliseconds(-2500));
+        assert_eq!(before.signed_since(before), chrono::Duration::zero());
+    }
+}
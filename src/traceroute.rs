@@ -0,0 +1,314 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    process,
+    time::{Duration, Instant},
+};
+use socket2::{SockAddr, Socket};
+use crate::icmp::{self, Message};
+
+/// Trace the route to `host` with the default `Tracer` settings.
+///
+/// Needs a raw socket to see the ICMP answers, so usually root or CAP_NET_RAW. IPv4 only.
+pub fn trace<A, H>(host: A, handler: &mut H) -> io::Result<Vec<Hop>>
+where
+    A: ToSocketAddrs,
+    H: Handler
+{
+    Tracer::new()?.trace(host, handler)
+}
+
+const DEFAULT_MAX_HOPS: u8 = 30;
+const DEFAULT_PROBES: usize = 3;
+// the traditional traceroute base port; each probe goes to the next one up
+const DEFAULT_PORT: u16 = crate::ports::TRACEROUTE;
+const PROBE_PAYLOAD: &[u8] = b"laji-protocols traceroute";
+const MAX_PACKET_LEN: usize = 1500;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum Mode {
+    /// UDP datagrams to unlikely ports; the destination answers with port unreachable.
+    Udp,
+    /// ICMP echo requests; the destination answers with an echo reply.
+    Icmp,
+}
+
+pub trait Handler {
+    fn on_hop(&mut self, _hop: &Hop) {}
+}
+
+impl Handler for () {}
+
+impl<F> Handler for F
+where
+    F: FnMut(&Hop)
+{
+    #[inline]
+    fn on_hop(&mut self, hop: &Hop) {
+        self(hop)
+    }
+}
+
+/// What came back for one TTL.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Hop {
+    ttl: u8,
+    addr: Option<Ipv4Addr>,
+    rtts: Vec<Option<Duration>>,
+    reached: bool,
+}
+
+impl Hop {
+    #[inline]
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    /// Address of the router (or the destination) that answered; `None` if no probe was answered.
+    #[inline]
+    pub fn addr(&self) -> Option<Ipv4Addr> {
+        self.addr
+    }
+
+    /// One entry per probe, `None` for probes that timed out.
+    #[inline]
+    pub fn rtts(&self) -> &[Option<Duration>] {
+        &self.rtts
+    }
+
+    /// Whether this hop is the destination itself.
+    #[inline]
+    pub fn reached(&self) -> bool {
+        self.reached
+    }
+}
+
+#[derive(Debug)]
+pub struct Tracer {
+    socket: Socket,
+    mode: Mode,
+    max_hops: u8,
+    probes: usize,
+    timeout: Duration,
+    port: u16,
+    identifier: u16,
+}
+
+impl Tracer {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            socket: icmp::raw_socket()?,
+            mode: Mode::Udp,
+            max_hops: DEFAULT_MAX_HOPS,
+            probes: DEFAULT_PROBES,
+            timeout: Duration::from_secs(1),
+            port: DEFAULT_PORT,
+            identifier: process::id() as u16,
+        })
+    }
+
+    #[inline]
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    #[inline]
+    pub fn max_hops(mut self, max_hops: u8) -> Self {
+        self.max_hops = max_hops.max(1);
+        self
+    }
+
+    #[inline]
+    pub fn probes(mut self, probes: usize) -> Self {
+        self.probes = probes.max(1);
+        self
+    }
+
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// First destination port for UDP probes; ignored in ICMP mode.
+    #[inline]
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn trace<A, H>(&mut self, host: A, handler: &mut H) -> io::Result<Vec<Hop>>
+    where
+        A: ToSocketAddrs,
+        H: Handler
+    {
+        let target = host.to_socket_addrs()?
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no IPv4 address to trace"))?;
+        let udp = match self.mode {
+            Mode::Udp => Some(UdpSocket::bind("0.0.0.0:0")?),
+            Mode::Icmp => None,
+        };
+        let mut hops = Vec::new();
+        let mut sequence = 0u16;
+        for ttl in 1..=self.max_hops {
+            let mut hop = Hop { ttl, addr: None, rtts: Vec::with_capacity(self.probes), reached: false };
+            for _ in 0..self.probes {
+                sequence = sequence.wrapping_add(1);
+                let probe = self.send_probe(udp.as_ref(), target, ttl, sequence)?;
+                match self.wait_answer(target, probe)? {
+                    Some((from, rtt, reached)) => {
+                        hop.addr.get_or_insert(from);
+                        hop.rtts.push(Some(rtt));
+                        hop.reached |= reached;
+                    }
+                    None => hop.rtts.push(None),
+                }
+            }
+            handler.on_hop(&hop);
+            let reached = hop.reached;
+            hops.push(hop);
+            if reached {
+                break;
+            }
+        }
+        Ok(hops)
+    }
+
+    fn send_probe(&self, udp: Option<&UdpSocket>, target: Ipv4Addr, ttl: u8, sequence: u16) -> io::Result<Probe> {
+        match udp {
+            Some(udp) => {
+                let port = self.port.wrapping_add(sequence);
+                udp.set_ttl(ttl as u32)?;
+                let start = Instant::now();
+                udp.send_to(PROBE_PAYLOAD, SocketAddrV4::new(target, port))?;
+                Ok(Probe::Udp { start, source_port: udp.local_addr()?.port(), port })
+            }
+            None => {
+                let mut buf = [0u8; MAX_PACKET_LEN];
+                let request = Message::EchoRequest { identifier: self.identifier, sequence, payload: PROBE_PAYLOAD };
+                let len = request.encode(&mut buf)?;
+                self.socket.set_ttl(ttl as u32)?;
+                let start = Instant::now();
+                self.socket.send_to(&buf[..len], &SockAddr::from(SocketAddrV4::new(target, 0)))?;
+                Ok(Probe::Icmp { start, identifier: self.identifier, sequence })
+            }
+        }
+    }
+
+    /// Wait for the ICMP message answering `probe`; the flag is set once it came from the destination.
+    fn wait_answer(&self, target: Ipv4Addr, probe: Probe) -> io::Result<Option<(Ipv4Addr, Duration, bool)>> {
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let deadline = probe.start() + self.timeout;
+        icmp::recv_until(&self.socket, &mut buf, deadline, |from, message| {
+            probe.answered_by(message, from, target).map(|reached| (from, probe.start().elapsed(), reached))
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Probe {
+    Udp { start: Instant, source_port: u16, port: u16 },
+    Icmp { start: Instant, identifier: u16, sequence: u16 },
+}
+
+impl Probe {
+    #[inline]
+    fn start(&self) -> Instant {
+        match *self {
+            Probe::Udp { start, .. } | Probe::Icmp { start, .. } => start,
+        }
+    }
+
+    /// `Some(reached)` if `message`, from `from`, answers this probe; reached only when the
+    /// target itself echoed, or said the probed UDP port is unreachable.
+    fn answered_by(&self, message: &Message, from: Ipv4Addr, target: Ipv4Addr) -> Option<bool> {
+        let (original, reached) = match (*self, *message) {
+            (Probe::Icmp { identifier, sequence, .. }, Message::EchoReply { identifier: i, sequence: s, .. }) =>
+                return if i == identifier && s == sequence { Some(from == target) } else { None },
+            (_, Message::TimeExceeded { original, .. }) => (original, false),
+            (_, Message::DestinationUnreachable { code, original }) =>
+                (original, code == icmp::CODE_PORT_UNREACHABLE && from == target),
+            _ => return None,
+        };
+        // the router quotes our IP header and at least the first 8 bytes after it
+        let quoted = icmp::strip_ipv4_header(original)?;
+        if quoted.len() < 8 {
+            return None;
+        }
+        let first = u16::from_be_bytes([quoted[0], quoted[1]]);
+        let second = u16::from_be_bytes([quoted[2], quoted[3]]);
+        let matched = match *self {
+            Probe::Udp { source_port, port, .. } => first == source_port && second == port,
+            Probe::Icmp { identifier, sequence, .. } => quoted[0] == icmp::TYPE_ECHO_REQUEST
+                && u16::from_be_bytes([quoted[4], quoted[5]]) == identifier
+                && u16::from_be_bytes([quoted[6], quoted[7]]) == sequence,
+        };
+        if matched { Some(reached) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IPv4 header as a router would quote it, protocol UDP
+    const QUOTED_IP: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x35, 0x00, 0x00, 0x40, 0x00, 0x01, 0x11, 0x00, 0x00,
+        0x0a, 0x00, 0x00, 0x02, 0x5d, 0xb8, 0xd8, 0x22,
+    ];
+
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    #[test]
+    fn match_udp_probe() {
+        let probe = Probe::Udp { start: Instant::now(), source_port: 40000, port: 33435 };
+        let mut original = QUOTED_IP.to_vec();
+        original.extend_from_slice(&[0x9c, 0x40, 0x82, 0x9b, 0x00, 0x21, 0x00, 0x00]);
+        let exceeded = Message::TimeExceeded { code: 0, original: &original };
+        assert_eq!(probe.answered_by(&exceeded, ROUTER, TARGET), Some(false));
+        let unreachable = Message::DestinationUnreachable { code: 3, original: &original };
+        assert_eq!(probe.answered_by(&unreachable, TARGET, TARGET), Some(true));
+        // a router that cannot forward it answers the probe without it reaching the target
+        assert_eq!(probe.answered_by(&unreachable, ROUTER, TARGET), Some(false));
+        let host_unreachable = Message::DestinationUnreachable { code: 1, original: &original };
+        assert_eq!(probe.answered_by(&host_unreachable, TARGET, TARGET), Some(false));
+        let other = Probe::Udp { start: Instant::now(), source_port: 40000, port: 33436 };
+        assert_eq!(other.answered_by(&exceeded, ROUTER, TARGET), None);
+    }
+
+    #[test]
+    fn match_icmp_probe() {
+        let probe = Probe::Icmp { start: Instant::now(), identifier: 0x1234, sequence: 2 };
+        let mut original = QUOTED_IP.to_vec();
+        original.extend_from_slice(&[icmp::TYPE_ECHO_REQUEST, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x02]);
+        let exceeded = Message::TimeExceeded { code: 0, original: &original };
+        assert_eq!(probe.answered_by(&exceeded, ROUTER, TARGET), Some(false));
+        let reply = Message::EchoReply { identifier: 0x1234, sequence: 2, payload: &[] };
+        assert_eq!(probe.answered_by(&reply, TARGET, TARGET), Some(true));
+        assert_eq!(probe.answered_by(&reply, ROUTER, TARGET), Some(false));
+        let late = Message::EchoReply { identifier: 0x1234, sequence: 1, payload: &[] };
+        assert_eq!(probe.answered_by(&late, TARGET, TARGET), None);
+        assert_eq!(probe.answered_by(&Message::TimeExceeded { code: 0, original: &QUOTED_IP }, ROUTER, TARGET), None);
+    }
+
+    #[test]
+    fn loopback_port_unreachable_fixture() {
+        let packet = crate::fixture::load("icmp/port-unreachable-loopback.hex");
+        let quoted = icmp::strip_ipv4_header(&packet).unwrap();
+        let message = Message::decode(quoted).unwrap();
+        let probe = Probe::Udp { start: Instant::now(), source_port: 40000, port: 33435 };
+        assert_eq!(probe.answered_by(&message, Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST), Some(true));
+        crate::fixture::assert_encodes(quoted, |buf| message.encode(buf));
+        if let Message::DestinationUnreachable { original, .. } = message {
+            assert!(original.ends_with(b"laji-protocols traceroute"));
+        } else {
+            panic!("expected destination unreachable, got {:?}", message);
+        }
+    }
+}
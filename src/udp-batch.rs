@@ -0,0 +1,217 @@
+use std::{io, net::{SocketAddr, UdpSocket}};
+
+/// Outgoing datagrams queued up to be sent together by `flush`.
+///
+/// On Linux a flush is a single `sendmmsg` call (repeated only if the kernel
+/// accepts part of the batch); elsewhere each datagram is sent with `send_to`.
+#[derive(Debug)]
+pub struct Batch {
+    data: Vec<u8>,
+    packets: Vec<(SocketAddr, usize, usize)>,
+    capacity: usize,
+}
+
+impl Batch {
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            data: Vec::new(),
+            packets: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, target: SocketAddr, payload: &[u8]) {
+        let start = self.data.len();
+        self.data.extend_from_slice(payload);
+        self.packets.push((target, start, self.data.len()));
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.packets.len() >= self.capacity
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.packets.clear();
+    }
+
+    /// Send every queued datagram and empty the batch, returning how many were sent.
+    pub fn flush(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let ans = send_all(socket, &self.data, &self.packets);
+        self.clear();
+        ans
+    }
+}
+
+/// Receive a datagram only if one is already waiting, leaving the socket blocking.
+///
+/// On Linux this polls the socket without waiting first; elsewhere nothing is ever
+/// ready, so batches stay at one datagram where `flush` could not send them together.
+#[cfg(target_os = "linux")]
+pub fn recv_ready(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fd = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    loop {
+        match unsafe { libc::poll(&mut fd, 1, 0) } {
+            0 => return Ok(None),
+            ans if ans > 0 => return socket.recv_from(buf).map(Some),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Receive a datagram only if one is already waiting, leaving the socket blocking.
+///
+/// On Linux this polls the socket without waiting first; elsewhere nothing is ever
+/// ready, so batches stay at one datagram where `flush` could not send them together.
+#[cfg(not(target_os = "linux"))]
+#[inline]
+pub fn recv_ready(_socket: &UdpSocket, _buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_all(socket: &UdpSocket, data: &[u8], packets: &[(SocketAddr, usize, usize)]) -> io::Result<usize> {
+    for &(target, start, end) in packets {
+        socket.send_to(&data[start..end], target)?;
+    }
+    Ok(packets.len())
+}
+
+#[cfg(target_os = "linux")]
+fn send_all(socket: &UdpSocket, data: &[u8], packets: &[(SocketAddr, usize, usize)]) -> io::Result<usize> {
+    use std::{mem, os::unix::io::AsRawFd};
+
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = packets.iter()
+        .map(|(target, _, _)| sockaddr_from(target))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = packets.iter()
+        .map(|&(_, start, end)| libc::iovec {
+            iov_base: data[start..end].as_ptr() as *mut libc::c_void,
+            iov_len: end - start,
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = addrs.iter_mut().zip(iovecs.iter_mut())
+        .map(|((storage, len), iov)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = storage as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = *len;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let remaining = &mut msgs[sent..];
+        let ans = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), remaining.as_mut_ptr(), remaining.len() as libc::c_uint, 0)
+        };
+        if ans < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        sent += ans as usize;
+    }
+    Ok(sent)
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr_from(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    use std::mem;
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn flush_batch() -> io::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let target = client.local_addr()?;
+        let mut batch = Batch::with_capacity(3);
+        batch.push(target, b"one");
+        batch.push(target, b"two");
+        batch.push(target, b"three");
+        assert!(batch.is_full());
+        assert_eq!(batch.flush(&server)?, 3);
+        assert!(batch.is_empty());
+        let mut buf = [0u8; 16];
+        for expected in &[&b"one"[..], b"two", b"three"] {
+            let (len, from) = client.recv_from(&mut buf)?;
+            assert_eq!(&buf[..len], *expected);
+            assert_eq!(from, server.local_addr()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn recv_only_ready() -> io::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        let mut buf = [0u8; 16];
+        assert_eq!(recv_ready(&server, &mut buf)?, None);
+        client.send_to(b"laji", server.local_addr()?)?;
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(recv_ready(&server, &mut buf)?, Some((4, client.local_addr()?)));
+        assert_eq!(recv_ready(&server, &mut buf)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,708 @@
+//! An in-process network of virtual hosts, for tests that need several nodes but no OS sockets.
+//!
+//! Hosts are joined by links with a latency and, for datagrams, a loss rate. Time is real
+//! time, so the crate's blocking servers and clients run on it unchanged, each in its own
+//! thread, through the `Datagram` trait or the `Read`/`Write` impls on `TcpStream`.
+use std::{
+    cmp::{self, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    net::{self, IpAddr, Shutdown, SocketAddr, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+const MAX_DATAGRAM_LEN: usize = 65507;
+const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// What the crate's UDP code needs from a socket, so it runs on both `std::net` and `virtnet`.
+pub trait Datagram {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize>;
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Datagram for net::UdpSocket {
+    #[inline]
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        net::UdpSocket::send_to(self, buf, target)
+    }
+
+    #[inline]
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        net::UdpSocket::recv_from(self, buf)
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        net::UdpSocket::local_addr(self)
+    }
+
+    #[inline]
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        net::UdpSocket::read_timeout(self)
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        net::UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+/// How traffic between two hosts behaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Link {
+    latency: Duration,
+    loss: f64,
+}
+
+impl Link {
+    #[inline]
+    pub fn new() -> Self {
+        Self { latency: Duration::from_millis(0), loss: 0.0 }
+    }
+
+    /// One-way delay; a stream keeps the latency its link had when it connected.
+    #[inline]
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Chance from 0 to 1 that a datagram is dropped; streams are reliable and never lose bytes.
+    #[inline]
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+}
+
+impl Default for Link {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A handle to the network; clones refer to the same one.
+#[derive(Clone, Debug)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    // next ephemeral port to try, per host
+    hosts: HashMap<IpAddr, u16>,
+    links: HashMap<(IpAddr, IpAddr), Link>,
+    default_link: Link,
+    udp: HashMap<SocketAddr, Arc<Inbox>>,
+    listeners: HashMap<SocketAddr, Arc<Backlog>>,
+    // local ends of outgoing streams
+    streams: HashSet<SocketAddr>,
+    sequence: u64,
+    rng: u64,
+}
+
+impl Network {
+    #[inline]
+    pub fn new() -> Self {
+        Self::with_seed(DEFAULT_SEED)
+    }
+
+    /// The seed drives which datagrams lossy links drop.
+    pub fn with_seed(seed: u64) -> Self {
+        let state = State {
+            hosts: HashMap::new(),
+            links: HashMap::new(),
+            default_link: Link::new(),
+            udp: HashMap::new(),
+            listeners: HashMap::new(),
+            streams: HashSet::new(),
+            sequence: 0,
+            // xorshift gets stuck at zero
+            rng: cmp::max(seed, 1),
+        };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Add a host, or get another handle to one already added with this address.
+    pub fn host<A>(&self, ip: A) -> Host
+    where
+        A: Into<IpAddr>
+    {
+        let ip = ip.into();
+        self.lock().hosts.entry(ip).or_insert(FIRST_EPHEMERAL_PORT);
+        Host { net: self.clone(), ip }
+    }
+
+    /// Configure the link between `a` and `b`, in both directions.
+    pub fn link<A, B>(&self, a: A, b: B, link: Link)
+    where
+        A: Into<IpAddr>,
+        B: Into<IpAddr>
+    {
+        let (a, b) = (a.into(), b.into());
+        self.lock().links.insert((cmp::min(a, b), cmp::max(a, b)), link);
+    }
+
+    /// The link used between hosts that have no link of their own.
+    #[inline]
+    pub fn set_default_link(&self, link: Link) {
+        self.lock().default_link = link;
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Default for Network {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn link(&self, a: IpAddr, b: IpAddr) -> Link {
+        if a == b {
+            return Link::new();
+        }
+        *self.links.get(&(cmp::min(a, b), cmp::max(a, b))).unwrap_or(&self.default_link)
+    }
+
+    #[inline]
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    fn lose(&mut self, link: Link) -> bool {
+        if link.loss <= 0.0 {
+            return false;
+        }
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let draw = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        ((draw >> 11) as f64 / (1u64 << 53) as f64) < link.loss
+    }
+
+    fn bind<F>(&mut self, ip: IpAddr, port: u16, in_use: F) -> io::Result<SocketAddr>
+    where
+        F: Fn(&Self, SocketAddr) -> bool
+    {
+        if port != 0 {
+            let addr = SocketAddr::new(ip, port);
+            if in_use(self, addr) {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use"));
+            }
+            return Ok(addr);
+        }
+        let mut port = self.hosts[&ip];
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX {
+            let addr = SocketAddr::new(ip, port);
+            port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !in_use(self, addr) {
+                self.hosts.insert(ip, port);
+                return Ok(addr);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no ephemeral ports left"))
+    }
+}
+
+/// Block on `ready` until notified or `wake`, `None` meaning no time limit.
+fn wait_until<'a, T>(ready: &Condvar, guard: MutexGuard<'a, T>, wake: Option<Instant>) -> MutexGuard<'a, T> {
+    match wake {
+        Some(wake) => {
+            let now = Instant::now();
+            let wait = if wake > now { wake - now } else { Duration::from_millis(0) };
+            ready.wait_timeout(guard, wait).unwrap().0
+        }
+        None => ready.wait(guard).unwrap(),
+    }
+}
+
+#[inline]
+fn timed_out() -> io::Error {
+    // what std hands back on Unix when a read timeout passes
+    io::Error::new(io::ErrorKind::WouldBlock, "timed out")
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::from_millis(0)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
+    }
+    Ok(())
+}
+
+fn resolve<A>(addr: A) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs
+{
+    addr.to_socket_addrs()?.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))
+}
+
+#[derive(Clone, Debug)]
+pub struct Host {
+    net: Network,
+    ip: IpAddr,
+}
+
+impl Host {
+    #[inline]
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    #[inline]
+    pub fn network(&self) -> &Network {
+        &self.net
+    }
+
+    /// Port 0 picks a free ephemeral port, as with the OS.
+    pub fn bind_udp(&self, port: u16) -> io::Result<UdpSocket> {
+        let mut state = self.net.lock();
+        let addr = state.bind(self.ip, port, |state, addr| state.udp.contains_key(&addr))?;
+        let inbox = Arc::new(Inbox::default());
+        state.udp.insert(addr, inbox.clone());
+        Ok(UdpSocket { net: self.net.clone(), addr, inbox, read_timeout: Mutex::new(None) })
+    }
+
+    pub fn listen_tcp(&self, port: u16) -> io::Result<TcpListener> {
+        let mut state = self.net.lock();
+        let addr = state.bind(self.ip, port, |state, addr|
+            state.listeners.contains_key(&addr) || state.streams.contains(&addr))?;
+        let backlog = Arc::new(Backlog::default());
+        state.listeners.insert(addr, backlog.clone());
+        Ok(TcpListener { net: self.net.clone(), addr, backlog })
+    }
+
+    /// Connecting takes no time; refused when nothing listens at `addr`.
+    pub fn connect_tcp<A>(&self, addr: A) -> io::Result<TcpStream>
+    where
+        A: ToSocketAddrs
+    {
+        let peer = resolve(addr)?;
+        let mut state = self.net.lock();
+        let backlog = state.listeners.get(&peer).cloned().ok_or_else(||
+            io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"))?;
+        let local = state.bind(self.ip, 0, |state, addr|
+            state.listeners.contains_key(&addr) || state.streams.contains(&addr))?;
+        state.streams.insert(local);
+        let latency = state.link(self.ip, peer.ip()).latency;
+        drop(state);
+        let (up, down) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let client = TcpStream::new(&self.net, local, peer, latency, down.clone(), up.clone(), true);
+        let server = TcpStream::new(&self.net, peer, local, latency, up, down, false);
+        backlog.queue.lock().unwrap().push_back(server);
+        backlog.ready.notify_one();
+        Ok(client)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct Parcel {
+    at: Instant,
+    sequence: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    queue: Mutex<BinaryHeap<Reverse<Parcel>>>,
+    ready: Condvar,
+}
+
+#[derive(Debug)]
+pub struct UdpSocket {
+    net: Network,
+    addr: SocketAddr,
+    inbox: Arc<Inbox>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl UdpSocket {
+    /// Datagrams to addresses nobody is bound to vanish, as they would on a real network.
+    pub fn send_to<A>(&self, buf: &[u8], target: A) -> io::Result<usize>
+    where
+        A: ToSocketAddrs
+    {
+        let target = resolve(target)?;
+        if buf.len() > MAX_DATAGRAM_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+        }
+        let mut state = self.net.lock();
+        let link = state.link(self.addr.ip(), target.ip());
+        let inbox = match state.udp.get(&target) {
+            Some(inbox) => inbox.clone(),
+            None => return Ok(buf.len()),
+        };
+        if state.lose(link) {
+            return Ok(buf.len());
+        }
+        let parcel = Parcel {
+            at: Instant::now() + link.latency,
+            sequence: state.next_sequence(),
+            from: self.addr,
+            data: buf.to_vec(),
+        };
+        drop(state);
+        inbox.queue.lock().unwrap().push(Reverse(parcel));
+        inbox.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    /// Like the OS, a datagram longer than `buf` is cut short.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self.read_timeout()?.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.inbox.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next = match queue.peek() {
+                Some(Reverse(parcel)) if parcel.at <= now => {
+                    let Reverse(parcel) = queue.pop().unwrap();
+                    let len = cmp::min(buf.len(), parcel.data.len());
+                    buf[..len].copy_from_slice(&parcel.data[..len]);
+                    return Ok((len, parcel.from));
+                }
+                Some(Reverse(parcel)) => Some(parcel.at),
+                None => None,
+            };
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(timed_out());
+            }
+            let wake = match (next, deadline) {
+                (Some(next), Some(deadline)) => Some(cmp::min(next, deadline)),
+                (next, deadline) => next.or(deadline),
+            };
+            queue = wait_until(&self.inbox.ready, queue, wake);
+        }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    #[inline]
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    #[inline]
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+impl Datagram for UdpSocket {
+    #[inline]
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    #[inline]
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    #[inline]
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UdpSocket::read_timeout(self)
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.net.lock().udp.remove(&self.addr);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    queue: Mutex<VecDeque<TcpStream>>,
+    ready: Condvar,
+}
+
+#[derive(Debug)]
+pub struct TcpListener {
+    net: Network,
+    addr: SocketAddr,
+    backlog: Arc<Backlog>,
+}
+
+impl TcpListener {
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut queue = self.backlog.queue.lock().unwrap();
+        loop {
+            if let Some(stream) = queue.pop_front() {
+                let peer = stream.peer;
+                return Ok((stream, peer));
+            }
+            queue = wait_until(&self.backlog.ready, queue, None);
+        }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        self.net.lock().listeners.remove(&self.addr);
+    }
+}
+
+/// One direction of a stream.
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    // the writer shut down; a reader that drained the chunks sees end of stream
+    write_closed: bool,
+    // the reader went away; writing fails
+    read_closed: bool,
+}
+
+impl Pipe {
+    fn close(&self, write: bool, read: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.write_closed |= write;
+        state.read_closed |= read;
+        if read {
+            state.chunks.clear();
+        }
+        self.ready.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpStream {
+    net: Network,
+    local: SocketAddr,
+    peer: SocketAddr,
+    latency: Duration,
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+    // whether `local` is an ephemeral port this stream holds
+    owns_port: bool,
+}
+
+impl TcpStream {
+    fn new(net: &Network, local: SocketAddr, peer: SocketAddr, latency: Duration,
+        incoming: Arc<Pipe>, outgoing: Arc<Pipe>, owns_port: bool) -> Self
+    {
+        Self { net: net.clone(), local, peer, latency, incoming, outgoing, read_timeout: Mutex::new(None), owns_port }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    #[inline]
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    #[inline]
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.outgoing.close(true, false);
+        }
+        if how != Shutdown::Write {
+            self.incoming.close(false, true);
+        }
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout()?.map(|timeout| Instant::now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let closed = state.write_closed || state.read_closed;
+            let next = match state.chunks.front_mut() {
+                Some((at, chunk)) if *at <= now => {
+                    let len = cmp::min(buf.len(), chunk.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    chunk.drain(..len);
+                    if chunk.is_empty() {
+                        state.chunks.pop_front();
+                    }
+                    return Ok(len);
+                }
+                Some((at, _)) => Some(*at),
+                None if closed => return Ok(0),
+                None => None,
+            };
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(timed_out());
+            }
+            let wake = match (next, deadline) {
+                (Some(next), Some(deadline)) => Some(cmp::min(next, deadline)),
+                (next, deadline) => next.or(deadline),
+            };
+            state = wait_until(&self.incoming.ready, state, wake);
+        }
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.read_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed by peer"));
+        }
+        if state.write_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream shut down for writing"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // keep chunks in order even if the clock is coarse
+        let at = Instant::now() + self.latency;
+        let at = state.chunks.back().map_or(at, |&(last, _)| cmp::max(last, at));
+        state.chunks.push_back((at, buf.to_vec()));
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.outgoing.close(true, false);
+        self.incoming.close(false, true);
+        if self.owns_port {
+            self.net.lock().streams.remove(&self.local);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "holepunch")]
+    use crate::{holepunch, stun};
+    use std::{net::Ipv4Addr, thread};
+
+    #[test]
+    fn udp_links() {
+        let net = Network::new();
+        let (a, b) = (net.host(Ipv4Addr::new(10, 0, 0, 1)), net.host(Ipv4Addr::new(10, 0, 0, 2)));
+        net.link(a.ip(), b.ip(), Link::new().latency(Duration::from_millis(30)));
+        let (sa, sb) = (a.bind_udp(0).unwrap(), b.bind_udp(9).unwrap());
+        assert_eq!(sa.local_addr().unwrap().port(), FIRST_EPHEMERAL_PORT);
+        assert_eq!(b.bind_udp(9).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let start = Instant::now();
+        sa.send_to(b"laji", "10.0.0.2:9").unwrap();
+        let mut buf = [0u8; 2];
+        assert_eq!(sb.recv_from(&mut buf).unwrap(), (2, sa.local_addr().unwrap()));
+        assert_eq!(&buf, b"la");
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        net.link(a.ip(), b.ip(), Link::new().loss(1.0));
+        sa.send_to(b"laji", "10.0.0.2:9").unwrap();
+        sb.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert_eq!(sb.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn tcp_streams() {
+        let net = Network::new();
+        let (a, b) = (net.host(Ipv4Addr::new(10, 0, 0, 1)), net.host(Ipv4Addr::new(10, 0, 0, 2)));
+        net.set_default_link(Link::new().latency(Duration::from_millis(10)));
+        assert_eq!(a.connect_tcp("10.0.0.2:7").unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        let listener = b.listen_tcp(7).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            stream.read_to_end(&mut line).unwrap();
+            stream.write_all(&line).unwrap();
+            peer
+        });
+        let mut stream = a.connect_tcp("10.0.0.2:7").unwrap();
+        stream.write_all(b"hello ").unwrap();
+        stream.write_all(b"virtnet").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut echo = String::new();
+        stream.read_to_string(&mut echo).unwrap();
+        assert_eq!(echo, "hello virtnet");
+        assert_eq!(server.join().unwrap(), stream.local_addr().unwrap());
+        assert!(stream.write_all(b"gone").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "holepunch")]
+    fn stun_and_hole_punching() {
+        let net = Network::new();
+        net.set_default_link(Link::new().latency(Duration::from_millis(5)));
+        let stun_socket = net.host(Ipv4Addr::new(192, 0, 2, 1)).bind_udp(3478).unwrap();
+        thread::spawn(move || stun::serve(&stun_socket, || |_origin| {}));
+        let rendezvous = holepunch::Rendezvous::with_socket(net.host(Ipv4Addr::new(192, 0, 2, 2)).bind_udp(9000).unwrap());
+        thread::spawn(move || rendezvous.run());
+
+        let a = net.host(Ipv4Addr::new(10, 0, 0, 1)).bind_udp(0).unwrap();
+        let b = net.host(Ipv4Addr::new(10, 0, 1, 1)).bind_udp(0).unwrap();
+        assert_eq!(stun::query(&a, "192.0.2.1:3478").unwrap(), a.local_addr().unwrap());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let other = thread::spawn(move || {
+            holepunch::Puncher::new(&b).punch("192.0.2.2:9000", "laji", &mut ()).unwrap()
+        });
+        assert_eq!(holepunch::Puncher::new(&a).punch("192.0.2.2:9000", "laji", &mut ()).unwrap(), b_addr);
+        assert_eq!(other.join().unwrap(), a_addr);
+    }
+}
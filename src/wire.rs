@@ -0,0 +1,237 @@
+//! Bounds-checked cursors for binary wire formats.
+//!
+//! `ByteReader` walks a received packet and `ByteWriter` fills a send buffer, both keeping
+//! count of how much is left, so a short packet or a small buffer is an `io::Error` rather
+//! than a panic on a slice index. Integers come in big endian, network order, as `_be`,
+//! and little endian as `_le`.
+use std::io;
+
+/// An `InvalidData` error, for a received message that does not parse.
+#[inline]
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[inline]
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "message truncated")
+}
+
+/// An `InvalidInput` error, for a send buffer a message does not fit.
+#[inline]
+pub fn too_small() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "buffer too small for message")
+}
+
+macro_rules! read_int {
+    ($($be:ident, $le:ident: $ty:ty;)*) => {$(
+        #[inline]
+        pub fn $be(&mut self) -> io::Result<$ty> {
+            self.array().map(<$ty>::from_be_bytes)
+        }
+
+        #[inline]
+        pub fn $le(&mut self) -> io::Result<$ty> {
+            self.array().map(<$ty>::from_le_bytes)
+        }
+    )*};
+}
+
+macro_rules! write_int {
+    ($($be:ident, $le:ident: $ty:ty;)*) => {$(
+        #[inline]
+        pub fn $be(&mut self, value: $ty) -> io::Result<()> {
+            self.write_bytes(&value.to_be_bytes())
+        }
+
+        #[inline]
+        pub fn $le(&mut self, value: $ty) -> io::Result<()> {
+            self.write_bytes(&value.to_le_bytes())
+        }
+    )*};
+}
+
+#[derive(Clone, Debug)]
+pub struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    #[inline]
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// How many bytes were read.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// The bytes not read yet, without reading them.
+    #[inline]
+    pub fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// The next `len` bytes.
+    #[inline]
+    pub fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(truncated());
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    #[inline]
+    pub fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.take(len).map(drop)
+    }
+
+    /// Read whatever is left.
+    #[inline]
+    pub fn take_rest(&mut self) -> &'a [u8] {
+        let rest = self.rest();
+        self.pos = self.buf.len();
+        rest
+    }
+
+    #[inline]
+    fn array<A: Default + AsMut<[u8]>>(&mut self) -> io::Result<A> {
+        let mut bytes = A::default();
+        let len = bytes.as_mut().len();
+        bytes.as_mut().copy_from_slice(self.take(len)?);
+        Ok(bytes)
+    }
+
+    #[inline]
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    read_int! {
+        read_u16_be, read_u16_le: u16;
+        read_u32_be, read_u32_le: u32;
+        read_u64_be, read_u64_le: u64;
+        read_i32_be, read_i32_le: i32;
+        read_i64_be, read_i64_le: i64;
+    }
+}
+
+#[derive(Debug)]
+pub struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> ByteWriter<'a> {
+    #[inline]
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// How many bytes were written, which is what an `encode` returns.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// What was written so far.
+    #[inline]
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    /// Fail unless `len` more bytes fit, to refuse a message before writing any of it.
+    #[inline]
+    pub fn reserve(&self, len: usize) -> io::Result<()> {
+        if self.remaining() < len {
+            return Err(too_small());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.reserve(bytes.len())?;
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    /// Write `len` copies of `byte`, as padding.
+    #[inline]
+    pub fn fill(&mut self, byte: u8, len: usize) -> io::Result<()> {
+        self.reserve(len)?;
+        for b in &mut self.buf[self.pos..self.pos + len] {
+            *b = byte;
+        }
+        self.pos += len;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn write_u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_bytes(&[value])
+    }
+
+    write_int! {
+        write_u16_be, write_u16_le: u16;
+        write_u32_be, write_u32_le: u32;
+        write_u64_be, write_u64_le: u64;
+        write_i32_be, write_i32_le: i32;
+        write_i64_be, write_i64_le: i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> io::Result<()> {
+        let mut buf = [0u8; 32];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u8(7)?;
+        writer.write_u16_be(0x0102)?;
+        writer.write_u16_le(0x0102)?;
+        writer.write_u32_be(0xdead_beef)?;
+        writer.write_u64_le(42)?;
+        writer.write_i32_be(-2)?;
+        writer.write_bytes(b"laji")?;
+        writer.fill(0, 3)?;
+        assert_eq!(writer.position(), 28);
+        assert_eq!(&writer.written()[..5], [7, 1, 2, 2, 1]);
+        assert!(writer.write_u64_be(1).is_err());
+        assert_eq!(writer.position(), 28);
+        writer.write_u16_le(0xffff)?;
+
+        let mut reader = ByteReader::new(&buf[..30]);
+        assert_eq!(reader.read_u8()?, 7);
+        assert_eq!((reader.read_u16_be()?, reader.read_u16_le()?), (0x0102, 0x0102));
+        assert_eq!(reader.read_u32_be()?, 0xdead_beef);
+        assert_eq!(reader.read_u64_le()?, 42);
+        assert_eq!(reader.read_i32_be()?, -2);
+        assert_eq!(reader.take(4)?, b"laji");
+        reader.skip(3)?;
+        assert_eq!(reader.remaining(), 2);
+        assert!(reader.read_u32_le().is_err());
+        assert_eq!(reader.rest(), [0xff, 0xff]);
+        assert_eq!(reader.take_rest(), [0xff, 0xff]);
+        assert_eq!(reader.read_u8().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}